@@ -0,0 +1,154 @@
+// record every provider API call to a rotating file when started with --debug-api
+// Path: src/api_log.rs
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::Local;
+use tracing::warn;
+
+// crossroads makes the HTTP calls itself, calls are recorded at its boundary: the request
+// stands for the method, the provider and object for the URL.
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+// rotated files kept next to the current one, `debug-api.log.1` being the most recent
+const ROTATED_LOGS: usize = 3;
+const MAX_BODY_LENGTH: usize = 512;
+// values following these markers never make it to the file
+const SECRET_MARKERS: &[&str] = &[
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "client_secret",
+    "password",
+    "Bearer ",
+    "key=",
+    "token",
+];
+
+struct ApiLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+static LOG: Mutex<Option<ApiLog>> = Mutex::new(None);
+
+pub fn enable(path: PathBuf) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let size = file.metadata()?.len();
+    *LOG.lock().unwrap() = Some(ApiLog { path, file, size });
+
+    Ok(())
+}
+
+// Replaces what follows a secret marker up to the next delimiter.
+fn redact(text: &str) -> String {
+    let mut redacted = text.to_string();
+
+    for marker in SECRET_MARKERS {
+        let mut from = 0;
+        while let Some(position) = redacted[from..].find(marker) {
+            let start = from + position + marker.len();
+            // skip the separator between a key and its value, e.g. `": "`
+            let value_start = start + redacted[start..].find(|c: char| !matches!(c, '"' | '\'' | ':' | '=' | ' ')).unwrap_or(redacted.len() - start);
+            let value_end = value_start + redacted[value_start..].find(|c: char| matches!(c, '"' | '\'' | ',' | '&' | ' ' | '}' | ')')).unwrap_or(redacted.len() - value_start);
+
+            if value_end > value_start {
+                redacted.replace_range(value_start..value_end, "[redacted]");
+                from = value_start + "[redacted]".len();
+            } else {
+                from = start;
+            }
+        }
+    }
+
+    redacted
+}
+
+fn truncate(text: &str) -> String {
+    match text.char_indices().nth(MAX_BODY_LENGTH) {
+        Some((end, _)) => format!("{}... ({} bytes)", &text[..end], text.len()),
+        None => text.to_string(),
+    }
+}
+
+impl ApiLog {
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for index in (1..ROTATED_LOGS).rev() {
+            let from = PathBuf::from(format!("{}.{}", self.path.display(), index));
+            if from.exists() {
+                fs::rename(&from, format!("{}.{}", self.path.display(), index + 1))?;
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path.display()))?;
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+// `response` is the error returned by the provider or a summary of what came back.
+pub fn record(provider: &str, request: &str, target: &str, duration: Duration, ok: bool, response: &str) {
+    let mut log = LOG.lock().unwrap();
+    let log = match log.as_mut() {
+        Some(log) => log,
+        None => return,
+    };
+
+    let line = format!(
+        "{} {} {}:{} {} {} ms {}\n",
+        Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+        request,
+        provider,
+        redact(target),
+        if ok { "ok" } else { "error" },
+        duration.as_millis(),
+        truncate(&redact(response)),
+    );
+
+    if log.size + line.len() as u64 > MAX_LOG_SIZE {
+        if let Err(error) = log.rotate() {
+            warn!("debug-api: unable to rotate {}: {}", log.path.display(), error);
+        }
+    }
+
+    if log.file.write_all(line.as_bytes()).is_ok() {
+        log.size += line.len() as u64;
+    }
+}
+
+pub fn enabled() -> bool {
+    LOG.lock().unwrap().is_some()
+}
+
+#[cfg(test)]
+mod api_log_test {
+    use super::*;
+
+    #[test]
+    fn redacts_json_tokens() {
+        let body = r#"{"access_token": "ya29.secret", "expires_in": 3599, "refresh_token":"1//other"}"#;
+
+        assert_eq!(redact(body), r#"{"access_token": "[redacted]", "expires_in": 3599, "refresh_token":"[redacted]"}"#);
+    }
+
+    #[test]
+    fn redacts_headers_and_query_strings() {
+        assert_eq!(redact("Authorization: Bearer abc.def"), "Authorization: Bearer [redacted]");
+        assert_eq!(redact("https://example.com/files?key=AIza123&alt=json"), "https://example.com/files?key=[redacted]&alt=json");
+    }
+
+    #[test]
+    fn leaves_other_text_alone() {
+        let text = "read_directory root: 12 files";
+
+        assert_eq!(redact(text), text);
+    }
+}
\ No newline at end of file
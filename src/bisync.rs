@@ -0,0 +1,304 @@
+// keep a local directory and a provider path in two-way sync, without going through the mount
+// Path: src/bisync.rs
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{Local, Utc};
+use crossroads::interfaces::filesystem::{File, FileSystem, FileType, Metadata as CrossroadsMetadata, ObjectId};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::fuse::conflicted_name;
+use crate::notifications::{self, Event};
+use crate::throttle::Throttle;
+
+// What a side looked like after the last successful cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Version {
+    size: u64,
+    modified_at: i64,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    id: ObjectId,
+    is_directory: bool,
+    version: Version,
+}
+
+// Last synced version of each path on both sides, relative to the synced roots.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    local: HashMap<String, Version>,
+    remote: HashMap<String, Version>,
+}
+
+enum Action {
+    Upload,
+    Download,
+    DeleteLocal,
+    DeleteRemote,
+    // both sides changed, the older version is kept as a conflicted copy on its side before
+    // the newer one replaces it, the copy reaches the other side on the next cycle. `upload`
+    // when the local version is the newer one.
+    Conflict { upload: bool },
+}
+
+pub struct Bisync<'a, L: FileSystem + ?Sized, R: FileSystem + ?Sized> {
+    local: &'a L,
+    local_root: ObjectId,
+    remote: &'a R,
+    remote_root: ObjectId,
+    state_path: PathBuf,
+    interval: Duration,
+    throttle: Option<&'a Throttle>,
+}
+
+async fn snapshot<F: FileSystem + ?Sized>(filesystem: &F, root: &ObjectId) -> Result<HashMap<String, Entry>, String> {
+    let mut entries = HashMap::new();
+    let mut pending = vec![(String::new(), root.clone())];
+
+    while let Some((relative, id)) = pending.pop() {
+        let files = filesystem.read_directory(id).await.map_err(|error| format!("{:?}", error))?;
+
+        for file in files {
+            let path = if relative.is_empty() { file.name.clone() } else { relative.clone() + "/" + file.name.as_str() };
+            let metadata = file.metadata.as_ref();
+            let version = Version {
+                size: metadata.and_then(|metadata| metadata.size).unwrap_or(0),
+                modified_at: metadata.and_then(|metadata| metadata.modified_at).map(|modified_at| modified_at.timestamp()).unwrap_or(0),
+            };
+
+            if file.id.is_directory() {
+                pending.push((path.clone(), file.id.clone()));
+            }
+
+            entries.insert(path, Entry { id: file.id.clone(), is_directory: file.id.is_directory(), version });
+        }
+    }
+
+    Ok(entries)
+}
+
+// Copies a file over an existing one or next to its siblings, creating it first.
+async fn copy<F: FileSystem + ?Sized, T: FileSystem + ?Sized>(
+    from: &F,
+    id: &ObjectId,
+    to: &T,
+    existing: Option<&ObjectId>,
+    parent: &ObjectId,
+    name: &str,
+    throttle: Option<&Throttle>,
+) -> Result<(), String> {
+    let data = from.read_file(id.clone()).await.map_err(|error| format!("{:?}", error))?;
+    if let Some(throttle) = throttle {
+        tokio::time::sleep(throttle.delay(data.len() as u64)).await;
+    }
+
+    let target = match existing {
+        Some(existing) => existing.clone(),
+        None => {
+            let target = ObjectId::new(parent.to_string() + "/" + name, FileType::File);
+            to.create(parent.clone(), File {
+                id: target.clone(),
+                name: name.to_string(),
+                metadata: Some(CrossroadsMetadata {
+                    mime_type: None,
+                    created_at: Some(Utc::now()),
+                    modified_at: Some(Utc::now()),
+                    meta_changed_at: Some(Utc::now()),
+                    accessed_at: None,
+                    size: None,
+                    open_path: None,
+                    owner: None,
+                    permissions: None,
+                }),
+            }).await.map_err(|error| format!("{:?}", error))?;
+            target
+        },
+    };
+
+    to.write_file(target, data.into()).await.map_err(|error| format!("{:?}", error))?;
+    Ok(())
+}
+
+fn parent_id(side: &HashMap<String, Entry>, parent: &str, root: &ObjectId) -> Result<ObjectId, String> {
+    match parent.is_empty() {
+        true => Ok(root.clone()),
+        false => side.get(parent).map(|entry| entry.id.clone()).ok_or(format!("{} is missing", parent)),
+    }
+}
+
+// Names in a folder of a snapshot, a conflicted copy mustn't take one of them.
+fn siblings(side: &HashMap<String, Entry>, parent: &str) -> Vec<String> {
+    side.keys()
+        .filter_map(|path| match path.rsplit_once('/') {
+            Some((path_parent, name)) if path_parent == parent => Some(name.to_string()),
+            None if parent.is_empty() => Some(path.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+impl<'a, L: FileSystem + ?Sized, R: FileSystem + ?Sized> Bisync<'a, L, R> {
+    pub fn new(local: &'a L, local_root: ObjectId, remote: &'a R, remote_root: ObjectId, state_path: PathBuf) -> Self {
+        Self {
+            local,
+            local_root,
+            remote,
+            remote_root,
+            state_path,
+            interval: Duration::from_secs(60),
+            throttle: None,
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_throttle(mut self, throttle: &'a Throttle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    pub async fn run(&self) {
+        loop {
+            if let Err(error) = self.cycle().await {
+                warn!("bisync: {}", error);
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    fn load_state(&self) -> State {
+        fs::read_to_string(&self.state_path).ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    // Each side is compared to the state left by the previous cycle to tell which one changed.
+    // When both did, the most recent modification keeps the name.
+    pub async fn cycle(&self) -> Result<(), String> {
+        let state = self.load_state();
+        let mut local = snapshot(self.local, &self.local_root).await?;
+        let mut remote = snapshot(self.remote, &self.remote_root).await?;
+
+        // parents sort before their children, so directories get created before their content
+        let paths: BTreeSet<String> = local.keys().chain(remote.keys()).cloned().collect();
+        let mut deleted: Vec<String> = Vec::new();
+
+        for path in &paths {
+            if deleted.iter().any(|directory| path.starts_with(&(directory.clone() + "/"))) {
+                continue;
+            }
+
+            let local_entry = local.get(path);
+            let remote_entry = remote.get(path);
+            let local_changed = local_entry.map(|entry| entry.version) != state.local.get(path).copied();
+            let remote_changed = remote_entry.map(|entry| entry.version) != state.remote.get(path).copied();
+
+            let action = match (local_entry, remote_entry) {
+                (Some(local_entry), Some(remote_entry)) if local_entry.is_directory || remote_entry.is_directory => None,
+                (Some(local_entry), Some(remote_entry)) => match (local_changed, remote_changed) {
+                    (true, false) => Some(Action::Upload),
+                    (false, true) => Some(Action::Download),
+                    // the same change made on both sides
+                    (true, true) if local_entry.version == remote_entry.version => None,
+                    (true, true) => Some(Action::Conflict { upload: local_entry.version.modified_at >= remote_entry.version.modified_at }),
+                    (false, false) => None,
+                },
+                (Some(_), None) if state.remote.contains_key(path) && !local_changed => Some(Action::DeleteLocal),
+                (Some(_), None) => Some(Action::Upload),
+                (None, Some(_)) if state.local.contains_key(path) && !remote_changed => Some(Action::DeleteRemote),
+                (None, Some(_)) => Some(Action::Download),
+                (None, None) => None,
+            };
+
+            if let Some(action) = action {
+                if matches!(action, Action::DeleteLocal | Action::DeleteRemote) {
+                    deleted.push(path.clone());
+                }
+                self.apply(action, path, &mut local, &mut remote).await?;
+            }
+        }
+
+        // the next cycle compares against what both sides look like now
+        let local = snapshot(self.local, &self.local_root).await?;
+        let remote = snapshot(self.remote, &self.remote_root).await?;
+        let state = State {
+            local: local.iter().map(|(path, entry)| (path.clone(), entry.version)).collect(),
+            remote: remote.iter().map(|(path, entry)| (path.clone(), entry.version)).collect(),
+        };
+        fs::write(&self.state_path, serde_json::to_string(&state).unwrap()).map_err(|error| error.to_string())?;
+
+        Ok(())
+    }
+
+    // Directories created along the way are added to the snapshots so their content can follow.
+    async fn apply(&self, action: Action, path: &str, local: &mut HashMap<String, Entry>, remote: &mut HashMap<String, Entry>) -> Result<(), String> {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+
+        match action {
+            Action::Upload => {
+                let entry = local[path].clone();
+                let parent_id = if parent.is_empty() { self.remote_root.clone() } else { remote.get(parent).ok_or(format!("{} has no parent on the provider", path))?.id.clone() };
+                info!("bisync: upload {}", path);
+
+                if entry.is_directory {
+                    let id = ObjectId::directory(parent_id.to_string() + "/" + name);
+                    self.remote.create(parent_id, File { id: id.clone(), name: name.to_string(), metadata: None }).await.map_err(|error| format!("{:?}", error))?;
+                    remote.insert(path.to_string(), Entry { id, ..entry });
+                } else {
+                    copy(self.local, &entry.id, self.remote, remote.get(path).map(|entry| &entry.id), &parent_id, name, self.throttle).await?;
+                }
+            },
+            Action::Download => {
+                let entry = remote[path].clone();
+                let parent_id = if parent.is_empty() { self.local_root.clone() } else { local.get(parent).ok_or(format!("{} has no local parent", path))?.id.clone() };
+                info!("bisync: download {}", path);
+
+                if entry.is_directory {
+                    let id = ObjectId::directory(parent_id.to_string() + "/" + name);
+                    self.local.create(parent_id, File { id: id.clone(), name: name.to_string(), metadata: None }).await.map_err(|error| format!("{:?}", error))?;
+                    local.insert(path.to_string(), Entry { id, ..entry });
+                } else {
+                    copy(self.remote, &entry.id, self.local, local.get(path).map(|entry| &entry.id), &parent_id, name, self.throttle).await?;
+                }
+            },
+            Action::Conflict { upload } => {
+                let copy_name = match upload {
+                    true => {
+                        let parent_id = parent_id(remote, parent, &self.remote_root)?;
+                        let copy_name = conflicted_name(name, &Local::now(), &siblings(remote, parent));
+                        copy(self.remote, &remote[path].id, self.remote, None, &parent_id, &copy_name, self.throttle).await?;
+                        copy(self.local, &local[path].id, self.remote, Some(&remote[path].id), &parent_id, name, self.throttle).await?;
+                        copy_name
+                    },
+                    false => {
+                        let parent_id = parent_id(local, parent, &self.local_root)?;
+                        let copy_name = conflicted_name(name, &Local::now(), &siblings(local, parent));
+                        copy(self.local, &local[path].id, self.local, None, &parent_id, &copy_name, self.throttle).await?;
+                        copy(self.remote, &remote[path].id, self.local, Some(&local[path].id), &parent_id, name, self.throttle).await?;
+                        copy_name
+                    },
+                };
+                warn!("bisync: {} changed on both sides, the {} version is kept as {}", path, if upload { "provider's" } else { "local" }, copy_name);
+                notifications::notify(Event::Conflict { name: name.to_string(), copy: copy_name });
+            },
+            Action::DeleteLocal => {
+                info!("bisync: delete local {}", path);
+                self.local.delete(local[path].id.clone()).await.map_err(|error| format!("{:?}", error))?;
+            },
+            Action::DeleteRemote => {
+                info!("bisync: delete remote {}", path);
+                self.remote.delete(remote[path].id.clone()).await.map_err(|error| format!("{:?}", error))?;
+            },
+        }
+
+        Ok(())
+    }
+}
\ No newline at end of file
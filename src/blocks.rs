@@ -0,0 +1,189 @@
+// keep chunks of recently read files in memory in front of the content cache
+// Path: src/blocks.rs
+use std::collections::HashMap;
+
+pub const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+pub const DEFAULT_MEMORY_SIZE: u64 = 256 * 1024 * 1024;
+pub const DEFAULT_READ_AHEAD: u64 = 4;
+
+// A chunk of a file, by provider, object, version and index in the file.
+type BlockKey = (String, String, String, u64);
+
+struct Block {
+    data: Vec<u8>,
+    // the least recently used blocks are dropped first
+    used: u64,
+}
+
+// Files are split in chunks of `chunk_size` bytes, the last one is shorter. Reads covered by
+// chunks in memory don't reach the disk cache nor the provider.
+pub struct BlockCache {
+    chunk_size: u64,
+    max_memory: u64,
+    memory: u64,
+    clock: u64,
+    blocks: HashMap<BlockKey, Block>,
+}
+
+impl BlockCache {
+    pub fn new(chunk_size: u64, max_memory: u64) -> Self {
+        Self {
+            chunk_size: std::cmp::max(chunk_size, 1),
+            max_memory,
+            memory: 0,
+            clock: 0,
+            blocks: HashMap::new(),
+        }
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    // The requested range, if every chunk it covers is in memory.
+    pub fn read(&mut self, provider: &str, object: &str, version: &str, offset: u64, size: usize) -> Option<Vec<u8>> {
+        let end = offset + size as u64;
+        let mut data = Vec::with_capacity(size);
+        let mut index = offset / self.chunk_size;
+
+        while index * self.chunk_size < end {
+            self.clock += 1;
+            let block = self.blocks.get_mut(&(provider.to_string(), object.to_string(), version.to_string(), index))?;
+            block.used = self.clock;
+
+            let start = index * self.chunk_size;
+            let from = (std::cmp::max(offset, start) - start) as usize;
+            let to = std::cmp::min((end - start) as usize, block.data.len());
+            data.extend_from_slice(&block.data[std::cmp::min(from, to)..to]);
+
+            // a short chunk ends the file
+            if (block.data.len() as u64) < self.chunk_size {
+                break;
+            }
+            index += 1;
+        }
+
+        Some(data)
+    }
+
+    pub fn contains(&self, provider: &str, object: &str, version: &str, index: u64) -> bool {
+        self.blocks.contains_key(&(provider.to_string(), object.to_string(), version.to_string(), index))
+    }
+
+    // Whether a whole file may be kept in memory, one taking more than half of it would
+    // push out everything else.
+    pub fn fits(&self, size: u64) -> bool {
+        size <= self.max_memory / 2
+    }
+
+    // Splits content starting at a chunk boundary in chunks, `offset` must be a multiple of
+    // the chunk size. `eof` tells the content goes up to the end of the file.
+    pub fn insert(&mut self, provider: &str, object: &str, version: &str, offset: u64, data: &[u8], eof: bool) {
+        let first = offset / self.chunk_size;
+        for (count, chunk) in data.chunks(self.chunk_size as usize).enumerate() {
+            self.insert_block((provider.to_string(), object.to_string(), version.to_string(), first + count as u64), chunk.to_vec());
+        }
+
+        // a file ending on a chunk boundary still needs its last, empty, chunk
+        if eof && data.len() as u64 % self.chunk_size == 0 {
+            let last = first + data.len() as u64 / self.chunk_size;
+            self.insert_block((provider.to_string(), object.to_string(), version.to_string(), last), Vec::new());
+        }
+    }
+
+    // Forgets every version of an object, after it was written to.
+    pub fn remove(&mut self, provider: &str, object: &str) {
+        let memory = &mut self.memory;
+        self.blocks.retain(|(block_provider, block_object, _, _), block| {
+            let keep = block_provider != provider || block_object != object;
+            if !keep {
+                *memory -= block.data.len() as u64;
+            }
+            keep
+        });
+    }
+
+    fn insert_block(&mut self, key: BlockKey, data: Vec<u8>) {
+        if data.len() as u64 > self.max_memory {
+            return;
+        }
+
+        self.clock += 1;
+        self.memory += data.len() as u64;
+        if let Some(previous) = self.blocks.insert(key, Block { data, used: self.clock }) {
+            self.memory -= previous.data.len() as u64;
+        }
+
+        while self.memory > self.max_memory {
+            let oldest = match self.blocks.iter().min_by_key(|(_, block)| block.used) {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            if let Some(block) = self.blocks.remove(&oldest) {
+                self.memory -= block.data.len() as u64;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod blocks_test {
+    use super::*;
+
+    #[test]
+    fn reads_across_chunks() {
+        let mut blocks = BlockCache::new(4, 1024);
+        blocks.insert("gdrive", "a", "v1", 0, b"abcdefghij", true);
+
+        assert_eq!(blocks.read("gdrive", "a", "v1", 0, 10), Some(b"abcdefghij".to_vec()));
+        assert_eq!(blocks.read("gdrive", "a", "v1", 2, 5), Some(b"cdefg".to_vec()));
+        // the short last chunk ends the file
+        assert_eq!(blocks.read("gdrive", "a", "v1", 8, 10), Some(b"ij".to_vec()));
+        assert_eq!(blocks.read("gdrive", "a", "v2", 0, 4), None);
+    }
+
+    #[test]
+    fn file_ending_on_a_chunk_boundary() {
+        let mut blocks = BlockCache::new(4, 1024);
+        blocks.insert("gdrive", "a", "v1", 0, b"abcdefgh", true);
+
+        assert!(blocks.contains("gdrive", "a", "v1", 2));
+        assert_eq!(blocks.read("gdrive", "a", "v1", 4, 10), Some(b"efgh".to_vec()));
+        assert_eq!(blocks.read("gdrive", "a", "v1", 8, 4), Some(Vec::new()));
+    }
+
+    #[test]
+    fn missing_chunk_misses_the_read() {
+        let mut blocks = BlockCache::new(4, 1024);
+        blocks.insert("gdrive", "a", "v1", 4, b"efghijkl", false);
+
+        assert!(!blocks.contains("gdrive", "a", "v1", 3));
+        assert_eq!(blocks.read("gdrive", "a", "v1", 4, 8), Some(b"efghijkl".to_vec()));
+        assert_eq!(blocks.read("gdrive", "a", "v1", 0, 8), None);
+        // past what was inserted, the file goes on
+        assert_eq!(blocks.read("gdrive", "a", "v1", 8, 8), None);
+    }
+
+    #[test]
+    fn drops_the_least_recently_used_chunks() {
+        let mut blocks = BlockCache::new(4, 8);
+        blocks.insert("gdrive", "a", "v1", 0, b"abcdefgh", false);
+        blocks.read("gdrive", "a", "v1", 4, 4);
+        blocks.insert("gdrive", "b", "v1", 0, b"wxyz", false);
+
+        assert!(!blocks.contains("gdrive", "a", "v1", 0));
+        assert!(blocks.contains("gdrive", "a", "v1", 1));
+        assert!(blocks.contains("gdrive", "b", "v1", 0));
+    }
+
+    #[test]
+    fn remove_forgets_every_version() {
+        let mut blocks = BlockCache::new(4, 1024);
+        blocks.insert("gdrive", "a", "v1", 0, b"abcd", true);
+        blocks.insert("gdrive", "a", "v2", 0, b"efgh", true);
+        blocks.remove("gdrive", "a");
+
+        assert!(!blocks.contains("gdrive", "a", "v1", 0));
+        assert!(!blocks.contains("gdrive", "a", "v2", 0));
+    }
+}
\ No newline at end of file
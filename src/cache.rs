@@ -0,0 +1,774 @@
+// keep the content of files read from providers on disk, optionally encrypted
+// Path: src/cache.rs
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::blocks::{DEFAULT_CHUNK_SIZE, DEFAULT_MEMORY_SIZE, DEFAULT_READ_AHEAD};
+use crate::config::config_file;
+
+// Limits are read from `cache.toml` in the config directory, sizes are in bytes, e.g.
+//
+//     dir = "/var/cache/orbital"
+//     max_size = 10737418240
+//     min_free = 1073741824
+//     chunk_size = 1048576
+//     memory_size = 268435456
+//     read_ahead = 4
+//     disk = true
+//
+//     [providers]
+//     work = 2147483648
+const CACHE_FILE_NAME: &str = "cache.toml";
+// in the data directory next to the credential files, which never have this name
+pub const KEY_FILE_NAME: &str = "cache.key";
+const INDEX_DIR_NAME: &str = "index";
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+// Encrypted entries are sealed in chunks of this many bytes, each with a nonce of its own, so
+// a range is read without decrypting the rest of the entry.
+const SEALED_CHUNK_SIZE: usize = 64 * 1024;
+const PARTIAL_DIR_NAME: &str = "partial";
+// entry names of the pinned objects, kept when their entry is replaced or removed
+const PINS_TREE_NAME: &str = "pins";
+
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+const DEFAULT_MIN_FREE: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub dir: PathBuf,
+    // total size of the entries
+    pub max_size: u64,
+    // free space left on the disk holding the cache, whatever the other limits
+    pub min_free: u64,
+    // size of the entries of each provider, by name
+    pub providers: HashMap<String, u64>,
+    // files are kept in memory by chunks of this size
+    pub chunk_size: u64,
+    // total size of the chunks in memory
+    pub memory_size: u64,
+    // chunks loaded ahead of sequential reads, providers can set their own
+    pub read_ahead: u64,
+    // without it files are only kept in memory
+    pub disk: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            dir: ProjectDirs::from("", "Orbital", "Files").map(|proj_dirs| proj_dirs.cache_dir().join("content")).unwrap_or_default(),
+            max_size: DEFAULT_MAX_SIZE,
+            min_free: DEFAULT_MIN_FREE,
+            providers: HashMap::new(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            memory_size: DEFAULT_MEMORY_SIZE,
+            read_ahead: DEFAULT_READ_AHEAD,
+            disk: true,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn load() -> Self {
+        let path = match config_file(CACHE_FILE_NAME) {
+            Some(path) => path,
+            None => return Self::default(),
+        };
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => return Self::default(),
+        };
+
+        match toml::from_str(&content) {
+            Ok(config) => config,
+            Err(error) => {
+                warn!("ignoring {}: {}", path.display(), error);
+                Self::default()
+            },
+        }
+    }
+}
+
+// What the index knows about a cached object, a remount reads it back instead of walking
+// the cache directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    provider: String,
+    // revision of the object the entry holds, any other revision misses the cache
+    version: String,
+    // bytes on disk
+    size: u64,
+    // seconds since the epoch, the least recently used entries are evicted first
+    used_at: u64,
+    // kept whatever the limits
+    pinned: bool,
+    encrypted: bool,
+    // sealed in chunks rather than whole
+    #[serde(default)]
+    chunked: bool,
+}
+
+// Encrypts content with the cache key, each time with a nonce of its own put in front.
+#[derive(Clone)]
+pub struct Sealer(XChaCha20Poly1305);
+
+impl Sealer {
+    pub fn seal(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.0.encrypt(&nonce, data).map_err(|_| io::Error::new(io::ErrorKind::Other, "unable to encrypt"))?;
+        Ok([nonce.as_slice(), ciphertext.as_slice()].concat())
+    }
+
+    pub fn open(&self, content: &[u8]) -> io::Result<Vec<u8>> {
+        if content.len() < NONCE_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated"));
+        }
+        let (nonce, ciphertext) = content.split_at(NONCE_SIZE);
+        self.0.decrypt(XNonce::from_slice(nonce), ciphertext).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "unable to decrypt"))
+    }
+
+    // Each chunk is sealed with its position and whether it ends the entry, so chunks can't
+    // be swapped around or the entry cut short without the next read failing.
+    fn seal_chunks(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let chunks: Vec<&[u8]> = match data.is_empty() {
+            true => vec![data],
+            false => data.chunks(SEALED_CHUNK_SIZE).collect(),
+        };
+
+        let mut content = Vec::with_capacity(data.len() + chunks.len() * (NONCE_SIZE + TAG_SIZE));
+        for (index, chunk) in chunks.iter().enumerate() {
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let aad = chunk_aad(index as u64, index + 1 == chunks.len());
+            let ciphertext = self.0.encrypt(&nonce, Payload { msg: chunk, aad: &aad }).map_err(|_| io::Error::new(io::ErrorKind::Other, "unable to encrypt"))?;
+            content.extend_from_slice(&nonce);
+            content.extend_from_slice(&ciphertext);
+        }
+        Ok(content)
+    }
+
+    fn open_chunk(&self, index: u64, last: bool, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        self.0.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: &chunk_aad(index, last) })
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "unable to decrypt"))
+    }
+
+    fn open_chunks(&self, content: &[u8]) -> io::Result<Vec<u8>> {
+        if content.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated"));
+        }
+        let count = sealed_chunks(content.len() as u64);
+        let mut data = Vec::with_capacity(content.len());
+        for (index, sealed) in content.chunks(SEALED_CHUNK_SIZE + NONCE_SIZE + TAG_SIZE).enumerate() {
+            data.extend(self.open_chunk(index as u64, index as u64 + 1 == count, sealed)?);
+        }
+        Ok(data)
+    }
+
+    // Up to `size` bytes from `offset` of an entry taking `sealed_size` bytes in `file`,
+    // only the chunks holding them are read and decrypted.
+    fn open_range(&self, file: &File, sealed_size: u64, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let chunk_size = SEALED_CHUNK_SIZE as u64;
+        let sealed_chunk_size = chunk_size + (NONCE_SIZE + TAG_SIZE) as u64;
+        let count = sealed_chunks(sealed_size);
+        let length = sealed_size.saturating_sub(count * (NONCE_SIZE + TAG_SIZE) as u64);
+
+        let start = std::cmp::min(offset, length);
+        let end = std::cmp::min(start + size as u64, length);
+        if start == end {
+            return Ok(Vec::new());
+        }
+
+        let first = start / chunk_size;
+        let mut data = Vec::new();
+        for index in first..=(end - 1) / chunk_size {
+            let at = index * sealed_chunk_size;
+            let mut sealed = vec![0; std::cmp::min(sealed_chunk_size, sealed_size - at) as usize];
+            file.read_exact_at(&mut sealed, at)?;
+            data.extend(self.open_chunk(index, index + 1 == count, &sealed)?);
+        }
+
+        let from = (start - first * chunk_size) as usize;
+        Ok(data[from..from + (end - start) as usize].to_vec())
+    }
+}
+
+fn chunk_aad(index: u64, last: bool) -> [u8; 9] {
+    let mut aad = [0; 9];
+    aad[..8].copy_from_slice(&index.to_le_bytes());
+    aad[8] = last as u8;
+    aad
+}
+
+// Chunks of an entry sealed in `sealed_size` bytes, an empty entry still has one.
+fn sealed_chunks(sealed_size: u64) -> u64 {
+    let sealed_chunk_size = (SEALED_CHUNK_SIZE + NONCE_SIZE + TAG_SIZE) as u64;
+    std::cmp::max(1, (sealed_size + sealed_chunk_size - 1) / sealed_chunk_size)
+}
+
+// Entries are named after a hash of their provider and object so object names and paths
+// don't show up in the cache directory either, the index only holds those hashes too.
+pub struct ContentCache {
+    dir: PathBuf,
+    index: sled::Db,
+    pins: sled::Tree,
+    cipher: Option<Sealer>,
+    config: CacheConfig,
+    // bytes used by the entries of each provider
+    usage: Mutex<HashMap<String, u64>>,
+}
+
+impl ContentCache {
+    pub fn new(config: CacheConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let index = sled::open(config.dir.join(INDEX_DIR_NAME)).map_err(index_error)?;
+        let pins = index.open_tree(PINS_TREE_NAME).map_err(index_error)?;
+
+        // leftovers of interrupted writes
+        let partial_dir = config.dir.join(PARTIAL_DIR_NAME);
+        fs::create_dir_all(&partial_dir)?;
+        remove_entries(&partial_dir, |_| true)?;
+
+        let cache = Self {
+            dir: config.dir.clone(),
+            index,
+            pins,
+            cipher: None,
+            config,
+            usage: Mutex::new(HashMap::new()),
+        };
+
+        // entries written before the index existed can't be told apart, start over
+        if !cache.index.was_recovered() {
+            cache.remove_unindexed()?;
+        }
+        cache.load_usage();
+
+        Ok(cache)
+    }
+
+    // Every entry is encrypted with a key generated on first use and kept next to the
+    // credentials in `key_dir`, so cached files are no more exposed than the accounts they
+    // come from. Entries cached in plaintext before, or sealed whole, are removed.
+    pub fn with_encryption(mut self, key_dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(key_dir)?;
+        let key_path = key_dir.join(KEY_FILE_NAME);
+
+        let key = match fs::read(&key_path) {
+            Ok(key) if key.len() == 32 => *Key::from_slice(&key),
+            _ => {
+                let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+                OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&key_path)?.write_all(&key)?;
+                key
+            },
+        };
+
+        for (name, record) in self.records() {
+            if !record.encrypted || !record.chunked {
+                self.remove_entry(&name);
+            }
+        }
+
+        self.cipher = Some(Sealer(XChaCha20Poly1305::new(&key)));
+        self.load_usage();
+        Ok(self)
+    }
+
+    pub fn get(&self, provider: &str, object: &str, version: &str) -> Option<Vec<u8>> {
+        let name = entry_name(provider, object);
+        let mut record = self.record(&name)?;
+        if record.version != version || record.encrypted != self.cipher.is_some() {
+            return None;
+        }
+
+        let content = match fs::read(self.dir.join(&name)) {
+            Ok(content) => content,
+            Err(_) => {
+                self.remove_entry(&name);
+                return None;
+            },
+        };
+
+        // keeps the entry away from eviction
+        record.used_at = now();
+        self.save_record(&name, &record);
+
+        match &self.cipher {
+            Some(cipher) => {
+                let data = cipher.open_chunks(&content).ok();
+                // written with a key that was since replaced
+                if data.is_none() {
+                    self.remove_entry(&name);
+                }
+                data
+            },
+            None => Some(content),
+        }
+    }
+
+    // Reads part of an entry without loading the rest, encrypted entries only have the
+    // chunks covering the range decrypted.
+    pub fn read_range(&self, provider: &str, object: &str, version: &str, offset: u64, size: usize) -> Option<Vec<u8>> {
+        let name = entry_name(provider, object);
+        let mut record = self.record(&name)?;
+        if record.version != version || record.encrypted != self.cipher.is_some() {
+            return None;
+        }
+
+        let file = match File::open(self.dir.join(&name)) {
+            Ok(file) => file,
+            Err(_) => {
+                self.remove_entry(&name);
+                return None;
+            },
+        };
+
+        let data = match &self.cipher {
+            Some(cipher) => match cipher.open_range(&file, record.size, offset, size) {
+                Ok(data) => data,
+                // written with a key that was since replaced
+                Err(_) => {
+                    self.remove_entry(&name);
+                    return None;
+                },
+            },
+            None => {
+                let start = std::cmp::min(offset, record.size);
+                let mut data = vec![0; std::cmp::min(size as u64, record.size - start) as usize];
+                file.read_exact_at(&mut data, start).ok()?;
+                data
+            },
+        };
+
+        record.used_at = now();
+        self.save_record(&name, &record);
+        Some(data)
+    }
+
+    // Replaces any other version of the object. Older entries are evicted to stay within the
+    // configured limits, an entry that can't fit is refused.
+    pub fn put(&self, provider: &str, object: &str, version: &str, data: &[u8]) -> io::Result<()> {
+        let content = match &self.cipher {
+            Some(cipher) => cipher.seal_chunks(data)?,
+            None => data.to_vec(),
+        };
+        let size = content.len() as u64;
+
+        let name = entry_name(provider, object);
+        let pinned = self.pins.contains_key(&name).unwrap_or(false);
+        self.remove_entry(&name);
+        self.make_room(provider, size)?;
+
+        // written aside first so a crash never leaves a truncated entry behind
+        let partial = self.dir.join(PARTIAL_DIR_NAME).join(&name);
+        OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&partial)?.write_all(&content)?;
+        fs::rename(partial, self.dir.join(&name))?;
+
+        self.save_record(&name, &Record {
+            provider: provider.to_string(),
+            version: version.to_string(),
+            size,
+            used_at: now(),
+            pinned,
+            encrypted: self.cipher.is_some(),
+            chunked: self.cipher.is_some(),
+        });
+        *self.usage.lock().unwrap().entry(provider.to_string()).or_default() += size;
+        Ok(())
+    }
+
+    // The writes kept on disk until they are sent are encrypted with the same key.
+    pub fn sealer(&self) -> Option<Sealer> {
+        self.cipher.clone()
+    }
+
+    pub fn contains(&self, provider: &str, object: &str, version: &str) -> bool {
+        self.record(&entry_name(provider, object)).map_or(false, |record| record.version == version && record.encrypted == self.cipher.is_some())
+    }
+
+    // Pinned objects are never evicted, whatever version of them gets cached.
+    pub fn set_pinned(&self, provider: &str, object: &str, pinned: bool) -> io::Result<()> {
+        let name = entry_name(provider, object);
+        let result = match pinned {
+            true => self.pins.insert(&name, provider.as_bytes()).map(|_| ()),
+            false => self.pins.remove(&name).map(|_| ()),
+        };
+        result.map_err(index_error)?;
+
+        if let Some(mut record) = self.record(&name) {
+            record.pinned = pinned;
+            self.save_record(&name, &record);
+        }
+        Ok(())
+    }
+
+    pub fn is_pinned(&self, provider: &str, object: &str) -> bool {
+        self.pins.contains_key(entry_name(provider, object)).unwrap_or(false)
+    }
+
+    pub fn pinned_count(&self, provider: &str) -> usize {
+        self.pins.iter().values().filter_map(|value| value.ok()).filter(|value| value.as_ref() == provider.as_bytes()).count()
+    }
+
+    pub fn remove(&self, provider: &str, object: &str) {
+        self.remove_entry(&entry_name(provider, object));
+    }
+
+    // Makes sure the index is on disk, sled otherwise writes it in the background.
+    pub fn flush(&self) -> io::Result<()> {
+        self.index.flush().map(|_| ()).map_err(index_error)
+    }
+
+    // Bytes used by the entries of every provider.
+    pub fn size(&self) -> u64 {
+        self.usage.lock().unwrap().values().sum()
+    }
+
+    pub fn provider_size(&self, provider: &str) -> u64 {
+        self.usage.lock().unwrap().get(provider).copied().unwrap_or(0)
+    }
+
+    fn make_room(&self, provider: &str, size: u64) -> io::Result<()> {
+        let refused = |reason: &str| Err(io::Error::new(io::ErrorKind::Other, format!("not cached, {}", reason)));
+
+        if let Some(quota) = self.config.providers.get(provider) {
+            self.evict(Some(provider), quota.saturating_sub(size));
+            // what is left is pinned
+            if self.used(Some(provider)) + size > *quota {
+                return refused("the provider quota is used up");
+            }
+        }
+
+        self.evict(None, self.config.max_size.saturating_sub(size));
+        if self.size() + size > self.config.max_size {
+            return refused("the cache is full");
+        }
+
+        let needed = self.config.min_free + size;
+        let available = available_space(&self.dir)?;
+        if available < needed {
+            self.evict(None, self.size().saturating_sub(needed - available));
+            if available_space(&self.dir)? < needed {
+                return refused("the disk is almost full");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn used(&self, provider: Option<&str>) -> u64 {
+        let usage = self.usage.lock().unwrap();
+        match provider {
+            Some(provider) => usage.get(provider).copied().unwrap_or(0),
+            None => usage.values().sum(),
+        }
+    }
+
+    // Removes the least recently used entries of `provider`, or of every provider, until they
+    // use at most `target` bytes. Pinned entries are left alone.
+    fn evict(&self, provider: Option<&str>, target: u64) {
+        if self.used(provider) <= target {
+            return;
+        }
+
+        let mut records: Vec<(String, Record)> = self.records()
+            .filter(|(_, record)| !record.pinned && provider.map_or(true, |provider| record.provider == provider))
+            .collect();
+        records.sort_by_key(|(_, record)| record.used_at);
+
+        for (name, _) in records {
+            if self.used(provider) <= target {
+                break;
+            }
+            self.remove_entry(&name);
+        }
+    }
+
+    fn record(&self, name: &str) -> Option<Record> {
+        let value = self.index.get(name).ok()??;
+        serde_json::from_slice(&value).ok()
+    }
+
+    fn records(&self) -> impl Iterator<Item = (String, Record)> {
+        self.index.iter()
+            .filter_map(|item| item.ok())
+            .filter_map(|(name, value)| Some((String::from_utf8(name.to_vec()).ok()?, serde_json::from_slice(&value).ok()?)))
+    }
+
+    fn save_record(&self, name: &str, record: &Record) {
+        if let Err(error) = self.index.insert(name, serde_json::to_vec(record).unwrap()) {
+            warn!("unable to update the cache index: {}", error);
+        }
+    }
+
+    fn remove_entry(&self, name: &str) {
+        let _ = fs::remove_file(self.dir.join(name));
+
+        if let Ok(Some(value)) = self.index.remove(name) {
+            if let Ok(record) = serde_json::from_slice::<Record>(&value) {
+                if let Some(used) = self.usage.lock().unwrap().get_mut(&record.provider) {
+                    *used = used.saturating_sub(record.size);
+                }
+            }
+        }
+    }
+
+    fn load_usage(&self) {
+        let mut usage = HashMap::new();
+        for (_, record) in self.records() {
+            *usage.entry(record.provider).or_default() += record.size;
+        }
+
+        *self.usage.lock().unwrap() = usage;
+    }
+
+    // Entries of the cache directory the index doesn't know about.
+    fn remove_unindexed(&self) -> io::Result<()> {
+        remove_entries(&self.dir, |name| !self.index.contains_key(name).unwrap_or(false))
+    }
+}
+
+// The directory can be set to one holding other files, only what looks like an entry is
+// removed and nothing is removed below it.
+fn remove_entries(dir: &Path, remove: impl Fn(&str) -> bool) -> io::Result<()> {
+    for entry in fs::read_dir(dir)?.filter_map(|entry| entry.ok()) {
+        let is_file = entry.file_type().map_or(false, |file_type| file_type.is_file());
+        let name = entry.file_name();
+        match name.to_str() {
+            Some(name) if is_file && is_entry_name(name) && remove(name) => fs::remove_file(entry.path())?,
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+fn entry_name(provider: &str, object: &str) -> String {
+    Sha256::digest(format!("{}:{}", provider, object).as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn is_entry_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+fn index_error(error: sled::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("cache index: {}", error))
+}
+
+// Bytes an unprivileged user can still write on the filesystem holding `dir`.
+fn available_space(dir: &Path) -> io::Result<u64> {
+    let path = CString::new(dir.as_os_str().as_bytes()).map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(test)]
+mod cache_test {
+    use super::*;
+
+    fn cache(dir: &Path, max_size: u64) -> ContentCache {
+        ContentCache::new(CacheConfig { dir: dir.to_path_buf(), max_size, min_free: 0, ..CacheConfig::default() }).unwrap()
+    }
+
+    // Entries put within the same second can't be told apart by their last use.
+    fn used_at(cache: &ContentCache, provider: &str, object: &str, used_at: u64) {
+        let name = entry_name(provider, object);
+        let mut record = cache.record(&name).unwrap();
+        record.used_at = used_at;
+        cache.save_record(&name, &record);
+    }
+
+    #[test]
+    fn get_only_the_version_put() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), 1024);
+
+        cache.put("gdrive", "a", "v1", b"hello").unwrap();
+        assert_eq!(cache.get("gdrive", "a", "v1"), Some(b"hello".to_vec()));
+        assert_eq!(cache.get("gdrive", "a", "v2"), None);
+        assert_eq!(cache.read_range("gdrive", "a", "v1", 1, 3), Some(b"ell".to_vec()));
+
+        cache.put("gdrive", "a", "v2", b"bye").unwrap();
+        assert_eq!(cache.get("gdrive", "a", "v1"), None);
+        assert_eq!(cache.provider_size("gdrive"), 3);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), 10);
+
+        cache.put("gdrive", "a", "v1", b"aaaa").unwrap();
+        cache.put("gdrive", "b", "v1", b"bbbb").unwrap();
+        used_at(&cache, "gdrive", "a", 1);
+        used_at(&cache, "gdrive", "b", 2);
+        cache.put("gdrive", "c", "v1", b"cccc").unwrap();
+
+        assert!(!cache.contains("gdrive", "a", "v1"));
+        assert!(cache.contains("gdrive", "b", "v1"));
+        assert!(cache.contains("gdrive", "c", "v1"));
+        assert_eq!(cache.size(), 8);
+    }
+
+    #[test]
+    fn keeps_pinned_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), 10);
+
+        cache.put("gdrive", "a", "v1", b"aaaa").unwrap();
+        cache.set_pinned("gdrive", "a", true).unwrap();
+        cache.put("gdrive", "b", "v1", b"bbbb").unwrap();
+        used_at(&cache, "gdrive", "a", 1);
+        used_at(&cache, "gdrive", "b", 2);
+        cache.put("gdrive", "c", "v1", b"cccc").unwrap();
+
+        assert!(cache.contains("gdrive", "a", "v1"));
+        assert!(!cache.contains("gdrive", "b", "v1"));
+        assert!(cache.is_pinned("gdrive", "a"));
+        assert_eq!(cache.pinned_count("gdrive"), 1);
+
+        // a new version of a pinned object stays pinned
+        cache.put("gdrive", "a", "v2", b"AAAA").unwrap();
+        assert!(cache.is_pinned("gdrive", "a"));
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let stray = entry_name("gdrive", "stray");
+        fs::write(dir.path().join("photo.jpg"), b"mine").unwrap();
+        fs::create_dir(dir.path().join("album")).unwrap();
+        fs::write(dir.path().join("album").join(&stray), b"mine too").unwrap();
+        fs::write(dir.path().join(&stray), b"left by an older cache").unwrap();
+
+        let cache = cache(dir.path(), 1024);
+        cache.put("gdrive", "a", "v1", b"hello").unwrap();
+
+        assert!(dir.path().join("photo.jpg").exists());
+        assert!(dir.path().join("album").join(&stray).exists());
+        assert!(!dir.path().join(&stray).exists());
+        assert!(cache.contains("gdrive", "a", "v1"));
+    }
+
+    #[test]
+    fn refuses_what_can_not_fit() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), 10);
+
+        assert!(cache.put("gdrive", "a", "v1", &[0; 11]).is_err());
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn stays_within_provider_quotas() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CacheConfig {
+            dir: dir.path().to_path_buf(),
+            min_free: 0,
+            providers: HashMap::from([("work".to_string(), 4)]),
+            ..CacheConfig::default()
+        };
+        let cache = ContentCache::new(config).unwrap();
+
+        cache.put("work", "a", "v1", b"aaaa").unwrap();
+        cache.put("work", "b", "v1", b"bbbb").unwrap();
+        cache.put("gdrive", "c", "v1", b"cccc").unwrap();
+
+        assert!(!cache.contains("work", "a", "v1"));
+        assert_eq!(cache.provider_size("work"), 4);
+        assert_eq!(cache.provider_size("gdrive"), 4);
+    }
+
+    #[test]
+    fn encrypts_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), 1024);
+        cache.put("gdrive", "plain", "v1", b"cached before").unwrap();
+
+        let cache = cache.with_encryption(keys.path()).unwrap();
+        // plaintext entries don't survive encryption being turned on
+        assert_eq!(cache.get("gdrive", "plain", "v1"), None);
+
+        cache.put("gdrive", "a", "v1", b"secret").unwrap();
+        assert_eq!(cache.get("gdrive", "a", "v1"), Some(b"secret".to_vec()));
+        assert_eq!(cache.read_range("gdrive", "a", "v1", 2, 10), Some(b"cret".to_vec()));
+
+        let on_disk = fs::read(dir.path().join(entry_name("gdrive", "a"))).unwrap();
+        assert!(!on_disk.windows(6).any(|window| window == b"secret"));
+    }
+
+    #[test]
+    fn reads_ranges_across_sealed_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), 1024 * 1024).with_encryption(keys.path()).unwrap();
+        let data: Vec<u8> = (0..SEALED_CHUNK_SIZE * 2 + 100).map(|byte| byte as u8).collect();
+
+        cache.put("gdrive", "a", "v1", &data).unwrap();
+        assert_eq!(cache.get("gdrive", "a", "v1"), Some(data.clone()));
+        let boundary = SEALED_CHUNK_SIZE as u64 - 10;
+        assert_eq!(cache.read_range("gdrive", "a", "v1", boundary, 20), Some(data[boundary as usize..boundary as usize + 20].to_vec()));
+        assert_eq!(cache.read_range("gdrive", "a", "v1", data.len() as u64 - 50, 100), Some(data[data.len() - 50..].to_vec()));
+        assert_eq!(cache.read_range("gdrive", "a", "v1", data.len() as u64, 10), Some(Vec::new()));
+
+        cache.put("gdrive", "empty", "v1", b"").unwrap();
+        assert_eq!(cache.get("gdrive", "empty", "v1"), Some(Vec::new()));
+        assert_eq!(cache.read_range("gdrive", "empty", "v1", 0, 10), Some(Vec::new()));
+    }
+
+    #[test]
+    fn refuses_a_cut_short_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), 1024 * 1024).with_encryption(keys.path()).unwrap();
+        cache.put("gdrive", "a", "v1", &vec![7; SEALED_CHUNK_SIZE * 2]).unwrap();
+
+        // the last chunk dropped, what is left still opens chunk by chunk
+        let path = dir.path().join(entry_name("gdrive", "a"));
+        let content = fs::read(&path).unwrap();
+        fs::write(&path, &content[..SEALED_CHUNK_SIZE + NONCE_SIZE + TAG_SIZE]).unwrap();
+
+        assert_eq!(cache.get("gdrive", "a", "v1"), None);
+    }
+
+    #[test]
+    fn sealer_opens_what_it_sealed() {
+        let sealer = Sealer(XChaCha20Poly1305::new(&XChaCha20Poly1305::generate_key(&mut OsRng)));
+        let sealed = sealer.seal(b"content").unwrap();
+
+        assert_eq!(sealer.open(&sealed).unwrap(), b"content");
+        assert!(sealer.open(&sealed[..NONCE_SIZE - 1]).is_err());
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(sealer.open(&tampered).is_err());
+    }
+}
\ No newline at end of file
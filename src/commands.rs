@@ -0,0 +1,667 @@
+// subcommands asking a mounted instance for information through its control directory
+// Path: src/commands.rs
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use clap::Subcommand;
+use crossroads::interfaces::filesystem::{File, FileSystem, FileType, ObjectId};
+use crossroads::providers::native_fs::NativeFs;
+use crossroads::storage::{ProviderId, ProviderType, ProvidersMap};
+use directories::ProjectDirs;
+
+use crate::bisync::Bisync;
+use crate::cache::KEY_FILE_NAME;
+use crate::config::Config;
+use crate::fuse::{await_device_token, await_loopback_token, human_size, is_auth_error, request_device_code, start_loopback_login, write_credentials, FuseFS, Health, ProviderUsage, Status, CONTROL_DIR_NAME};
+use crate::sync::{self, SyncOptions};
+use crate::throttle::Throttle;
+use crate::vault::{self, Vault, VAULT_FILE_NAME};
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "List the largest or duplicated files under a folder of the mount")]
+    Report {
+        #[arg(value_parser = ["large", "duplicates"])]
+        kind: String,
+        #[arg(long, default_value_t = 10, help = "Number of files listed by the large report")]
+        top: usize,
+        #[arg(help = "Folder of the mount, its root by default")]
+        path: Option<String>,
+    },
+    #[command(about = "Show the space and API requests used on each provider")]
+    Usage {
+        #[arg(long, help = "Print the raw JSON")]
+        json: bool,
+    },
+    #[command(about = "Copy a folder over another one, through the mount or not")]
+    Sync {
+        #[arg(long, help = "Remove from the destination what isn't in the source anymore")]
+        delete: bool,
+        #[arg(long, help = "Only print what would be done")]
+        dry_run: bool,
+        #[arg(long, help = "Files transferred at the same time")]
+        jobs: Option<usize>,
+        src: PathBuf,
+        dst: PathBuf,
+    },
+    #[command(about = "Keep a local folder and a provider folder in sync both ways")]
+    Bisync {
+        #[arg(long, default_value_t = 60, help = "Seconds between two passes")]
+        interval: u64,
+        local: PathBuf,
+        #[arg(help = "PROVIDER[/PATH]")]
+        remote: String,
+    },
+    #[command(about = "Log in to a Google Drive or OneDrive account and save its credential file")]
+    Login {
+        #[arg(value_parser = ["GoogleDrive", "OneDrive"])]
+        provider_type: String,
+        #[arg(help = "Name of the provider, its folder at the root of the mount")]
+        name: String,
+    },
+    #[command(about = "Move the credential files of the data directory to the encrypted credential store")]
+    Seal {
+        #[arg(long, value_name = "FILE", help = "File holding the passphrase, asked on the terminal otherwise")]
+        key_file: Option<PathBuf>,
+    },
+    #[command(about = "Check the credentials and connectivity of every provider")]
+    Doctor,
+    #[command(about = "Run a small round trip on every provider")]
+    Selftest,
+    #[command(about = "Show the state of the providers, transfers and caches of the mount")]
+    Status {
+        #[arg(long, help = "Redraw every second until q is pressed")]
+        watch: bool,
+    },
+}
+
+// Subcommands reading control files need `mount_point`, the others work without the mount.
+pub fn run(command: Command, mount_point: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let mounted = || mount_point.ok_or("the mount point is required, give it with --mount or ORBITAL_MOUNT");
+
+    match command {
+        Command::Report { kind, top, path } => report(&kind, top, path, mounted()?),
+        Command::Usage { json } => usage(json, mounted()?),
+        Command::Sync { delete, dry_run, jobs, src, dst } => sync(delete, dry_run, jobs, &src, &dst),
+        Command::Bisync { interval, local, remote } => bisync(Duration::from_secs(interval), &local, &remote),
+        Command::Login { provider_type, name } => login(&provider_type, &name),
+        Command::Seal { key_file } => seal(key_file.as_deref()),
+        Command::Doctor => doctor(),
+        Command::Selftest => selftest(),
+        Command::Status { watch } => status(watch, mounted()?),
+    }
+}
+
+// report large [--top N] [PATH]
+// report duplicates [PATH]
+fn report(kind: &str, top: usize, path: Option<String>, mount_point: &Path) -> Result<(), Box<dyn Error>> {
+    let path = path.map_or_else(|| "/".to_string(), |path| mount_relative(mount_point, &path));
+
+    match kind {
+        "large" => print!("{}", request(mount_point, "report", &format!("large {} {}", top, path))?),
+        "duplicates" => print!("{}", request(mount_point, "report", &format!("duplicates {}", path))?),
+        _ => return Err(format!("unknown report {}", kind).into()),
+    }
+
+    Ok(())
+}
+
+// usage [--json]
+fn usage(as_json: bool, mount_point: &Path) -> Result<(), Box<dyn Error>> {
+    let json = request(mount_point, "report", "usage")?;
+
+    if as_json {
+        println!("{}", json);
+        return Ok(());
+    }
+
+    let usages: Vec<ProviderUsage> = serde_json::from_str(&json)?;
+    let size = |bytes: Option<u64>| bytes.map(human_size).unwrap_or_else(|| "-".to_string());
+
+    let requests = |count: u64, budget: Option<u64>| match budget {
+        Some(budget) => format!("{}/{}", count, budget),
+        None => count.to_string(),
+    };
+
+    println!("{:<24} {:>10} {:>10} {:>10} {:>10} {:>7} {:>10} {:>13} {:>10}", "PROVIDER", "TOTAL", "USED", "FREE", "CACHED", "PINNED", "UPLOADING", "REQ/100S", "REQ/DAY");
+    for usage in usages {
+        println!(
+            "{:<24} {:>10} {:>10} {:>10} {:>10} {:>7} {:>10} {:>13} {:>10}",
+            usage.provider,
+            size(usage.quota_total),
+            size(usage.quota_used),
+            size(usage.quota_free),
+            human_size(usage.cached_bytes),
+            usage.pinned_files,
+            human_size(usage.pending_upload_bytes),
+            requests(usage.api_requests_100s, usage.api_budget_100s),
+            requests(usage.api_requests_today, usage.api_budget_day),
+        );
+    }
+
+    Ok(())
+}
+
+const PROGRESS_WIDTH: usize = 20;
+
+fn progress_bar(done: u64, total: u64) -> String {
+    let filled = if total == 0 { 0 } else { (done.min(total) * PROGRESS_WIDTH as u64 / total) as usize };
+    format!("[{}{}]", "#".repeat(filled), " ".repeat(PROGRESS_WIDTH - filled))
+}
+
+fn hit_rate(hits: u64, misses: u64) -> String {
+    if hits + misses == 0 {
+        "-".to_string()
+    } else {
+        format!("{:.0}%", hits as f64 * 100.0 / (hits + misses) as f64)
+    }
+}
+
+fn render_status(status: &Status) -> String {
+    let mut screen = String::new();
+
+    screen += &format!("{:<24} {:<8} {:>9} {:>9}\n", "PROVIDER", "HEALTH", "REQ/100S", "DEFERRED");
+    for provider in &status.providers {
+        let health = match provider.health {
+            Health::Online => "online",
+            Health::Offline => "offline",
+            Health::Degraded => "degraded",
+            Health::Reauth => "reauth",
+        };
+        screen += &format!("{:<24} {:<8} {:>9} {:>9}\n", provider.provider, health, provider.api_requests_100s, provider.deferred);
+    }
+
+    screen += "\ntransfers\n";
+    if status.transfers.is_empty() {
+        screen += "  none\n";
+    }
+    for transfer in &status.transfers {
+        screen += &format!(
+            "  {} {} {} / {} {:>5}s  {}\n",
+            if transfer.upload { "up  " } else { "down" },
+            progress_bar(transfer.done, transfer.total),
+            human_size(transfer.done),
+            human_size(transfer.total),
+            transfer.seconds,
+            transfer.path,
+        );
+    }
+
+    screen += &format!(
+        "\ncache hit rate: listings {}, attributes {}, content {}\n",
+        hit_rate(status.cache.listing_hits, status.cache.listing_misses),
+        hit_rate(status.cache.metadata_hits, status.cache.metadata_misses),
+        hit_rate(status.cache.content_hits, status.cache.content_misses),
+    );
+
+    screen += "\nrecent errors\n";
+    if status.errors.is_empty() {
+        screen += "  none\n";
+    }
+    for error in &status.errors {
+        screen += &format!("  {}\n", error);
+    }
+
+    screen
+}
+
+// Keys are read one at a time and not echoed while the monitor runs, the terminal is put
+// back as it was when it ends.
+struct RawTerminal(Option<libc::termios>);
+
+impl RawTerminal {
+    fn enter() -> RawTerminal {
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            // not a terminal, the monitor still redraws
+            return RawTerminal(None);
+        }
+        let mut raw = termios;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) };
+        // the alternate screen, without the cursor
+        print!("\x1b[?1049h\x1b[?25l");
+        RawTerminal(Some(termios))
+    }
+
+    // The key pressed within `timeout`, if any.
+    fn key(&self, timeout: Duration) -> Option<u8> {
+        if self.0.is_none() {
+            std::thread::sleep(timeout);
+            return None;
+        }
+        let mut poll = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) } <= 0 {
+            return None;
+        }
+        let mut key = 0u8;
+        match unsafe { libc::read(libc::STDIN_FILENO, &mut key as *mut u8 as *mut libc::c_void, 1) } {
+            1 => Some(key),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Some(termios) = &self.0 {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios) };
+            print!("\x1b[?25h\x1b[?1049l");
+            let _ = io::stdout().flush();
+        }
+    }
+}
+
+// status [--watch]
+// With --watch the screen is redrawn every second: q quits, p pauses the redraws and r
+// redraws right away.
+fn status(watch: bool, mount_point: &Path) -> Result<(), Box<dyn Error>> {
+    let path = mount_point.join(CONTROL_DIR_NAME).join("status");
+    let read = || -> Result<String, Box<dyn Error>> {
+        let status: Status = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        Ok(render_status(&status))
+    };
+
+    if !watch {
+        print!("{}", read()?);
+        return Ok(());
+    }
+
+    let terminal = RawTerminal::enter();
+    let mut paused = false;
+    let mut screen = read()?;
+
+    loop {
+        let help = if paused { "paused, p to resume, r to redraw, q to quit" } else { "p to pause, r to redraw, q to quit" };
+        // clear the terminal and go back to its top left corner
+        print!("\x1b[2J\x1b[H{}\n{}\n", screen, help);
+        io::stdout().flush()?;
+
+        match terminal.key(Duration::from_secs(1)) {
+            // Ctrl-C and Escape quit as well
+            Some(b'q') | Some(3) | Some(0x1b) => return Ok(()),
+            Some(b'p') => paused = !paused,
+            Some(b'r') => screen = read()?,
+            _ if !paused => screen = read()?,
+            _ => (),
+        }
+    }
+}
+
+// sync [--delete] [--dry-run] [--jobs N] SRC DST
+fn sync(delete: bool, dry_run: bool, jobs: Option<usize>, src: &Path, dst: &Path) -> Result<(), Box<dyn Error>> {
+    let mut options = SyncOptions { delete, dry_run, ..Default::default() };
+    if let Some(jobs) = jobs {
+        options.jobs = jobs;
+    }
+
+    let summary = sync::sync(src, dst, &options)?;
+    println!("{} copied, {} deleted, {} unchanged, {} failed", summary.copied, summary.deleted, summary.unchanged, summary.failed);
+
+    Ok(())
+}
+
+// bisync [--interval SECONDS] LOCAL PROVIDER[/PATH]
+fn bisync(interval: Duration, local: &Path, remote: &str) -> Result<(), Box<dyn Error>> {
+    let local = fs::canonicalize(local)?;
+    let remote = remote.trim_matches('/').to_string();
+    let (provider, remote_path) = remote.split_once('/').unwrap_or((remote.as_str(), ""));
+
+    let proj_dirs = ProjectDirs::from("", "Orbital", "Files").ok_or("unable to find the data directory")?;
+    let state_dir = proj_dirs.data_dir().join("bisync");
+    fs::create_dir_all(&state_dir)?;
+    let state_path = state_dir.join(format!("{}.json", remote.replace('/', "_")));
+
+    let throttle = Throttle::load();
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build()?.block_on(async {
+        let mut providers = ProvidersMap::new(Config::load().api_keys.providers_options()).await;
+        let provider_id = FuseFS::register_provider(&mut providers, provider).await.ok_or(format!("unable to load provider {}", provider))?;
+        let remote_fs = providers.get_provider(provider_id).unwrap();
+        let remote_fs = remote_fs.as_filesystem().unwrap();
+
+        let mut remote_root = ObjectId::root();
+        for name in remote_path.split('/').filter(|name| !name.is_empty()) {
+            remote_root = remote_fs.read_directory(remote_root).await.map_err(|error| format!("{:?}", error))?
+                .into_iter()
+                .find(|file| file.name == name && file.id.is_directory())
+                .ok_or(format!("{} not found on {}", remote_path, provider))?
+                .id;
+        }
+
+        let local_fs = NativeFs { root: "".to_string() };
+        let local_root = ObjectId::directory(local.to_string_lossy().to_string());
+
+        Bisync::new(&local_fs, local_root, remote_fs, remote_root, state_path)
+            .with_interval(interval)
+            .with_throttle(&throttle)
+            .run()
+            .await;
+
+        Ok(())
+    })
+}
+
+// login GoogleDrive|OneDrive NAME
+// Drive is logged in through a browser on this machine, which comes back to a local port.
+// OneDrive runs the device authorization flow, the user enters a code in any browser. Then
+// writes the credential file mounted as NAME. Logging in again to the same name replaces its
+// token.
+fn login(provider_type: &str, name: &str) -> Result<(), Box<dyn Error>> {
+    if name.is_empty() || name.contains(['.', '/']) {
+        return Err(format!("{} can't name a provider, it can't contain a dot or a slash", name).into());
+    }
+    let kind = match provider_type {
+        "GoogleDrive" => ProviderType::GoogleDrive,
+        "OneDrive" => ProviderType::OneDrive,
+        _ => return Err(format!("unknown provider type {}", provider_type).into()),
+    };
+
+    let keys = Config::load().api_keys;
+    if let Some(error) = keys.missing(&kind) {
+        return Err(error.into());
+    }
+
+    let proj_dirs = ProjectDirs::from("", "Orbital", "Files").ok_or("unable to find the data directory")?;
+    let path = proj_dirs.data_dir().join(format!("{}.{}", name, provider_type));
+
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let content = rt.block_on(async {
+        let client = reqwest::Client::new();
+        match kind {
+            ProviderType::GoogleDrive => {
+                let login = start_loopback_login(&kind, &keys).await?;
+                println!("open {} in a browser on this machine", login.url);
+                println!("waiting for the login to go through...");
+                await_loopback_token(&client, &kind, &keys, login).await
+            },
+            _ => {
+                let code = request_device_code(&client, &kind, &keys).await?;
+                println!("open {} and enter the code {}", code.verification_url, code.user_code);
+                println!("waiting for the login to go through...");
+                await_device_token(&client, &kind, &keys, &code).await
+            },
+        }
+    })?;
+
+    fs::create_dir_all(proj_dirs.data_dir())?;
+    write_credentials(&path, &content).map_err(|error| format!("unable to save {}: {}", path.display(), error))?;
+    println!("saved {}", path.display());
+    println!("to use it without remounting, write \"add {}\" to .orbital/mounts, or {} to .orbital/reauth if it is mounted already", name, name);
+    if Vault::path(proj_dirs.data_dir()).exists() {
+        println!("run seal to move it to the encrypted credential store");
+    }
+
+    Ok(())
+}
+
+// seal [--key-file FILE]
+// Adds the credential files to the store, created with a new passphrase the first time, then
+// deletes them. The mount asks for the passphrase when it starts.
+fn seal(key_file: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let proj_dirs = ProjectDirs::from("", "Orbital", "Files").ok_or("unable to find the data directory")?;
+    let path = Vault::path(proj_dirs.data_dir());
+
+    let (mut store, passphrase) = match path.exists() {
+        true => {
+            let passphrase = vault::passphrase(key_file, "passphrase of the credential store: ")?;
+            (Vault::open(&path, &passphrase).map_err(|error| format!("unable to open {}: {}", path.display(), error))?, passphrase)
+        },
+        false => (Vault::default(), vault::new_passphrase(key_file)?),
+    };
+
+    let mut sealed = Vec::new();
+    for file_name in credential_files(&proj_dirs)? {
+        let provider_type = file_name.split_once('.').map_or("", |(_, provider_type)| provider_type);
+        // the cache key and anything else that isn't a credential file stays
+        let content = match fs::read_to_string(proj_dirs.data_dir().join(&file_name)) {
+            Ok(content) if FuseFS::parse_credentials(provider_type, &content).is_some() => content,
+            _ => continue,
+        };
+        store.files.insert(file_name.clone(), content);
+        sealed.push(file_name);
+    }
+
+    if sealed.is_empty() {
+        println!("no credential file to add to {}", path.display());
+        return Ok(());
+    }
+
+    store.save(&path, &passphrase).map_err(|error| format!("unable to save {}: {}", path.display(), error))?;
+    for file_name in sealed {
+        fs::remove_file(proj_dirs.data_dir().join(&file_name))?;
+        println!("sealed {}", file_name);
+    }
+
+    Ok(())
+}
+
+const DOCTOR_TIMEOUT: Duration = Duration::from_secs(10);
+const LOGIN_HINT: &str = "log in again with the login subcommand, then write the provider name to .orbital/reauth if it is mounted";
+
+fn check(ok: bool, message: String) -> bool {
+    println!("  {:<5} {}", if ok { "ok" } else { "FAIL" }, message);
+    ok
+}
+
+fn hint(hint: &str) {
+    println!("        hint: {}", hint);
+}
+
+// File names of the credential store, sorted by provider name.
+fn credential_files(proj_dirs: &ProjectDirs) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut names: Vec<String> = fs::read_dir(proj_dirs.data_dir())
+        .map_err(|error| format!("unable to read {}: {}", proj_dirs.data_dir().display(), error))?
+        .flatten()
+        .filter(|entry| entry.file_type().map_or(false, |file_type| file_type.is_file()))
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| name != VAULT_FILE_NAME && name != KEY_FILE_NAME)
+        .collect();
+    names.sort();
+
+    if names.is_empty() {
+        println!("no provider configured in {}", proj_dirs.data_dir().display());
+    }
+
+    Ok(names)
+}
+
+// doctor
+// Goes through the same steps as mounting for every credential file, stopping at the first
+// one failing for each provider.
+fn doctor() -> Result<(), Box<dyn Error>> {
+    let proj_dirs = ProjectDirs::from("", "Orbital", "Files").ok_or("unable to find the data directory")?;
+    let names = credential_files(&proj_dirs)?;
+    let keys = Config::load().api_keys;
+
+    let mut healthy = true;
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+
+    for file_name in names {
+        let (name, provider_type) = file_name.split_once('.').unwrap_or((file_name.as_str(), ""));
+        println!("{} ({})", name, if provider_type.is_empty() { "unknown" } else { provider_type });
+
+        let content = match fs::read_to_string(proj_dirs.data_dir().join(&file_name)) {
+            Ok(content) => content,
+            Err(error) => {
+                check(false, format!("credential file can't be read: {}", error));
+                healthy = false;
+                continue;
+            },
+        };
+        let (provider_type, credentials) = match FuseFS::parse_credentials(provider_type, &content) {
+            Some(parsed) => parsed,
+            None => {
+                check(false, "credential file can't be parsed".to_string());
+                healthy = false;
+                hint("the file name must end with .GoogleDrive, .OneDrive or .S3 and hold the saved token, log in again to recreate it");
+                continue;
+            },
+        };
+        check(true, "credential file parsed".to_string());
+
+        if let Some(error) = keys.missing(&provider_type) {
+            check(false, error);
+            healthy = false;
+            continue;
+        }
+
+        healthy &= rt.block_on(async {
+            let mut providers = ProvidersMap::new(keys.providers_options()).await;
+            let provider_id = ProviderId { id: name.to_string(), provider_type };
+
+            let started = Instant::now();
+            match tokio::time::timeout(DOCTOR_TIMEOUT, providers.add_provider(provider_id.clone(), credentials)).await {
+                Ok(Ok(_)) => check(true, format!("token accepted in {} ms", started.elapsed().as_millis())),
+                Ok(Err(error)) => {
+                    check(false, format!("token rejected: {:?}", error));
+                    hint(LOGIN_HINT);
+                    return false;
+                },
+                Err(_) => {
+                    check(false, format!("no answer after {} s", DOCTOR_TIMEOUT.as_secs()));
+                    hint("check the network connection and proxy settings");
+                    return false;
+                },
+            };
+
+            let provider = providers.get_provider(provider_id).unwrap();
+            let started = Instant::now();
+            match tokio::time::timeout(DOCTOR_TIMEOUT, provider.as_filesystem().unwrap().read_directory(ObjectId::root())).await {
+                Ok(Ok(files)) => check(true, format!("listed {} entries in {} ms", files.len(), started.elapsed().as_millis())),
+                Ok(Err(error)) => {
+                    check(false, format!("listing failed: {:?}", error));
+                    hint(if is_auth_error(&error) { LOGIN_HINT } else { "the provider may be having an outage, try again later" });
+                    false
+                },
+                Err(_) => {
+                    check(false, format!("listing got no answer after {} s", DOCTOR_TIMEOUT.as_secs()));
+                    hint("check the network connection and proxy settings");
+                    false
+                },
+            }
+        });
+    }
+
+    if healthy {
+        Ok(())
+    } else {
+        Err("some providers need attention".into())
+    }
+}
+
+const SELFTEST_CONTENT: &[u8] = b"orbital files self-test\n";
+
+async fn find_child<F: FileSystem + ?Sized>(filesystem: &F, parent: &ObjectId, name: &str) -> Result<ObjectId, String> {
+    filesystem.read_directory(parent.clone()).await.map_err(|error| format!("{:?}", error))?
+        .into_iter()
+        .find(|file| file.name == name)
+        .map(|file| file.id)
+        .ok_or(format!("{} doesn't show up in its parent", name))
+}
+
+// Creates a directory, writes, reads back, renames and deletes a file in it.
+async fn exercise<F: FileSystem + ?Sized>(filesystem: &F, directory: &mut Option<ObjectId>) -> Result<(), String> {
+    let step = |message: &str, started: Instant| check(true, format!("{} in {} ms", message, started.elapsed().as_millis()));
+
+    let started = Instant::now();
+    let name = format!(".orbital-selftest-{}", std::process::id());
+    let id = ObjectId::directory(ObjectId::root().to_string() + "/" + name.as_str());
+    filesystem.create(ObjectId::root(), File { id, name: name.clone(), metadata: None }).await.map_err(|error| format!("create directory: {:?}", error))?;
+    let dir = find_child(filesystem, &ObjectId::root(), &name).await?;
+    *directory = Some(dir.clone());
+    step("created a directory", started);
+
+    let started = Instant::now();
+    let id = ObjectId::new(dir.to_string() + "/test.txt", FileType::File);
+    filesystem.create(dir.clone(), File { id, name: "test.txt".to_string(), metadata: None }).await.map_err(|error| format!("create file: {:?}", error))?;
+    let file = find_child(filesystem, &dir, "test.txt").await?;
+    filesystem.write_file(file.clone(), SELFTEST_CONTENT.to_vec().into()).await.map_err(|error| format!("write: {:?}", error))?;
+    step("wrote a file", started);
+
+    let started = Instant::now();
+    let content = filesystem.read_file(file.clone()).await.map_err(|error| format!("read: {:?}", error))?;
+    if content.as_slice() != SELFTEST_CONTENT {
+        return Err(format!("read back {} bytes that differ from the {} written", content.len(), SELFTEST_CONTENT.len()));
+    }
+    step("read it back", started);
+
+    let started = Instant::now();
+    let file = filesystem.rename(file, "renamed.txt".to_string()).await.map_err(|error| format!("rename: {:?}", error))?;
+    find_child(filesystem, &dir, "renamed.txt").await?;
+    step("renamed it", started);
+
+    let started = Instant::now();
+    filesystem.delete(file).await.map_err(|error| format!("delete file: {:?}", error))?;
+    filesystem.delete(dir).await.map_err(|error| format!("delete directory: {:?}", error))?;
+    *directory = None;
+    step("deleted it", started);
+
+    Ok(())
+}
+
+// selftest
+// Runs a small round trip on every provider through the provider API, the mount isn't needed.
+fn selftest() -> Result<(), Box<dyn Error>> {
+    let proj_dirs = ProjectDirs::from("", "Orbital", "Files").ok_or("unable to find the data directory")?;
+    let mut passed = true;
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+
+    for file_name in credential_files(&proj_dirs)? {
+        let name = file_name.split_once('.').map_or(file_name.as_str(), |(name, _)| name);
+        println!("{}", name);
+
+        passed &= rt.block_on(async {
+            let mut providers = ProvidersMap::new(Config::load().api_keys.providers_options()).await;
+            let provider_id = match FuseFS::register_provider(&mut providers, name).await {
+                Some(provider_id) => provider_id,
+                None => return check(false, "unable to load the provider, run `doctor` for details".to_string()),
+            };
+            let provider = providers.get_provider(provider_id).unwrap();
+            let filesystem = provider.as_filesystem().unwrap();
+
+            let mut directory = None;
+            match exercise(filesystem, &mut directory).await {
+                Ok(()) => true,
+                Err(error) => {
+                    check(false, error);
+                    // leave nothing behind
+                    if let Some(directory) = directory {
+                        let _ = filesystem.delete(directory).await;
+                    }
+                    false
+                },
+            }
+        });
+    }
+
+    if passed {
+        Ok(())
+    } else {
+        Err("self-test failed".into())
+    }
+}
+
+// Paths can be given as they appear on the mount or relative to its root.
+fn mount_relative(mount_point: &Path, path: &str) -> String {
+    let canonical = (fs::canonicalize(mount_point), fs::canonicalize(path));
+
+    if let (Ok(mount_point), Ok(path)) = canonical {
+        if let Ok(relative) = path.strip_prefix(&mount_point) {
+            return Path::new("/").join(relative).to_string_lossy().to_string();
+        }
+    }
+
+    path.to_string()
+}
+
+// Control files answer the last request written to them.
+fn request(mount_point: &Path, file: &str, request: &str) -> io::Result<String> {
+    let path = mount_point.join(CONTROL_DIR_NAME).join(file);
+
+    OpenOptions::new().write(true).open(&path)?.write_all(request.as_bytes())?;
+    fs::read_to_string(&path)
+}
\ No newline at end of file
@@ -0,0 +1,324 @@
+// read the configuration file describing providers and mount settings
+// Path: src/config.rs
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crossroads::storage::{ProviderType, ProvidersOptions};
+use directories::{BaseDirs, ProjectDirs};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::cache::CacheConfig;
+
+// Read from `files.toml` in the config directory, every section is optional, e.g.
+//
+//     [mount]
+//     mountpoint = "/mnt/cloud"
+//     options = ["allow_other", "noexec"]
+//
+//     [ttl]
+//     metadata = 30
+//
+//     [api_keys]
+//     google_drive = "1234-abcd.apps.googleusercontent.com"
+//     google_drive_secret = "GOCSPX-abcd"
+//
+//     [[provider]]
+//     name = "work"
+//     type = "GoogleDrive"
+//     credentials = "/home/me/secrets/work.json"
+//     display_name = "Work Drive"
+//     read_ahead = 16
+//
+//     [[provider]]
+//     name = "projects"
+//     type = "NativeFs"
+//     root = "/srv/projects"
+//
+//     [[provider]]
+//     name = "archive"
+//     type = "S3"
+//     credentials = "/home/me/secrets/archive.json"
+//     ttl = { entry = 3600, attr = 3600, metadata = 3600, listing = 3600 }
+//
+//     [profile.work]
+//     mountpoint = "/mnt/work"
+//     providers = ["work", "archive"]
+//     cache_dir = "/home/me/.cache/work"
+const CONFIG_FILE_NAME: &str = "files.toml";
+// Every configuration file is in ~/.config/orbital, or $XDG_CONFIG_HOME/orbital.
+const CONFIG_DIR_NAME: &str = "orbital";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderEntry {
+    pub name: String,
+    // as in the credential file names: GoogleDrive, OneDrive, S3 or NativeFs
+    #[serde(rename = "type")]
+    pub provider_type: String,
+    // credential file of cloud providers
+    pub credentials: Option<PathBuf>,
+    // directory served by NativeFs providers
+    pub root: Option<PathBuf>,
+    // name of the provider's folder at the root of the mount, its name by default
+    pub display_name: Option<String>,
+    // chunks read ahead of sequential reads, in place of the cache's setting
+    pub read_ahead: Option<u64>,
+    // in place of the [mount] section's poll_interval
+    pub poll_interval: Option<u64>,
+    // in place of the [ttl] section's settings for this provider's files
+    #[serde(default)]
+    pub ttl: ProviderTtl,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MountConfig {
+    pub mountpoint: Option<PathBuf>,
+    // as given to -o, the command line ones come after
+    pub options: Vec<String>,
+    // credential files found in the data directory are mounted along the configured providers
+    pub scan_data_dir: bool,
+    // the home directory is mounted as "Local files"
+    pub local_files: bool,
+    // seconds between two checks of the recently listed folders for changes made elsewhere, 0
+    // to not check
+    pub poll_interval: u64,
+}
+
+impl Default for MountConfig {
+    fn default() -> Self {
+        Self {
+            mountpoint: None,
+            options: Vec::new(),
+            scan_data_dir: true,
+            local_files: true,
+            poll_interval: 60,
+        }
+    }
+}
+
+// Mounted with `--profile <name>`, what it leaves out comes from the rest of the file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub mountpoint: Option<PathBuf>,
+    // names of the [[provider]] entries mounted, every one of them when missing
+    pub providers: Option<Vec<String>>,
+    // in place of the content cache's directory, two profiles mounted at once shouldn't share it
+    pub cache_dir: Option<PathBuf>,
+    // after the [mount] section's
+    pub options: Vec<String>,
+    // as in the [mount] section, off by default when the profile lists its providers
+    pub scan_data_dir: Option<bool>,
+    pub local_files: Option<bool>,
+}
+
+// In seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TtlConfig {
+    // names and attributes the kernel keeps without asking the mount again
+    pub entry: u64,
+    pub attr: u64,
+    // attributes received from a provider
+    pub metadata: u64,
+    // directory listings
+    pub listing: u64,
+    // names looked up and not found
+    pub negative: u64,
+}
+
+impl Default for TtlConfig {
+    fn default() -> Self {
+        Self {
+            entry: 1,
+            attr: 1,
+            metadata: 5,
+            listing: 1,
+            negative: 5,
+        }
+    }
+}
+
+impl TtlConfig {
+    pub fn entry(&self) -> Duration {
+        Duration::from_secs(self.entry)
+    }
+
+    pub fn attr(&self) -> Duration {
+        Duration::from_secs(self.attr)
+    }
+
+    pub fn metadata(&self) -> Duration {
+        Duration::from_secs(self.metadata)
+    }
+
+    pub fn listing(&self) -> Duration {
+        Duration::from_secs(self.listing)
+    }
+
+    pub fn negative(&self) -> Duration {
+        Duration::from_secs(self.negative)
+    }
+}
+
+// A provider's own TTLs, the ones it leaves out come from the [ttl] section.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ProviderTtl {
+    pub entry: Option<u64>,
+    pub attr: Option<u64>,
+    pub metadata: Option<u64>,
+    pub listing: Option<u64>,
+}
+
+impl ProviderTtl {
+    pub fn is_empty(&self) -> bool {
+        self.entry.is_none() && self.attr.is_none() && self.metadata.is_none() && self.listing.is_none()
+    }
+
+    pub fn over(&self, ttl: &TtlConfig) -> TtlConfig {
+        TtlConfig {
+            entry: self.entry.unwrap_or(ttl.entry),
+            attr: self.attr.unwrap_or(ttl.attr),
+            metadata: self.metadata.unwrap_or(ttl.metadata),
+            listing: self.listing.unwrap_or(ttl.listing),
+            negative: ttl.negative,
+        }
+    }
+}
+
+// OAuth client ids of the Drive and OneDrive apps, the environment variables take precedence
+// over the configuration file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ApiKeys {
+    pub google_drive: Option<String>,
+    // Google asks for the secret of desktop clients to refresh their tokens
+    pub google_drive_secret: Option<String>,
+    pub onedrive: Option<String>,
+}
+
+impl ApiKeys {
+    fn with_env(self) -> Self {
+        Self {
+            google_drive: std::env::var("GOOGLE_DRIVE_CLIENT_KEY").ok().or(self.google_drive),
+            google_drive_secret: std::env::var("GOOGLE_DRIVE_CLIENT_SECRET").ok().or(self.google_drive_secret),
+            onedrive: std::env::var("ONEDRIVE_CLIENT_ID").ok().or(self.onedrive),
+        }
+    }
+
+    // Tells why providers of this type can't be used, if they can't.
+    pub fn missing(&self, provider_type: &ProviderType) -> Option<String> {
+        let (key, variable, setting) = match provider_type {
+            ProviderType::GoogleDrive => (&self.google_drive, "GOOGLE_DRIVE_CLIENT_KEY", "google_drive"),
+            ProviderType::OneDrive => (&self.onedrive, "ONEDRIVE_CLIENT_ID", "onedrive"),
+            _ => return None,
+        };
+
+        match key {
+            Some(_) => None,
+            None => Some(format!("no {:?} API key, set {} or {} in the [api_keys] section of {}", provider_type, variable, setting, CONFIG_FILE_NAME)),
+        }
+    }
+
+    pub fn providers_options(&self) -> ProvidersOptions {
+        ProvidersOptions {
+            google_api_key: self.google_drive.clone(),
+            onedrive_api_key: self.onedrive.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    #[serde(rename = "provider")]
+    pub providers: Vec<ProviderEntry>,
+    pub mount: MountConfig,
+    // takes the place of `cache.toml` when present
+    pub cache: Option<CacheConfig>,
+    pub ttl: TtlConfig,
+    pub api_keys: ApiKeys,
+    #[serde(rename = "profile")]
+    pub profiles: HashMap<String, Profile>,
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let mut config = Self::read();
+        config.api_keys = config.api_keys.with_env();
+        config
+    }
+
+    // Keeps the providers of the profile and puts its settings in place of the file's.
+    pub fn with_profile(mut self, name: &str) -> Result<Self, String> {
+        let profile = self.profiles.get(name).cloned().ok_or_else(|| format!("no profile {} in {}", name, CONFIG_FILE_NAME))?;
+
+        if let Some(names) = &profile.providers {
+            if let Some(unknown) = names.iter().find(|name| !self.providers.iter().any(|provider| provider.name == **name)) {
+                return Err(format!("profile {} lists {}, which isn't a [[provider]] of {}", name, unknown, CONFIG_FILE_NAME));
+            }
+            self.providers.retain(|provider| names.contains(&provider.name));
+        }
+
+        let listed = profile.providers.is_some();
+        self.mount.scan_data_dir = profile.scan_data_dir.unwrap_or(self.mount.scan_data_dir && !listed);
+        self.mount.local_files = profile.local_files.unwrap_or(self.mount.local_files && !listed);
+        self.mount.options.extend(profile.options);
+        if let Some(mountpoint) = profile.mountpoint {
+            self.mount.mountpoint = Some(mountpoint);
+        }
+        if let Some(dir) = profile.cache_dir {
+            let mut cache = self.cache.take().unwrap_or_else(CacheConfig::load);
+            cache.dir = dir;
+            self.cache = Some(cache);
+        }
+
+        Ok(self)
+    }
+
+    fn read() -> Self {
+        let path = match config_file(CONFIG_FILE_NAME) {
+            Some(path) => path,
+            None => return Self::default(),
+        };
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => return Self::default(),
+        };
+
+        match toml::from_str(&content) {
+            Ok(config) => config,
+            Err(error) => {
+                warn!("ignoring {}: {}", path.display(), error);
+                Self::default()
+            },
+        }
+    }
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    BaseDirs::new().map(|base_dirs| base_dirs.config_dir().join(CONFIG_DIR_NAME))
+}
+
+// The directory versions before this one read their configuration from, ~/.config/files.
+pub fn legacy_config_dir() -> Option<PathBuf> {
+    ProjectDirs::from("", "Orbital", "Files").map(|proj_dirs| proj_dirs.config_dir().to_path_buf())
+}
+
+// Where a configuration file is read from. One only found in the legacy directory is still
+// read, with a warning.
+pub fn config_file(name: &str) -> Option<PathBuf> {
+    let path = config_dir()?.join(name);
+    if !path.exists() {
+        if let Some(legacy) = legacy_config_dir().map(|dir| dir.join(name)).filter(|legacy| legacy.exists()) {
+            warn!("reading {}, move it to {}", legacy.display(), path.display());
+            return Some(legacy);
+        }
+    }
+    Some(path)
+}
\ No newline at end of file
@@ -0,0 +1,160 @@
+// answer JSON commands sent on a unix socket next to the mount, through its control directory
+// Path: src/control_socket.rs
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::fuse::{CONTROL_DIR_NAME, PINNED_XATTR};
+
+// One request by line, each answered by a line holding `{"ok": true, "result": ...}` or
+// `{"ok": false, "error": "..."}`, e.g.
+//
+//     {"command": "list_providers"}
+//     {"command": "cache_stats"}
+//     {"command": "invalidate", "path": "/GoogleDrive/Documents"}
+//     {"command": "pin", "path": "/GoogleDrive/Documents/notes.txt", "pinned": true}
+//     {"command": "pause_sync"}
+//     {"command": "resume_sync"}
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    ListProviders,
+    CacheStats,
+    // paths are relative to the root of the mount
+    Invalidate { path: PathBuf },
+    Pin {
+        path: PathBuf,
+        #[serde(default = "pin_by_default")]
+        pinned: bool,
+    },
+    PauseSync,
+    ResumeSync,
+}
+
+fn pin_by_default() -> bool {
+    true
+}
+
+pub struct ControlSocket {
+    path: PathBuf,
+    mount_point: PathBuf,
+    listener: UnixListener,
+}
+
+impl ControlSocket {
+    // `/mnt/cloud` listens on `/mnt/.cloud.sock`. Bound before the daemon is sandboxed, only
+    // its owner can connect. A socket left by a previous mount is replaced.
+    pub fn bind(mount_point: &Path) -> io::Result<Self> {
+        let mount_point = fs::canonicalize(mount_point)?;
+        let name = mount_point.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the mount point has no name"))?;
+        let path = mount_point.with_file_name(format!(".{}.sock", name.to_string_lossy()));
+
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        Ok(Self { path, mount_point, listener })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Every connection gets its own thread, the commands reach the filesystem through the mount
+    // like the scheduled jobs.
+    pub fn start(self) {
+        thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        warn!("control socket: {}", error);
+                        continue;
+                    },
+                };
+                let mount_point = self.mount_point.clone();
+                thread::spawn(move || {
+                    if let Err(error) = serve(stream, &mount_point) {
+                        warn!("control socket: {}", error);
+                    }
+                });
+            }
+        });
+    }
+}
+
+fn serve(stream: UnixStream, mount_point: &Path) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let answer = match serde_json::from_str::<Command>(&line) {
+            Ok(command) => match run(&command, mount_point) {
+                Ok(result) => json!({ "ok": true, "result": result }),
+                Err(error) => json!({ "ok": false, "error": error.to_string() }),
+            },
+            Err(error) => json!({ "ok": false, "error": format!("invalid command: {}", error) }),
+        };
+        writeln!(writer, "{}", answer)?;
+    }
+
+    Ok(())
+}
+
+fn run(command: &Command, mount_point: &Path) -> io::Result<Value> {
+    let control = |file: &str| mount_point.join(CONTROL_DIR_NAME).join(file);
+
+    match command {
+        Command::ListProviders => {
+            let providers: Vec<Value> = fs::read_to_string(control("mounts"))?
+                .lines()
+                .filter_map(|line| {
+                    let mut words = line.split(' ');
+                    Some(json!({ "name": words.next()?, "type": words.next()?, "state": words.next()? }))
+                })
+                .collect();
+            Ok(Value::from(providers))
+        },
+        Command::CacheStats => {
+            let status: Value = serde_json::from_str(&fs::read_to_string(control("status"))?)?;
+            Ok(status["cache"].clone())
+        },
+        Command::Invalidate { path } => write_control(&control("invalidate"), &path.to_string_lossy()),
+        Command::Pin { path, pinned } => {
+            let path = mount_point.join(path.strip_prefix("/").unwrap_or(path));
+            set_pinned(&path, *pinned)?;
+            Ok(Value::Null)
+        },
+        Command::PauseSync => write_control(&control("sync"), "pause"),
+        Command::ResumeSync => write_control(&control("sync"), "resume"),
+    }
+}
+
+fn write_control(path: &Path, request: &str) -> io::Result<Value> {
+    OpenOptions::new().write(true).open(path)?.write_all(request.as_bytes())?;
+    Ok(Value::Null)
+}
+
+fn set_pinned(path: &Path, pinned: bool) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(PINNED_XATTR)?;
+    let value: &[u8] = if pinned { b"1" } else { b"0" };
+
+    let result = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0) };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
\ No newline at end of file
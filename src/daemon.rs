@@ -0,0 +1,79 @@
+// detach from the terminal once the filesystem is mounted
+// Path: src/daemon.rs
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+use directories::ProjectDirs;
+
+const PID_FILE_NAME: &str = "mount.pid";
+
+// Held by the detached process, tells the one started from the terminal how the mount went.
+pub struct Readiness {
+    pipe: File,
+}
+
+// Forks before any thread exists. The parent stays attached to the terminal until the child
+// reports the mount, then exits with its outcome, so scripts can rely on the exit status.
+pub fn detach() -> io::Result<Readiness> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(reader);
+            if unsafe { libc::setsid() } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Readiness { pipe: writer })
+        },
+        _ => {
+            drop(writer);
+            let mut outcome = String::new();
+            let _ = (&reader).read_to_string(&mut outcome);
+            match outcome.as_str() {
+                "ready" => std::process::exit(0),
+                "" => eprintln!("the mount exited before mounting"),
+                error => eprintln!("{}", error),
+            }
+            std::process::exit(1);
+        },
+    }
+}
+
+impl Readiness {
+    // The terminal is let go, what is logged to stderr is lost from now on unless logged to
+    // a file.
+    pub fn ready(mut self) {
+        let _ = self.pipe.write_all(b"ready");
+        if let Ok(null) = File::options().read(true).write(true).open("/dev/null") {
+            for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+                unsafe { libc::dup2(null.as_raw_fd(), fd) };
+            }
+        }
+    }
+
+    pub fn failed(mut self, error: &str) {
+        let _ = self.pipe.write_all(error.as_bytes());
+    }
+}
+
+// In the runtime directory, the cache directory where there is none. Profiles mounted at once
+// each get their own.
+pub fn default_pid_file(profile: Option<&str>) -> Option<PathBuf> {
+    let proj_dirs = ProjectDirs::from("", "Orbital", "Files")?;
+    let name = profile.map_or(PID_FILE_NAME.to_string(), |profile| format!("mount-{}.pid", profile));
+    Some(proj_dirs.runtime_dir().unwrap_or(proj_dirs.cache_dir()).join(name))
+}
+
+pub fn write_pid_file(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, format!("{}\n", std::process::id()))
+}
\ No newline at end of file
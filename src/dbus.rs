@@ -0,0 +1,115 @@
+// expose the mount to desktop shells over the session bus
+// Path: src/dbus.rs
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use zbus::blocking::{Connection, ConnectionBuilder};
+use zbus::{dbus_interface, fdo, SignalContext};
+use tracing::{info, warn};
+
+use crate::fuse::{Status, CONTROL_DIR_NAME};
+use crate::notifications::Event;
+
+const BUS_NAME: &str = "org.orbital.Files";
+const OBJECT_PATH: &str = "/org/orbital/Files";
+
+// Set once the service is up, the events are also sent as signals then.
+static CONNECTION: OnceLock<Connection> = OnceLock::new();
+
+// Answers through the control directory of the mount, like the subcommands.
+struct Files {
+    mount_point: PathBuf,
+}
+
+impl Files {
+    fn status(&self) -> fdo::Result<Status> {
+        let content = fs::read_to_string(self.mount_point.join(CONTROL_DIR_NAME).join("status")).map_err(|error| fdo::Error::IOError(error.to_string()))?;
+        serde_json::from_str(&content).map_err(|error| fdo::Error::Failed(error.to_string()))
+    }
+}
+
+#[dbus_interface(name = "org.orbital.Files1")]
+impl Files {
+    #[dbus_interface(property)]
+    fn mount_point(&self) -> String {
+        self.mount_point.to_string_lossy().to_string()
+    }
+
+    // The whole status as the status control file has it.
+    fn get_status(&self) -> fdo::Result<String> {
+        serde_json::to_string(&self.status()?).map_err(|error| fdo::Error::Failed(error.to_string()))
+    }
+
+    // Name and health of every provider, e.g. ("work", "reauth").
+    fn list_providers(&self) -> fdo::Result<Vec<(String, String)>> {
+        Ok(self.status()?.providers.into_iter()
+            .map(|provider| (provider.provider, serde_json::to_value(provider.health).ok().and_then(|health| health.as_str().map(str::to_string)).unwrap_or_default()))
+            .collect())
+    }
+
+    // Path, direction, bytes done and total of the transfers in progress.
+    fn list_transfers(&self) -> fdo::Result<Vec<(String, bool, u64, u64)>> {
+        Ok(self.status()?.transfers.into_iter().map(|transfer| (transfer.path, transfer.upload, transfer.done, transfer.total)).collect())
+    }
+
+    // Called once the user logged in again, the provider reloads its credential file.
+    fn reauthenticate(&self, provider: &str) -> fdo::Result<()> {
+        let path = self.mount_point.join(CONTROL_DIR_NAME).join("reauth");
+        OpenOptions::new().write(true).open(path)
+            .and_then(|mut file| file.write_all(provider.as_bytes()))
+            .map_err(|error| fdo::Error::Failed(error.to_string()))
+    }
+
+    #[dbus_interface(signal)]
+    async fn reauth_required(context: &SignalContext<'_>, provider: &str) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn provider_degraded(context: &SignalContext<'_>, provider: &str) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn upload_finished(context: &SignalContext<'_>, name: &str, size: u64) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn conflict(context: &SignalContext<'_>, name: &str, copy: &str) -> zbus::Result<()>;
+}
+
+// Owns the bus name until the daemon exits, the mount works the same without a session bus.
+pub fn start(mount_point: PathBuf) {
+    let connection = ConnectionBuilder::session()
+        .and_then(|builder| builder.name(BUS_NAME))
+        .and_then(|builder| builder.serve_at(OBJECT_PATH, Files { mount_point }))
+        .and_then(|builder| builder.build());
+
+    match connection {
+        Ok(connection) => {
+            info!("dbus: serving {} on the session bus", BUS_NAME);
+            let _ = CONNECTION.set(connection);
+        },
+        Err(error) => warn!("dbus: unable to serve {}: {}", BUS_NAME, error),
+    }
+}
+
+pub fn emit(event: &Event) {
+    let connection = match CONNECTION.get() {
+        Some(connection) => connection,
+        None => return,
+    };
+    let context = match SignalContext::new(connection.inner(), OBJECT_PATH) {
+        Ok(context) => context,
+        Err(_) => return,
+    };
+
+    let result = zbus::block_on(async {
+        match event {
+            Event::ReauthRequired { provider } => Files::reauth_required(&context, provider).await,
+            Event::ProviderDegraded { provider } => Files::provider_degraded(&context, provider).await,
+            Event::UploadFinished { name, size } => Files::upload_finished(&context, name, *size as u64).await,
+            Event::Conflict { name, copy } => Files::conflict(&context, name, copy).await,
+        }
+    });
+    if let Err(error) = result {
+        warn!("dbus: unable to signal {:?}: {}", event, error);
+    }
+}
\ No newline at end of file
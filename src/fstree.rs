@@ -1,9 +1,26 @@
 
-use std::{collections::HashMap, sync::{Arc, Mutex, Weak}, time::{SystemTime, Duration}};
+use std::{collections::HashMap, fs, io, path::PathBuf, sync::{Arc, Mutex, Weak}, time::{SystemTime, UNIX_EPOCH}};
 
 use derivative::Derivative;
+use serde::{Deserialize, Serialize};
 use crossroads::{storage::ProviderId, interfaces::filesystem::{ObjectId, Permissions, UserId}};
 use fuser::FileAttr;
+use tracing::warn;
+
+use crate::config::TtlConfig;
+use crate::state;
+
+// Inodes handed out to objects, saved on unmount in the cache directory so the same object
+// gets the same inode on the next mount.
+const INODES_FILE_NAME: &str = "inodes.json";
+
+#[derive(Default, Serialize, Deserialize)]
+struct InodeDb {
+    generation: u64,
+    next_inode: u64,
+    // provider, object id, inode
+    inodes: Vec<(String, String, u64)>,
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum FileState {
@@ -20,13 +37,32 @@ pub struct FsNode {
     pub name: String,
     pub metadata: Option<Metadata>,
     pub expire_at: Option<SystemTime>,
+    pub metadata_expire_at: Option<SystemTime>,
     pub provider_id: Arc<ProviderId>,
+    // (uid, gid) of the only user allowed to see this node on a multi-user mount
+    pub owner: Option<(u32, u32)>,
+    // as the provider's listing gave them
+    pub mime_type: Option<String>,
+    pub web_url: Option<String>,
+    // the provider's revision when last listed or stat'ed, an upload made from an older one
+    // goes to a conflicted copy
+    pub revision: Option<String>,
     #[derivative(PartialEq="ignore")]
     pub content_state: FileState,
     #[derivative(PartialEq="ignore")]
     pub children: Vec<Arc<Mutex<FsNode>>>,
 }
 
+impl FsNode {
+    // On a multi-user mount, nodes of another user's providers don't exist for the requester.
+    pub fn visible_to(&self, uid: u32) -> bool {
+        match self.owner {
+            Some((owner, _)) => owner == uid,
+            None => true,
+        }
+    }
+}
+
 impl From<FsNode> for FileAttr {
     fn from(node: FsNode) -> Self {
         let metadata = node.metadata.unwrap_or(Metadata {
@@ -68,8 +104,20 @@ pub struct FsTree {
     inodes: HashMap<u64, Weak<Mutex<FsNode>>>,
     names: HashMap<(u64, String), Weak<Mutex<FsNode>>>,
     ids: HashMap<(ObjectId, ProviderId), Weak<Mutex<FsNode>>>,
+    // parent inode and name of every node, kept apart so paths can be built without locking
+    parents: HashMap<u64, (u64, String)>,
     next_inode: u64,
+    // inodes of the objects seen by this mount or the previous ones, by provider and object id
+    known_inodes: HashMap<(String, String), u64>,
+    // Changes whenever the saved inodes are lost, it tells file handles kept by NFS clients
+    // across a restart apart from the new inodes.
+    pub generation: u64,
     root: Arc<Mutex<FsNode>>,
+    uid: u32,
+    gid: u32,
+    // how long attributes and listings are trusted, by default and by provider name
+    pub ttl: TtlConfig,
+    pub provider_ttls: HashMap<String, TtlConfig>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -121,15 +169,28 @@ impl From<crossroads::interfaces::filesystem::Metadata> for Metadata {
     }
 }
 
+// Tells two revisions of an object apart. crossroads gives no ETag, the modification time and
+// size the provider reports stand for one.
+pub fn revision(metadata: &crossroads::interfaces::filesystem::Metadata) -> Option<String> {
+    let modified_at = metadata.modified_at?;
+    Some(format!("{}:{}", modified_at.to_rfc3339(), metadata.size.unwrap_or(0)))
+}
+
 impl FsTree {
-    pub fn new(providers: Vec<ProviderId>) -> FsTree {
+    // `providers` are shown at the root with the given names.
+    pub fn new(providers: Vec<(ProviderId, String)>, uid: u32, gid: u32) -> FsTree {
         let root = FsNode {
             id: ObjectId::root(),
             name: "/".to_string(),
             provider_id: Arc::new(ProviderId {id: "".to_string(), provider_type: crossroads::storage::ProviderType::NativeFs}),
             inode: 1,
             expire_at: None,
+            metadata_expire_at: None,
             metadata: None,
+            owner: None,
+            mime_type: None,
+            web_url: None,
+            revision: None,
             content_state: FileState::ShallowReady,
             children: Vec::new()
         };
@@ -138,25 +199,33 @@ impl FsTree {
             inodes: HashMap::new(),
             names: HashMap::new(),
             ids: HashMap::new(),
+            parents: HashMap::new(),
             next_inode: 2,
+            known_inodes: HashMap::new(),
+            generation: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs()),
             root: Arc::new(Mutex::new(root)),
+            uid,
+            gid,
+            ttl: TtlConfig::default(),
+            provider_ttls: HashMap::new(),
         };
+        blut.load_inodes();
 
-        for provider_id in providers {
+        for (provider_id, name) in providers {
             blut.new_provider(
                 ObjectId::root(),
-                provider_id.id.clone().as_str(),
+                name.as_str(),
                 0,
                 Arc::new(provider_id),
+                None,
             );
         }
 
         blut
     }
 
-    pub fn new_provider(&mut self, id: ObjectId, name: &str, size: u64, provider_id: Arc<ProviderId>) -> Arc<Mutex<FsNode>> {
-        let inode = self.next_inode;
-        self.next_inode += 1;
+    pub fn new_provider(&mut self, id: ObjectId, name: &str, size: u64, provider_id: Arc<ProviderId>, owner: Option<(u32, u32)>) -> Arc<Mutex<FsNode>> {
+        let inode = self.allocate_inode(&provider_id, &id);
 
         let file = Arc::new(Mutex::new(FsNode {
             id: id.clone(),
@@ -164,6 +233,7 @@ impl FsTree {
             provider_id: provider_id.clone(),
             inode,
             expire_at: None,
+            metadata_expire_at: None,
             metadata: Some(Metadata {
                 size,
                 blocks: 0,
@@ -172,12 +242,16 @@ impl FsTree {
                 ctime: SystemTime::UNIX_EPOCH,
                 crtime: SystemTime::UNIX_EPOCH,
                 perm: 0o777,
-                uid: 501,
-                gid: 20,
+                uid: self.uid,
+                gid: self.gid,
                 rdev: 0,
                 blksize: 512,
                 flags: 0,
             }),
+            owner,
+            mime_type: None,
+            web_url: None,
+            revision: None,
             content_state: FileState::ShallowReady,
             children: Vec::new()
         }));
@@ -187,21 +261,26 @@ impl FsTree {
         self.inodes.insert(inode, Arc::downgrade(&file).clone());
         self.ids.insert((id, (*provider_id).clone()), Arc::downgrade(&file).clone());
         self.names.insert((1, name.to_string()), Arc::downgrade(&file).clone());
+        self.parents.insert(inode, (1, name.to_string()));
 
         file
     }
 
     pub fn new_file(&mut self, parent: &mut FsNode, id: ObjectId, name: &str, metadata: Option<Metadata>, provider_id: Arc<ProviderId>) -> Arc<Mutex<FsNode>> {
-        let inode = self.next_inode;
-        self.next_inode += 1;
+        let inode = self.allocate_inode(&provider_id, &id);
 
         let file = Arc::new(Mutex::new(FsNode {
             id: id.clone(),
             name: name.to_string(),
             provider_id: provider_id.clone(),
             inode,
-            expire_at: Some(SystemTime::now() + Duration::from_secs(1)),
+            expire_at: Some(SystemTime::now() + self.ttl(&provider_id).listing()),
+            metadata_expire_at: metadata.map(|_| SystemTime::now() + self.ttl(&provider_id).metadata()),
             metadata: metadata,
+            owner: parent.owner,
+            mime_type: None,
+            web_url: None,
+            revision: None,
             content_state: FileState::ShallowReady,
             children: Vec::new()
         }));
@@ -211,10 +290,85 @@ impl FsTree {
         self.inodes.insert(inode, Arc::downgrade(&file).clone());
         self.ids.insert((id, (*provider_id).clone()), Arc::downgrade(&file).clone());
         self.names.insert((parent.inode, name.to_string()), Arc::downgrade(&file).clone());
+        self.parents.insert(inode, (parent.inode, name.to_string()));
 
         file
     }
 
+    fn allocate_inode(&mut self, provider_id: &ProviderId, id: &ObjectId) -> u64 {
+        let key = (provider_id.id.clone(), id.as_str().to_string());
+        // a renamed object keeps its inode, a new object taking its old id gets another one
+        if let Some(inode) = self.known_inodes.get(&key) {
+            if self.find_with_inode(*inode).is_none() {
+                return *inode;
+            }
+        }
+
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.known_inodes.insert(key, inode);
+        inode
+    }
+
+    fn inodes_path() -> Option<PathBuf> {
+        state::cache_dir().map(|dir| dir.join(INODES_FILE_NAME))
+    }
+
+    fn load_inodes(&mut self) {
+        let path = match Self::inodes_path() {
+            Some(path) => path,
+            None => return,
+        };
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => return,
+        };
+
+        match serde_json::from_str::<InodeDb>(&content) {
+            Ok(db) => {
+                self.generation = db.generation;
+                self.next_inode = std::cmp::max(db.next_inode, 2);
+                self.known_inodes = db.inodes.into_iter().map(|(provider, id, inode)| ((provider, id), inode)).collect();
+            },
+            Err(error) => warn!("ignoring {}: {}", path.display(), error),
+        }
+    }
+
+    pub fn ttl(&self, provider_id: &ProviderId) -> &TtlConfig {
+        self.provider_ttls.get(&provider_id.id).unwrap_or(&self.ttl)
+    }
+
+    pub fn save_inodes(&self) -> io::Result<()> {
+        let path = match Self::inodes_path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let db = InodeDb {
+            generation: self.generation,
+            next_inode: self.next_inode,
+            inodes: self.known_inodes.iter().map(|((provider, id), inode)| (provider.clone(), id.clone(), *inode)).collect(),
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // written aside first, a crash halfway must not lose every inode
+        let partial = path.with_extension("partial");
+        fs::write(&partial, serde_json::to_vec(&db)?)?;
+        fs::rename(partial, path)
+    }
+
+    // After a callback panicked, the nodes it held locked are unlocked poisoned. A listing it
+    // started goes on in its task.
+    pub fn clear_poison(&self) {
+        let nodes = self.inodes.values().filter_map(|node| node.upgrade()).chain(std::iter::once(self.root.clone()));
+        for node in nodes {
+            node.clear_poison();
+        }
+    }
+
     pub fn find_with_inode(&self, inode: u64) -> Option<Arc<Mutex<FsNode>>> {
         if let Some(node) = self.inodes.get(&inode).cloned() {
             node.upgrade()
@@ -223,6 +377,16 @@ impl FsTree {
         }
     }
 
+    // Root entries are matched against the requesting user since several users of a
+    // multi-user mount can have a provider with the same name.
+    pub fn find_provider(&self, name: &str, uid: u32) -> Option<Arc<Mutex<FsNode>>> {
+        self.providers(uid).into_iter().find(|node| node.lock().unwrap().name == name)
+    }
+
+    pub fn providers(&self, uid: u32) -> Vec<Arc<Mutex<FsNode>>> {
+        self.root.lock().unwrap().children.iter().filter(|node| node.lock().unwrap().visible_to(uid)).cloned().collect()
+    }
+
     pub fn find_with_name(&self, parent_inode: u64, name: &str) -> Option<Arc<Mutex<FsNode>>> {
         if let Some(node) = self.names.get(&(parent_inode, name.to_string())).cloned() {
             node.upgrade()
@@ -239,10 +403,47 @@ impl FsTree {
         }
     }
 
+    // Path of a node from the mount root, e.g. `/GoogleDrive/Documents/notes.txt`.
+    pub fn path(&self, inode: u64) -> Option<PathBuf> {
+        let mut names = Vec::new();
+        let mut current = inode;
+
+        while current != 1 {
+            let (parent, name) = self.parents.get(&current)?;
+            names.push(name.as_str());
+            current = *parent;
+        }
+
+        Some(names.iter().rev().fold(PathBuf::from("/"), |path, name| path.join(name)))
+    }
+
+    pub fn parent(&self, inode: u64) -> Option<u64> {
+        self.parents.get(&inode).map(|(parent, _)| *parent)
+    }
+
     pub fn rename(&mut self, parent_inode: u64, old_name: &str, new_name: &str) {
         if let Some(file) = self.names.remove(&(parent_inode, old_name.to_string())) {
             self.names.insert((parent_inode, new_name.to_string()), file.clone());
         }
+
+        if let Some((_, name)) = self.parents.values_mut().find(|(parent, name)| *parent == parent_inode && name == old_name) {
+            *name = new_name.to_string();
+        }
+    }
+
+    // Forgets a provider's folder at the root and everything below it. The inodes stay known,
+    // the provider gets them back if it is mounted again.
+    pub fn remove_provider(&mut self, node_ref: Arc<Mutex<FsNode>>) {
+        self.root.lock().unwrap().children.retain(|child| !Arc::ptr_eq(child, &node_ref));
+
+        let mut pending = vec![(1, node_ref)];
+        while let Some((parent_inode, node_ref)) = pending.pop() {
+            {
+                let node = node_ref.lock().unwrap();
+                pending.extend(node.children.iter().map(|child| (node.inode, child.clone())));
+            }
+            self.remove(parent_inode, node_ref);
+        }
     }
 
     pub fn remove(&mut self, parent_inode: u64, node_ref: Arc<Mutex<FsNode>>) {
@@ -250,6 +451,7 @@ impl FsTree {
 
         self.inodes.remove(&node.inode);
         self.names.remove(&(parent_inode, node.name.clone()));
+        self.parents.remove(&node.inode);
         self.ids.remove(&(node.id.clone(), node.provider_id.as_ref().clone()));
     }
 }
\ No newline at end of file
@@ -1,212 +1,1119 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, UNIX_EPOCH, SystemTime};
+use std::time::{Duration, Instant, UNIX_EPOCH, SystemTime};
 use directories::{ProjectDirs, UserDirs};
 use crossroads::providers::google_drive::Token;
 use crossroads::providers::onedrive::token::OneDriveToken;
 use std::fs;
 
-use fuser::{FileType, FileAttr, Filesystem, ReplyAttr, ReplyEntry, Request};
-use crossroads::interfaces::filesystem::{ObjectId, FileSystem};
+use fuser::{FileType, FileAttr, Filesystem, KernelConfig, ReplyAttr, ReplyEntry, ReplyXattr, Request};
+use fuser::consts::FUSE_EXPORT_SUPPORT;
+use crossroads::interfaces::filesystem::{ObjectId, File, FileSystem};
 use crossroads::providers::native_fs::NativeFs;
 use crossroads::storage::{ProvidersMap, ProviderId};
+use futures::future::join_all;
 use serde_json::Value;
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::Semaphore;
+use tokio::task::{JoinHandle, JoinSet};
 use crossroads::storage::ProviderType;
 
 use std::ffi::OsStr;
+use libc::{c_int, EACCES, EINVAL, EIO, ENETUNREACH, ENOENT, EPERM, EROFS};
+use tracing::{error, info, info_span, warn};
 
-use crate::fstree::{FsTree, FsNode, FileState};
+use crate::blocks::{BlockCache, DEFAULT_CHUNK_SIZE, DEFAULT_MEMORY_SIZE, DEFAULT_READ_AHEAD};
+use crate::cache::ContentCache;
+use crate::config::{ApiKeys, Config, ProviderEntry};
+use crate::fstree::{revision, FsTree, FsNode, FileState};
+use crate::notifications::{self, Event};
+use crate::privileges::Owner;
+use crate::telemetry;
+use crate::vault::Vault;
+use guard::DeletionGuard;
+use denials::{Denial, Denials};
+use failures::{Failure, Failures};
+use stats::OperationStats;
+use status::{CacheCounters, Transfers};
+use search::Searches;
+use walk::Crawls;
+use policy::Operation;
+use quota::ApiQuotas;
+use pending::PendingCreates;
+use deletes::DeleteQueue;
+use journal::Journal;
+use spill::Spills;
+use tokens::Refreshed;
+use handles::Handles;
+use dispatch::{Awaited, Caller, Completion, Dispatcher, Providers};
+use listings::Listings;
+use negative::NegativeEntries;
+use capabilities::Capabilities;
+pub use policy::Policy;
+pub use mime::MimeMap;
+pub use control::CONTROL_DIR_NAME;
+pub use xattr::PINNED_XATTR;
+pub use report::{human_size, ProviderUsage};
+pub use errors::is_auth_error;
+pub use breaker::record_result;
+pub use stats::record_request;
+pub use tokens::{await_device_token, await_loopback_token, request_device_code, start_loopback_login, write_credentials};
+use errors::errno;
+pub use status::{Health, Status};
+pub use changes::Poller;
+pub use modes::Modes;
+pub use dirty::conflicted_name;
 
 mod attr;
 mod node;
 mod dir;
 mod symlink;
+mod interrupt;
+mod errors;
+mod control;
+mod guard;
+mod policy;
+mod denials;
+mod failures;
+mod status;
+mod mime;
+mod xattr;
+mod walk;
+mod search;
+mod report;
+mod quota;
+mod pending;
+mod deletes;
+mod handles;
+mod dispatch;
+mod dirty;
+mod listings;
+mod capabilities;
+mod readahead;
+mod negative;
+mod changes;
+mod statfs;
+mod journal;
+mod spill;
+mod breaker;
+mod tokens;
+mod attach;
+mod stats;
+mod health;
+mod modes;
+
+// A provider that couldn't be reached when it was registered, its root stays mounted and
+// registration is retried in the background on access once `retry_at` is passed.
+struct OfflineProvider {
+    credentials: Value,
+    retry_at: SystemTime,
+    // tried again by a task
+    probing: bool,
+}
+
+impl OfflineProvider {
+    fn new(credentials: Value, retry_at: SystemTime) -> Self {
+        Self { credentials, retry_at, probing: false }
+    }
+}
+
+// An account found in the configuration, the credential store or a data directory, not
+// registered yet.
+struct FoundProvider {
+    provider_id: ProviderId,
+    credentials: Value,
+    // shown at the root
+    name: String,
+    owner: Option<(u32, u32)>,
+    // where its refreshed tokens are written back
+    path: Option<PathBuf>,
+}
 
 pub struct FuseFS {
-    providers: ProvidersMap,
+    // shared with the tasks running provider requests
+    providers: Providers,
+    offline: HashMap<ProviderId, OfflineProvider>,
+    // providers whose token was rejected, their subtree is off limits until re-authenticated
+    reauth_required: HashSet<ProviderId>,
+    credential_paths: HashMap<ProviderId, PathBuf>,
+    // directories served by NativeFs providers, permissions are changed there directly
+    native_roots: HashMap<ProviderId, String>,
     tree: FsTree,
     mount_point: PathBuf,
+    uid: u32,
+    gid: u32,
+    permanent_delete: bool,
+    // files uploaded at once to a provider when several are flushed together
+    upload_parallelism: usize,
+    upload_limits: HashMap<ProviderId, Arc<Semaphore>>,
+    // rmdir of a non-empty folder deletes it with its content when its provider can
+    recursive_rmdir: bool,
+    // inode of the provider folder served at the root of the mount in place of the providers
+    single_root: Option<u64>,
+    // mounted with `ro`, nothing reaches the providers but the control files stay writable
+    read_only: bool,
+    modes: Modes,
+    deletion_guard: DeletionGuard,
+    policy: Policy,
+    denials: Denials,
+    failures: Failures,
+    // time spent in each FUSE callback and how often it failed
+    operation_stats: OperationStats,
+    transfers: Transfers,
+    cache_counters: CacheCounters,
+    mime_map: MimeMap,
+    searches: Searches,
+    // subtrees listed in the background for walks
+    crawls: Crawls,
+    // API requests sent to each provider, background work backs off as their quota runs out
+    quotas: ApiQuotas,
+    // empty files created locally, they reach their provider on first write or in a batch
+    pending_creates: PendingCreates,
+    // cloud deletes waiting to be sent in a batch, the objects are already gone from the tree
+    delete_queue: DeleteQueue,
+    // writes, renames and deletes waiting for their provider to be reachable again
+    journal: Journal,
+    // what dirty handles wrote, kept on disk until it is uploaded or in the journal
+    spills: Spills,
+    // OAuth clients the tokens are refreshed with
+    api_keys: ApiKeys,
+    refreshed: Refreshed,
+    // content each open file handle reads from
+    handles: Handles,
+    // declared when each provider is registered
+    capabilities: HashMap<ProviderId, Capabilities>,
+    // content of files read from providers, read again without a request while unchanged
+    content_cache: Option<ContentCache>,
+    // recently read chunks, in front of the content cache
+    blocks: BlockCache,
+    // chunks loaded ahead of sequential reads, by default and by provider name
+    read_ahead: u64,
+    provider_read_ahead: HashMap<String, u64>,
+    dispatcher: Dispatcher,
+    listings: Listings,
+    negative_entries: NegativeEntries,
+    // lists folders again in the background, by default and by provider name
+    poller: Poller,
+    poll_interval: Duration,
+    poll_intervals: HashMap<String, Duration>,
+    // polling and token refresh of each provider, stopped when it is detached
+    provider_tasks: HashMap<ProviderId, Vec<JoinHandle<()>>>,
+    // Runs every provider request, built on init so its threads are created once the daemon
+    // dropped its privileges and entered the sandbox.
+    runtime: Option<Runtime>,
+    // answer to the last path written to the find control file
+    found: String,
+    // answer to the last command written to the report control file
+    report: String,
 }
 
 const TTL: Duration = Duration::from_secs(1);
+const DEFAULT_UPLOAD_PARALLELISM: usize = 4;
+
+// Credential stores are read concurrently, and the accounts are all tried at once under a
+// single timeout so unreachable ones don't add up.
+const MAX_PARALLEL_INIT: usize = 4;
+const PROVIDER_INIT_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+// how long an unmount waits for the uploads, creations and deletes it sends
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(60);
+// how long an unmount waits for the journal's workers
+const JOURNAL_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+// how long an unmount waits for the background tasks once they are told to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
-const ROOT_DIR_ATTR: FileAttr = FileAttr {
-    ino: 1,
-    size: 0,
-    blocks: 0,
-    atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-    mtime: UNIX_EPOCH,
-    ctime: UNIX_EPOCH,
-    crtime: UNIX_EPOCH,
-    kind: FileType::Directory,
-    perm: 0o755,
-    nlink: 4,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-    blksize: 512,
-};
+// Folder at the root of trash-capable providers receiving deleted files.
+const TRASH_DIR_NAME: &str = ".Trash";
 
 impl FuseFS {
-    pub async fn new(mut providers: ProvidersMap, mount_point: &Path) -> Self {
-        let storage = NativeFs { root : "".to_string() };
+    // `users` lists the other local users served by a multi-user mount, each one only sees
+    // the providers found in their own credential store.
+    pub async fn new(mut providers: ProvidersMap, mount_point: &Path, uid: u32, gid: u32, users: &[Owner], config: &Config, vault: &Vault) -> Self {
+        let own = if users.is_empty() { None } else { Some((uid, gid)) };
+        let mut found = Vec::new();
+        let mut offline = HashMap::new();
+        let mut credential_paths = HashMap::new();
+        let mut native_roots: HashMap<ProviderId, String> = config.providers.iter()
+            .filter(|entry| entry.provider_type == "NativeFs")
+            .filter_map(|entry| Some((ProviderId { id: entry.name.clone(), provider_type: ProviderType::NativeFs }, entry.root.as_ref()?.to_string_lossy().to_string() + "/")))
+            .collect();
+
+        found.extend(Self::load_configured(&config.providers, own));
+        found.extend(Self::load_sealed(vault, own));
 
-        if let Some(proj_dirs) = ProjectDirs::from("", "Orbital", "Files") {
+        if let Some(proj_dirs) = ProjectDirs::from("", "Orbital", "Files").filter(|_| config.mount.scan_data_dir) {
             let data_dir = (proj_dirs.data_dir().to_string_lossy() + "/").to_string();
-            let x = &data_dir.clone();
-            if !std::path::Path::new(data_dir.as_str()).exists() {
-                fs::create_dir_all(data_dir.clone()).expect(format!("Unable to create directory {}", data_dir).as_str());
+            if let Err(error) = fs::create_dir_all(&data_dir) {
+                warn!("unable to create {}: {}", data_dir, error);
             }
-            let files = storage.read_directory(ObjectId::directory(data_dir.clone())).await.unwrap();
-    
-            for file in files {
-                let path = x.clone() + "/" + file.name.as_str();
-                let content = storage.read_file(ObjectId::plain_text(path.clone())).await.unwrap();
-                let file_name_split: Vec<&str> = file.name.splitn(2, ".").collect();
-    
-    
-                let content_string = String::from_utf8(content).unwrap();
-    
-                match file_name_split[1] {
-                    "S3" => {
-                        let credentials: Value = serde_json::from_str(content_string.as_str()).unwrap();
-                        providers.add_provider(ProviderId { id: file_name_split[0].to_string(), provider_type: ProviderType::S3 }, credentials).await.unwrap();
-                    },
-                    "GoogleDrive" => {
-                        let tokens: HashMap<String, Token> = serde_json::from_str(content_string.as_str()).unwrap();
-                        providers.add_provider(ProviderId { id: file_name_split[0].to_string(), provider_type: ProviderType::GoogleDrive }, serde_json::to_value(tokens).unwrap()).await.unwrap();
-                    },
-                    "OneDrive" => {
-                        let token: Option<OneDriveToken> = serde_json::from_str(content_string.as_str()).unwrap();
-                        providers.add_provider(ProviderId { id: file_name_split[0].to_string(), provider_type: ProviderType::OneDrive }, serde_json::to_value(token).unwrap()).await.unwrap();
-                    },
-                    _ => ()
+
+            found.extend(Self::load_credentials(&data_dir, own).await);
+
+            // other users keep their store at the same place relative to their home
+            if let Some(user_dirs) = UserDirs::new() {
+                if let Ok(relative_data_dir) = proj_dirs.data_dir().strip_prefix(user_dirs.home_dir()) {
+                    for user in users {
+                        let user_data_dir = user.home.join(relative_data_dir);
+                        if user_data_dir.exists() {
+                            let user_data_dir = (user_data_dir.to_string_lossy() + "/").to_string();
+                            found.extend(Self::load_credentials(&user_data_dir, Some((user.uid, user.gid))).await);
+                        }
+                    }
                 }
-                
             }
         }
     
-        if let Some(user_dirs) = UserDirs::new() {
+        let registered = Providers::default();
+        let mut loaded = Self::register_all(&registered, &mut offline, &mut credential_paths, found, &config.api_keys).await;
+
+        if let Some(user_dirs) = UserDirs::new().filter(|_| config.mount.local_files) {
             let home_path = (user_dirs.home_dir().to_string_lossy() + "/").to_string();
             let provider = ProviderId {
                 id: "Local files".to_string(),
                 provider_type: crossroads::storage::ProviderType::NativeFs,
             };
     
-            providers.add_provider(provider, serde_json::to_value(home_path.clone()).unwrap()).await.unwrap();
+            match providers.add_provider(provider.clone(), Value::from(home_path.clone())).await {
+                Ok(_) => {
+                    native_roots.insert(provider.clone(), home_path);
+                    loaded.push((provider, "Local files".to_string(), own));
+                },
+                Err(error) => warn!("local files are not mounted: {:?}", error),
+            }
         }
 
-        let providers_list = providers.list_providers();
+        let capabilities = loaded.iter().map(|(provider_id, _, _)| (provider_id.clone(), Capabilities::of(&provider_id.provider_type))).collect();
+
+        let shared = loaded.iter().filter(|(_, _, owner)| owner.is_none()).map(|(provider_id, name, _)| (provider_id.clone(), name.clone())).collect();
+        let mut tree = FsTree::new(shared, uid, gid);
+        tree.ttl = config.ttl.clone();
+        tree.provider_ttls = config.providers.iter()
+            .filter(|entry| !entry.ttl.is_empty())
+            .map(|entry| (entry.name.clone(), entry.ttl.over(&config.ttl)))
+            .collect();
+
+        for (provider_id, name, owner) in loaded {
+            if owner.is_some() {
+                tree.new_provider(ObjectId::root(), &name, 0, Arc::new(provider_id), owner);
+            }
+        }
         
-        FuseFS { providers, tree: FsTree::new(providers_list), mount_point: fs::canonicalize(mount_point).unwrap().to_path_buf() }
+        registered.insert(providers);
+        let journal = Journal::load();
+        let spills = Spills::new();
+        FuseFS {
+            providers: registered.clone(),
+            offline,
+            reauth_required: HashSet::new(),
+            credential_paths,
+            native_roots,
+            tree,
+            mount_point: fs::canonicalize(mount_point).unwrap().to_path_buf(),
+            uid,
+            gid,
+            permanent_delete: false,
+            upload_parallelism: DEFAULT_UPLOAD_PARALLELISM,
+            upload_limits: HashMap::new(),
+            recursive_rmdir: false,
+            single_root: None,
+            read_only: false,
+            modes: Modes::default(),
+            deletion_guard: DeletionGuard::default(),
+            policy: Policy::default(),
+            denials: Denials::default(),
+            failures: Failures::default(),
+            operation_stats: OperationStats::default(),
+            transfers: Transfers::default(),
+            cache_counters: CacheCounters::default(),
+            mime_map: MimeMap::default(),
+            searches: Searches::default(),
+            crawls: Crawls::default(),
+            quotas: ApiQuotas::default(),
+            pending_creates: PendingCreates::default(),
+            delete_queue: DeleteQueue::default(),
+            journal,
+            spills,
+            api_keys: config.api_keys.clone(),
+            refreshed: Refreshed::default(),
+            handles: Handles::default(),
+            dispatcher: Dispatcher::default(),
+            listings: Listings::default(),
+            negative_entries: NegativeEntries::new(config.ttl.negative()),
+            poller: Poller::new(registered),
+            poll_interval: Duration::from_secs(config.mount.poll_interval),
+            poll_intervals: config.providers.iter().filter_map(|entry| Some((entry.name.clone(), Duration::from_secs(entry.poll_interval?)))).collect(),
+            provider_tasks: HashMap::new(),
+            capabilities,
+            content_cache: None,
+            blocks: BlockCache::new(DEFAULT_CHUNK_SIZE, DEFAULT_MEMORY_SIZE),
+            read_ahead: DEFAULT_READ_AHEAD,
+            provider_read_ahead: config.providers.iter().filter_map(|entry| Some((entry.name.clone(), entry.read_ahead?))).collect(),
+            runtime: None,
+            found: String::new(),
+            report: String::new(),
+        }
+    }
+
+    // Deletes objects for good on every provider instead of moving them to the trash.
+    pub fn with_permanent_delete(mut self, permanent_delete: bool) -> Self {
+        self.permanent_delete = permanent_delete;
+        self
+    }
+
+    // Shares what the session needs to tell the kernel about remote changes.
+    pub fn poller(&self) -> Poller {
+        self.poller.clone()
+    }
+
+    pub fn with_upload_parallelism(mut self, upload_parallelism: usize) -> Self {
+        self.upload_parallelism = std::cmp::max(upload_parallelism, 1);
+        self
+    }
+
+    pub fn with_content_cache(mut self, content_cache: ContentCache) -> Self {
+        self.journal.set_sealer(content_cache.sealer());
+        self.spills.set_sealer(content_cache.sealer());
+        self.content_cache = Some(content_cache);
+        self
+    }
+
+    pub fn with_block_cache(mut self, chunk_size: u64, memory_size: u64, read_ahead: u64) -> Self {
+        self.blocks = BlockCache::new(chunk_size, memory_size);
+        self.read_ahead = read_ahead;
+        self
+    }
+
+    // Every credential file found in `data_dir`. Providers belonging to a user of a multi-user
+    // mount get their uid as prefix so accounts with the same name don't collide.
+    async fn load_credentials(data_dir: &str, owner: Option<(u32, u32)>) -> Vec<FoundProvider> {
+        let storage = NativeFs { root : "".to_string() };
+        let semaphore = Arc::new(Semaphore::new(MAX_PARALLEL_INIT));
+        let mut tasks = JoinSet::new();
+        let mut loaded = Vec::new();
+
+        let files = match storage.read_directory(ObjectId::directory(data_dir.to_string())).await {
+            Ok(files) => files,
+            Err(error) => {
+                warn!("unable to list {}: {:?}", data_dir, error);
+                return loaded;
+            },
+        };
+
+        for file in files {
+            let path = data_dir.to_string() + "/" + file.name.as_str();
+            let semaphore = semaphore.clone();
+
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await.unwrap();
+                let content = tokio::fs::read_to_string(&path).await.ok()?;
+                let file_name_split: Vec<&str> = file.name.splitn(2, ".").collect();
+
+                if file_name_split.len() < 2 {
+                    return None;
+                }
+
+                let (provider_type, credentials) = Self::parse_credentials(file_name_split[1], &content)?;
+
+                Some((file_name_split[0].to_string(), provider_type, credentials, PathBuf::from(&path)))
+            });
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            if let Ok(Some((name, provider_type, credentials, path))) = result {
+                let id = match owner {
+                    Some((uid, _)) => format!("{}:{}", uid, name),
+                    None => name.clone(),
+                };
+                let provider_id = ProviderId { id, provider_type };
+                loaded.push(FoundProvider { provider_id, credentials, name, owner, path: Some(path) });
+            }
+        }
+
+        loaded
+    }
+
+    // The credential files of the encrypted store, as if they were in the data directory.
+    // Having no file to write to, their refreshed tokens only last until unmount.
+    fn load_sealed(vault: &Vault, owner: Option<(u32, u32)>) -> Vec<FoundProvider> {
+        let mut loaded = Vec::new();
+
+        for (file_name, content) in &vault.files {
+            let parsed = file_name.split_once('.').and_then(|(name, provider_type)| Some((name, Self::parse_credentials(provider_type, content)?)));
+            let (name, (provider_type, credentials)) = match parsed {
+                Some(parsed) => parsed,
+                None => {
+                    warn!("{} of the credential store can't be parsed, skipping it", file_name);
+                    continue;
+                },
+            };
+
+            let provider_id = ProviderId { id: name.to_string(), provider_type };
+            loaded.push(FoundProvider { provider_id, credentials, name: name.to_string(), owner, path: None });
+        }
+
+        loaded
+    }
+
+    // The providers listed in the configuration file, shown under their display name.
+    // Entries whose credentials can't be read are skipped.
+    fn load_configured(entries: &[ProviderEntry], owner: Option<(u32, u32)>) -> Vec<FoundProvider> {
+        let mut loaded = Vec::new();
+
+        for entry in entries {
+            let (provider_type, credentials) = match entry.provider_type.as_str() {
+                "NativeFs" => match &entry.root {
+                    Some(root) => (ProviderType::NativeFs, serde_json::to_value(root.to_string_lossy() + "/").unwrap()),
+                    None => {
+                        warn!("provider {} has no root, skipping it", entry.name);
+                        continue;
+                    },
+                },
+                provider_type => {
+                    let content = entry.credentials.as_ref().and_then(|path| fs::read_to_string(path).ok());
+                    match content.and_then(|content| Self::parse_credentials(provider_type, &content)) {
+                        Some(parsed) => parsed,
+                        None => {
+                            warn!("provider {} has no readable {} credentials, skipping it", entry.name, provider_type);
+                            continue;
+                        },
+                    }
+                },
+            };
+
+            loaded.push(FoundProvider {
+                provider_id: ProviderId { id: entry.name.clone(), provider_type },
+                credentials,
+                name: entry.display_name.clone().unwrap_or_else(|| entry.name.clone()),
+                owner,
+                path: entry.credentials.clone(),
+            });
+        }
+
+        loaded
+    }
+
+    // Mounts every account found, online or not, those without an API key for their type are
+    // left out. The accounts are tried all at once, each in a map of its own, under a single
+    // timeout so unreachable ones don't add up. Those that didn't answer are mounted offline and
+    // tried again in the background.
+    async fn register_all(providers: &Providers, offline: &mut HashMap<ProviderId, OfflineProvider>, credential_paths: &mut HashMap<ProviderId, PathBuf>, found: Vec<FoundProvider>, keys: &ApiKeys) -> Vec<(ProviderId, String, Option<(u32, u32)>)> {
+        let found: Vec<FoundProvider> = found.into_iter()
+            .filter(|found| match keys.missing(&found.provider_id.provider_type) {
+                Some(error) => {
+                    warn!("provider {} is not mounted: {}", found.provider_id.id, error);
+                    false
+                },
+                None => true,
+            })
+            .collect();
+
+        let deadline = Instant::now() + PROVIDER_INIT_TIMEOUT;
+        let registered = join_all(found.iter().map(|found| {
+            tokio::time::timeout_at(deadline.into(), register(keys, &found.provider_id, found.credentials.clone()))
+        })).await;
+
+        let mut loaded = Vec::new();
+        for (found, registered) in found.into_iter().zip(registered) {
+            let provider_id = found.provider_id;
+            match registered {
+                Ok(Some(registered)) => providers.insert(registered),
+                _ => {
+                    warn!("provider {} is unreachable, mounting it offline", provider_id.id);
+                    offline.insert(provider_id.clone(), OfflineProvider::new(found.credentials, SystemTime::now() + RECONNECT_INTERVAL));
+                },
+            }
+            if let Some(path) = found.path {
+                credential_paths.insert(provider_id.clone(), path);
+            }
+
+            loaded.push((provider_id, found.name, found.owner));
+        }
+
+        loaded
+    }
+
+    // Registers a single provider from the credential store, for commands working without
+    // the mount.
+    pub async fn register_provider(providers: &mut ProvidersMap, name: &str) -> Option<ProviderId> {
+        let proj_dirs = ProjectDirs::from("", "Orbital", "Files")?;
+
+        for entry in fs::read_dir(proj_dirs.data_dir()).ok()? {
+            let file_name = entry.ok()?.file_name().to_string_lossy().to_string();
+            let file_name_split: Vec<&str> = file_name.splitn(2, ".").collect();
+            if file_name_split.len() < 2 || file_name_split[0] != name {
+                continue;
+            }
+
+            let content = fs::read_to_string(proj_dirs.data_dir().join(&file_name)).ok()?;
+            let (provider_type, credentials) = Self::parse_credentials(file_name_split[1], &content)?;
+            let provider_id = ProviderId { id: name.to_string(), provider_type };
+
+            providers.add_provider(provider_id.clone(), credentials).await.ok()?;
+            return Some(provider_id);
+        }
+
+        None
+    }
+
+    pub fn parse_credentials(provider_type: &str, content: &str) -> Option<(ProviderType, Value)> {
+        match provider_type {
+            "S3" => {
+                let credentials: Value = serde_json::from_str(content).ok()?;
+                Some((ProviderType::S3, credentials))
+            },
+            "GoogleDrive" => {
+                let tokens: HashMap<String, Token> = serde_json::from_str(content).ok()?;
+                Some((ProviderType::GoogleDrive, serde_json::to_value(tokens).ok()?))
+            },
+            "OneDrive" => {
+                let token: Option<OneDriveToken> = serde_json::from_str(content).ok()?;
+                Some((ProviderType::OneDrive, serde_json::to_value(token).ok()?))
+            },
+            _ => None
+        }
+    }
+
+    // Provider requests share one runtime, connections and tokens are kept from one request to
+    // the next.
+    fn runtime(&self) -> Handle {
+        self.runtime.as_ref().expect("runtime used before init").handle().clone()
+    }
+
+    // Tells whether a provider can be used, trying to register an offline provider again once
+    // its retry delay is over. The error is the errno to answer for its subtree.
+    fn check_provider(&mut self, provider_id: &ProviderId) -> Result<(), c_int> {
+        if self.reauth_required.contains(provider_id) {
+            return Err(EACCES);
+        }
+        if breaker::is_open(&provider_id.id) {
+            self.start_probe(provider_id);
+            return Err(ENETUNREACH);
+        }
+
+        match self.offline.get(provider_id) {
+            Some(offline) if offline.probing || offline.retry_at > SystemTime::now() => Err(ENETUNREACH),
+            Some(_) => {
+                self.start_reconnect(provider_id);
+                Err(ENETUNREACH)
+            },
+            None => Ok(()),
+        }
+    }
+
+    // Tries an offline provider again in a map of its own, the request that noticed it was
+    // due doesn't wait for the answer.
+    fn start_reconnect(&mut self, provider_id: &ProviderId) {
+        let offline = match self.offline.get_mut(provider_id) {
+            Some(offline) => offline,
+            None => return,
+        };
+        offline.probing = true;
+
+        let (keys, credentials, provider_id) = (self.api_keys.clone(), offline.credentials.clone(), Arc::new(provider_id.clone()));
+        self.spawn(async move {
+            let providers = tokio::time::timeout(PROVIDER_INIT_TIMEOUT, register(&keys, &provider_id, credentials)).await.ok().flatten();
+            Some(Completion::Probed { provider_id, providers })
+        });
+    }
+
+    // Offline providers whose retry delay is over.
+    fn reconnect_due(&mut self) {
+        let due: Vec<ProviderId> = self.offline.iter()
+            .filter(|(_, offline)| !offline.probing && offline.retry_at <= SystemTime::now())
+            .map(|(provider_id, _)| provider_id.clone())
+            .collect();
+        for provider_id in due {
+            self.start_reconnect(&provider_id);
+        }
+    }
+
+    // A provider that answered is used from the map it was registered in.
+    pub fn apply_probed(&mut self, provider_id: &ProviderId, providers: Option<ProvidersMap>) {
+        let offline = match self.offline.get_mut(provider_id) {
+            Some(offline) => offline,
+            None => return,
+        };
+        match providers {
+            Some(providers) => {
+                info!("provider {} is back online", provider_id.id);
+                self.providers.insert(providers);
+                self.offline.remove(provider_id);
+            },
+            None => {
+                offline.probing = false;
+                offline.retry_at = SystemTime::now() + RECONNECT_INTERVAL;
+            },
+        }
+    }
+
+    // Stops using a provider whose credentials were rejected instead of hammering it with
+    // requests that are bound to fail.
+    fn require_reauth(&mut self, provider_id: &ProviderId) {
+        self.refresh_token_now(provider_id);
+        if self.reauth_required.insert(provider_id.clone()) {
+            info!("provider {} requires a new login, write its name to {}/reauth once done", provider_id.id, control::CONTROL_DIR_NAME);
+            notifications::notify(Event::ReauthRequired { provider: provider_id.id.clone() });
+        }
+    }
+
+    // Reloads the credential file of a provider and registers it again in the background, its
+    // subtree is usable again once the new token is accepted.
+    fn reauthenticate(&mut self, id: &str) -> Result<(), c_int> {
+        let provider_id = self.reauth_required.iter().find(|provider_id| provider_id.id == id).cloned().ok_or(ENOENT)?;
+        let path = self.credential_paths.get(&provider_id).cloned().ok_or(ENOENT)?;
+
+        let content = fs::read_to_string(&path).map_err(|_| EIO)?;
+        let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+        let file_name_split: Vec<&str> = file_name.splitn(2, ".").collect();
+        let (_, credentials) = Self::parse_credentials(file_name_split.get(1).ok_or(EINVAL)?, &content).ok_or(EINVAL)?;
+
+        info!("provider {} is registered again with its new credentials", provider_id.id);
+        self.reauth_required.remove(&provider_id);
+        self.offline.insert(provider_id.clone(), OfflineProvider::new(credentials, SystemTime::now()));
+        self.start_reconnect(&provider_id);
+        Ok(())
+    }
+
+    // Lets `rmdir` remove a non-empty folder in one provider request on Drive and OneDrive
+    // instead of waiting for the kernel to unlink its content one entry at a time.
+    pub fn with_recursive_rmdir(mut self, recursive_rmdir: bool) -> Self {
+        self.recursive_rmdir = recursive_rmdir;
+        self
     }
 
-    fn get_children(&mut self, node: &mut FsNode) -> Vec<Arc<Mutex<FsNode>>> {
+    // Who the files of cloud providers belong to and their permissions, the mounting user's by
+    // default. Local files keep theirs.
+    pub fn with_ownership(mut self, uid: u32, gid: u32, modes: Modes) -> Self {
+        self.uid = uid;
+        self.gid = gid;
+        self.modes = modes;
+        self
+    }
+
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    // Serves one provider's files at the root of the mount, `name` is the provider's or its
+    // folder's. The control directory is still there, it is only not listed.
+    pub fn with_single_provider(mut self, name: &str) -> Result<Self, String> {
+        let root = self.tree.providers(self.uid).into_iter()
+            .find(|node| {
+                let node = node.lock().unwrap();
+                node.provider_id.id == name || node.name == name
+            })
+            .ok_or_else(|| format!("no provider named {}", name))?;
+        self.single_root = Some(root.lock().unwrap().inode);
+        Ok(self)
+    }
+
+    // Refuses recursive deletes removing more than this many files or bytes from a cloud
+    // provider until they are approved through the control directory.
+    pub fn with_deletion_guard(mut self, max_files: Option<usize>, max_bytes: Option<u64>) -> Self {
+        self.deletion_guard = DeletionGuard::new(max_files, max_bytes);
+        self
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_mime_map(mut self, mime_map: MimeMap) -> Self {
+        self.mime_map = mime_map;
+        self
+    }
+
+    // Evaluates the configured rules for a mutating operation on `path`, a path from the
+    // mount root as given by the tree.
+    fn check_policy(&mut self, req: &Caller, provider_id: &ProviderId, operation: Operation, path: &Path, size: u64) -> Result<(), c_int> {
+        if self.read_only {
+            return Err(EROFS);
+        }
+        let in_provider = Path::new("/").join(path.components().skip(2).collect::<PathBuf>());
+
+        let result = self.policy.check(&provider_id.id, operation, &in_provider, size).map_err(|rule| rule.to_string());
+        if let Err(rule) = result {
+            self.deny(req, operation, path, format!("policy ({})", rule));
+            return Err(EPERM);
+        }
+
+        Ok(())
+    }
+
+    // Keeps track of a refused operation so a surprising EPERM can be explained by reading
+    // the denials control file.
+    fn deny(&mut self, req: &Caller, operation: Operation, path: &Path, reason: String) {
+        self.denials.record(Denial {
+            at: SystemTime::now(),
+            operation,
+            path: path.to_path_buf(),
+            reason,
+            uid: req.uid(),
+            pid: req.pid(),
+        });
+    }
+
+    fn capabilities(&self, provider_id: &ProviderId) -> Capabilities {
+        self.capabilities.get(provider_id).copied().unwrap_or_else(|| Capabilities::of(&provider_id.provider_type))
+    }
+
+    // Keeps a provider error around for the errors control file, the application only gets
+    // an errno.
+    fn fail(&mut self, operation: &'static str, inode: u64, provider_id: &ProviderId, error: String) {
+        self.operation_stats.record_error(operation);
+        self.failures.record(Failure {
+            at: SystemTime::now(),
+            operation,
+            path: self.tree.path(inode).unwrap_or_default(),
+            provider: provider_id.id.clone(),
+            error,
+        });
+    }
+
+    // Records a provider's refusal and tells the errno it maps to, a rejected token also takes
+    // the provider offline until it is authenticated again.
+    fn failed(&mut self, operation: &'static str, inode: u64, provider_id: &ProviderId, error: String) -> c_int {
+        if is_auth_error(&error) {
+            self.require_reauth(provider_id);
+        }
+        let errno = errno(&error);
+        self.fail(operation, inode, provider_id, error);
+        errno
+    }
+
+    // Runs a callback so that a panic fails the one operation instead of the mount. The reply
+    // dropped on the way out answers EIO, the locks the callback held are usable again. What
+    // the callback logs is tagged with the operation and inode. What the tasks finished is
+    // applied first.
+    fn isolate<F: FnOnce(&mut Self)>(&mut self, operation: &'static str, inode: u64, callback: F) {
+        let _span = info_span!("fuse", op = operation, ino = inode).entered();
+        let started = Instant::now();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.apply_completions();
+            callback(self);
+            self.flush_due();
+        }));
+        self.operation_stats.record(operation, started.elapsed());
+        let panic = match result {
+            Ok(()) => return,
+            Err(panic) => panic,
+        };
+        self.operation_stats.record_error(operation);
+        let message = panic.downcast_ref::<&str>().map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+
+        self.tree.clear_poison();
+        let provider = self.tree.find_with_inode(inode).map(|node| node.lock().unwrap().provider_id.id.clone()).unwrap_or_default();
+        self.failures.record(Failure {
+            at: SystemTime::now(),
+            operation,
+            path: self.tree.path(inode).unwrap_or_default(),
+            provider,
+            error: format!("panicked: {}", message),
+        });
+    }
+
+    // The kernel's root is the provider's folder when a single provider is mounted.
+    fn mapped(&self, inode: u64) -> u64 {
+        match self.single_root {
+            Some(root) if inode == 1 => root,
+            _ => inode,
+        }
+    }
+
+    fn root_attr(&self) -> FileAttr {
+        FileAttr {
+            ino: 1,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH, // 1970-01-01 00:00:00
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::Directory,
+            perm: self.modes.perm(true),
+            nlink: 4,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 512,
+        }
+    }
+
+    // The kernel keeps a node's name and attributes as long as its provider is configured to.
+    fn reply_entry(&self, reply: ReplyEntry, node: &FsNode) {
+        reply.entry(&self.tree.ttl(&node.provider_id).entry(), &self.file_attr(node), self.tree.generation);
+    }
+
+    fn reply_attr(&self, reply: ReplyAttr, node: &FsNode) {
+        reply.attr(&self.tree.ttl(&node.provider_id).attr(), &self.file_attr(node));
+    }
+
+    // Cloud providers have no notion of local users, their files belong to whoever mounted them
+    // or, on a multi-user mount, to the user whose credentials they come from. Their permissions
+    // are the mount's.
+    fn file_attr(&self, node: &FsNode) -> FileAttr {
+        node_attr(node, self.uid, self.gid, &self.modes)
+    }
+
+    // A folder's children, None while its provider lists it. The callback then waits for the
+    // listing, see wait, and takes what it brought when run again.
+    fn get_children(&mut self, node: &mut FsNode) -> Option<Vec<Arc<Mutex<FsNode>>>> {
         if node.content_state == FileState::DeepReady {
             if let Some(expire_at) = node.expire_at {
                 if expire_at > SystemTime::now() {
-                    return node.children.clone();
+                    self.cache_counters.listing_hits += 1;
+                    return Some(node.children.clone());
                 }
             }
         }
+        // what the listing the callback waited for brought, even if it failed
+        if self.dispatcher.is_listed(node.inode) {
+            return Some(node.children.clone());
+        }
 
+        self.cache_counters.listing_misses += 1;
         return self.fetch_children(node);
     }
 
-    fn fetch_children(&mut self, node: &mut FsNode) -> Vec<Arc<Mutex<FsNode>>> {
-        let children;
-        let path = node.id.clone();
+    // Drops a node deleted from elsewhere without listing its whole folder again, as crossroads
+    // has no delta listings telling what changed in a folder.
+    fn forget_deleted(&mut self, inode: u64, node: Arc<Mutex<FsNode>>) {
+        let parent = match self.tree.parent(inode) {
+            Some(parent) => parent,
+            None => return,
+        };
 
-        if !path.is_directory() || path.as_str().contains("fuse/mnt"){
-            return Vec::new();
+        if let Some(parent_node) = self.tree.find_with_inode(parent) {
+            parent_node.lock().unwrap().children.retain(|child| !Arc::ptr_eq(child, &node));
         }
+        self.tree.remove(parent, node);
+    }
 
-        let fs_provider = self.providers.get_provider((*node.provider_id).clone()).unwrap();
+    // Brings the children of a directory in line with a fresh listing from its provider. The
+    // listing's metadata is kept so the stats following a readdir are served locally.
+    fn apply_listing(&mut self, node: &mut FsNode, files: Vec<File>) {
+        let provider_id = node.provider_id.clone();
 
-        
-        
-        match node.content_state.clone() {
-            FileState::ShallowReady => {
-                node.content_state = FileState::Loading;
-
-                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
-                let res = rt.block_on(async {
-                    fs_provider.as_filesystem().unwrap().read_directory(path).await
-                }).unwrap();
-
-                let provider_id = node.provider_id.clone();
-
-                for file in res {
-                    println!("{}", file.name.as_str());
-                    self.tree.new_file(
-                        node,
-                        file.id.clone(),
-                        file.name.as_str(),
-                        if let Some(metadata) = file.metadata { Some(metadata.into()) } else { None },
-                        provider_id.clone(),
-                    );
+        let files: Vec<File> = files.into_iter().filter(|file| !self.delete_queue.contains(&provider_id, &file.id)).collect();
+        let pending_creates = &self.pending_creates;
+        node.children.retain(|child| {
+            let child = child.lock().unwrap();
+            files.iter().find(|file| file.id == child.id).is_some() || pending_creates.contains(child.inode)
+        });
+
+        for file in files {
+            let mime_type = file.metadata.as_ref().and_then(|metadata| metadata.mime_type.clone());
+            let web_url = file.metadata.as_ref().and_then(|metadata| metadata.open_path.clone());
+            let revision = file.metadata.as_ref().and_then(revision);
+
+            if let Some(child) = node.children.iter().find(|child| child.lock().unwrap().id == file.id) {
+                if let Some(metadata) = file.metadata {
+                    let mut child = child.lock().unwrap();
+                    child.mime_type = mime_type;
+                    child.web_url = web_url;
+                    child.revision = revision;
+                    // the provider doesn't know about writes still buffered
+                    if self.handles.is_written(child.inode) {
+                        continue;
+                    }
+                    child.metadata = Some(metadata.into());
+                    child.metadata_expire_at = Some(SystemTime::now() + self.tree.ttl(&provider_id).metadata());
                 }
+                continue;
+            }
+            let name = self.mime_map.display_name(&file.name, mime_type.as_deref());
+            let child = self.tree.new_file(
+                node,
+                file.id.clone(),
+                name.as_str(),
+                if let Some(metadata) = file.metadata { Some(metadata.into()) } else { None },
+                provider_id.clone(),
+            );
+            let mut child = child.lock().unwrap();
+            child.mime_type = mime_type;
+            child.web_url = web_url;
+            child.revision = revision;
+        }
 
-                node.expire_at = Some(SystemTime::now() + Duration::from_secs(1));
+        node.expire_at = Some(SystemTime::now() + self.tree.ttl(&provider_id).listing());
 
-                node.content_state = FileState::DeepReady;
+        node.content_state = FileState::DeepReady;
+        self.poller.watch(node);
+    }
 
-                children = node.children.clone();
-            },
-            FileState::Loading => {
-                while node.content_state == FileState::Loading {
-                    std::thread::sleep(Duration::from_millis(100));
-                }
-                children = node.children.clone();
-            },
-            FileState::DeepReady => {
-                node.content_state = FileState::Loading;
+    // Lists a folder from a task, its listing is applied before the callbacks waiting for it
+    // run again.
+    fn fetch_children(&mut self, node: &mut FsNode) -> Option<Vec<Arc<Mutex<FsNode>>>> {
+        if !node.id.is_directory() || self.is_mount_point(node) {
+            return Some(Vec::new());
+        }
 
-                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
-                let res = rt.block_on(async {
-                    fs_provider.as_filesystem().unwrap().read_directory(path).await
-                }).unwrap();
+        // offline providers keep serving whatever was already listed
+        if self.check_provider(&node.provider_id).is_err() {
+            return Some(node.children.clone());
+        }
 
-                let provider_id = node.provider_id.clone();
+        // listed once for every callback needing it
+        if self.dispatcher.is_awaited(Awaited::Listing(node.inode)) {
+            return None;
+        }
+        let providers = match self.providers.get(&node.provider_id) {
+            Some(providers) => providers,
+            None => return Some(node.children.clone()),
+        };
 
-                node.children.retain(|child| {
-                    let child = child.lock().unwrap();
-                    res.iter().find(|file| file.id == child.id).is_some()
-                });
+        self.quotas.record(&node.provider_id);
+        let previous = node.content_state;
+        node.content_state = FileState::Loading;
 
-                for file in res {
-                    if node.children.iter().find(|child| child.lock().unwrap().id == file.id).is_some() {
-                        continue;
+        let (inode, provider_id, id) = (node.inode, node.provider_id.clone(), node.id.clone());
+        let trace = telemetry::provider_request(&node.provider_id, "read_directory", &node.id);
+        self.spawn_awaited(&[Awaited::Listing(inode)], async move {
+            let provider = providers.get_provider(provider_id.as_ref().clone()).unwrap();
+            let result = provider.as_filesystem().unwrap().read_directory(id).await;
+            trace.finish(&result, 0);
+            Completion::Listed { inode, provider_id, previous, result: result.map_err(|error| format!("{:?}", error)) }
+        });
+        None
+    }
+
+    // Whatever was listed before is still served when the listing failed.
+    pub fn apply_listed(&mut self, inode: u64, provider_id: &ProviderId, previous: FileState, result: Result<Vec<File>, String>) {
+        if let Some(dir) = self.tree.find_with_inode(inode) {
+            let mut dir = dir.lock().unwrap();
+            match result {
+                Ok(files) => self.apply_listing(&mut dir, files),
+                Err(error) => {
+                    if dir.content_state == FileState::Loading {
+                        dir.content_state = previous;
                     }
-                    self.tree.new_file(
-                        node,
-                        file.id.clone(),
-                        file.name.as_str(),
-                        if let Some(metadata) = file.metadata { Some(metadata.into()) } else { None },
-                        provider_id.clone(),
-                    );
-                }
+                    drop(dir);
+                    self.fail("readdir", inode, provider_id, error.clone());
+                    if is_auth_error(&error) {
+                        self.require_reauth(provider_id);
+                    }
+                },
+            }
+        }
 
-                node.expire_at = Some(SystemTime::now() + Duration::from_secs(1));
+        self.dispatcher.set_listed(Some(inode));
+        self.resume(Awaited::Listing(inode), Ok(()));
+        self.dispatcher.set_listed(None);
+    }
 
-                node.content_state = FileState::DeepReady;
-                children = node.children.clone();
-            },
+    // A local provider serving a folder the mount is in would list the mount itself, waiting on
+    // its own callbacks.
+    fn is_mount_point(&self, node: &FsNode) -> bool {
+        match self.native_roots.get(&node.provider_id) {
+            Some(root) => Path::new(&(root.clone() + node.id.as_str())) == self.mount_point,
+            None => false,
         }
+    }
+}
 
-        children
+// As FuseFS::file_attr, for the tasks answering the kernel away from the filesystem.
+fn node_attr(node: &FsNode, uid: u32, gid: u32, modes: &Modes) -> FileAttr {
+    let mut attr: FileAttr = node.clone().into();
+
+    if let Some((uid, gid)) = node.owner {
+        attr.uid = uid;
+        attr.gid = gid;
+    } else if node.provider_id.provider_type != ProviderType::NativeFs {
+        attr.uid = uid;
+        attr.gid = gid;
+    }
+    if node.provider_id.provider_type != ProviderType::NativeFs {
+        attr.perm = modes.perm(attr.kind == FileType::Directory);
     }
+
+    attr
 }
 
 impl Filesystem for FuseFS {
+    // Lets knfsd re-export the mount, lookups of `.` and `..` resolve any known inode.
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        if let Err(unsupported) = config.add_capabilities(FUSE_EXPORT_SUPPORT) {
+            warn!("kernel doesn't support exporting the mount over NFS ({:#x})", unsupported);
+        }
+
+        match tokio::runtime::Builder::new_multi_thread().enable_all().thread_name("provider").build() {
+            Ok(runtime) => self.runtime = Some(runtime),
+            Err(error) => {
+                error!("unable to start the provider runtime: {}", error);
+                return Err(EIO);
+            },
+        }
+        // what a crash left unflushed goes to the journal before anything is written again
+        if let Err(error) = self.spills.recover(&self.journal) {
+            warn!("unable to recover the unflushed writes: {}", error);
+        }
+        self.start_polling();
+        self.start_workers();
+        self.start_token_refresh();
+        self.start_ticker();
+        self.reconnect_due();
+        Ok(())
+    }
+
+    // Called on unmount, whatever was only kept in memory is sent or saved before the daemon
+    // exits.
+    fn destroy(&mut self) {
+        self.dispatcher.stop();
+        self.apply_completions();
+        self.flush_handles();
+        self.flush_pending_creates(true);
+        self.settle(SETTLE_TIMEOUT);
+        // the files created and written are sent before what may replace them is deleted
+        self.flush_deletes(true);
+        self.settle(SETTLE_TIMEOUT);
+        // the operations still waiting are sent on the next mount
+        let journal = self.journal.clone();
+        self.runtime().block_on(journal.drain(JOURNAL_DRAIN_TIMEOUT));
+
+        if let Some(content_cache) = &self.content_cache {
+            if let Err(error) = content_cache.flush() {
+                warn!("unable to save the cache index: {}", error);
+            }
+        }
+
+        if let Err(error) = self.tree.save_inodes() {
+            warn!("unable to save the inodes: {}", error);
+        }
+
+        // polls and token refreshes, then whatever else still runs on the runtime
+        for task in self.provider_tasks.drain().flat_map(|(_, tasks)| tasks) {
+            task.abort();
+        }
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+        }
+    }
+
     fn lookup(&mut self, req: &Request, parent_inode: u64, name: &OsStr, reply: ReplyEntry) {
-        self.internal_lookup(req, parent_inode, name, reply)
+        let parent_inode = if name == CONTROL_DIR_NAME { parent_inode } else { self.mapped(parent_inode) };
+        let _trace = telemetry::operation("lookup", parent_inode);
+        let req = Caller::from(req);
+        self.isolate("lookup", parent_inode, |fs| fs.internal_lookup(&req, parent_inode, name, reply))
+    }
+
+    // Only search directories are dropped, nodes of the tree live as long as their listing.
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        let ino = self.mapped(ino);
+        self.searches.forget(ino, nlookup);
     }
 
     fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        self.internal_getattr(req, ino, reply)
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("getattr", ino);
+        let req = Caller::from(req);
+        self.isolate("getattr", ino, |fs| fs.internal_getattr(&req, ino, reply))
     }
 
     fn setattr(
@@ -227,7 +1134,10 @@ impl Filesystem for FuseFS {
             flags: Option<u32>,
             reply: ReplyAttr,
         ) {
-        self.internal_setattr(req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime, flags, reply)
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("setattr", ino);
+        let req = Caller::from(req);
+        self.isolate("setattr", ino, |fs| fs.internal_setattr(&req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime, flags, reply))
     }
 
     fn mknod(
@@ -240,11 +1150,17 @@ impl Filesystem for FuseFS {
             rdev: u32,
             reply: ReplyEntry,
         ) {
-        self.internal_mknod(req, parent, name, mode, umask, rdev, reply)
+        let parent = self.mapped(parent);
+        let _trace = telemetry::operation("mknod", parent);
+        let req = Caller::from(req);
+        self.isolate("mknod", parent, |fs| fs.internal_mknod(&req, parent, name, mode, umask, rdev, reply))
     }
 
     fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-        self.internal_unlink(req, parent, name, reply)
+        let parent = self.mapped(parent);
+        let _trace = telemetry::operation("unlink", parent);
+        let req = Caller::from(req);
+        self.isolate("unlink", parent, |fs| fs.internal_unlink(&req, parent, name, reply))
     }
 
     fn read(
@@ -258,7 +1174,10 @@ impl Filesystem for FuseFS {
             lock_owner: Option<u64>,
             reply: fuser::ReplyData,
         ) {
-        self.internal_read(req, ino, fh, offset, size, flags, lock_owner, reply)
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("read", ino);
+        let req = Caller::from(req);
+        self.isolate("read", ino, |fs| fs.internal_read(&req, ino, fh, offset, size, flags, lock_owner, reply))
     }
 
     fn rename(
@@ -271,11 +1190,47 @@ impl Filesystem for FuseFS {
             flags: u32,
             reply: fuser::ReplyEmpty,
         ) {
-        self.internal_rename(req, parent, name, newparent, newname, flags, reply)
+        let (parent, newparent) = (self.mapped(parent), self.mapped(newparent));
+        let _trace = telemetry::operation("rename", parent);
+        let req = Caller::from(req);
+        self.isolate("rename", parent, |fs| fs.internal_rename(&req, parent, name, newparent, newname, flags, reply))
     }
 
     fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
-        self.internal_open(req, ino, flags, reply)
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("open", ino);
+        let req = Caller::from(req);
+        self.isolate("open", ino, |fs| fs.internal_open(&req, ino, flags, reply))
+    }
+
+    fn release(
+            &mut self,
+            req: &Request<'_>,
+            ino: u64,
+            fh: u64,
+            flags: i32,
+            lock_owner: Option<u64>,
+            flush: bool,
+            reply: fuser::ReplyEmpty,
+        ) {
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("release", ino);
+        let req = Caller::from(req);
+        self.isolate("release", ino, |fs| fs.internal_release(&req, ino, fh, flags, lock_owner, flush, reply))
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: fuser::ReplyEmpty) {
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("flush", ino);
+        let req = Caller::from(req);
+        self.isolate("flush", ino, |fs| fs.internal_flush(&req, ino, fh, lock_owner, reply))
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: fuser::ReplyEmpty) {
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("fsync", ino);
+        let req = Caller::from(req);
+        self.isolate("fsync", ino, |fs| fs.internal_fsync(&req, ino, fh, datasync, reply))
     }
 
     fn write(
@@ -290,7 +1245,10 @@ impl Filesystem for FuseFS {
             lock_owner: Option<u64>,
             reply: fuser::ReplyWrite,
         ) {
-        self.internal_write(req, ino, fh, offset, data, write_flags, flags, lock_owner, reply)
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("write", ino);
+        let req = Caller::from(req);
+        self.isolate("write", ino, |fs| fs.internal_write(&req, ino, fh, offset, data, write_flags, flags, lock_owner, reply))
     }
 
     fn mkdir(
@@ -302,11 +1260,38 @@ impl Filesystem for FuseFS {
             umask: u32,
             reply: ReplyEntry,
         ) {
-        self.internal_mkdir(req, parent, name, mode, umask, reply)
+        let parent = self.mapped(parent);
+        let _trace = telemetry::operation("mkdir", parent);
+        let req = Caller::from(req);
+        self.isolate("mkdir", parent, |fs| fs.internal_mkdir(&req, parent, name, mode, umask, reply))
     }
 
     fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-        self.internal_rmdir(req, parent, name, reply)
+        let parent = self.mapped(parent);
+        let _trace = telemetry::operation("rmdir", parent);
+        let req = Caller::from(req);
+        self.isolate("rmdir", parent, |fs| fs.internal_rmdir(&req, parent, name, reply))
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("opendir", ino);
+        let req = Caller::from(req);
+        self.isolate("opendir", ino, |fs| fs.internal_opendir(&req, ino, flags, reply))
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, reply: fuser::ReplyEmpty) {
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("releasedir", ino);
+        let req = Caller::from(req);
+        self.isolate("releasedir", ino, |fs| fs.internal_releasedir(&req, ino, fh, flags, reply))
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("statfs", ino);
+        let req = Caller::from(req);
+        self.isolate("statfs", ino, |fs| fs.internal_statfs(&req, ino, reply))
     }
 
     fn readdir(
@@ -317,7 +1302,10 @@ impl Filesystem for FuseFS {
             offset: i64,
             reply: fuser::ReplyDirectory,
         ) {
-        self.internal_readdir(req, ino, fh, offset, reply)
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("readdir", ino);
+        let req = Caller::from(req);
+        self.isolate("readdir", ino, |fs| fs.internal_readdir(&req, ino, fh, offset, reply))
     }
 
     fn symlink(
@@ -328,10 +1316,59 @@ impl Filesystem for FuseFS {
             link: &Path,
             reply: ReplyEntry,
         ) {
-        self.internal_symlink(req, parent, name, link, reply)
+        let parent = self.mapped(parent);
+        let _trace = telemetry::operation("symlink", parent);
+        let req = Caller::from(req);
+        self.isolate("symlink", parent, |fs| fs.internal_symlink(&req, parent, name, link, reply))
     }
 
     fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: fuser::ReplyData) {
-        self.internal_readlink(req, ino, reply)
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("readlink", ino);
+        let req = Caller::from(req);
+        self.isolate("readlink", ino, |fs| fs.internal_readlink(&req, ino, reply))
+    }
+
+    fn getxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("getxattr", ino);
+        let req = Caller::from(req);
+        self.isolate("getxattr", ino, |fs| fs.internal_getxattr(&req, ino, name, size, reply))
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("listxattr", ino);
+        let req = Caller::from(req);
+        self.isolate("listxattr", ino, |fs| fs.internal_listxattr(&req, ino, size, reply))
     }
+
+    fn setxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, value: &[u8], _flags: i32, _position: u32, reply: fuser::ReplyEmpty) {
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("setxattr", ino);
+        let req = Caller::from(req);
+        self.isolate("setxattr", ino, |fs| fs.internal_setxattr(&req, ino, name, value, reply))
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("removexattr", ino);
+        let req = Caller::from(req);
+        self.isolate("removexattr", ino, |fs| fs.internal_removexattr(&req, ino, name, reply))
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        let ino = self.mapped(ino);
+        let _trace = telemetry::operation("access", ino);
+        let req = Caller::from(req);
+        self.isolate("access", ino, |fs| fs.internal_access(&req, ino, mask, reply))
+    }
+}
+
+// Registers a provider in a map of its own, so accounts can be tried at once and added while
+// the other providers are in use. None when it doesn't accept its credentials.
+async fn register(keys: &ApiKeys, provider_id: &ProviderId, credentials: Value) -> Option<ProvidersMap> {
+    let mut providers = ProvidersMap::new(keys.providers_options()).await;
+    providers.add_provider(provider_id.clone(), credentials).await.ok()?;
+    Some(providers)
 }
\ No newline at end of file
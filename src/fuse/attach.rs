@@ -0,0 +1,132 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crossroads::interfaces::filesystem::ObjectId;
+use crossroads::storage::{ProviderId, ProviderType};
+use directories::ProjectDirs;
+use libc::{c_int, EBUSY, EEXIST, EINVAL, ENOENT};
+use tracing::{info, warn};
+
+use crate::cache::KEY_FILE_NAME;
+use crate::vault::VAULT_FILE_NAME;
+use super::{FuseFS, OfflineProvider};
+use super::capabilities::Capabilities;
+use super::dispatch::Awaited;
+
+impl FuseFS {
+    // `NAME TYPE STATE` for every mounted provider, read from the providers control file.
+    pub fn providers_listing(&self) -> String {
+        let mut lines: Vec<String> = self.capabilities.keys()
+            .map(|provider_id| {
+                let state = if self.reauth_required.contains(provider_id) {
+                    "reauth"
+                } else if self.offline.contains_key(provider_id) {
+                    "offline"
+                } else {
+                    "online"
+                };
+                format!("{} {:?} {}\n", provider_id.id, provider_id.provider_type, state)
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+
+    // Mounts the credential file NAME.<Type> of the data directory, or the one at `source` when
+    // it is a path. Shown at the root under its name, offline if it can't be reached yet.
+    pub fn attach_provider(&mut self, source: &str) -> Result<(), c_int> {
+        let path = match source.contains('/') {
+            true => PathBuf::from(source),
+            false => find_credential_file(source).ok_or(ENOENT)?,
+        };
+        let file_name = path.file_name().ok_or(EINVAL)?.to_string_lossy().to_string();
+        let (name, provider_type) = file_name.split_once('.').ok_or(EINVAL)?;
+        if self.capabilities.keys().any(|provider_id| provider_id.id == name) || self.tree.find_provider(name, self.uid).is_some() {
+            return Err(EEXIST);
+        }
+
+        let content = fs::read_to_string(&path).map_err(|_| ENOENT)?;
+        let (provider_type, credentials) = Self::parse_credentials(provider_type, &content).ok_or(EINVAL)?;
+        let provider_id = ProviderId { id: name.to_string(), provider_type };
+
+        if let Some(error) = self.api_keys.missing(&provider_id.provider_type) {
+            warn!("provider {} is not mounted: {}", provider_id.id, error);
+            return Err(EINVAL);
+        }
+        // registered in the background, as a provider that went offline
+        self.offline.insert(provider_id.clone(), OfflineProvider::new(credentials, SystemTime::now()));
+        self.start_reconnect(&provider_id);
+
+        self.capabilities.insert(provider_id.clone(), Capabilities::of(&provider_id.provider_type));
+        self.credential_paths.insert(provider_id.clone(), path.clone());
+        self.tree.new_provider(ObjectId::root(), name, 0, Arc::new(provider_id.clone()), None);
+        self.negative_entries.forget(1);
+        self.poller.forget_entry(name, &provider_id);
+
+        self.start_polling_provider(provider_id.clone());
+        if matches!(provider_id.provider_type, ProviderType::GoogleDrive | ProviderType::OneDrive) {
+            self.start_provider_refresh(provider_id.clone(), path);
+        }
+        // what an earlier mount left for it
+        if self.journal.is_queued(&provider_id.id) {
+            self.start_worker(&provider_id.id);
+        }
+        info!("provider {} mounted", provider_id.id);
+        Ok(())
+    }
+
+    // Takes a provider's folder out of the mount once nothing written to it is left to send.
+    // crossroads can't unregister a provider, it is only not used anymore.
+    pub fn detach_provider(&mut self, id: &str, uid: u32) -> Result<(), c_int> {
+        let provider_id = self.capabilities.keys().find(|provider_id| provider_id.id == id).cloned().ok_or(ENOENT)?;
+        let root = self.tree.providers(uid).into_iter().find(|node| *node.lock().unwrap().provider_id == provider_id).ok_or(ENOENT)?;
+        // it is the whole mount
+        if Some(root.lock().unwrap().inode) == self.single_root {
+            return Err(EBUSY);
+        }
+
+        self.flush_pending_creates(true);
+        self.flush_deletes(true);
+        // their tasks use the provider until their completion
+        if self.dispatcher.is_awaited(Awaited::Deletes) || self.pending_creates.is_sending() {
+            warn!("provider {} still has deletes or creations on the way, not unmounting it", provider_id.id);
+            return Err(EBUSY);
+        }
+        let dirty = self.handles.dirty_handles().into_iter()
+            .filter_map(|handle| self.handles.dirty(handle))
+            .filter_map(|(inode, _)| self.tree.find_with_inode(inode))
+            .any(|node| *node.lock().unwrap().provider_id == provider_id);
+        if dirty || self.journal.is_queued(&provider_id.id) {
+            warn!("provider {} still has writes to send, not unmounting it", provider_id.id);
+            return Err(EBUSY);
+        }
+
+        for task in self.provider_tasks.remove(&provider_id).unwrap_or_default() {
+            task.abort();
+        }
+        self.forget_refresh(&provider_id);
+        self.providers.remove(&provider_id);
+        self.capabilities.remove(&provider_id);
+        self.credential_paths.remove(&provider_id);
+        self.offline.remove(&provider_id);
+        self.reauth_required.remove(&provider_id);
+
+        let name = root.lock().unwrap().name.clone();
+        self.tree.remove_provider(root);
+        self.poller.forget_entry(&name, &provider_id);
+        info!("provider {} unmounted", provider_id.id);
+        Ok(())
+    }
+}
+
+fn find_credential_file(name: &str) -> Option<PathBuf> {
+    let proj_dirs = ProjectDirs::from("", "Orbital", "Files")?;
+    fs::read_dir(proj_dirs.data_dir()).ok()?
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|file_name| file_name != VAULT_FILE_NAME && file_name != KEY_FILE_NAME)
+        .find(|file_name| file_name.split_once('.').map_or(false, |(file_name, _)| file_name == name))
+        .map(|file_name| proj_dirs.data_dir().join(file_name))
+}
\ No newline at end of file
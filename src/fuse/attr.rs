@@ -1,76 +1,239 @@
 use std::{ffi::OsStr};
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::time::SystemTime;
-use libc::ENOENT;
+use libc::{c_int, ENOENT, EACCES, EINVAL, EIO, ENETUNREACH, ENOTSUP, EPERM, EROFS, O_WRONLY, R_OK, W_OK, X_OK};
 
-use fuser::{ReplyAttr, ReplyEntry, Request};
+use fuser::{FileAttr, FileType, ReplyAttr, ReplyEntry};
+use crossroads::storage::ProviderId;
+use tracing::debug;
 
-use super::{FuseFS, TTL, ROOT_DIR_ATTR};
+use crate::fstree::{revision, FsNode, Metadata};
+use crate::telemetry;
+use super::{node_attr, FuseFS, TTL};
+use super::interrupt::interruptible;
+use super::dispatch::{Awaited, Caller, Completion};
+use super::errors::{errno, is_auth_error, is_network_error, is_not_found_error};
+use super::control::{is_control_inode, CONTROL_DIR_INODE, CONTROL_DIR_NAME};
+use super::dir::{parse_info_inode, PROVIDERS_DIR_INODE};
+use super::search::SEARCH_DIR_NAME;
+use super::policy::Operation;
 
 impl FuseFS {
-    pub fn internal_lookup(&mut self, _req: &Request, parent_inode: u64, name: &OsStr, reply: ReplyEntry) {
-        println!("lookup: {parent_inode}, {}", name.to_str().unwrap());
+    pub fn internal_lookup(&mut self, req: &Caller, parent_inode: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!(parent = parent_inode, name = ?name, "lookup");
+
+        if name == "." || name == ".." {
+            return self.lookup_relative(req, parent_inode, name, reply);
+        }
+
+        if (parent_inode == 1 && name == CONTROL_DIR_NAME) || is_control_inode(parent_inode) {
+            return self.control_lookup(parent_inode, name.to_str().unwrap(), req.uid(), reply);
+        }
+
+        if self.searches.contains(parent_inode) {
+            return self.search_lookup(req, parent_inode, name.to_str().unwrap(), reply);
+        }
+
+        if parent_inode == 1 {
+            match self.tree.find_provider(name.to_str().unwrap(), req.uid()) {
+                Some(fs_node) => self.reply_entry(reply, &fs_node.lock().unwrap()),
+                None => reply.error(ENOENT),
+            }
+            return;
+        }
+
+        if let Some(parent_node) = self.tree.find_with_inode(parent_inode) {
+            if !parent_node.lock().unwrap().visible_to(req.uid()) {
+                reply.error(ENOENT);
+                return;
+            }
+        }
 
         let mut node = self.tree.find_with_name(parent_inode, name.to_str().unwrap());
 
+        // files created through the mount are in the tree, whatever was missing before
+        if node.is_none() && self.negative_entries.contains(parent_inode, name.to_str().unwrap()) {
+            return reply.error(ENOENT);
+        }
+
         if node.is_none() {
             if let Some(parent_node) = self.tree.find_with_inode(parent_inode) {
                 if let Ok(mut parent_node) = parent_node.lock() {
-                    self.get_children(&mut parent_node);
+                    // looked up again once the folder is listed
+                    if self.get_children(&mut parent_node).is_none() {
+                        drop(parent_node);
+                        let (req, name) = (*req, name.to_os_string());
+                        return self.wait(Awaited::Listing(parent_inode), move |fs, _| fs.internal_lookup(&req, parent_inode, &name, reply));
+                    }
                     node = self.tree.find_with_name(parent_inode, name.to_str().unwrap());
                 }
             }
+            // `.search` only leads to the search directory where no file has that name
+            if node.is_none() && name == SEARCH_DIR_NAME {
+                return self.search_lookup(req, parent_inode, SEARCH_DIR_NAME, reply);
+            }
+            if node.is_none() {
+                self.negative_entries.insert(parent_inode, name.to_str().unwrap());
+            }
         }
         
         if let Some(fs_node) = node {
             if let Ok(node) = fs_node.lock() {
-                reply.entry(&TTL, &(*node).clone().into(), 0);
+                self.reply_entry(reply, &node);
             }
         } else {
             reply.error(ENOENT);
         }
     }
 
+    // When the mount is exported over NFS, knfsd turns file handles back into entries by
+    // looking up `.` and `..` on any inode the kernel still knows about.
+    fn lookup_relative(&mut self, req: &Caller, inode: u64, name: &OsStr, reply: ReplyEntry) {
+        let target = if name == "." {
+            inode
+        } else if let Some(parent) = self.searches.parent(inode) {
+            parent
+        } else if inode == CONTROL_DIR_INODE {
+            1
+        } else if inode == PROVIDERS_DIR_INODE {
+            CONTROL_DIR_INODE
+        } else if parse_info_inode(inode).is_some() {
+            PROVIDERS_DIR_INODE
+        } else {
+            self.tree.parent(inode).unwrap_or(1)
+        };
+        // the provider's folder is the root when it is mounted alone
+        let target = if Some(target) == self.single_root { 1 } else { target };
+
+        if target == 1 {
+            return reply.entry(&TTL, &self.root_attr(), 0);
+        }
+
+        if is_control_inode(target) {
+            return match self.control_attr(target) {
+                Some(attr) => reply.entry(&TTL, &attr, 0),
+                None => reply.error(ENOENT),
+            };
+        }
+
+        if self.searches.contains(target) {
+            self.searches.looked_up(target);
+            return reply.entry(&TTL, &self.search_attr(target), 0);
+        }
+
+        match self.tree.find_with_inode(target) {
+            Some(node) if node.lock().unwrap().visible_to(req.uid()) => {
+                self.reply_entry(reply, &node.lock().unwrap())
+            },
+            _ => reply.error(ENOENT),
+        }
+    }
+
     pub fn internal_setattr(
             &mut self,
-            _req: &Request<'_>,
+            req: &Caller,
             ino: u64,
-            _mode: Option<u32>,
+            mode: Option<u32>,
             uid: Option<u32>,
             gid: Option<u32>,
             size: Option<u64>,
             atime: Option<fuser::TimeOrNow>,
             mtime: Option<fuser::TimeOrNow>,
             ctime: Option<std::time::SystemTime>,
-            _fh: Option<u64>,
+            fh: Option<u64>,
             crtime: Option<std::time::SystemTime>,
             _chgtime: Option<std::time::SystemTime>,
             _bkuptime: Option<std::time::SystemTime>,
             _flags: Option<u32>,
             reply: ReplyAttr,
         ) {
-        println!("setattr: {}", ino);
+        debug!(ino, "setattr");
+
+        // truncating a control file before writing to it is a no-op
+        if is_control_inode(ino) {
+            return self.control_reply_attr(ino, reply);
+        }
+
+        // modes, owners and times included, not only the size
+        if self.read_only {
+            return reply.error(EROFS);
+        }
 
         if let Some(fs_node) = self.tree.find_with_inode(ino) {
-            if let Ok(node) = fs_node.lock() {
-                if let Some(mut metadata) = node.metadata {
-                    metadata.size = size.unwrap_or(metadata.size);
-                    metadata.atime = match atime.unwrap_or(fuser::TimeOrNow::Now) {
-                        fuser::TimeOrNow::SpecificTime(time) => time,
-                        fuser::TimeOrNow::Now => SystemTime::now(),
-                    };
-                    metadata.mtime = match mtime.unwrap_or(fuser::TimeOrNow::Now) {
-                        fuser::TimeOrNow::SpecificTime(time) => time,
-                        fuser::TimeOrNow::Now => SystemTime::now(),
-                    };
-                    metadata.ctime = ctime.unwrap_or(metadata.ctime);
-                    metadata.crtime = crtime.unwrap_or(metadata.crtime);
-                    metadata.uid = uid.unwrap_or(metadata.uid);
-                    metadata.gid = gid.unwrap_or(metadata.gid);
-                } else {
+            if let Ok(mut node) = fs_node.lock() {
+                if !node.visible_to(req.uid()) {
                     reply.error(ENOENT);
                     return;
                 }
-                reply.attr(&TTL, &(*node).clone().into())
+
+                if size.is_some() && !self.capabilities(&node.provider_id).truncate {
+                    return reply.error(ENOTSUP);
+                }
+
+                if node.metadata.is_none() {
+                    reply.error(ENOENT);
+                    return;
+                }
+
+                if mode.is_some() || uid.is_some() || gid.is_some() {
+                    if !self.capabilities(&node.provider_id).permissions {
+                        return reply.error(ENOTSUP);
+                    }
+                    if let Err(errno) = self.set_permissions(req, &node, mode, uid, gid) {
+                        return reply.error(errno);
+                    }
+                }
+
+                let mut upload = None;
+                if let Some(size) = size {
+                    match self.truncate(req, &mut node, fh, size) {
+                        Ok(Truncation::Done) => (),
+                        Ok(Truncation::Wait(awaited)) => {
+                            drop(node);
+                            let req = *req;
+                            return self.wait(awaited, move |fs, result| match result {
+                                Ok(()) => fs.internal_setattr(&req, ino, mode, uid, gid, Some(size), atime, mtime, ctime, fh, crtime, None, None, None, reply),
+                                Err(errno) => reply.error(errno),
+                            });
+                        },
+                        Ok(Truncation::Upload(handle)) => upload = Some(handle),
+                        Err(errno) => return reply.error(errno),
+                    }
+                }
+
+                let metadata = node.metadata.as_mut().unwrap();
+                metadata.size = size.unwrap_or(metadata.size);
+                // only what was asked for, a chmod leaves both times alone and a truncate moves
+                // the modification time like a write would
+                if let Some(atime) = atime {
+                    metadata.atime = time_or_now(atime);
+                }
+                match (mtime, size) {
+                    (Some(mtime), _) => metadata.mtime = time_or_now(mtime),
+                    (None, Some(_)) => metadata.mtime = SystemTime::now(),
+                    (None, None) => (),
+                }
+                metadata.ctime = ctime.unwrap_or(metadata.ctime);
+                metadata.crtime = crtime.unwrap_or(metadata.crtime);
+                metadata.perm = mode.map_or(metadata.perm, |mode| (mode & 0o7777) as u16);
+                metadata.uid = uid.unwrap_or(metadata.uid);
+                metadata.gid = gid.unwrap_or(metadata.gid);
+
+                let handle = match upload {
+                    Some(handle) => handle,
+                    None => return self.reply_attr(reply, &node),
+                };
+                drop(node);
+                self.flush_then(handle, move |fs, result| {
+                    fs.handles.release(handle);
+                    match (result, fs.tree.find_with_inode(ino)) {
+                        (Ok(()), Some(node)) => fs.reply_attr(reply, &node.lock().unwrap()),
+                        (Ok(()), None) => reply.error(ENOENT),
+                        (Err(errno), _) => reply.error(errno),
+                    }
+                });
             } else {
                 reply.error(ENOENT);
                 return;
@@ -80,41 +243,345 @@ impl FuseFS {
         }
     }
 
-    pub fn internal_getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        println!("getattr: {}", ino);
+    // Applies chmod and chown to the file a NativeFs provider serves. The daemon may be allowed
+    // more than the caller, and without default_permissions the kernel checks nothing, so the
+    // caller must own the file on disk as chmod(2) and chown(2) require.
+    fn set_permissions(&mut self, req: &Caller, node: &FsNode, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>) -> Result<(), c_int> {
+        let root = self.native_roots.get(&node.provider_id).ok_or(ENOTSUP)?;
+        let path = root.clone() + node.id.as_str();
+
+        let owner = fs::metadata(&path).map_err(|error| error.raw_os_error().unwrap_or(EIO))?.uid();
+        if !may_change_owner(req.uid(), owner, uid) {
+            return Err(EPERM);
+        }
+
+        if let Some(mode) = mode {
+            if let Err(error) = fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777)) {
+                return Err(error.raw_os_error().unwrap_or(EIO));
+            }
+        }
+
+        if uid.is_some() || gid.is_some() {
+            let c_path = CString::new(path).map_err(|_| EINVAL)?;
+            // -1 leaves the id as it is
+            let result = unsafe { libc::chown(c_path.as_ptr(), uid.unwrap_or(u32::MAX), gid.unwrap_or(u32::MAX)) };
+            if result != 0 {
+                return Err(std::io::Error::last_os_error().raw_os_error().unwrap_or(EIO));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Changes a file's size on its provider. Through an open handle the change goes to the
+    // handle's buffer and is uploaded with what it writes, otherwise the file is rewritten
+    // through a handle of its own.
+    fn truncate(&mut self, req: &Caller, node: &mut FsNode, fh: Option<u64>, size: u64) -> Result<Truncation, c_int> {
+        let path = self.tree.path(node.inode).unwrap_or_default();
+        self.check_policy(req, &node.provider_id, Operation::Write, &path, size)?;
+
+        // nothing to cut from a file not created yet
+        let pending = self.pending_creates.contains(node.inode);
+        if pending && size == 0 {
+            return Ok(Truncation::Done);
+        }
+        if pending {
+            let parent_id = self.parent_id(node.inode);
+            if let Some(awaited) = self.create_pending(node, parent_id) {
+                return Ok(Truncation::Wait(awaited));
+            }
+        }
+
+        if let Some(cache) = &self.content_cache {
+            cache.remove(&node.provider_id.id, node.id.as_str());
+        }
+        self.blocks.remove(&node.provider_id.id, node.id.as_str());
+
+        let fh = fh.filter(|fh| self.handles.get(*fh, node.inode).is_ok()).unwrap_or(0);
+        if self.handles.buffer(fh, node.inode).is_none() {
+            // emptying a file doesn't need its content
+            let content = match size {
+                0 => Vec::new(),
+                _ => match self.current_content(req, "truncate", node, fh, pending) {
+                    Ok(Some(content)) => content,
+                    Ok(None) => return Ok(Truncation::Wait(Awaited::Content(node.inode))),
+                    Err((errno, error)) => {
+                        self.fail("truncate", node.inode, &node.provider_id, error);
+                        return Err(errno);
+                    },
+                },
+            };
+
+            if fh == 0 {
+                let handle = self.handles.open(node.inode, O_WRONLY);
+                self.handles.start_buffer(handle, content, node.revision.clone());
+                self.handles.truncate(handle, size as usize);
+                return Ok(Truncation::Upload(handle));
+            }
+            self.handles.start_buffer(fh, content, node.revision.clone());
+        }
+
+        self.handles.invalidate(fh);
+        self.dispatcher.forget(fh);
+        self.handles.truncate(fh, size as usize);
+        self.spill(fh, node, size as usize, 0).map(|_| Truncation::Done)
+    }
+
+    // The check the kernel makes itself with default_permissions, against the owner and modes
+    // the mount shows. Of the caller's groups only the primary one is known.
+    pub fn internal_access(&mut self, req: &Caller, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        debug!(ino, mask, "access");
+
+        let attr = if ino == 1 {
+            Some(self.root_attr())
+        } else if is_control_inode(ino) {
+            self.control_attr(ino)
+        } else if self.searches.contains(ino) {
+            Some(self.search_attr(ino))
+        } else {
+            self.tree.find_with_inode(ino).and_then(|node| {
+                let node = node.lock().unwrap();
+                node.visible_to(req.uid()).then(|| self.file_attr(&node))
+            })
+        };
+        let attr = match attr {
+            Some(attr) => attr,
+            None => return reply.error(ENOENT),
+        };
+
+        if mask & W_OK != 0 && self.read_only && !is_control_inode(ino) {
+            return reply.error(EROFS);
+        }
+        match permitted(&attr, req.uid(), req.gid(), mask) {
+            true => reply.ok(),
+            false => reply.error(EACCES),
+        }
+    }
+
+    pub fn internal_getattr(&mut self, req: &Caller, ino: u64, reply: ReplyAttr) {
+        debug!(ino, "getattr");
 
         if ino == 1 {
-            reply.attr(&TTL, &ROOT_DIR_ATTR);
+            reply.attr(&TTL, &self.root_attr());
             return;
         }
 
+        if is_control_inode(ino) {
+            return self.control_reply_attr(ino, reply);
+        }
+
+        if self.searches.contains(ino) {
+            return reply.attr(&TTL, &self.search_attr(ino));
+        }
+
         if let Some(fs_node) = self.tree.find_with_inode(ino) {
-            if let Ok(mut node) = fs_node.lock() {
-                let provider = self.providers.get_provider(node.provider_id.as_ref().clone()).unwrap();
+            if let Ok(node) = fs_node.lock() {
+                if !node.visible_to(req.uid()) {
+                    reply.error(ENOENT);
+                    return;
+                }
 
-                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
-    
-                rt.block_on(async {
-                    let metadata = provider.as_filesystem().unwrap().get_metadata(node.id.clone()).await.unwrap();
+                // only known locally until it is created on the provider, or written to
+                if self.pending_creates.contains(ino) || self.handles.is_written(ino) {
+                    return self.reply_attr(reply, &node);
+                }
 
-                    node.metadata = Some(metadata.into());
-                });
+                if let Some(expire_at) = node.metadata_expire_at {
+                    if node.metadata.is_some() && expire_at > SystemTime::now() {
+                        self.cache_counters.metadata_hits += 1;
+                        self.reply_attr(reply, &node);
+                        return;
+                    }
+                }
+                self.cache_counters.metadata_misses += 1;
+
+                let providers = self.check_provider(&node.provider_id).and_then(|()| self.providers.get(&node.provider_id).ok_or(ENETUNREACH));
+                let providers = match providers {
+                    Ok(providers) => providers,
+                    // the last known attributes are better than nothing
+                    Err(errno) => {
+                        match node.metadata {
+                            Some(_) => self.reply_attr(reply, &node),
+                            None => reply.error(errno),
+                        }
+                        return;
+                    },
+                };
 
-                reply.attr(&TTL, &(*node).clone().into());
+                // Answered by a task, a slow provider doesn't hold the other callbacks. The node
+                // is updated by the next callback.
+                self.quotas.record(&node.provider_id);
+                let mut snapshot = node.clone();
+                let (uid, gid, modes) = (self.uid, self.gid, self.modes);
+                let ttl = self.tree.ttl(&node.provider_id).attr();
+                let pid = req.pid();
+                self.spawn(async move {
+                    let provider = providers.get_provider(snapshot.provider_id.as_ref().clone()).unwrap();
+                    let trace = telemetry::provider_request(&snapshot.provider_id, "get_metadata", &snapshot.id);
+                    let metadata = interruptible(pid, provider.as_filesystem().unwrap().get_metadata(snapshot.id.clone())).await;
+                    match &metadata {
+                        Ok(result) => trace.finish(result, 0),
+                        Err(errno) => trace.finish::<(), _>(&Err(errno), 0),
+                    }
+
+                    let result = match metadata {
+                        Ok(Ok(metadata)) => {
+                            let revision = revision(&metadata);
+                            snapshot.metadata = Some(metadata.into());
+                            reply.attr(&ttl, &node_attr(&snapshot, uid, gid, &modes));
+                            Ok((revision, snapshot.metadata.unwrap()))
+                        },
+                        Ok(Err(error)) => {
+                            let error = format!("{:?}", error);
+                            if is_auth_error(&error) {
+                                reply.error(EACCES);
+                            } else if is_not_found_error(&error) {
+                                reply.error(ENOENT);
+                            } else if is_network_error(&error) && snapshot.metadata.is_some() {
+                                // offline, the last known attributes again
+                                reply.attr(&ttl, &node_attr(&snapshot, uid, gid, &modes));
+                            } else {
+                                reply.error(errno(&error));
+                            }
+                            Err(error)
+                        },
+                        Err(errno) => {
+                            reply.error(errno);
+                            return Some(Completion::Failed { operation: "getattr", inode: ino, provider_id: snapshot.provider_id, error: "interrupted".to_string() });
+                        },
+                    };
+                    Some(Completion::Metadata { inode: ino, provider_id: snapshot.provider_id, result })
+                });
             }
         } else {
             reply.error(ENOENT);
         }
     }
+
+    // What a getattr task got from the provider. A file created or written meanwhile keeps its
+    // own attributes, the provider's are older.
+    pub fn apply_metadata(&mut self, inode: u64, provider_id: &ProviderId, result: Result<(Option<String>, Metadata), String>) {
+        if self.pending_creates.contains(inode) || self.handles.is_written(inode) {
+            return;
+        }
+        let fs_node = match self.tree.find_with_inode(inode) {
+            Some(fs_node) => fs_node,
+            None => return,
+        };
+
+        match result {
+            Ok((revision, metadata)) => {
+                if let Ok(mut node) = fs_node.lock() {
+                    node.revision = revision;
+                    node.metadata = Some(metadata);
+                    node.metadata_expire_at = Some(SystemTime::now() + self.tree.ttl(provider_id).metadata());
+                }
+            },
+            Err(error) if is_not_found_error(&error) => self.forget_deleted(inode, fs_node),
+            // offline, the last known attributes stay
+            Err(error) if is_network_error(&error) && fs_node.lock().map_or(false, |node| node.metadata.is_some()) => (),
+            Err(error) => {
+                self.failed("getattr", inode, provider_id, error);
+            },
+        }
+    }
+}
+
+// What a truncate leaves to do before the file has its new size.
+enum Truncation {
+    Done,
+    // the file is created or its content fetched first, the truncate runs again then
+    Wait(Awaited),
+    // the file is rewritten through this handle, its size is right once it is flushed
+    Upload(u64),
+}
+
+fn time_or_now(time: fuser::TimeOrNow) -> SystemTime {
+    match time {
+        fuser::TimeOrNow::SpecificTime(time) => time,
+        fuser::TimeOrNow::Now => SystemTime::now(),
+    }
+}
+
+// Only root gives a file away, its owner may change its mode and group.
+fn may_change_owner(caller: u32, owner: u32, uid: Option<u32>) -> bool {
+    caller == 0 || (caller == owner && uid.map_or(true, |uid| uid == owner))
+}
+
+// Root reads and writes anything, and executes what anyone can.
+fn permitted(attr: &FileAttr, uid: u32, gid: u32, mask: i32) -> bool {
+    let mask = (mask & (R_OK | W_OK | X_OK)) as u16;
+    if uid == 0 {
+        return mask & X_OK as u16 == 0 || attr.kind == FileType::Directory || attr.perm & 0o111 != 0;
+    }
+
+    let bits = if uid == attr.uid {
+        attr.perm >> 6
+    } else if gid == attr.gid {
+        attr.perm >> 3
+    } else {
+        attr.perm
+    };
+    bits & mask == mask
 }
 
 #[cfg(test)]
 mod attr_test {
     use super::*;
-    use super::super::*;
-    
+
+    fn attr(kind: FileType, perm: u16) -> FileAttr {
+        FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind,
+            perm,
+            nlink: 1,
+            uid: 1000,
+            gid: 100,
+            rdev: 0,
+            flags: 0,
+            blksize: 512,
+        }
+    }
+
     #[test]
-    fn get_node() {
-        
+    fn permitted_by_owner_group_and_others() {
+        let file = attr(FileType::RegularFile, 0o640);
+
+        assert!(permitted(&file, 1000, 100, R_OK | W_OK));
+        assert!(!permitted(&file, 1000, 100, X_OK));
+        assert!(permitted(&file, 1001, 100, R_OK));
+        assert!(!permitted(&file, 1001, 100, W_OK));
+        assert!(!permitted(&file, 1001, 101, R_OK));
+    }
+
+    #[test]
+    fn permitted_checks_only_the_owner_bits_of_the_owner() {
+        // the owner doesn't get what the group may do
+        let file = attr(FileType::RegularFile, 0o060);
+
+        assert!(!permitted(&file, 1000, 100, R_OK));
+    }
+
+    #[test]
+    fn permitted_to_root_but_execution() {
+        let file = attr(FileType::RegularFile, 0o000);
+        let script = attr(FileType::RegularFile, 0o700);
+        let dir = attr(FileType::Directory, 0o000);
+
+        assert!(permitted(&file, 0, 0, R_OK | W_OK));
+        assert!(!permitted(&file, 0, 0, X_OK));
+        assert!(permitted(&script, 0, 0, X_OK));
+        assert!(permitted(&dir, 0, 0, X_OK));
+    }
+
+    #[test]
+    fn existence_is_always_permitted() {
+        assert!(permitted(&attr(FileType::RegularFile, 0o000), 1001, 101, libc::F_OK));
     }
 }
\ No newline at end of file
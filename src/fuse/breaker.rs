@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local};
+use crossroads::interfaces::filesystem::ObjectId;
+use crossroads::storage::ProviderId;
+use tracing::{info, warn};
+
+use crate::notifications::{self, Event};
+use crate::telemetry;
+use super::FuseFS;
+use super::errors::{is_network_error, is_transient_error};
+
+// Requests in a row that got no answer, or a 429 or 5xx one, before a provider is given up on.
+const FAILURE_THRESHOLD: u32 = 5;
+// How often a provider given up on is asked for its root, the first answer brings it back.
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct Circuit {
+    failures: u32,
+    last_error: String,
+    // set while the provider is given up on
+    open_since: Option<SystemTime>,
+    probing: bool,
+}
+
+// By provider name. Every provider request reports its outcome here through telemetry, the
+// ones made by background tasks included.
+static CIRCUITS: Mutex<BTreeMap<String, Circuit>> = Mutex::new(BTreeMap::new());
+
+// Any answer other than an overloaded provider's shows the provider is there.
+pub fn record_result<T, E: Debug>(provider: &str, result: &Result<T, E>) {
+    let mut circuits = CIRCUITS.lock().unwrap();
+    let circuit = circuits.entry(provider.to_string()).or_default();
+
+    let error = match result {
+        Err(error) if is_network_error(error) || is_transient_error(error) => format!("{:?}", error),
+        _ => {
+            if circuit.open_since.take().is_some() {
+                info!("provider {} answers again", provider);
+            }
+            circuit.failures = 0;
+            return;
+        },
+    };
+
+    circuit.failures += 1;
+    circuit.last_error = error;
+    if circuit.failures >= FAILURE_THRESHOLD && circuit.open_since.is_none() {
+        warn!("provider {} failed {} requests in a row, serving it from the cache: {}", provider, circuit.failures, circuit.last_error);
+        circuit.open_since = Some(SystemTime::now());
+        notifications::notify(Event::ProviderDegraded { provider: provider.to_string() });
+    }
+}
+
+pub fn is_open(provider: &str) -> bool {
+    CIRCUITS.lock().unwrap().get(provider).map_or(false, |circuit| circuit.open_since.is_some())
+}
+
+// Why a provider's files are served from the cache, if they are.
+pub fn status(provider: &str) -> Option<String> {
+    let circuits = CIRCUITS.lock().unwrap();
+    let circuit = circuits.get(provider)?;
+    let since = DateTime::<Local>::from(circuit.open_since?).format("%Y-%m-%d %H:%M:%S");
+
+    Some(format!("unreachable since {}, only cached files are available: {}", since, circuit.last_error))
+}
+
+// Tells whether the caller is the one to start probing the provider.
+fn claim_probe(provider: &str) -> bool {
+    let mut circuits = CIRCUITS.lock().unwrap();
+    match circuits.get_mut(provider) {
+        Some(circuit) if circuit.open_since.is_some() && !circuit.probing => {
+            circuit.probing = true;
+            true
+        },
+        _ => false,
+    }
+}
+
+fn release_probe(provider: &str) {
+    if let Some(circuit) = CIRCUITS.lock().unwrap().get_mut(provider) {
+        circuit.probing = false;
+    }
+}
+
+impl FuseFS {
+    // The provider's subtree answers like an offline provider's meanwhile, a single request
+    // every PROBE_INTERVAL finds out when it is back.
+    pub fn start_probe(&mut self, provider_id: &ProviderId) {
+        if !claim_probe(&provider_id.id) {
+            return;
+        }
+
+        let providers = self.providers.clone();
+        let provider_id = provider_id.clone();
+        self.runtime().spawn(async move {
+            while is_open(&provider_id.id) {
+                tokio::time::sleep(PROBE_INTERVAL).await;
+
+                let providers = match providers.get(&provider_id) {
+                    Some(providers) => providers,
+                    None => break,
+                };
+                let provider = match providers.get_provider(provider_id.clone()) {
+                    Some(provider) => provider,
+                    None => break,
+                };
+                let root = ObjectId::root();
+                let trace = telemetry::provider_request(&provider_id, "read_directory", &root);
+                let result = provider.as_filesystem().unwrap().read_directory(root).await;
+                trace.finish(&result, 0);
+            }
+            release_probe(&provider_id.id);
+        });
+    }
+}
\ No newline at end of file
@@ -0,0 +1,69 @@
+use crossroads::storage::ProviderType;
+
+// What a provider can do, operations it can't are refused before reaching it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub rename: bool,
+    // moving an object to another folder
+    pub move_to: bool,
+    pub symlinks: bool,
+    pub truncate: bool,
+    // reading part of a file without downloading it whole
+    pub range_reads: bool,
+    pub versioning: bool,
+    // chmod and chown
+    pub permissions: bool,
+}
+
+impl Capabilities {
+    // Declared per provider type when a provider is registered, crossroads has no way to ask.
+    pub fn of(provider_type: &ProviderType) -> Self {
+        match provider_type {
+            ProviderType::NativeFs => Self {
+                rename: true,
+                move_to: true,
+                symlinks: true,
+                truncate: true,
+                range_reads: true,
+                versioning: false,
+                permissions: true,
+            },
+            ProviderType::GoogleDrive | ProviderType::OneDrive => Self {
+                rename: true,
+                move_to: true,
+                symlinks: false,
+                truncate: true,
+                range_reads: false,
+                versioning: true,
+                permissions: false,
+            },
+            // S3 objects can only be copied to a new key, and written whole
+            _ => Self {
+                rename: false,
+                move_to: false,
+                symlinks: false,
+                truncate: true,
+                range_reads: false,
+                versioning: false,
+                permissions: false,
+            },
+        }
+    }
+
+    // Names shown in the status.
+    pub fn names(&self) -> Vec<String> {
+        [
+            ("rename", self.rename),
+            ("move", self.move_to),
+            ("symlinks", self.symlinks),
+            ("truncate", self.truncate),
+            ("range-reads", self.range_reads),
+            ("versioning", self.versioning),
+            ("permissions", self.permissions),
+        ]
+        .into_iter()
+        .filter(|(_, supported)| *supported)
+        .map(|(name, _)| name.to_string())
+        .collect()
+    }
+}
\ No newline at end of file
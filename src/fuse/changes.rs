@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crossroads::interfaces::filesystem::{File, ObjectId};
+use crossroads::storage::ProviderId;
+use fuser::Notifier;
+
+use crate::fstree::FsNode;
+use super::FuseFS;
+use super::dispatch::{Completion, Providers};
+
+// Folders not listed through the mount for this long are no longer polled.
+const WATCH_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+// Size and modification time, a file whose signature changed was edited elsewhere.
+type Signature = (Option<u64>, Option<SystemTime>);
+
+struct WatchedChild {
+    inode: u64,
+    name: String,
+    signature: Signature,
+}
+
+struct Watched {
+    provider_id: Arc<ProviderId>,
+    id: ObjectId,
+    listed_at: SystemTime,
+    // by object id
+    children: HashMap<String, WatchedChild>,
+}
+
+// Lists the folders the mount listed recently again in the background and tells the kernel to
+// drop what it cached of the ones changed from elsewhere, e.g. the provider's web UI.
+#[derive(Clone, Default)]
+pub struct Poller {
+    dirs: Arc<Mutex<HashMap<u64, Watched>>>,
+    providers: Providers,
+    // set once the session exists
+    notifier: Arc<Mutex<Option<Notifier>>>,
+    // when each provider's folders were last listed, by the mount or a poll
+    synced: Arc<Mutex<HashMap<ProviderId, SystemTime>>>,
+}
+
+impl Poller {
+    pub fn set_notifier(&self, notifier: Notifier) {
+        *self.notifier.lock().unwrap() = Some(notifier);
+    }
+
+    pub fn new(providers: Providers) -> Self {
+        Self {
+            providers,
+            ..Self::default()
+        }
+    }
+
+    // Records what a folder held when the mount listed it.
+    pub fn watch(&self, dir: &FsNode) {
+        // a child locked by the caller counts as added on the next poll, a spurious refresh
+        let children = dir.children.iter().filter_map(|child| {
+            let child = child.try_lock().ok()?;
+            Some((child.id.as_str().to_string(), WatchedChild {
+                inode: child.inode,
+                name: child.name.clone(),
+                signature: (child.metadata.map(|metadata| metadata.size), child.metadata.map(|metadata| metadata.mtime)),
+            }))
+        }).collect();
+
+        self.synced.lock().unwrap().insert(dir.provider_id.as_ref().clone(), SystemTime::now());
+        self.dirs.lock().unwrap().insert(dir.inode, Watched {
+            provider_id: dir.provider_id.clone(),
+            id: dir.id.clone(),
+            listed_at: SystemTime::now(),
+            children,
+        });
+    }
+
+    pub fn invalidate_inode(&self, inode: u64) {
+        if let Some(notifier) = self.notifier.lock().unwrap().as_ref() {
+            let _ = notifier.inval_inode(inode, 0, 0);
+        }
+    }
+
+    // Tells the kernel a root entry appeared or went away, and stops watching the folders of
+    // a provider that isn't mounted anymore.
+    pub fn forget_entry(&self, name: &str, provider_id: &ProviderId) {
+        self.dirs.lock().unwrap().retain(|_, dir| *dir.provider_id != *provider_id);
+        self.synced.lock().unwrap().remove(provider_id);
+        if let Some(notifier) = self.notifier.lock().unwrap().as_ref() {
+            let _ = notifier.inval_entry(1, OsStr::new(name));
+            let _ = notifier.inval_inode(1, 0, 0);
+        }
+    }
+
+    pub fn last_sync(&self, provider_id: &ProviderId) -> Option<SystemTime> {
+        self.synced.lock().unwrap().get(provider_id).copied()
+    }
+
+    // Lists the provider's watched folders and returns the ones that changed with their new
+    // content.
+    async fn poll(&self, provider_id: &ProviderId) -> Vec<(u64, Vec<File>)> {
+        // offline or detached meanwhile
+        let providers = match self.providers.get(provider_id) {
+            Some(providers) => providers,
+            None => return Vec::new(),
+        };
+
+        let now = SystemTime::now();
+        let dirs: Vec<(u64, ObjectId)> = {
+            let mut dirs = self.dirs.lock().unwrap();
+            dirs.retain(|_, dir| now.duration_since(dir.listed_at).map_or(true, |age| age < WATCH_WINDOW));
+            dirs.iter().filter(|(_, dir)| *dir.provider_id == *provider_id).map(|(inode, dir)| (*inode, dir.id.clone())).collect()
+        };
+        if dirs.is_empty() {
+            return Vec::new();
+        }
+
+        let provider = providers.get_provider(provider_id.clone()).unwrap();
+        let mut changed = Vec::new();
+
+        for (inode, id) in dirs {
+            let files = match provider.as_filesystem().unwrap().read_directory(id).await {
+                Ok(files) => files,
+                // the mount's own requests report the failures
+                Err(_) => continue,
+            };
+            self.synced.lock().unwrap().insert(provider_id.clone(), SystemTime::now());
+
+            let mut dirs = self.dirs.lock().unwrap();
+            let dir = match dirs.get_mut(&inode) {
+                Some(dir) => dir,
+                None => continue,
+            };
+            if self.invalidate(inode, dir, &files) {
+                // watched again once the listing is applied to the tree
+                dirs.remove(&inode);
+                changed.push((inode, files));
+            }
+        }
+
+        changed
+    }
+
+    // Tells the kernel about the entries added, removed or modified since the folder was
+    // listed. Returns whether there were any.
+    fn invalidate(&self, inode: u64, dir: &Watched, files: &[File]) -> bool {
+        let mut names = Vec::new();
+        let mut modified = Vec::new();
+
+        for file in files {
+            let signature = signature(file);
+            match dir.children.get(file.id.as_str()) {
+                // what the provider doesn't tell can't have changed
+                Some(child) if (signature.0.is_some() && child.signature.0 != signature.0) || (signature.1.is_some() && child.signature.1 != signature.1) => modified.push(child.inode),
+                Some(_) => (),
+                None => names.push(file.name.clone()),
+            }
+        }
+        for (id, child) in &dir.children {
+            if !files.iter().any(|file| file.id.as_str() == id) {
+                names.push(child.name.clone());
+            }
+        }
+
+        if names.is_empty() && modified.is_empty() {
+            return false;
+        }
+
+        if let Some(notifier) = self.notifier.lock().unwrap().as_ref() {
+            for name in &names {
+                let _ = notifier.inval_entry(inode, OsStr::new(name));
+            }
+            for child in &modified {
+                let _ = notifier.inval_inode(*child, 0, 0);
+            }
+            let _ = notifier.inval_inode(inode, 0, 0);
+        }
+
+        true
+    }
+}
+
+fn signature(file: &File) -> Signature {
+    match &file.metadata {
+        Some(metadata) => (metadata.size, metadata.modified_at.clone().map(|modified_at| modified_at.into())),
+        None => (None, None),
+    }
+}
+
+impl FuseFS {
+    // One task per provider, the ones with a zero interval aren't polled.
+    pub fn start_polling(&mut self) {
+        let provider_ids: Vec<ProviderId> = self.capabilities.keys().cloned().collect();
+        for provider_id in provider_ids {
+            self.start_polling_provider(provider_id);
+        }
+    }
+
+    pub fn start_polling_provider(&mut self, provider_id: ProviderId) {
+        let interval = self.poll_intervals.get(&provider_id.id).copied().unwrap_or(self.poll_interval);
+        if interval.is_zero() {
+            return;
+        }
+
+        let poller = self.poller.clone();
+        let journal = self.journal.clone();
+        let sender = self.dispatcher.sender();
+        let polled = provider_id.clone();
+        let task = self.runtime().spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if journal.is_paused() {
+                    continue;
+                }
+                for (inode, files) in poller.poll(&polled).await {
+                    let _ = sender.send(Completion::Changed { inode, files });
+                }
+            }
+        });
+        self.provider_tasks.entry(provider_id).or_default().push(task);
+    }
+
+    // Whatever the mount and the kernel know of the node is asked to its provider again on
+    // next access, the listing too for a folder.
+    pub fn invalidate_node(&mut self, node: &mut FsNode) {
+        node.expire_at = None;
+        node.metadata_expire_at = None;
+        self.negative_entries.forget(node.inode);
+        self.poller.invalidate_inode(node.inode);
+    }
+
+    // crossroads has no changes feed, the listing the poller got is what the tree is updated
+    // with, in place of listing the folder again on its next lookup or readdir.
+    pub fn apply_change(&mut self, inode: u64, files: Vec<File>) {
+        if let Some(dir) = self.tree.find_with_inode(inode) {
+            self.apply_listing(&mut dir.lock().unwrap(), files);
+        }
+        self.negative_entries.forget(inode);
+    }
+}
\ No newline at end of file
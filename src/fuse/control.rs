@@ -0,0 +1,349 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use libc::{c_int, EACCES, EBUSY, EINVAL, ENOENT};
+use chrono::{DateTime, Local};
+
+use fuser::{FileType, FileAttr, ReplyDirectory, ReplyEntry};
+use tracing::info;
+
+use super::FuseFS;
+use super::dispatch::Caller;
+use super::dir::{parse_info_inode, PROVIDERS_DIR_INODE, PROVIDERS_DIR_NAME};
+use super::search::Query;
+use super::walk::CRAWLING_NOTE;
+
+// The control directory lives at the root of the mount next to the providers. Its inodes are
+// taken from the top of the range so they never collide with the ones handed out by FsTree.
+pub const CONTROL_DIR_NAME: &str = ".orbital";
+pub const CONTROL_DIR_INODE: u64 = u64::MAX - 1;
+
+// Contents are generated on every read, the kernel must not cache them.
+const CONTROL_TTL: Duration = Duration::from_secs(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlFile {
+    // lists providers waiting for a new login, writing a provider name reloads its credentials
+    Reauth,
+    // lists providers allowed to bypass the deletion guard, writing a provider name approves it
+    ApproveDelete,
+    // latest operations refused by the policy rules or the deletion guard
+    Denials,
+    // latest operations that failed on the provider side
+    Errors,
+    // health of the providers, transfers in progress, cache hits and recent errors as JSON
+    Status,
+    // writing a query searches every provider, reading gives back the matching paths
+    Search,
+    // writing a path lists everything below it, reading gives back the paths found
+    Find,
+    // writing `large <top> <path>`, `duplicates <path>` or `usage` computes a report, reading
+    // gives it back
+    Report,
+    // lists the mounted providers, writing `add <name or credential file>` or `remove <name>`
+    // mounts or unmounts one
+    Mounts,
+    // writing a path drops what is cached of it
+    Invalidate,
+    // tells whether background sync runs, writing `pause` or `resume` changes it, `discard
+    // <name>` drops what the journal keeps for a provider that isn't mounted
+    Sync,
+    // calls, errors and latency of each FUSE operation and provider request as JSON
+    Stats,
+    // reachability, token validity and queued operations of each provider as JSON
+    Health,
+}
+
+impl ControlFile {
+    const ALL: [ControlFile; 13] = [
+        ControlFile::Reauth,
+        ControlFile::ApproveDelete,
+        ControlFile::Denials,
+        ControlFile::Errors,
+        ControlFile::Status,
+        ControlFile::Search,
+        ControlFile::Find,
+        ControlFile::Report,
+        ControlFile::Mounts,
+        ControlFile::Invalidate,
+        ControlFile::Sync,
+        ControlFile::Stats,
+        ControlFile::Health,
+    ];
+
+    pub fn from_inode(ino: u64) -> Option<Self> {
+        Self::ALL.iter().copied().find(|file| file.inode() == ino)
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|file| file.name() == name)
+    }
+
+    pub fn inode(self) -> u64 {
+        CONTROL_DIR_INODE - 1 - Self::ALL.iter().position(|file| *file == self).unwrap() as u64
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ControlFile::Reauth => "reauth",
+            ControlFile::ApproveDelete => "approve-delete",
+            ControlFile::Denials => "denials",
+            ControlFile::Errors => "errors",
+            ControlFile::Status => "status",
+            ControlFile::Search => "search",
+            ControlFile::Find => "find",
+            ControlFile::Report => "report",
+            ControlFile::Mounts => "mounts",
+            ControlFile::Invalidate => "invalidate",
+            ControlFile::Sync => "sync",
+            ControlFile::Stats => "stats",
+            ControlFile::Health => "health",
+        }
+    }
+
+    fn writable(self) -> bool {
+        match self {
+            ControlFile::Reauth | ControlFile::ApproveDelete | ControlFile::Search | ControlFile::Find | ControlFile::Report | ControlFile::Mounts | ControlFile::Invalidate | ControlFile::Sync => true,
+            ControlFile::Denials | ControlFile::Errors | ControlFile::Status | ControlFile::Stats | ControlFile::Health => false,
+        }
+    }
+}
+
+pub fn is_control_inode(ino: u64) -> bool {
+    ino == CONTROL_DIR_INODE || ControlFile::from_inode(ino).is_some() || ino == PROVIDERS_DIR_INODE || parse_info_inode(ino).is_some()
+}
+
+impl FuseFS {
+    pub fn control_attr(&mut self, ino: u64) -> Option<FileAttr> {
+        let mut attr = FileAttr {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 512,
+        };
+
+        if let Some((root, file)) = parse_info_inode(ino) {
+            if let Some(file) = file {
+                attr.kind = FileType::RegularFile;
+                attr.perm = 0o444;
+                attr.nlink = 1;
+                attr.size = self.info_content(root, file)?.len() as u64;
+            } else if self.info_provider(root, self.uid).is_none() {
+                return None;
+            }
+        } else if ino != CONTROL_DIR_INODE && ino != PROVIDERS_DIR_INODE {
+            let file = ControlFile::from_inode(ino)?;
+            attr.kind = FileType::RegularFile;
+            attr.perm = if file.writable() { 0o644 } else { 0o444 };
+            attr.nlink = 1;
+            attr.size = self.control_content(file).len() as u64;
+        }
+
+        Some(attr)
+    }
+
+    pub fn control_content(&mut self, file: ControlFile) -> Vec<u8> {
+        match file {
+            ControlFile::Reauth => {
+                let mut ids: Vec<String> = self.reauth_required.iter().map(|provider_id| provider_id.id.clone() + "\n").collect();
+                ids.sort();
+                ids.concat().into_bytes()
+            },
+            ControlFile::ApproveDelete => {
+                let mut approved: Vec<String> = self.deletion_guard.approved().iter()
+                    .map(|(provider_id, remaining)| format!("{} {}s\n", provider_id.id, remaining.as_secs()))
+                    .collect();
+                approved.sort();
+                approved.concat().into_bytes()
+            },
+            ControlFile::Denials => {
+                self.denials.iter()
+                    .map(|denial| format!(
+                        "{} uid={} pid={} {:?} {}: {}\n",
+                        DateTime::<Local>::from(denial.at).format("%Y-%m-%d %H:%M:%S"),
+                        denial.uid,
+                        denial.pid,
+                        denial.operation,
+                        denial.path.display(),
+                        denial.reason,
+                    ))
+                    .collect::<String>()
+                    .into_bytes()
+            },
+            ControlFile::Errors => self.failures.iter().map(|failure| failure.to_string() + "\n").collect::<String>().into_bytes(),
+            ControlFile::Status => serde_json::to_vec_pretty(&self.status(self.uid)).unwrap(),
+            ControlFile::Search => self.searches.latest().as_bytes().to_vec(),
+            ControlFile::Find => self.found.as_bytes().to_vec(),
+            ControlFile::Report => self.report.as_bytes().to_vec(),
+            ControlFile::Mounts => self.providers_listing().into_bytes(),
+            ControlFile::Invalidate => Vec::new(),
+            ControlFile::Sync => if self.journal.is_paused() { b"paused\n".to_vec() } else { b"running\n".to_vec() },
+            ControlFile::Stats => self.stats(),
+            ControlFile::Health => serde_json::to_vec_pretty(&self.health(self.uid)).unwrap(),
+        }
+    }
+
+    pub fn control_write(&mut self, req: &Caller, file: ControlFile, data: &[u8]) -> Result<(), c_int> {
+        match file {
+            ControlFile::Reauth => {
+                for id in String::from_utf8_lossy(data).lines().map(str::trim).filter(|id| !id.is_empty()) {
+                    self.reauthenticate(id)?;
+                }
+                Ok(())
+            },
+            ControlFile::ApproveDelete => {
+                for id in String::from_utf8_lossy(data).lines().map(str::trim).filter(|id| !id.is_empty()) {
+                    let provider_id = self.capabilities.keys().find(|provider_id| provider_id.id == id).cloned().ok_or(ENOENT)?;
+                    info!("deletion guard lifted for {}", provider_id.id);
+                    self.deletion_guard.approve(provider_id);
+                }
+                Ok(())
+            },
+            ControlFile::Denials | ControlFile::Errors | ControlFile::Status | ControlFile::Stats | ControlFile::Health => Err(EACCES),
+            ControlFile::Search => {
+                let query = Query::parse(&String::from_utf8_lossy(data)).ok_or(EINVAL)?;
+                self.search_providers(req.uid(), &query);
+                Ok(())
+            },
+            ControlFile::Find => {
+                let path = PathBuf::from(String::from_utf8_lossy(data).trim());
+                let root = self.resolve(&path, req.uid())?;
+                let uid = req.uid();
+
+                let mut found = Vec::new();
+                let complete = self.walk(root, &mut |node| {
+                    if node.visible_to(uid) {
+                        found.push(node.inode);
+                    }
+                    true
+                });
+
+                self.found = found.iter()
+                    .filter_map(|inode| self.tree.path(*inode))
+                    .map(|path| path.display().to_string() + "\n")
+                    .collect();
+                if !complete {
+                    self.found += CRAWLING_NOTE;
+                }
+                Ok(())
+            },
+            ControlFile::Report => {
+                let request = String::from_utf8_lossy(data);
+                let mut words = request.trim().splitn(3, ' ');
+
+                self.report = match (words.next(), words.next(), words.next()) {
+                    (Some("duplicates"), Some(path), rest) => {
+                        let path = rest.map_or(path.to_string(), |rest| format!("{} {}", path, rest));
+                        self.report_duplicates(req.uid(), Path::new(&path))?
+                    },
+                    (Some("large"), Some(top), Some(path)) => {
+                        let top = top.parse().map_err(|_| EINVAL)?;
+                        self.report_large(req.uid(), top, Path::new(path))?
+                    },
+                    (Some("usage"), None, None) => serde_json::to_string_pretty(&self.usage(req.uid())).unwrap(),
+                    _ => return Err(EINVAL),
+                };
+                Ok(())
+            },
+            ControlFile::Mounts => {
+                for line in String::from_utf8_lossy(data).lines().map(str::trim).filter(|line| !line.is_empty()) {
+                    match line.split_once(' ') {
+                        Some(("add", source)) => self.attach_provider(source.trim())?,
+                        Some(("remove", id)) => self.detach_provider(id.trim(), req.uid())?,
+                        _ => return Err(EINVAL),
+                    }
+                }
+                Ok(())
+            },
+            ControlFile::Invalidate => {
+                let path = PathBuf::from(String::from_utf8_lossy(data).trim());
+                let node = self.resolve(&path, req.uid())?;
+                self.invalidate_node(&mut node.lock().unwrap());
+                Ok(())
+            },
+            // Uploads and deletes left to the journal wait, and folders aren't polled for remote
+            // changes. What is written meanwhile is still sent when flushed.
+            ControlFile::Sync => {
+                let request = String::from_utf8_lossy(data);
+                let paused = match request.trim().split_once(' ') {
+                    Some(("discard", provider)) => {
+                        // a mounted provider's worker may be sending them
+                        if self.capabilities.keys().any(|provider_id| provider_id.id == provider.trim()) {
+                            return Err(EBUSY);
+                        }
+                        let discarded = self.journal.discard(provider.trim());
+                        info!("{} journaled operations of {} discarded", discarded, provider.trim());
+                        return Ok(());
+                    },
+                    _ => match request.trim() {
+                        "pause" => true,
+                        "resume" => false,
+                        _ => return Err(EINVAL),
+                    },
+                };
+                info!("background sync {}", if paused { "paused" } else { "resumed" });
+                self.journal.set_paused(paused);
+                Ok(())
+            },
+        }
+    }
+
+    pub fn control_lookup(&mut self, parent_inode: u64, name: &str, uid: u32, reply: ReplyEntry) {
+        let ino = if parent_inode == 1 {
+            CONTROL_DIR_INODE
+        } else if parent_inode == CONTROL_DIR_INODE && name == PROVIDERS_DIR_NAME {
+            PROVIDERS_DIR_INODE
+        } else if parent_inode == CONTROL_DIR_INODE {
+            match ControlFile::from_name(name) {
+                Some(file) => file.inode(),
+                None => return reply.error(ENOENT),
+            }
+        } else {
+            match self.info_lookup(parent_inode, name, uid) {
+                Some(ino) => ino,
+                None => return reply.error(ENOENT),
+            }
+        };
+
+        match self.control_attr(ino) {
+            Some(attr) => reply.entry(&CONTROL_TTL, &attr, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    pub fn control_reply_attr(&mut self, ino: u64, reply: fuser::ReplyAttr) {
+        match self.control_attr(ino) {
+            Some(attr) => reply.attr(&CONTROL_TTL, &attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    pub fn control_readdir(&mut self, offset: i64, mut reply: ReplyDirectory) {
+        let mut entries = vec![
+            (CONTROL_DIR_INODE, FileType::Directory, "."),
+            (1, FileType::Directory, ".."),
+        ];
+        entries.extend(ControlFile::ALL.iter().map(|file| (file.inode(), FileType::RegularFile, file.name())));
+        entries.push((PROVIDERS_DIR_INODE, FileType::Directory, PROVIDERS_DIR_NAME));
+
+        for (index, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, index as i64 + 1, kind, OsStr::from_bytes(name.as_bytes())) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+}
\ No newline at end of file
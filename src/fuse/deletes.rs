@@ -0,0 +1,277 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crossroads::interfaces::filesystem::{ObjectId, File, FileSystem, Metadata as CrossroadsMetadata};
+use crossroads::storage::{ProviderId, ProviderType};
+use futures::future::join_all;
+use libc::ENETUNREACH;
+
+use crate::telemetry;
+use super::{FuseFS, TRASH_DIR_NAME};
+use super::dispatch::{Awaited, Completion};
+use super::errors::{is_network_error, is_transient_error};
+use super::failures::Failure;
+use super::journal::Deferred;
+
+// Queued deletes are sent once a provider has a full batch or the oldest one waited this
+// long, `rm -r` keeps unlinking meanwhile. The ticker sends them when nothing else comes.
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+// Deletes sent at once to a provider, as many as Drive batches, Graph 20 and S3
+// DeleteObjects 1000 keys. crossroads has none of these batch calls, a batch is its deletes
+// sent concurrently. Other providers delete right away.
+fn batch_size(provider_type: &ProviderType) -> usize {
+    match provider_type {
+        ProviderType::GoogleDrive => 100,
+        ProviderType::OneDrive => 20,
+        ProviderType::S3 => 1000,
+        _ => 1,
+    }
+}
+
+// Drive and OneDrive folders take their content along when deleted.
+pub fn deletes_recursively(provider_type: &ProviderType) -> bool {
+    matches!(provider_type, ProviderType::GoogleDrive | ProviderType::OneDrive)
+}
+
+pub struct QueuedDelete {
+    pub id: ObjectId,
+    // from the mount root, to report failures once the node is gone
+    pub path: PathBuf,
+}
+
+#[derive(Default)]
+pub struct DeleteQueue {
+    queued: HashMap<ProviderId, Vec<QueuedDelete>>,
+    since: Option<Instant>,
+}
+
+impl DeleteQueue {
+    // Listings leave out objects waiting to be deleted.
+    pub fn contains(&self, provider_id: &ProviderId, id: &ObjectId) -> bool {
+        self.queued.get(provider_id).map_or(false, |queued| queued.iter().any(|delete| &delete.id == id))
+    }
+
+    fn push(&mut self, provider_id: &ProviderId, id: ObjectId, path: PathBuf, folder: bool) {
+        let queued = self.queued.entry(provider_id.clone()).or_default();
+
+        // the folder's own delete covers whatever was unlinked inside it
+        if folder && deletes_recursively(&provider_id.provider_type) {
+            queued.retain(|delete| !delete.path.starts_with(&path));
+        }
+
+        queued.push(QueuedDelete { id, path });
+        self.since.get_or_insert_with(Instant::now);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+
+    fn due(&self) -> bool {
+        self.since.map_or(false, |since| since.elapsed() >= MAX_DELAY)
+            || self.queued.iter().any(|(provider_id, queued)| queued.len() >= batch_size(&provider_id.provider_type))
+    }
+}
+
+impl FuseFS {
+    // Deletes an object the tree already forgot, in a batch when its provider has one.
+    pub fn queue_delete(&mut self, provider_id: &ProviderId, id: ObjectId, path: PathBuf, folder: bool) {
+        if batch_size(&provider_id.provider_type) == 1 {
+            return self.delete_objects(provider_id, vec![QueuedDelete { id, path }]);
+        }
+
+        self.delete_queue.push(provider_id, id, path, folder);
+        self.flush_deletes(false);
+    }
+
+    // Sends the queued deletes once a batch is due, or right away with `force`.
+    pub fn flush_deletes(&mut self, force: bool) {
+        if self.delete_queue.queued.is_empty() || !(force || self.delete_queue.due()) {
+            return;
+        }
+
+        self.delete_queue.since = None;
+        for (provider_id, mut queued) in self.delete_queue.queued.drain().collect::<Vec<_>>() {
+            while !queued.is_empty() {
+                let batch = queued.drain(..std::cmp::min(queued.len(), batch_size(&provider_id.provider_type))).collect();
+                self.delete_objects(&provider_id, batch);
+            }
+        }
+    }
+
+    // Deletes that can't reach their provider wait in the journal, as do the ones queued
+    // behind operations already waiting there. The others are sent from a task.
+    fn delete_objects(&mut self, provider_id: &ProviderId, deletes: Vec<QueuedDelete>) {
+        let wait = self.journal.is_queued(&provider_id.id) || self.check_provider(provider_id) == Err(ENETUNREACH);
+        let providers = match self.providers.get(provider_id) {
+            Some(providers) if !wait => providers,
+            _ => {
+                let results = deletes.iter().map(|_| None).collect();
+                return self.record_deletes(provider_id, deletes, results);
+            },
+        };
+
+        for _ in &deletes {
+            self.quotas.record(provider_id);
+        }
+        let permanent = self.permanent_delete(provider_id);
+        let provider_id = provider_id.clone();
+        self.spawn_awaited(&[Awaited::Deletes], async move {
+            let provider = providers.get_provider(provider_id.clone()).unwrap();
+            let results = send_deletes(provider.as_filesystem().unwrap(), &provider_id, permanent, &deletes).await;
+            Completion::Deleted { provider_id, deletes, results }
+        });
+    }
+
+    pub fn apply_deleted(&mut self, provider_id: &ProviderId, deletes: Vec<QueuedDelete>, results: Vec<Result<(), String>>) {
+        self.record_deletes(provider_id, deletes, results.into_iter().map(Some).collect());
+        self.resume(Awaited::Deletes, Ok(()));
+    }
+
+    // Deletes not sent, or that failed for the network, go to the journal.
+    fn record_deletes(&mut self, provider_id: &ProviderId, deletes: Vec<QueuedDelete>, results: Vec<Option<Result<(), String>>>) {
+        for (delete, result) in deletes.into_iter().zip(results) {
+            let error = match result {
+                Some(Ok(())) => continue,
+                Some(Err(error)) if !is_network_error(&error) && !is_transient_error(&error) => error,
+                _ => {
+                    let deferred = Deferred::Delete {
+                        provider: provider_id.id.clone(),
+                        id: delete.id.as_str().to_string(),
+                        folder: delete.id.is_directory(),
+                        path: delete.path.clone(),
+                    };
+                    match self.defer(deferred, None) {
+                        Ok(()) => continue,
+                        Err(error) => error,
+                    }
+                },
+            };
+
+            self.failures.record(Failure {
+                at: SystemTime::now(),
+                operation: "delete",
+                path: delete.path,
+                provider: provider_id.id.clone(),
+                error,
+            });
+        }
+    }
+
+    // Providers with a trash in their web UI get deleted objects moved to a trash folder so
+    // a mistaken `rm` can be undone, the others delete right away.
+    pub fn permanent_delete(&self, provider_id: &ProviderId) -> bool {
+        self.permanent_delete || !matches!(provider_id.provider_type, ProviderType::GoogleDrive | ProviderType::OneDrive)
+    }
+}
+
+// crossroads has no call for the trash of Drive and OneDrive, deleted objects go to a
+// `.Trash` folder of their own at the root instead, where they can be moved back from.
+// This isn't the provider's restorable trash: the folder is visible at the provider root
+// like any other, and what it holds still counts against the quota until it is emptied.
+// Deleting from that folder deletes for good. A name already taken in the trash is
+// renamed first, since OneDrive refuses to move onto it, and named back when the move fails.
+pub async fn send_deletes(filesystem: &dyn FileSystem, provider_id: &ProviderId, permanent: bool, deletes: &[QueuedDelete]) -> Vec<Result<(), String>> {
+    let trashed = deletes.iter().any(|delete| !permanent && !in_trash(&delete.path));
+    let (trash, mut taken) = match trashed {
+        false => (None, HashSet::new()),
+        true => match trash_folder(filesystem).await {
+            Ok(trash) => trash,
+            Err(error) => return deletes.iter().map(|_| Err(error.clone())).collect(),
+        },
+    };
+
+    // names in the trash given out before the moves run at once
+    let names: Vec<Option<String>> = deletes.iter().map(|delete| {
+        let name = delete.path.file_name()?.to_string_lossy().to_string();
+        match taken.contains(&name) {
+            false => {
+                taken.insert(name);
+                None
+            },
+            true => {
+                let free = free_name(&name, &taken);
+                taken.insert(free.clone());
+                Some(free)
+            },
+        }
+    }).collect();
+
+    join_all(deletes.iter().zip(names).map(|(delete, name)| {
+        let trash = trash.clone().filter(|_| !permanent && !in_trash(&delete.path));
+        async move {
+            let trace = telemetry::provider_request(provider_id, if trash.is_none() { "delete" } else { "move_to_trash" }, &delete.id);
+            let result = match trash {
+                Some(trash) => async {
+                    let id = match &name {
+                        Some(name) => filesystem.rename(delete.id.clone(), name.clone()).await?,
+                        None => delete.id.clone(),
+                    };
+                    let moved = filesystem.move_to(id.clone(), trash).await.map(|_| ());
+                    // the object stays where it was, under its own name for the journal's retry
+                    if let (Err(_), Some(original)) = (&moved, name.and(delete.path.file_name())) {
+                        let _ = filesystem.rename(id, original.to_string_lossy().to_string()).await;
+                    }
+                    moved
+                }.await,
+                None => filesystem.delete(delete.id.clone()).await.map(|_| ()),
+            };
+            trace.finish(&result, 0);
+            result.map_err(|error| format!("{:?}", error))
+        }
+    })).await
+}
+
+// `/<provider>/.Trash/...` from the mount root.
+fn in_trash(path: &Path) -> bool {
+    path.components().nth(2).map_or(false, |component| component.as_os_str() == TRASH_DIR_NAME)
+}
+
+// `notes (2).txt` when `notes.txt` is already in the trash.
+fn free_name(name: &str, taken: &HashSet<String>) -> String {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    (2..).map(|number| format!("{} ({}){}", stem, number, extension)).find(|candidate| !taken.contains(candidate)).unwrap()
+}
+
+// The trash folder's id and the names already in it.
+async fn trash_folder(filesystem: &dyn FileSystem) -> Result<(ObjectId, HashSet<String>), String> {
+    if let Some(trash) = find_trash(filesystem).await? {
+        let names = filesystem.read_directory(trash.clone()).await.map_err(|error| format!("{:?}", error))?
+            .into_iter()
+            .map(|file| file.name)
+            .collect();
+        return Ok((trash, names));
+    }
+
+    filesystem.create(ObjectId::root(), File {
+        id: ObjectId::directory(ObjectId::root().to_string() + "/" + TRASH_DIR_NAME),
+        name: TRASH_DIR_NAME.to_string(),
+        metadata: Some(CrossroadsMetadata {
+            mime_type: Some("directory".to_string()),
+            created_at: None,
+            modified_at: None,
+            meta_changed_at: None,
+            accessed_at: None,
+            size: None,
+            open_path: None,
+            owner: None,
+            permissions: None,
+        }),
+    }).await.map_err(|error| format!("{:?}", error))?;
+
+    // Drive and OneDrive give the folder an id of their own, it is looked up again
+    let trash = find_trash(filesystem).await?.ok_or_else(|| format!("{} was created but can't be found", TRASH_DIR_NAME))?;
+    Ok((trash, HashSet::new()))
+}
+
+async fn find_trash(filesystem: &dyn FileSystem) -> Result<Option<ObjectId>, String> {
+    Ok(filesystem.read_directory(ObjectId::root()).await.map_err(|error| format!("{:?}", error))?
+        .into_iter()
+        .find(|file| file.name == TRASH_DIR_NAME && file.id.is_directory())
+        .map(|file| file.id))
+}
\ No newline at end of file
@@ -0,0 +1,38 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tracing::warn;
+
+use super::policy::Operation;
+
+// Only the latest denials are kept, enough to explain the EPERM someone just ran into.
+const MAX_DENIALS: usize = 100;
+
+pub struct Denial {
+    pub at: SystemTime,
+    pub operation: Operation,
+    pub path: PathBuf,
+    pub reason: String,
+    pub uid: u32,
+    pub pid: u32,
+}
+
+#[derive(Default)]
+pub struct Denials {
+    entries: VecDeque<Denial>,
+}
+
+impl Denials {
+    pub fn record(&mut self, denial: Denial) {
+        warn!("{:?} on {} denied: {}", denial.operation, denial.path.display(), denial.reason);
+
+        if self.entries.len() == MAX_DENIALS {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(denial);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Denial> {
+        self.entries.iter()
+    }
+}
\ No newline at end of file
@@ -1,71 +1,251 @@
 use std::{ffi::OsStr};
 use std::os::unix::ffi::OsStrExt;
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
-use libc::ENOENT;
+use libc::{ENETUNREACH, ENOENT, ENOTEMPTY, EPERM};
 
-use fuser::{FileType, FileAttr, ReplyDirectory, ReplyEntry, Request};
+use chrono::{DateTime, Local};
+use fuser::{FileType, FileAttr, ReplyDirectory, ReplyEntry, ReplyOpen};
 use crossroads::interfaces::filesystem::{ObjectId, File, Metadata as CrossroadsMetadata};
+use crossroads::storage::{ProviderId, ProviderType};
+use tracing::debug;
 
-use super::{FuseFS, TTL};
+use crate::fstree::FsNode;
+use super::FuseFS;
+use super::dispatch::{Awaited, Caller, Completion};
+use super::control::{CONTROL_DIR_INODE, CONTROL_DIR_NAME};
+use super::policy::Operation;
+use super::deletes::deletes_recursively;
+use super::listings::Entry;
+
+// Offsets handed to the kernel are derived from inodes rather than positions in the children,
+// entries are listed by increasing inode so a refresh between two calls neither repeats nor
+// skips the entries that are still there. `.` and `..` take the first two offsets.
+const CHILD_COOKIE_BASE: i64 = 2;
+// The control directory comes after every provider at the root.
+const CONTROL_DIR_COOKIE: i64 = i64::MAX;
+
+// The control directory's `providers` folder holds a folder by provider, named after it, with
+// what is known of its account one value per file. Their inodes are taken below the control
+// files', a block by provider from the inode of its root.
+pub const PROVIDERS_DIR_NAME: &str = "providers";
+pub const PROVIDERS_DIR_INODE: u64 = CONTROL_DIR_INODE - 64;
+const INFO_INODES: u64 = 8;
+// provider roots beyond this inode get no folder, in practice they are all far below it
+const INFO_ROOTS: u64 = 1 << 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InfoFile {
+    // `total`, `used` and `free` bytes, `unknown` when the provider doesn't tell
+    Quota,
+    // GoogleDrive, OneDrive, S3 or NativeFs
+    Type,
+    // online, offline, degraded or reauth, as in the status control file
+    Status,
+    // when the provider's folders were last listed, `never` before the first listing
+    LastSync,
+}
+
+impl InfoFile {
+    const ALL: [InfoFile; 4] = [InfoFile::Quota, InfoFile::Type, InfoFile::Status, InfoFile::LastSync];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|file| file.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            InfoFile::Quota => "quota",
+            InfoFile::Type => "type",
+            InfoFile::Status => "status",
+            InfoFile::LastSync => "last_sync",
+        }
+    }
+}
+
+// The folder of the provider whose root has the inode `root`, or one of its files.
+pub fn info_inode(root: u64, file: Option<InfoFile>) -> u64 {
+    let index = file.map_or(0, |file| 1 + InfoFile::ALL.iter().position(|other| *other == file).unwrap() as u64);
+    PROVIDERS_DIR_INODE - 1 - root * INFO_INODES - index
+}
+
+pub fn parse_info_inode(ino: u64) -> Option<(u64, Option<InfoFile>)> {
+    let offset = (PROVIDERS_DIR_INODE - 1).checked_sub(ino)?;
+    let root = offset / INFO_INODES;
+    if root >= INFO_ROOTS {
+        return None;
+    }
+    match offset % INFO_INODES {
+        0 => Some((root, None)),
+        index => Some((root, Some(*InfoFile::ALL.get(index as usize - 1)?))),
+    }
+}
 
 impl FuseFS {
-    pub fn internal_readdir(&mut self, _req: &Request, dir_inode: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        println!("readdir: {}", dir_inode);
+    pub fn internal_readdir(&mut self, req: &Caller, dir_inode: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        debug!(ino = dir_inode, offset, "readdir");
 
         if dir_inode == 1 {
-            let providers = self.providers.list_providers();
-            if offset < providers.len().try_into().unwrap() {
-                let provider = providers.get(offset as usize).unwrap();
-                let provider_name = provider.id.clone();
-                let provider_name = provider_name.as_bytes();
-                let node = self.tree.find_with_ids(ObjectId::root(), (*provider).clone());
-                if let Ok(node) = node.unwrap().lock() {
-                    let _ = reply.add(node.inode, offset + 1, FileType::Directory, OsStr::from_bytes(provider_name));
-                }
+            let mut providers: Vec<(u64, String)> = self.tree.providers(req.uid()).iter().map(|node| {
+                let node = node.lock().unwrap();
+                (node.inode, node.name.clone())
+            }).collect();
+            providers.sort();
+
+            let entries = providers.into_iter()
+                .map(|(inode, name)| (inode, inode as i64, FileType::Directory, name))
+                .chain([(CONTROL_DIR_INODE, CONTROL_DIR_COOKIE, FileType::Directory, CONTROL_DIR_NAME.to_string())]);
+            return add_entries(entries, offset, reply);
+        }
+
+        if dir_inode == CONTROL_DIR_INODE {
+            return self.control_readdir(offset, reply);
+        }
+
+        if dir_inode == PROVIDERS_DIR_INODE {
+            let mut providers: Vec<(u64, String)> = self.tree.providers(req.uid()).iter().map(|node| {
+                let node = node.lock().unwrap();
+                (node.inode, node.provider_id.id.clone())
+            }).collect();
+            providers.sort();
+
+            let entries = [(PROVIDERS_DIR_INODE, FileType::Directory, ".".to_string()), (CONTROL_DIR_INODE, FileType::Directory, "..".to_string())].into_iter()
+                .chain(providers.into_iter().map(|(root, name)| (info_inode(root, None), FileType::Directory, name)));
+            return add_entries(entries.enumerate().map(|(index, (inode, kind, name))| (inode, index as i64 + 1, kind, name)), offset, reply);
+        }
+
+        if let Some((root, None)) = parse_info_inode(dir_inode) {
+            if self.info_provider(root, req.uid()).is_none() {
+                return reply.error(ENOENT);
             }
-            reply.ok();
-            return;
+            let entries = [(dir_inode, FileType::Directory, ".".to_string()), (PROVIDERS_DIR_INODE, FileType::Directory, "..".to_string())].into_iter()
+                .chain(InfoFile::ALL.iter().map(|file| (info_inode(root, Some(*file)), FileType::RegularFile, file.name().to_string())));
+            return add_entries(entries.enumerate().map(|(index, (inode, kind, name))| (inode, index as i64 + 1, kind, name)), offset, reply);
+        }
+
+        if self.searches.contains(dir_inode) {
+            return self.search_readdir(req, dir_inode, offset, reply);
         }
 
-        match offset {
-            0 => {let _ = reply.add(1, 1, FileType::Directory, OsStr::from_bytes(b"."));},
-            1 => {let _ = reply.add(1, 2, FileType::Directory, OsStr::from_bytes(b".."));},
-            _ => {
-                println!("offset: {}", offset);
-
-                if let Some(fs_node) = self.tree.find_with_inode(dir_inode) {
-                    let children = self.get_children(&mut fs_node.lock().unwrap());
-                    if offset - 2 < children.len().try_into().unwrap() {
-                        let child = children.get((offset) as usize - 2).unwrap().as_ref();
-                        if let Ok(child) = child.lock() {
-                            let file_name = child.name.clone();
-                            let file_name = file_name.as_bytes();
-                            let file_type = if child.id.is_directory() {
-                                FileType::Directory
-                            } else {
-                                FileType::RegularFile
-                            };
-                            let _ = reply.add(child.inode, offset + 1, file_type, OsStr::from_bytes(file_name));
-                        }
+        // from the folder's content when it was opened, or as it is now without a handle
+        let children = match self.listings.get(fh, dir_inode) {
+            Some(entries) => entries.iter().map(|entry| (entry.inode, entry.kind, entry.name.clone())).collect(),
+            None => match self.tree.find_with_inode(dir_inode) {
+                Some(fs_node) => {
+                    if !fs_node.lock().unwrap().visible_to(req.uid()) {
+                        reply.error(ENOENT);
+                        return;
                     }
-                }
-            }
+
+                    // listed again once the folder is
+                    let listed = self.get_children(&mut fs_node.lock().unwrap());
+                    let listed = match listed {
+                        Some(listed) => listed,
+                        None => {
+                            let req = *req;
+                            return self.wait(Awaited::Listing(dir_inode), move |fs, _| fs.internal_readdir(&req, dir_inode, fh, offset, reply));
+                        },
+                    };
+                    let mut children: Vec<_> = listed.iter().map(|child| {
+                        let entry = entry(&child.lock().unwrap());
+                        (entry.inode, entry.kind, entry.name)
+                    }).collect();
+                    children.sort_by_key(|(inode, _, _)| *inode);
+                    children
+                },
+                None => Vec::new(),
+            },
+        };
+
+        // the provider's folder is the root when it is mounted alone
+        let (this, parent) = match self.single_root {
+            Some(root) if root == dir_inode => (1, 1),
+            _ => (dir_inode, self.tree.parent(dir_inode).filter(|parent| Some(*parent) != self.single_root).unwrap_or(1)),
+        };
+        let entries = [(this, 1, FileType::Directory, ".".to_string()), (parent, 2, FileType::Directory, "..".to_string())].into_iter()
+            .chain(children.into_iter().map(|(inode, kind, name)| (inode, inode as i64 + CHILD_COOKIE_BASE, kind, name)));
+        add_entries(entries, offset, reply);
+    }
+
+    pub fn internal_opendir(&mut self, req: &Caller, ino: u64, flags: i32, reply: ReplyOpen) {
+        debug!(ino, "opendir");
+
+        // listed from what they hold at each call
+        if ino == 1 || ino == CONTROL_DIR_INODE || ino == PROVIDERS_DIR_INODE || parse_info_inode(ino).is_some() || self.searches.contains(ino) {
+            return reply.opened(0, 0);
+        }
+
+        let node = match self.tree.find_with_inode(ino) {
+            Some(node) => node,
+            None => return reply.error(ENOENT),
+        };
+        if !node.lock().unwrap().visible_to(req.uid()) {
+            return reply.error(ENOENT);
         }
 
+        let children = self.get_children(&mut node.lock().unwrap());
+        let children = match children {
+            Some(children) => children,
+            None => {
+                let req = *req;
+                return self.wait(Awaited::Listing(ino), move |fs, _| fs.internal_opendir(&req, ino, flags, reply));
+            },
+        };
+        let entries = children.iter().map(|child| entry(&child.lock().unwrap())).collect();
+        reply.opened(self.listings.open(ino, entries), 0);
+    }
+
+    pub fn internal_releasedir(&mut self, _req: &Caller, ino: u64, fh: u64, _flags: i32, reply: fuser::ReplyEmpty) {
+        debug!(ino, "releasedir");
+
+        self.listings.release(fh);
         reply.ok();
     }
 
-    pub fn internal_rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-        println!("rmdir: {}", name.to_str().unwrap());
+    pub fn internal_rmdir(&mut self, req: &Caller, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        debug!(parent, name = ?name, "rmdir");
 
         if let Some(node) = self.tree.find_with_name(parent, name.to_str().unwrap()) {
-            if let Ok(node) = node.lock() {
-                let provider = self.providers.get_provider(node.provider_id.as_ref().clone()).unwrap();
-                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
-    
-                rt.block_on(async {
-                    provider.as_filesystem().unwrap().delete(node.id.clone()).await.unwrap();
-                });
+            if !node.lock().unwrap().visible_to(req.uid()) {
+                reply.error(ENOENT);
+                return;
+            }
+
+            if let Ok(mut node) = node.lock() {
+                if let Err(errno) = self.check_provider(&node.provider_id) {
+                    return reply.error(errno);
+                }
+
+                // folders are emptied by the kernel first, unless their provider can delete
+                // them with their content and that was asked for
+                let children = match self.get_children(&mut node) {
+                    Some(children) => children,
+                    None => {
+                        let awaited = Awaited::Listing(node.inode);
+                        drop(node);
+                        let (req, name) = (*req, name.to_os_string());
+                        return self.wait(awaited, move |fs, _| fs.internal_rmdir(&req, parent, &name, reply));
+                    },
+                };
+                if !children.is_empty() && !(self.recursive_rmdir && deletes_recursively(&node.provider_id.provider_type)) {
+                    return reply.error(ENOTEMPTY);
+                }
+
+                let size = node.metadata.map(|metadata| metadata.size).unwrap_or(0);
+                let path = self.tree.path(node.inode).unwrap_or_default();
+                if let Err(errno) = self.check_policy(req, &node.provider_id, Operation::Delete, &path, size) {
+                    return reply.error(errno);
+                }
+
+                // the files going along count for the deletion guard, as far as they were listed
+                let sizes = children.iter().map(|child| child.lock().unwrap().metadata.map_or(0, |metadata| metadata.size)).collect::<Vec<_>>();
+                let guarded = sizes.into_iter().chain([size]).all(|size| self.deletion_guard.allow(&node.provider_id, size));
+                if node.provider_id.provider_type != ProviderType::NativeFs && !guarded {
+                    self.deny(req, Operation::Delete, &path, "deletion guard, approve it in the control directory".to_string());
+                    return reply.error(EPERM);
+                }
+
+                self.queue_delete(&node.provider_id, node.id.clone(), path, true);
             }
 
             if let Some(parent_node) = self.tree.find_with_inode(parent) {
@@ -75,32 +255,64 @@ impl FuseFS {
             }
 
             self.tree.remove(parent, node);
+            reply.ok();
+        } else {
+            reply.error(ENOENT);
         }
-
-        reply.ok();
     }
 
     pub fn internal_mkdir(
         &mut self,
-        _req: &Request<'_>,
+        req: &Caller,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
+        mode: u32,
+        umask: u32,
         reply: ReplyEntry,
     ) {
-        println!("mkdir: {}", name.to_str().unwrap());
+        debug!(parent, name = ?name, "mkdir");
 
         if let Some(parent_dir) = self.tree.find_with_inode(parent) {
-            if let Ok(mut parent_dir) = parent_dir.lock() {
-                let provider = self.providers.get_provider(parent_dir.provider_id.as_ref().clone()).unwrap();
-                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
-    
-                rt.block_on(async {
-                    let id = ObjectId::directory(parent_dir.id.to_string() + "/" + name.to_str().unwrap());
-                    provider.as_filesystem().unwrap().create(parent_dir.id.clone(), File {
+            if let Ok(parent_dir) = parent_dir.lock() {
+                if !parent_dir.visible_to(req.uid()) {
+                    reply.error(ENOENT);
+                    return;
+                }
+
+                if let Err(errno) = self.check_provider(&parent_dir.provider_id) {
+
+                    return reply.error(errno);
+
+                }
+
+                let path = self.tree.path(parent).unwrap_or_default().join(name);
+                if let Err(errno) = self.check_policy(req, &parent_dir.provider_id, Operation::Create, &path, 0) {
+                    return reply.error(errno);
+                }
+
+                // a folder of the same name may still be waiting to be deleted
+                self.flush_deletes(true);
+                if self.dispatcher.is_awaited(Awaited::Deletes) {
+                    drop(parent_dir);
+                    let (req, name) = (*req, name.to_os_string());
+                    return self.wait(Awaited::Deletes, move |fs, _| fs.internal_mkdir(&req, parent, &name, mode, umask, reply));
+                }
+
+                let providers = match self.providers.get(&parent_dir.provider_id) {
+                    Some(providers) => providers,
+                    None => return reply.error(ENETUNREACH),
+                };
+                self.quotas.record(&parent_dir.provider_id);
+
+                // answered once the folder is in the tree
+                let (provider_id, parent_id) = (parent_dir.provider_id.clone(), parent_dir.id.clone());
+                let id = ObjectId::directory(parent_dir.id.to_string() + "/" + name.to_str().unwrap());
+                let name = name.to_str().unwrap().to_string();
+                self.spawn_awaited(&[], async move {
+                    let provider = providers.get_provider(provider_id.as_ref().clone()).unwrap();
+                    let result = provider.as_filesystem().unwrap().create(parent_id, File {
                         id: id.clone(),
-                        name: name.to_str().unwrap().to_string(),
+                        name: name.clone(),
                         metadata: Some(CrossroadsMetadata {
                             mime_type: Some("directory".to_string()),
                             created_at: None,
@@ -112,33 +324,112 @@ impl FuseFS {
                             owner: None,
                             permissions: None,
                         }),
-                    }).await.unwrap();
-
-                    let provider_id = parent_dir.provider_id.clone();
-
-                    let new_file = self.tree.new_file(&mut parent_dir, id, name.to_str().unwrap(), None, provider_id);
-
-                    reply.entry(&TTL, &FileAttr {
-                        ino: new_file.lock().unwrap().inode,
-                        size: 0,
-                        blocks: 0,
-                        atime: SystemTime::now(), // 1970-01-01 00:00:00
-                        mtime: SystemTime::now(),
-                        ctime: SystemTime::now(),
-                        crtime: SystemTime::now(),
-                        kind: FileType::Directory,
-                        perm: 0o755,
-                        nlink: 0,
-                        uid: 501,
-                        gid: 20,
-                        rdev: 0,
-                        flags: 0,
-                        blksize: 512,                    
-                    }, 0);
+                    }).await;
+                    let result = result.map(|_| ()).map_err(|error| format!("{:?}", error));
+                    Completion::FolderCreated { parent, provider_id, id, name, result, reply }
                 });
             }
         } else {
             reply.error(ENOENT);
         }
     }
+
+    pub fn apply_folder_created(&mut self, parent: u64, provider_id: &ProviderId, id: ObjectId, name: &str, result: Result<(), String>, reply: ReplyEntry) {
+        if let Err(error) = result {
+            let errno = self.failed("mkdir", parent, provider_id, error);
+            return reply.error(errno);
+        }
+        let parent_dir = match self.tree.find_with_inode(parent) {
+            Some(parent_dir) => parent_dir,
+            None => return reply.error(ENOENT),
+        };
+        let mut parent_dir = parent_dir.lock().unwrap();
+
+        let provider_id = parent_dir.provider_id.clone();
+
+        let entry_ttl = self.tree.ttl(&provider_id).entry();
+        let new_file = self.tree.new_file(&mut parent_dir, id, name, None, provider_id);
+
+        reply.entry(&entry_ttl, &FileAttr {
+            ino: new_file.lock().unwrap().inode,
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::now(), // 1970-01-01 00:00:00
+            mtime: SystemTime::now(),
+            ctime: SystemTime::now(),
+            crtime: SystemTime::now(),
+            kind: FileType::Directory,
+            perm: self.modes.perm(true),
+            nlink: 0,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 512,                    
+        }, self.tree.generation);
+    }
+}
+
+impl FuseFS {
+    // The root of a mounted provider, by its inode.
+    pub fn info_provider(&self, root: u64, uid: u32) -> Option<Arc<Mutex<FsNode>>> {
+        self.tree.providers(uid).into_iter().find(|node| node.lock().unwrap().inode == root)
+    }
+
+    pub fn info_lookup(&self, parent: u64, name: &str, uid: u32) -> Option<u64> {
+        if parent == PROVIDERS_DIR_INODE {
+            let root = self.tree.providers(uid).into_iter().find(|node| node.lock().unwrap().provider_id.id == name)?;
+            let root = root.lock().unwrap().inode;
+            return Some(info_inode(root, None));
+        }
+
+        match parse_info_inode(parent)? {
+            (root, None) => Some(info_inode(root, Some(InfoFile::from_name(name)?))),
+            _ => None,
+        }
+    }
+
+    pub fn info_content(&self, root: u64, file: InfoFile) -> Option<Vec<u8>> {
+        let node = self.info_provider(root, self.uid)?;
+        let provider_id = node.lock().unwrap().provider_id.clone();
+
+        let content = match file {
+            // only local providers have a known size, the one of their disk
+            InfoFile::Quota => match self.native_statfs(root) {
+                Some(stat) => format!(
+                    "total {}\nused {}\nfree {}\n",
+                    stat.f_blocks as u64 * stat.f_frsize as u64,
+                    (stat.f_blocks - stat.f_bfree) as u64 * stat.f_frsize as u64,
+                    stat.f_bavail as u64 * stat.f_frsize as u64,
+                ),
+                None => "unknown\n".to_string(),
+            },
+            InfoFile::Type => format!("{:?}\n", provider_id.provider_type),
+            InfoFile::Status => serde_json::to_string(&self.health_of(&provider_id)).unwrap().trim_matches('"').to_string() + "\n",
+            InfoFile::LastSync => match self.poller.last_sync(&provider_id) {
+                Some(at) => DateTime::<Local>::from(at).to_rfc3339() + "\n",
+                None => "never\n".to_string(),
+            },
+        };
+        Some(content.into_bytes())
+    }
+}
+
+fn entry(node: &FsNode) -> Entry {
+    Entry {
+        inode: node.inode,
+        kind: if node.id.is_directory() { FileType::Directory } else { FileType::RegularFile },
+        name: node.name.clone(),
+    }
+}
+
+// Adds the entries after `offset` until the kernel's buffer is full.
+fn add_entries(entries: impl Iterator<Item = (u64, i64, FileType, String)>, offset: i64, mut reply: ReplyDirectory) {
+    for (inode, cookie, kind, name) in entries.filter(|(_, cookie, _, _)| *cookie > offset) {
+        if reply.add(inode, cookie, kind, OsStr::from_bytes(name.as_bytes())) {
+            break;
+        }
+    }
+
+    reply.ok();
 }
\ No newline at end of file
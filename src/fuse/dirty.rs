@@ -0,0 +1,331 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Local};
+use crossroads::interfaces::filesystem::{File, FileSystem, FileType, ObjectId};
+use crossroads::storage::ProviderId;
+use libc::{c_int, EIO, ENETUNREACH, ENOENT};
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+use crate::fstree::{revision, FsNode};
+use crate::notifications::{self, Event, LARGE_UPLOAD_SIZE};
+use crate::telemetry;
+use super::FuseFS;
+use super::dispatch::{Awaited, Completion};
+use super::errors::{is_network_error, is_transient_error};
+use super::journal::Deferred;
+use super::node::content_version;
+
+impl FuseFS {
+    // Uploads what was written through a handle since its last flush from a task, the handle
+    // keeps its buffer to be written to again. The content is in the journal before it is sent,
+    // when the provider can't take it the journal's worker keeps trying. Returns the upload to
+    // wait for, which may be another handle's of the same file.
+    pub fn flush_handle(&mut self, handle: u64) -> Result<Option<Awaited>, c_int> {
+        let (inode, content) = match self.handles.dirty(handle) {
+            Some(dirty) => dirty,
+            None => return Ok(None),
+        };
+        // one upload of a file at a time, they'd race for its revision
+        if self.dispatcher.is_awaited(Awaited::Upload(inode)) {
+            return Ok(Some(Awaited::Upload(inode)));
+        }
+
+        let node = self.tree.find_with_inode(inode).ok_or(ENOENT)?;
+        let node = node.lock().unwrap();
+
+        let base = self.handles.base(handle);
+        if self.journal.is_queued(&node.provider_id.id) || self.check_provider(&node.provider_id) == Err(ENETUNREACH) {
+            self.defer_write(&node, base, &content)?;
+            self.mark_clean(handle);
+            return Ok(None);
+        }
+        let providers = self.providers.get(&node.provider_id).ok_or(ENETUNREACH)?;
+
+        let seq = self.journal_write(&node, base.clone(), &content);
+        self.record_upload(&node, &base);
+        let parallelism = self.upload_parallelism;
+        let limit = self.upload_limits.entry(node.provider_id.as_ref().clone()).or_insert_with(|| Arc::new(Semaphore::new(parallelism))).clone();
+
+        let path = self.tree.path(inode).unwrap_or_default();
+        let transfer = self.transfers.start(path.display().to_string(), true, content.len() as u64);
+        let (provider_id, id, parent, name) = (node.provider_id.clone(), node.id.clone(), self.parent_id(inode), node.name.clone());
+        let flush = Flush { handle, inode, written: self.handles.written(handle), seq, base: base.clone(), content: content.clone(), transfer };
+        self.spawn_awaited(&[Awaited::Upload(inode)], async move {
+            // crossroads sends a file in a single request, so the files are what is uploaded
+            // concurrently, at most `upload_parallelism` at a time to each provider
+            let _permit = limit.acquire().await;
+            let provider = providers.get_provider(provider_id.as_ref().clone()).unwrap();
+            let result = upload_checked(provider.as_filesystem().unwrap(), &provider_id, id, parent, &name, base, content).await;
+            Completion::Flushed { flush, result }
+        });
+        Ok(Some(Awaited::Upload(inode)))
+    }
+
+    // Flushes a handle and runs `done` once what it wrote reached the provider or the journal,
+    // after the upload of the file already on the way.
+    pub fn flush_then<F>(&mut self, handle: u64, done: F)
+    where
+        F: FnOnce(&mut FuseFS, Result<(), c_int>) + Send + 'static,
+    {
+        match self.flush_handle(handle) {
+            Ok(Some(awaited)) => self.wait(awaited, move |fs, result| match result {
+                // written meanwhile, or the upload was another handle's
+                Ok(()) => fs.flush_then(handle, done),
+                Err(errno) => done(fs, Err(errno)),
+            }),
+            Ok(None) => done(self, Ok(())),
+            Err(errno) => done(self, Err(errno)),
+        }
+    }
+
+    // What a flush sent, the handle is clean unless it was written to meanwhile.
+    pub fn apply_flushed(&mut self, flush: Flush, result: Result<Uploaded, String>) {
+        let Flush { handle, inode, written, seq, base, content, transfer } = flush;
+        self.transfers.finish(transfer, result.as_ref().map_or(0, |_| content.len() as u64));
+
+        let node = match self.tree.find_with_inode(inode) {
+            Some(node) => node,
+            // unlinked meanwhile, nothing is left to write to
+            None => {
+                if let Some(seq) = seq {
+                    self.journal.finish(seq, None);
+                }
+                self.mark_clean(handle);
+                return self.resume(Awaited::Upload(inode), Ok(()));
+            },
+        };
+        let mut node = node.lock().unwrap();
+        let result = match (seq, self.uploaded(&mut node, content.len(), base, result)) {
+            (Some(seq), Err(ENETUNREACH)) if self.journal.retry(seq, false) => {
+                self.keep_for_retry(&node, &content);
+                Ok(())
+            },
+            (seq, result) => {
+                if let Some(seq) = seq {
+                    self.journal.finish(seq, result.clone().ok().flatten());
+                }
+                result.map(|base| self.handles.set_base(handle, base))
+            },
+        };
+        drop(node);
+
+        if result.is_ok() && self.handles.written(handle) == written {
+            self.mark_clean(handle);
+        }
+        self.resume(Awaited::Upload(inode), result);
+    }
+
+    // Written to the journal as being sent, None when it couldn't be saved and the upload
+    // goes without it.
+    fn journal_write(&mut self, node: &FsNode, base: Option<String>, content: &[u8]) -> Option<u64> {
+        match self.journal.push(self.deferred_write(node, base), Some(content), true) {
+            Ok(seq) => Some(seq),
+            Err(error) => {
+                warn!("unable to journal the write of {}: {}", node.name, error);
+                None
+            },
+        }
+    }
+
+    pub fn deferred_write(&self, node: &FsNode, base: Option<String>) -> Deferred {
+        Deferred::Write {
+            provider: node.provider_id.id.clone(),
+            id: node.id.as_str().to_string(),
+            path: self.tree.path(node.inode).unwrap_or_default(),
+            parent: self.parent_id(node.inode).as_str().to_string(),
+            name: node.name.clone(),
+            base,
+            follows: false,
+        }
+    }
+
+    // Leaves a write to the journal's worker, reads get the content from the cache meanwhile.
+    fn defer_write(&mut self, node: &FsNode, base: Option<String>, content: &[u8]) -> Result<(), c_int> {
+        if let Err(error) = self.defer(self.deferred_write(node, base), Some(content)) {
+            self.fail("write", node.inode, &node.provider_id, error);
+            return Err(EIO);
+        }
+        self.keep_for_retry(node, content);
+        Ok(())
+    }
+
+    fn keep_for_retry(&mut self, node: &FsNode, content: &[u8]) {
+        self.start_worker(&node.provider_id.id);
+        if let (Some(cache), Some(version)) = (&self.content_cache, content_version(node)) {
+            if let Err(error) = cache.put(&node.provider_id.id, node.id.as_str(), &version, content) {
+                warn!("unable to cache {}: {}", node.id.as_str(), error);
+            }
+        }
+    }
+
+    // The write, the revision it leaves and the check before it when there is one.
+    fn record_upload(&mut self, node: &FsNode, base: &Option<String>) {
+        let requests = if base.is_some() { 3 } else { 2 };
+        for _ in 0..requests {
+            self.quotas.record(&node.provider_id);
+        }
+    }
+
+    pub fn parent_id(&self, inode: u64) -> ObjectId {
+        let parent = self.tree.parent(inode).and_then(|parent| self.tree.find_with_inode(parent));
+        parent.map_or_else(ObjectId::root, |parent| parent.lock().unwrap().id.clone())
+    }
+
+    pub fn uploaded(&mut self, node: &mut FsNode, uploaded: usize, base: Option<String>, result: Result<Uploaded, String>) -> Result<Option<String>, c_int> {
+        match result {
+            Ok(result) => {
+                if uploaded >= LARGE_UPLOAD_SIZE {
+                    notifications::notify(Event::UploadFinished { name: node.name.clone(), size: uploaded });
+                }
+                node.revision = result.revision.clone();
+                // the next stat asks the provider for the attributes it recorded
+                node.metadata_expire_at = None;
+
+                let copy = match result.copy {
+                    Some(copy) => copy,
+                    None => return Ok(result.revision),
+                };
+                // Someone else's version stays in place. The handle keeps writing to the copy,
+                // it still starts from the revision it conflicts with.
+                info!("{} changed on its provider meanwhile, written to {}", node.name, copy);
+                notifications::notify(Event::Conflict { name: node.name.clone(), copy });
+                if let Some(parent) = self.tree.parent(node.inode).and_then(|parent| self.tree.find_with_inode(parent)) {
+                    parent.lock().unwrap().expire_at = None;
+                }
+                Ok(base)
+            },
+            // left for the journal's worker
+            Err(error) if is_network_error(&error) || is_transient_error(&error) => Err(ENETUNREACH),
+            Err(error) => Err(self.failed("write", node.inode, &node.provider_id, error)),
+        }
+    }
+
+    // Called on unmount, nothing written may stay in memory. The uploads are settled before
+    // the daemon exits.
+    pub fn flush_handles(&mut self) {
+        for handle in self.handles.dirty_handles() {
+            self.flush_then(handle, |_, _| ());
+        }
+    }
+}
+
+// An upload of what a handle wrote, as it was when sent.
+pub struct Flush {
+    handle: u64,
+    inode: u64,
+    // the handle's writes when it was sent
+    written: u64,
+    seq: Option<u64>,
+    base: Option<String>,
+    content: Vec<u8>,
+    transfer: u64,
+}
+
+// What reached the provider.
+#[derive(Default)]
+pub struct Uploaded {
+    // the conflicted copy the content went to instead of the file
+    pub copy: Option<String>,
+    // the file's revision on the provider afterwards
+    pub revision: Option<String>,
+}
+
+// crossroads has no conditional write, the provider's revision is checked right before the
+// upload instead. Without a `base` the file is overwritten whatever its revision.
+pub async fn upload_checked(filesystem: &dyn FileSystem, provider_id: &ProviderId, id: ObjectId, parent: ObjectId, name: &str, base: Option<String>, content: Vec<u8>) -> Result<Uploaded, String> {
+    let current = match &base {
+        Some(_) => remote_revision(filesystem, provider_id, &id).await,
+        None => None,
+    };
+    let conflict = matches!((&base, &current), (Some(base), Some(current)) if base != current);
+
+    let (target, copy) = match conflict {
+        true => {
+            // a listing that fails leaves the name unchecked, providers refuse or rename a
+            // duplicate at worst
+            let trace = telemetry::provider_request(provider_id, "read_directory", &parent);
+            let listing = filesystem.read_directory(parent.clone()).await;
+            trace.finish(&listing, 0);
+            let taken: Vec<String> = listing.map(|files| files.into_iter().map(|file| file.name).collect()).unwrap_or_default();
+
+            let copy = conflicted_name(name, &Local::now(), &taken);
+            let target = ObjectId::new(parent.to_string() + "/" + &copy, FileType::File);
+            let trace = telemetry::provider_request(provider_id, "create", &parent);
+            let result = filesystem.create(parent, File { id: target.clone(), name: copy.clone(), metadata: None }).await;
+            trace.finish(&result, 0);
+            result.map_err(|error| format!("{:?}", error))?;
+            (target, Some(copy))
+        },
+        false => (id.clone(), None),
+    };
+
+    let size = content.len() as u64;
+    let trace = telemetry::provider_request(provider_id, "write_file", &target);
+    let result = filesystem.write_file(target, content.into()).await;
+    trace.finish(&result, size);
+    result.map_err(|error| format!("{:?}", error))?;
+
+    let revision = match conflict {
+        true => current,
+        false => remote_revision(filesystem, provider_id, &id).await,
+    };
+    Ok(Uploaded { copy, revision })
+}
+
+async fn remote_revision(filesystem: &dyn FileSystem, provider_id: &ProviderId, id: &ObjectId) -> Option<String> {
+    let trace = telemetry::provider_request(provider_id, "get_metadata", id);
+    let result = filesystem.get_metadata(id.clone()).await;
+    trace.finish(&result, 0);
+    result.ok().as_ref().and_then(revision)
+}
+
+// `report.pdf` becomes `report (conflicted copy 2024-05-01 14.30.05).pdf`, followed by a
+// number when a copy made the same second is already in the folder.
+pub fn conflicted_name(name: &str, at: &DateTime<Local>, taken: &[String]) -> String {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    let copy = format!("{} (conflicted copy {})", stem, at.format("%Y-%m-%d %H.%M.%S"));
+
+    let mut candidate = copy.clone() + &extension;
+    let mut number = 2;
+    while taken.contains(&candidate) {
+        candidate = format!("{} {}{}", copy, number, extension);
+        number += 1;
+    }
+    candidate
+}
+
+#[cfg(test)]
+mod dirty_test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 5, 1, 14, 30, 5).unwrap()
+    }
+
+    #[test]
+    fn conflicted_name_keeps_the_extension() {
+        assert_eq!(conflicted_name("report.pdf", &at(), &[]), "report (conflicted copy 2024-05-01 14.30.05).pdf");
+        assert_eq!(conflicted_name("archive.tar.gz", &at(), &[]), "archive.tar (conflicted copy 2024-05-01 14.30.05).gz");
+    }
+
+    #[test]
+    fn conflicted_name_without_extension() {
+        assert_eq!(conflicted_name("README", &at(), &[]), "README (conflicted copy 2024-05-01 14.30.05)");
+        assert_eq!(conflicted_name(".bashrc", &at(), &[]), ".bashrc (conflicted copy 2024-05-01 14.30.05)");
+    }
+
+    #[test]
+    fn conflicted_name_numbers_copies_of_the_same_second() {
+        let taken = vec![
+            "report (conflicted copy 2024-05-01 14.30.05).pdf".to_string(),
+            "report (conflicted copy 2024-05-01 14.30.05) 2.pdf".to_string(),
+        ];
+
+        assert_eq!(conflicted_name("report.pdf", &at(), &taken), "report (conflicted copy 2024-05-01 14.30.05) 3.pdf");
+    }
+}
\ No newline at end of file
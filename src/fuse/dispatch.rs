@@ -0,0 +1,558 @@
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossroads::interfaces::filesystem::{File, ObjectId};
+use crossroads::storage::{ProviderId, ProvidersMap};
+use fuser::{ReplyEmpty, ReplyEntry, Request};
+use futures::future::{BoxFuture, Shared};
+use libc::c_int;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::fstree::{FileState, Metadata};
+use super::FuseFS;
+use super::control::CONTROL_DIR_NAME;
+use super::deletes::QueuedDelete;
+use super::dirty::{Flush, Uploaded};
+use super::journal::Deferred;
+use super::walk::Listing;
+
+#[derive(Debug, Clone)]
+pub struct TaskError {
+    pub message: String,
+    // the provider rejected its credentials
+    pub auth: bool,
+}
+
+pub type Download = Shared<BoxFuture<'static, Result<Arc<Vec<u8>>, TaskError>>>;
+
+// How often the ticker looks for batched work left waiting.
+const TICK: Duration = Duration::from_secs(1);
+
+// Who a request came from, kept by the callbacks that run again once a task is done.
+#[derive(Debug, Clone, Copy)]
+pub struct Caller {
+    uid: u32,
+    gid: u32,
+    pid: u32,
+}
+
+impl Caller {
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+}
+
+impl From<&Request<'_>> for Caller {
+    fn from(req: &Request<'_>) -> Self {
+        Self { uid: req.uid(), gid: req.gid(), pid: req.pid() }
+    }
+}
+
+// Provider requests a callback can't answer without. The callback waits for their task and
+// runs again once it is done, by inode for what concerns a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Awaited {
+    // a folder being listed
+    Listing(u64),
+    // the content a write or truncate starts from
+    Content(u64),
+    // a file only known locally being created on its provider
+    Created(u64),
+    // what a file's handle wrote being uploaded
+    Upload(u64),
+    // deletes sent to their providers, an object of the same name may be on the way out
+    Deletes,
+}
+
+// A callback run again once what it waited for is done, with the errno of a task that failed.
+pub type Resume = Box<dyn FnOnce(&mut FuseFS, Result<(), c_int>) + Send>;
+
+#[derive(Default)]
+struct Waiting {
+    running: usize,
+    failed: Option<c_int>,
+    resumes: Vec<Resume>,
+}
+
+// Work finished on the runtime. Tasks reply to the kernel themselves, what they change in the
+// filesystem is applied by the next callback. The callbacks waiting for a task are run again,
+// or reply, once its completion is applied.
+pub enum Completion {
+    Read {
+        fetch: u64,
+        handle: u64,
+        inode: u64,
+        provider_id: Arc<ProviderId>,
+        object: ObjectId,
+        version: Option<String>,
+        transfer: u64,
+        result: Result<Arc<Vec<u8>>, TaskError>,
+    },
+    Failed {
+        operation: &'static str,
+        inode: u64,
+        provider_id: Arc<ProviderId>,
+        error: String,
+    },
+    // the download of a file pinned while it wasn't cached
+    Pinned {
+        inode: u64,
+        provider_id: Arc<ProviderId>,
+        object: ObjectId,
+        version: String,
+        transfer: u64,
+        result: Result<Vec<u8>, String>,
+    },
+    // what getattr asked the provider, the node takes it if nothing newer was written
+    Metadata {
+        inode: u64,
+        provider_id: Arc<ProviderId>,
+        result: Result<(Option<String>, Metadata), String>,
+    },
+    // the subtrees a walk found missing from the tree, listed level by level
+    Crawled {
+        roots: Vec<u64>,
+        listings: Vec<Listing>,
+    },
+    // a folder changed from elsewhere and what it now holds
+    Changed {
+        inode: u64,
+        files: Vec<File>,
+    },
+    // a folder listed for the callbacks needing its children, in `previous` state before
+    Listed {
+        inode: u64,
+        provider_id: Arc<ProviderId>,
+        previous: FileState,
+        result: Result<Vec<File>, String>,
+    },
+    // the content of a file a write or truncate without a buffer starts from
+    Fetched {
+        operation: &'static str,
+        inode: u64,
+        provider_id: Arc<ProviderId>,
+        result: Result<Vec<u8>, (c_int, String)>,
+    },
+    // files only known locally created on their providers, by inode
+    Created {
+        creations: Vec<(u64, Arc<ProviderId>, Result<(), String>)>,
+    },
+    // a batch of deletes sent to a provider
+    Deleted {
+        provider_id: ProviderId,
+        deletes: Vec<QueuedDelete>,
+        results: Vec<Result<(), String>>,
+    },
+    // what a handle wrote was uploaded, or not
+    Flushed {
+        flush: Flush,
+        result: Result<Uploaded, String>,
+    },
+    // a rename or move, `renamed` is the object's id once renamed if the move after it failed
+    Renamed {
+        inode: u64,
+        provider_id: Arc<ProviderId>,
+        parent: u64,
+        name: String,
+        newname: String,
+        renamed: Option<ObjectId>,
+        result: Result<ObjectId, String>,
+        reply: ReplyEmpty,
+    },
+    FolderCreated {
+        parent: u64,
+        provider_id: Arc<ProviderId>,
+        id: ObjectId,
+        name: String,
+        result: Result<(), String>,
+        reply: ReplyEntry,
+    },
+    Linked {
+        parent: u64,
+        provider_id: Arc<ProviderId>,
+        name: String,
+        result: Result<(), String>,
+        reply: ReplyEntry,
+    },
+    // an operation of the journal was sent, or given up on
+    Replayed {
+        provider_id: Arc<ProviderId>,
+        deferred: Deferred,
+        result: Result<Uploaded, TaskError>,
+    },
+    // the provider's token was refreshed, as the credential file now holds it, and registered
+    // with it in a map of its own when it could be
+    TokenRefreshed {
+        provider_id: Arc<ProviderId>,
+        credentials: Value,
+        providers: Option<ProvidersMap>,
+    },
+    // an offline provider was tried again, in a map of its own it is registered in if it answered
+    Probed {
+        provider_id: Arc<ProviderId>,
+        providers: Option<ProvidersMap>,
+    },
+}
+
+// Each provider by the map it was registered in. crossroads needs the only reference to a map to
+// add a provider to it, the ones registered once mounted get a map of their own instead, so the
+// tasks using the others never have to give them back. Shared with the tasks running for as long
+// as the mount.
+#[derive(Clone, Default)]
+pub struct Providers(Arc<Mutex<HashMap<ProviderId, Arc<ProvidersMap>>>>);
+
+impl Providers {
+    // Every provider of the map, in place of the map it was registered in before.
+    pub fn insert(&self, providers: ProvidersMap) {
+        let providers = Arc::new(providers);
+        let mut registered = self.0.lock().unwrap();
+        for provider_id in providers.list_providers() {
+            registered.insert(provider_id, providers.clone());
+        }
+    }
+
+    // None for a provider that is offline or was never registered.
+    pub fn get(&self, provider_id: &ProviderId) -> Option<Arc<ProvidersMap>> {
+        self.0.lock().unwrap().get(provider_id).cloned()
+    }
+
+    pub fn remove(&self, provider_id: &ProviderId) {
+        self.0.lock().unwrap().remove(provider_id);
+    }
+}
+
+struct Fetch {
+    id: u64,
+    inode: u64,
+    download: Download,
+}
+
+// Downloads in flight by file handle, the reads arriving meanwhile wait for the same download
+// instead of starting their own. The tasks callbacks wait for, and the callbacks waiting.
+pub struct Dispatcher {
+    sender: Sender<Completion>,
+    // behind a lock only so the filesystem stays Sync
+    receiver: Mutex<Receiver<Completion>>,
+    next_fetch: u64,
+    fetches: HashMap<u64, Fetch>,
+    awaited: Mutex<HashMap<Awaited, Waiting>>,
+    // the folder whose listing is being applied, its waiting callbacks take what it holds
+    listed: Option<u64>,
+    // the content fetched for the waiting writes and truncates of a file, while they run again
+    fetched: Option<(u64, Vec<u8>)>,
+    // the folder a path written to a control file waits for
+    resolving: Option<u64>,
+    // a task a callback waits for is done, the ticker has a callback come for it
+    wake: Sender<()>,
+    woken: Mutex<Option<Receiver<()>>>,
+    // batched deletes or creations wait to be sent, a callback must come to send them
+    waiting: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Default for Dispatcher {
+    fn default() -> Self {
+        let (sender, receiver) = channel();
+        let (wake, woken) = channel();
+        Self {
+            sender,
+            receiver: Mutex::new(receiver),
+            next_fetch: 0,
+            fetches: HashMap::new(),
+            awaited: Mutex::default(),
+            listed: None,
+            fetched: None,
+            resolving: None,
+            wake,
+            woken: Mutex::new(Some(woken)),
+            waiting: Arc::default(),
+            stopped: Arc::default(),
+        }
+    }
+}
+
+impl Dispatcher {
+    pub fn download(&self, handle: u64, inode: u64) -> Option<Download> {
+        self.fetches.get(&handle).filter(|fetch| fetch.inode == inode).map(|fetch| fetch.download.clone())
+    }
+
+    pub fn start_download(&mut self, handle: u64, inode: u64, download: Download) -> u64 {
+        self.next_fetch += 1;
+        self.fetches.insert(handle, Fetch { id: self.next_fetch, inode, download });
+        self.next_fetch
+    }
+
+    // For tasks running for as long as the mount.
+    pub fn sender(&self) -> Sender<Completion> {
+        self.sender.clone()
+    }
+
+    // Written or released handles don't get the content downloaded before.
+    pub fn forget(&mut self, handle: u64) {
+        self.fetches.remove(&handle);
+    }
+
+    pub fn set_waiting(&self, waiting: bool) {
+        self.waiting.store(waiting, Ordering::Relaxed);
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_awaited(&mut self, awaited: Awaited) -> bool {
+        self.awaited.get_mut().unwrap().contains_key(&awaited)
+    }
+
+    // Whether the listing of the folder was just applied, for the callbacks that waited for it.
+    pub fn is_listed(&self, inode: u64) -> bool {
+        self.listed == Some(inode)
+    }
+
+    pub fn set_listed(&mut self, listed: Option<u64>) {
+        self.listed = listed;
+    }
+
+    pub fn fetched(&self, inode: u64) -> Option<Vec<u8>> {
+        self.fetched.as_ref().filter(|(fetched, _)| *fetched == inode).map(|(_, content)| content.clone())
+    }
+
+    pub fn set_fetched(&mut self, fetched: Option<(u64, Vec<u8>)>) {
+        self.fetched = fetched;
+    }
+
+    pub fn set_resolving(&mut self, resolving: Option<u64>) {
+        self.resolving = resolving;
+    }
+
+    pub fn take_resolving(&mut self) -> Option<u64> {
+        self.resolving.take()
+    }
+
+    fn finish(&mut self, handle: u64, fetch: u64) -> bool {
+        match self.fetches.get(&handle) {
+            Some(current) if current.id == fetch => {
+                self.fetches.remove(&handle);
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FuseFS {
+    // Runs a provider request without holding the FUSE loop, its completion is applied later.
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = Option<Completion>> + Send + 'static,
+    {
+        let sender = self.dispatcher.sender.clone();
+        self.runtime().spawn(async move {
+            if let Some(completion) = task.await {
+                let _ = sender.send(completion);
+            }
+        });
+    }
+
+    // Runs a provider request callbacks wait for or that replies once applied, its completion is
+    // applied as soon as the ticker has a callback come. The callbacks waiting on `awaited` run
+    // again once it is.
+    pub fn spawn_awaited<F>(&mut self, awaited: &[Awaited], task: F)
+    where
+        F: Future<Output = Completion> + Send + 'static,
+    {
+        for awaited in awaited {
+            self.dispatcher.awaited.get_mut().unwrap().entry(*awaited).or_default().running += 1;
+        }
+
+        let (sender, wake) = (self.dispatcher.sender.clone(), self.dispatcher.wake.clone());
+        self.runtime().spawn(async move {
+            let _ = sender.send(task.await);
+            let _ = wake.send(());
+        });
+    }
+
+    // Runs `resume` once the tasks of `awaited` are done, right away when none is running. The
+    // callback gives up what it holds of the tree meanwhile and starts over.
+    pub fn wait<F>(&mut self, awaited: Awaited, resume: F)
+    where
+        F: FnOnce(&mut FuseFS, Result<(), c_int>) + Send + 'static,
+    {
+        match self.dispatcher.awaited.get_mut().unwrap().get_mut(&awaited) {
+            Some(waiting) => waiting.resumes.push(Box::new(resume)),
+            None => resume(self, Ok(())),
+        }
+    }
+
+    // A task of `awaited` is done, its callbacks run again once it was the last one.
+    pub fn resume(&mut self, awaited: Awaited, result: Result<(), c_int>) {
+        let awaiting = self.dispatcher.awaited.get_mut().unwrap();
+        let waiting = match awaiting.get_mut(&awaited) {
+            Some(waiting) => waiting,
+            None => return,
+        };
+        waiting.running = waiting.running.saturating_sub(1);
+        if let Err(errno) = result {
+            waiting.failed = Some(errno);
+        }
+        if waiting.running > 0 {
+            return;
+        }
+
+        let waiting = awaiting.remove(&awaited).unwrap();
+        let result = waiting.failed.map_or(Ok(()), Err);
+        for resume in waiting.resumes {
+            resume(self, result);
+        }
+    }
+
+    // The filesystem only changes in callbacks. When a task a callback waits for is done, or
+    // batched work waits and no request comes, the ticker looks up the control folder, the
+    // kernel asks the mount each time as its entry isn't cached, and the callback applies the
+    // completion or sends what is due.
+    pub fn start_ticker(&self) {
+        let woken = match self.dispatcher.woken.lock().unwrap().take() {
+            Some(woken) => woken,
+            None => return,
+        };
+        let waiting = self.dispatcher.waiting.clone();
+        let stopped = self.dispatcher.stopped.clone();
+        let control_dir = self.mount_point.join(CONTROL_DIR_NAME);
+
+        let started = thread::Builder::new().name("ticker".to_string()).spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                let done = match woken.recv_timeout(TICK) {
+                    Ok(()) => true,
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                };
+                // a single callback applies every completion sent so far
+                while woken.try_recv().is_ok() {}
+
+                if (done | waiting.swap(false, Ordering::Relaxed)) && !stopped.load(Ordering::Relaxed) {
+                    let _ = fs::metadata(&control_dir);
+                }
+            }
+        });
+        if let Err(error) = started {
+            warn!("unable to start the ticker, provider requests and batches wait for the next request: {}", error);
+        }
+    }
+
+    // Called after every callback, the batches left have the ticker come back for them.
+    pub fn flush_due(&mut self) {
+        self.flush_deletes(false);
+        self.flush_pending_creates(false);
+        self.dispatcher.set_waiting(!self.delete_queue.is_empty() || !self.pending_creates.is_empty());
+    }
+
+    // Applies what the finished tasks changed, called first by every callback.
+    pub fn apply_completions(&mut self) {
+        loop {
+            let completion = match self.dispatcher.receiver.lock().unwrap().try_recv() {
+                Ok(completion) => completion,
+                Err(_) => break,
+            };
+            self.apply(completion);
+        }
+
+        self.reconnect_queued();
+    }
+
+    // Called on unmount, no callback comes anymore to apply what the tasks callbacks wait for
+    // send. Gives up on them after `timeout`.
+    pub fn settle(&mut self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        self.apply_completions();
+
+        while self.dispatcher.awaited.get_mut().unwrap().values().any(|waiting| waiting.running > 0) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let completion = match self.dispatcher.receiver.lock().unwrap().recv_timeout(left) {
+                Ok(completion) => completion,
+                Err(_) => {
+                    warn!("provider requests still running at unmount, not waiting for them");
+                    return;
+                },
+            };
+            self.apply(completion);
+        }
+    }
+
+    fn apply(&mut self, completion: Completion) {
+        match completion {
+            Completion::Read { fetch, handle, inode, provider_id, object, version, transfer, result } => {
+                self.transfers.finish(transfer, result.as_ref().map_or(0, |data| data.len() as u64));
+                let current = self.dispatcher.finish(handle, fetch);
+
+                match result {
+                    Ok(data) => {
+                        // the handle reads its next ranges from the cache, or from its own
+                        // copy when the file couldn't be cached
+                        let cached = match (&self.content_cache, version) {
+                            (Some(cache), Some(version)) => match cache.put(&provider_id.id, object.as_str(), &version, &data) {
+                                Ok(()) => Some(version),
+                                Err(error) => {
+                                    warn!("unable to cache {}: {}", object.as_str(), error);
+                                    None
+                                },
+                            },
+                            // without a disk cache files small enough stay in memory
+                            (None, Some(version)) if self.blocks.fits(data.len() as u64) => Some(version),
+                            _ => None,
+                        };
+                        // Providers can't be asked for ranges, so the whole file arrives at
+                        // once. Split in chunks, the reads following this one are served from
+                        // memory rather than from the disk.
+                        if let Some(version) = &cached {
+                            if self.blocks.fits(data.len() as u64) {
+                                self.blocks.insert(&provider_id.id, object.as_str(), version, 0, &data, true);
+                            }
+                        }
+                        match (current, cached) {
+                            (false, _) => (),
+                            (true, Some(version)) => self.handles.set_version(handle, version),
+                            (true, None) => self.handles.set_snapshot(handle, data),
+                        }
+                    },
+                    Err(error) => {
+                        self.fail("read", inode, &provider_id, error.message);
+                        if error.auth {
+                            self.require_reauth(&provider_id);
+                        }
+                    },
+                }
+            },
+            Completion::Failed { operation, inode, provider_id, error } => self.fail(operation, inode, &provider_id, error),
+            Completion::Pinned { inode, provider_id, object, version, transfer, result } => self.apply_pinned(inode, &provider_id, &object, &version, transfer, result),
+            Completion::Metadata { inode, provider_id, result } => self.apply_metadata(inode, &provider_id, result),
+            Completion::Crawled { roots, listings } => self.apply_crawl(roots, listings),
+            Completion::Changed { inode, files } => self.apply_change(inode, files),
+            Completion::Listed { inode, provider_id, previous, result } => self.apply_listed(inode, &provider_id, previous, result),
+            Completion::Fetched { operation, inode, provider_id, result } => self.apply_fetched(operation, inode, &provider_id, result),
+            Completion::Created { creations } => self.apply_created(creations),
+            Completion::Deleted { provider_id, deletes, results } => self.apply_deleted(&provider_id, deletes, results),
+            Completion::Flushed { flush, result } => self.apply_flushed(flush, result),
+            Completion::Renamed { inode, provider_id, parent, name, newname, renamed, result, reply } => self.apply_renamed(inode, &provider_id, parent, &name, &newname, renamed, result, reply),
+            Completion::FolderCreated { parent, provider_id, id, name, result, reply } => self.apply_folder_created(parent, &provider_id, id, &name, result, reply),
+            Completion::Linked { parent, provider_id, name, result, reply } => self.apply_linked(parent, &provider_id, name, result, reply),
+            Completion::Replayed { provider_id, deferred, result } => self.apply_replayed(&provider_id, deferred, result),
+            Completion::TokenRefreshed { provider_id, credentials, providers } => self.apply_refreshed_token(&provider_id, credentials, providers),
+            Completion::Probed { provider_id, providers } => self.apply_probed(&provider_id, providers),
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,186 @@
+use std::fmt::Debug;
+
+use libc::{c_int, EACCES, EEXIST, EFBIG, EIO, ENAMETOOLONG, ENETUNREACH, ENOENT, ENOSPC};
+
+// crossroads doesn't give us typed errors, providers surface the HTTP status or the OAuth
+// error code in their message so that's what we look for. Statuses only count where the
+// message says they are one, see `has_status`.
+const AUTH_STATUSES: &[u16] = &[401];
+const AUTH_ERROR_MARKERS: &[&str] = &[
+    "unauthorized",
+    "unauthenticated",
+    "invalid_grant",
+    "invalid_token",
+    "token expired",
+    "token has been expired",
+];
+
+// Objects deleted from elsewhere, e.g. OneDrive's itemNotFound.
+const NOT_FOUND_STATUSES: &[u16] = &[404];
+const NOT_FOUND_MARKERS: &[&str] = &[
+    "not found",
+    "notfound",
+    "no such file",
+];
+
+// The request never got an answer, e.g. reqwest's connection and timeout errors.
+const NETWORK_ERROR_MARKERS: &[&str] = &[
+    "error trying to connect",
+    "connection refused",
+    "connection reset",
+    "dns error",
+    "timed out",
+    "network is unreachable",
+];
+
+// The credentials are fine but not allowed to do this, e.g. Drive's insufficientPermissions.
+const PERMISSION_STATUSES: &[u16] = &[403];
+const PERMISSION_MARKERS: &[&str] = &[
+    "forbidden",
+    "permission denied",
+    "insufficientpermissions",
+    "accessdenied",
+    "access denied",
+];
+
+// The account ran out of storage, e.g. Drive's storageQuotaExceeded or OneDrive's
+// quotaLimitReached.
+const NO_SPACE_STATUSES: &[u16] = &[507];
+const NO_SPACE_MARKERS: &[&str] = &[
+    "insufficient storage",
+    "storagequotaexceeded",
+    "quotalimitreached",
+    "quota exceeded",
+    "no space left",
+];
+
+// Another object already has the name, e.g. OneDrive's nameAlreadyExists.
+const EXISTS_STATUSES: &[u16] = &[409];
+const EXISTS_MARKERS: &[&str] = &[
+    "already exists",
+    "alreadyexists",
+    "conflict",
+];
+
+const TOO_LARGE_STATUSES: &[u16] = &[413];
+const TOO_LARGE_MARKERS: &[&str] = &[
+    "too large",
+    "maxfilesizeexceeded",
+];
+
+const NAME_TOO_LONG_MARKERS: &[&str] = &[
+    "name too long",
+    "pathtoolong",
+    "path is too long",
+];
+
+// The provider is overloaded or rate limiting, the same request may go through later.
+const TRANSIENT_STATUSES: &[u16] = &[429, 500, 502, 503, 504];
+const TRANSIENT_ERROR_MARKERS: &[&str] = &[
+    "too many requests",
+    "ratelimitexceeded",
+    "backenderror",
+    "service unavailable",
+];
+
+pub fn is_auth_error<E: Debug>(error: &E) -> bool {
+    let message = format!("{:?}", error).to_lowercase();
+
+    matches(&message, AUTH_STATUSES, AUTH_ERROR_MARKERS)
+}
+
+pub fn is_not_found_error<E: Debug>(error: &E) -> bool {
+    let message = format!("{:?}", error).to_lowercase();
+
+    matches(&message, NOT_FOUND_STATUSES, NOT_FOUND_MARKERS)
+}
+
+pub fn is_network_error<E: Debug>(error: &E) -> bool {
+    let message = format!("{:?}", error).to_lowercase();
+
+    has_marker(&message, NETWORK_ERROR_MARKERS)
+}
+
+pub fn is_transient_error<E: Debug>(error: &E) -> bool {
+    let message = format!("{:?}", error).to_lowercase();
+
+    matches(&message, TRANSIENT_STATUSES, TRANSIENT_ERROR_MARKERS)
+}
+
+fn matches(message: &str, statuses: &[u16], markers: &[&str]) -> bool {
+    has_status(message, statuses) || has_marker(message, markers)
+}
+
+fn has_marker(message: &str, markers: &[&str]) -> bool {
+    markers.iter().any(|marker| message.contains(marker))
+}
+
+// A status written as one, e.g. reqwest's `Status(404)`, `status: 404`, `HTTP 404` or the `"code": 404` of
+// Google's JSON errors, escaped or not. The same digits in a file name, an id or a size are not.
+fn has_status(message: &str, statuses: &[u16]) -> bool {
+    statuses.iter().any(|status| {
+        let status = status.to_string();
+        message.match_indices(&status).any(|(at, _)| {
+            if message[at + status.len()..].starts_with(|c: char| c.is_ascii_alphanumeric()) {
+                return false;
+            }
+            let before = message[..at].trim_end_matches([' ', ':', '=', '(', '"', '\\']);
+            before.ends_with("status") || before.ends_with("code") || before.ends_with("http")
+        })
+    })
+}
+
+// What the kernel is told when a provider refuses a request, EIO when nothing in the message
+// says more.
+pub fn errno<E: Debug>(error: &E) -> c_int {
+    let message = format!("{:?}", error).to_lowercase();
+
+    if matches(&message, AUTH_STATUSES, AUTH_ERROR_MARKERS) || matches(&message, PERMISSION_STATUSES, PERMISSION_MARKERS) {
+        EACCES
+    } else if matches(&message, NO_SPACE_STATUSES, NO_SPACE_MARKERS) {
+        ENOSPC
+    } else if matches(&message, NOT_FOUND_STATUSES, NOT_FOUND_MARKERS) {
+        ENOENT
+    } else if matches(&message, EXISTS_STATUSES, EXISTS_MARKERS) {
+        EEXIST
+    } else if matches(&message, TOO_LARGE_STATUSES, TOO_LARGE_MARKERS) {
+        EFBIG
+    } else if has_marker(&message, NAME_TOO_LONG_MARKERS) {
+        ENAMETOOLONG
+    } else if has_marker(&message, NETWORK_ERROR_MARKERS) {
+        ENETUNREACH
+    } else {
+        EIO
+    }
+}
+
+#[cfg(test)]
+mod errors_test {
+    use super::*;
+
+    #[test]
+    fn errno_from_statuses() {
+        assert_eq!(errno(&"Status(401)"), EACCES);
+        assert_eq!(errno(&"status: 403"), EACCES);
+        assert_eq!(errno(&"HTTP 404"), ENOENT);
+        assert_eq!(errno(&r#"{"error": {"code": 409, "message": "duplicate"}}"#), EEXIST);
+        assert_eq!(errno(&"Status(413)"), EFBIG);
+        assert_eq!(errno(&"Status(507)"), ENOSPC);
+    }
+
+    #[test]
+    fn errno_from_markers() {
+        assert_eq!(errno(&"invalid_grant"), EACCES);
+        assert_eq!(errno(&"storageQuotaExceeded"), ENOSPC);
+        assert_eq!(errno(&"itemNotFound"), ENOENT);
+        assert_eq!(errno(&"nameAlreadyExists"), EEXIST);
+        assert_eq!(errno(&"pathTooLong"), ENAMETOOLONG);
+        assert_eq!(errno(&"error trying to connect: dns error"), ENETUNREACH);
+    }
+
+    #[test]
+    fn errno_ignores_numbers_that_are_not_statuses() {
+        assert_eq!(errno(&"unable to upload invoice-404.pdf"), EIO);
+        assert_eq!(errno(&"size 4013 doesn't match"), EIO);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,51 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use chrono::{DateTime, Local};
+use tracing::warn;
+
+// Only the latest failures are kept, enough to explain the EIO an application just got.
+const MAX_FAILURES: usize = 100;
+
+pub struct Failure {
+    pub at: SystemTime,
+    pub operation: &'static str,
+    pub path: PathBuf,
+    pub provider: String,
+    pub error: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} [{}]: {}",
+            DateTime::<Local>::from(self.at).format("%Y-%m-%d %H:%M:%S"),
+            self.operation,
+            self.path.display(),
+            self.provider,
+            self.error,
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct Failures {
+    entries: VecDeque<Failure>,
+}
+
+impl Failures {
+    pub fn record(&mut self, failure: Failure) {
+        warn!("{} on {} failed: {}", failure.operation, failure.path.display(), failure.error);
+
+        if self.entries.len() == MAX_FAILURES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(failure);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Failure> {
+        self.entries.iter()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,77 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+use crossroads::storage::ProviderId;
+use tracing::warn;
+
+// `rm -rf` reaches us as one unlink/rmdir per entry, deletions are counted over this window
+// to recognize a recursive delete.
+const WINDOW: Duration = Duration::from_secs(60);
+// How long an approval written to the control file lasts.
+const APPROVAL_DURATION: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Default)]
+pub struct DeletionGuard {
+    max_files: Option<usize>,
+    max_bytes: Option<u64>,
+    recent: HashMap<ProviderId, VecDeque<(SystemTime, u64)>>,
+    approved: HashMap<ProviderId, SystemTime>,
+}
+
+impl DeletionGuard {
+    pub fn new(max_files: Option<usize>, max_bytes: Option<u64>) -> Self {
+        Self {
+            max_files,
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    // Records a deletion and tells whether it may go through.
+    pub fn allow(&mut self, provider_id: &ProviderId, size: u64) -> bool {
+        if self.max_files.is_none() && self.max_bytes.is_none() {
+            return true;
+        }
+
+        let now = SystemTime::now();
+
+        if let Some(expires_at) = self.approved.get(provider_id) {
+            if *expires_at > now {
+                return true;
+            }
+            self.approved.remove(provider_id);
+        }
+
+        let recent = self.recent.entry(provider_id.clone()).or_default();
+        while let Some((deleted_at, _)) = recent.front() {
+            if *deleted_at + WINDOW > now {
+                break;
+            }
+            recent.pop_front();
+        }
+
+        let files = recent.len() + 1;
+        let bytes = recent.iter().map(|(_, size)| size).sum::<u64>() + size;
+
+        if self.max_files.map_or(false, |max| files > max) || self.max_bytes.map_or(false, |max| bytes > max) {
+            warn!("refusing to delete more than {} files / {} bytes from {} without approval", files - 1, bytes - size, provider_id.id);
+            return false;
+        }
+
+        recent.push_back((now, size));
+        true
+    }
+
+    pub fn approve(&mut self, provider_id: ProviderId) {
+        self.recent.remove(&provider_id);
+        self.approved.insert(provider_id, SystemTime::now() + APPROVAL_DURATION);
+    }
+
+    pub fn approved(&self) -> Vec<(&ProviderId, Duration)> {
+        let now = SystemTime::now();
+
+        self.approved.iter()
+            .filter_map(|(provider_id, expires_at)| Some((provider_id, expires_at.duration_since(now).ok()?)))
+            .collect()
+    }
+}
\ No newline at end of file
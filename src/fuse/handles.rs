@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use libc::{c_int, EBADF};
+
+// A file opened through the mount.
+pub struct OpenFile {
+    pub inode: u64,
+    // as given to open(2)
+    pub flags: i32,
+    // Content the handle reads from, fetched on its first read and kept until the handle is
+    // released so a reader never mixes bytes of two versions of the file.
+    snapshot: Option<Arc<Vec<u8>>>,
+    // Version of the file the handle reads by range from the content cache, in place of a
+    // snapshot.
+    version: Option<String>,
+    // Content written through the handle, uploaded in one request when it is flushed instead
+    // of on every write.
+    buffer: Option<Vec<u8>>,
+    // written since the last upload
+    dirty: bool,
+    // writes and truncates so far, an upload only leaves the handle clean if none came since
+    written: u64,
+    // the provider's revision the buffer started from, see FsNode::revision
+    base: Option<String>,
+    // where the last read ended, a read starting there is sequential
+    read_end: u64,
+}
+
+#[derive(Default)]
+pub struct Handles {
+    next_handle: u64,
+    files: HashMap<u64, OpenFile>,
+}
+
+impl Handles {
+    // Handles start at 1, 0 is what control files and directories get.
+    pub fn open(&mut self, inode: u64, flags: i32) -> u64 {
+        self.next_handle += 1;
+        self.files.insert(self.next_handle, OpenFile { inode, flags, snapshot: None, version: None, buffer: None, dirty: false, written: 0, base: None, read_end: 0 });
+        self.next_handle
+    }
+
+    // A handle the kernel didn't get from open, or got for another file, is refused.
+    pub fn get(&self, handle: u64, inode: u64) -> Result<&OpenFile, c_int> {
+        self.files.get(&handle).filter(|file| file.inode == inode).ok_or(EBADF)
+    }
+
+    pub fn snapshot(&self, handle: u64, inode: u64) -> Option<Arc<Vec<u8>>> {
+        self.get(handle, inode).ok()?.snapshot.clone()
+    }
+
+    pub fn set_snapshot(&mut self, handle: u64, data: Arc<Vec<u8>>) {
+        if let Some(file) = self.files.get_mut(&handle) {
+            file.snapshot = Some(data);
+        }
+    }
+
+    // Tells whether a read follows the previous one, a first read from the start counts.
+    pub fn record_read(&mut self, handle: u64, offset: u64, size: u64) -> bool {
+        match self.files.get_mut(&handle) {
+            Some(file) => {
+                let sequential = file.read_end == offset;
+                file.read_end = offset + size;
+                sequential
+            },
+            None => false,
+        }
+    }
+
+    pub fn version(&self, handle: u64, inode: u64) -> Option<String> {
+        self.get(handle, inode).ok()?.version.clone()
+    }
+
+    pub fn set_version(&mut self, handle: u64, version: String) {
+        if let Some(file) = self.files.get_mut(&handle) {
+            file.version = Some(version);
+        }
+    }
+
+    // A handle writing to the file reads what it wrote, the others keep their version.
+    pub fn invalidate(&mut self, handle: u64) {
+        if let Some(file) = self.files.get_mut(&handle) {
+            file.snapshot = None;
+            file.version = None;
+        }
+    }
+
+    // What a handle wrote, its reads see it before it is uploaded.
+    pub fn buffer(&self, handle: u64, inode: u64) -> Option<&Vec<u8>> {
+        self.get(handle, inode).ok()?.buffer.as_ref()
+    }
+
+    // Starts buffering a handle from the current content of its file, as of `base`.
+    pub fn start_buffer(&mut self, handle: u64, data: Vec<u8>, base: Option<String>) {
+        if let Some(file) = self.files.get_mut(&handle) {
+            file.buffer = Some(data);
+            file.base = base;
+        }
+    }
+
+    pub fn base(&self, handle: u64) -> Option<String> {
+        self.files.get(&handle)?.base.clone()
+    }
+
+    pub fn set_base(&mut self, handle: u64, base: Option<String>) {
+        if let Some(file) = self.files.get_mut(&handle) {
+            file.base = base;
+        }
+    }
+
+    // The journal sent what a handle of the file wrote from `from`, the handles still on that
+    // revision go on from the one the upload left.
+    pub fn rebase(&mut self, inode: u64, from: &Option<String>, to: Option<String>) {
+        for file in self.files.values_mut().filter(|file| file.inode == inode && &file.base == from) {
+            file.base = to.clone();
+        }
+    }
+
+    // Returns the new size of the file.
+    pub fn write(&mut self, handle: u64, offset: usize, data: &[u8]) -> u64 {
+        let file = self.files.get_mut(&handle).unwrap();
+        let buffer = file.buffer.get_or_insert_with(Vec::new);
+        let end = offset + data.len();
+        if buffer.len() < end {
+            buffer.resize(end, 0);
+        }
+        buffer[offset..end].copy_from_slice(data);
+        file.dirty = true;
+        file.written += 1;
+        buffer.len() as u64
+    }
+
+    // Cuts or extends what the handle wrote with zeroes, its buffer must hold the file's
+    // content unless it is emptied.
+    pub fn truncate(&mut self, handle: u64, size: usize) {
+        if let Some(file) = self.files.get_mut(&handle) {
+            file.buffer.get_or_insert_with(Vec::new).resize(size, 0);
+            file.dirty = true;
+            file.written += 1;
+        }
+    }
+
+    // The content to upload and the file it goes to, for handles written since their last upload.
+    pub fn dirty(&self, handle: u64) -> Option<(u64, Vec<u8>)> {
+        let file = self.files.get(&handle).filter(|file| file.dirty)?;
+        Some((file.inode, file.buffer.clone().unwrap_or_default()))
+    }
+
+    pub fn written(&self, handle: u64) -> u64 {
+        self.files.get(&handle).map_or(0, |file| file.written)
+    }
+
+    pub fn is_dirty(&self, handle: u64) -> bool {
+        self.files.get(&handle).map_or(false, |file| file.dirty)
+    }
+
+    pub fn mark_clean(&mut self, handle: u64) {
+        if let Some(file) = self.files.get_mut(&handle) {
+            file.dirty = false;
+        }
+    }
+
+    // A file with writes not uploaded yet, its local attributes are the right ones.
+    pub fn is_written(&self, inode: u64) -> bool {
+        self.files.values().any(|file| file.inode == inode && file.dirty)
+    }
+
+    // Inode and size of the content of the handles written since their last upload.
+    pub fn dirty_sizes(&self) -> Vec<(u64, u64)> {
+        self.files.values().filter(|file| file.dirty).map(|file| (file.inode, file.buffer.as_ref().map_or(0, |buffer| buffer.len() as u64))).collect()
+    }
+
+    pub fn dirty_handles(&self) -> Vec<u64> {
+        self.files.iter().filter(|(_, file)| file.dirty).map(|(handle, _)| *handle).collect()
+    }
+
+    pub fn release(&mut self, handle: u64) -> Option<OpenFile> {
+        self.files.remove(&handle)
+    }
+}
\ No newline at end of file
@@ -0,0 +1,75 @@
+use chrono::Utc;
+use crossroads::storage::{ProviderId, ProviderType};
+use serde::Serialize;
+
+use super::FuseFS;
+use super::breaker;
+use super::tokens::token_expiry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenState {
+    Valid,
+    // past its expiry, the refresh keeps failing
+    Expired,
+    // refused by the provider, a new login is needed
+    Rejected,
+    // the provider authenticates without a token
+    None,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderHealth {
+    pub provider: String,
+    // answers requests, neither offline nor served from the cache after failing
+    pub reachable: bool,
+    pub token: TokenState,
+    // when the credential file tells
+    pub token_expires_at: Option<String>,
+    // operations in the journal waiting to be sent
+    pub queue: usize,
+}
+
+// Made from what the mount already knows, reading it sends no request to the providers.
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    // every provider reachable with a usable token
+    pub healthy: bool,
+    pub sync_paused: bool,
+    pub providers: Vec<ProviderHealth>,
+}
+
+impl FuseFS {
+    pub fn health(&self, uid: u32) -> HealthReport {
+        let providers: Vec<ProviderHealth> = self.tree.providers(uid).iter()
+            .map(|provider| self.provider_health(&provider.lock().unwrap().provider_id))
+            .collect();
+
+        HealthReport {
+            healthy: providers.iter().all(|provider| provider.reachable && matches!(provider.token, TokenState::Valid | TokenState::None)),
+            sync_paused: self.journal.is_paused(),
+            providers,
+        }
+    }
+
+    fn provider_health(&self, provider_id: &ProviderId) -> ProviderHealth {
+        let expires_at = self.credential_paths.get(provider_id).and_then(|path| token_expiry(path));
+        let token = if self.reauth_required.contains(provider_id) {
+            TokenState::Rejected
+        } else if !matches!(provider_id.provider_type, ProviderType::GoogleDrive | ProviderType::OneDrive) {
+            TokenState::None
+        } else if expires_at.map_or(false, |expires_at| expires_at <= Utc::now()) {
+            TokenState::Expired
+        } else {
+            TokenState::Valid
+        };
+
+        ProviderHealth {
+            provider: provider_id.id.clone(),
+            reachable: !self.offline.contains_key(provider_id) && !breaker::is_open(&provider_id.id),
+            token,
+            token_expires_at: expires_at.map(|expires_at| expires_at.to_rfc3339()),
+            queue: self.journal.count(&provider_id.id),
+        }
+    }
+}
\ No newline at end of file
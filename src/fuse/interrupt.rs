@@ -0,0 +1,52 @@
+use std::future::Future;
+use std::time::Duration;
+use libc::{c_int, EINTR};
+use tracing::debug;
+
+// How often the requesting process is checked while a provider request is in flight.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// fuser answers FUSE_INTERRUPT on its own without telling the filesystem, so instead we watch
+// the process that issued the request. When the kernel interrupts a request the signal stays
+// pending on the caller until we reply, which is what we look for.
+pub async fn interruptible<F: Future>(pid: u32, future: F) -> Result<F::Output, c_int> {
+    // requests issued by the kernel itself have no caller to watch
+    if pid == 0 {
+        return Ok(future.await);
+    }
+
+    tokio::select! {
+        output = future => Ok(output),
+        _ = wait_for_interrupt(pid) => {
+            debug!(pid, "interrupted");
+            Err(EINTR)
+        },
+    }
+}
+
+async fn wait_for_interrupt(pid: u32) {
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        if is_interrupted(pid) {
+            return;
+        }
+    }
+}
+
+fn is_interrupted(pid: u32) -> bool {
+    let status = match std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(status) => status,
+        // the caller is gone, nobody is waiting for the answer anymore
+        Err(_) => return true,
+    };
+
+    let interrupting = [libc::SIGINT, libc::SIGTERM, libc::SIGKILL, libc::SIGHUP, libc::SIGQUIT]
+        .iter()
+        .fold(0u64, |mask, signal| mask | 1 << (signal - 1));
+
+    status.lines()
+        .filter(|line| line.starts_with("SigPnd:") || line.starts_with("ShdPnd:"))
+        .filter_map(|line| u64::from_str_radix(line[7..].trim(), 16).ok())
+        .any(|pending| pending & interrupting != 0)
+}
\ No newline at end of file
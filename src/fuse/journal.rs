@@ -0,0 +1,724 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime};
+
+use crossroads::interfaces::filesystem::{FileSystem, FileType, ObjectId};
+use crossroads::storage::ProviderId;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::cache::Sealer;
+use crate::state;
+use super::FuseFS;
+use super::deletes::{send_deletes, QueuedDelete};
+use super::dirty::{upload_checked, Uploaded};
+use super::dispatch::{Completion, Providers, TaskError};
+use super::errors::{is_auth_error, is_network_error, is_transient_error};
+use super::failures::Failure;
+
+// Operations waiting for their provider, kept in the data directory with the content written
+// so they survive a restart.
+const JOURNAL_DIR_NAME: &str = "journal";
+const OPERATIONS_FILE_NAME: &str = "operations.json";
+// Content of the writes given up on or discarded, left for the user to recover.
+const FAILED_DIR_NAME: &str = "failed";
+
+// A failed operation is retried after twice as long each time, up to MAX_BACKOFF.
+const FIRST_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+// Attempts at an operation the provider keeps refusing with e.g. a 503, network errors are
+// retried until it answers.
+const MAX_ATTEMPTS: u32 = 10;
+// How often a worker looks again for a provider it couldn't use.
+const PROVIDER_WAIT: Duration = Duration::from_secs(5);
+
+// Objects are kept by provider name and id as crossroads gives them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Deferred {
+    // The content is in the journal's file named after the entry. `base` is the revision it
+    // was written from, unless it `follows` a write of the same file still being sent.
+    Write {
+        provider: String,
+        id: String,
+        path: PathBuf,
+        // where the conflicted copy goes
+        #[serde(default)]
+        parent: String,
+        #[serde(default)]
+        name: String,
+        #[serde(default)]
+        base: Option<String>,
+        #[serde(default)]
+        follows: bool,
+    },
+    Rename { provider: String, id: String, folder: bool, name: Option<String>, parent: Option<String>, path: PathBuf },
+    Delete { provider: String, id: String, folder: bool, path: PathBuf },
+}
+
+impl Deferred {
+    fn provider(&self) -> &str {
+        match self {
+            Deferred::Write { provider, .. } | Deferred::Rename { provider, .. } | Deferred::Delete { provider, .. } => provider,
+        }
+    }
+
+    fn object(&self) -> (&str, &str) {
+        match self {
+            Deferred::Write { provider, id, .. } | Deferred::Rename { provider, id, .. } | Deferred::Delete { provider, id, .. } => (provider, id),
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            Deferred::Write { path, .. } | Deferred::Rename { path, .. } | Deferred::Delete { path, .. } => path,
+        }
+    }
+
+    pub fn operation(&self) -> &'static str {
+        match self {
+            Deferred::Write { .. } => "write",
+            Deferred::Rename { .. } => "rename",
+            Deferred::Delete { .. } => "delete",
+        }
+    }
+
+    fn is_write(&self) -> bool {
+        matches!(self, Deferred::Write { .. })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    seq: u64,
+    deferred: Deferred,
+    #[serde(default)]
+    attempts: u32,
+    #[serde(default)]
+    retry_at: Option<SystemTime>,
+    // being sent, by a worker or by the flush that queued it
+    #[serde(skip)]
+    running: bool,
+    // the content was encrypted with the cache key
+    #[serde(default)]
+    sealed: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Operations {
+    next_seq: u64,
+    entries: Vec<Entry>,
+}
+
+// What a worker does next.
+enum Next {
+    Idle,
+    Wait(Duration),
+    Send(Vec<(Entry, Option<Vec<u8>>)>),
+}
+
+// Every upload is saved here before it is sent, and stays until the provider took it, so
+// neither an outage nor a crash loses what was written. Renames and deletes that couldn't reach
+// their provider wait here too. Each provider's operations are sent in order by a worker of
+// its own, one failing for another reason than the network or an overloaded provider is
+// dropped and reported like any failure.
+#[derive(Clone, Default)]
+pub struct Journal {
+    dir: Option<PathBuf>,
+    operations: Arc<Mutex<Operations>>,
+    // providers whose worker is running
+    workers: Arc<Mutex<HashSet<String>>>,
+    // the workers leave every operation waiting until resumed
+    paused: Arc<AtomicBool>,
+    // set with --encrypt-cache, the content written is encrypted like the cache
+    sealer: Option<Sealer>,
+}
+
+impl Journal {
+    pub fn load() -> Self {
+        let dir = match state::data_dir() {
+            Some(dir) => dir.join(JOURNAL_DIR_NAME),
+            None => return Self::default(),
+        };
+
+        let operations = match fs::read_to_string(dir.join(OPERATIONS_FILE_NAME)) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|error| {
+                warn!("ignoring the journal in {}: {}", dir.display(), error);
+                Operations::default()
+            }),
+            Err(_) => Operations::default(),
+        };
+        if !operations.entries.is_empty() {
+            info!("{} operations waiting for their provider", operations.entries.len());
+        }
+
+        Self {
+            dir: Some(dir),
+            operations: Arc::new(Mutex::new(operations)),
+            workers: Arc::default(),
+            paused: Arc::default(),
+            sealer: None,
+        }
+    }
+
+    // Before the workers start, the clones they get have it too.
+    pub fn set_sealer(&mut self, sealer: Option<Sealer>) {
+        self.sealer = sealer;
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn count(&self, provider: &str) -> usize {
+        self.operations.lock().unwrap().entries.iter().filter(|entry| entry.deferred.provider() == provider).count()
+    }
+
+    // Size of the content the writes waiting for a provider hold, as kept on disk.
+    pub fn pending_bytes(&self, provider: &str) -> u64 {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return 0,
+        };
+        self.operations.lock().unwrap().entries.iter()
+            .filter(|entry| entry.deferred.is_write() && entry.deferred.provider() == provider)
+            .filter_map(|entry| fs::metadata(dir.join(entry.seq.to_string())).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    // A provider's next operations go behind the ones already waiting.
+    pub fn is_queued(&self, provider: &str) -> bool {
+        self.count(provider) > 0
+    }
+
+    fn is_busy(&self) -> bool {
+        let now = SystemTime::now();
+        self.operations.lock().unwrap().entries.iter().any(|entry| entry.running || entry.retry_at.map_or(true, |retry_at| retry_at <= now))
+    }
+
+    fn providers(&self) -> HashSet<String> {
+        self.operations.lock().unwrap().entries.iter().map(|entry| entry.deferred.provider().to_string()).collect()
+    }
+
+    // Only the last content written to an object is worth sending, and nothing once the object
+    // is deleted. A `running` entry is sent by the caller. Returns the entry's number.
+    pub fn push(&self, mut deferred: Deferred, content: Option<&[u8]>, running: bool) -> io::Result<u64> {
+        let dir = self.dir.clone().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no data directory"))?;
+        fs::create_dir_all(&dir)?;
+        let mut operations = self.operations.lock().unwrap();
+
+        let earlier: Vec<Entry> = operations.entries.iter()
+            .filter(|entry| entry.deferred.is_write() && entry.deferred.object() == deferred.object())
+            .cloned()
+            .collect();
+        for entry in earlier {
+            if let (Deferred::Write { base, follows, .. }, Deferred::Write { base: new_base, follows: new_follows, .. }) = (&entry.deferred, &mut deferred) {
+                match entry.running {
+                    // its revision is only known once it is sent
+                    true => *new_follows = true,
+                    // never sent, the revision it was written from is this one's too
+                    false => {
+                        *new_base = base.clone();
+                        *new_follows = *follows;
+                    },
+                }
+            }
+            if !entry.running && !matches!(deferred, Deferred::Rename { .. }) {
+                operations.entries.retain(|other| other.seq != entry.seq);
+                let _ = fs::remove_file(dir.join(entry.seq.to_string()));
+            }
+        }
+
+        let seq = operations.next_seq;
+        operations.next_seq += 1;
+        if let Some(content) = content {
+            match &self.sealer {
+                Some(sealer) => state::write_private(&dir.join(seq.to_string()), &sealer.seal(content)?)?,
+                None => state::write_private(&dir.join(seq.to_string()), content)?,
+            }
+        }
+        let sealed = content.is_some() && self.sealer.is_some();
+        operations.entries.push(Entry { seq, deferred, attempts: 0, retry_at: None, running, sealed });
+        self.save(&operations)?;
+        Ok(seq)
+    }
+
+    // Forgets a sent entry. The writes following it are checked against the revision it left.
+    pub fn finish(&self, seq: u64, revision: Option<String>) {
+        let mut operations = self.operations.lock().unwrap();
+        let finished = match operations.entries.iter().position(|entry| entry.seq == seq) {
+            Some(index) => operations.entries.remove(index),
+            None => return,
+        };
+        if let Some(dir) = &self.dir {
+            let _ = fs::remove_file(dir.join(seq.to_string()));
+        }
+
+        for entry in operations.entries.iter_mut().filter(|entry| entry.deferred.object() == finished.deferred.object()) {
+            if let Deferred::Write { base, follows, .. } = &mut entry.deferred {
+                if *follows && finished.deferred.is_write() {
+                    *base = revision.clone();
+                    *follows = false;
+                }
+            }
+        }
+
+        if let Err(error) = self.save(&operations) {
+            warn!("unable to save the journal: {}", error);
+        }
+    }
+
+    // Schedules another attempt, unless `limited` ones ran out. Tells whether there will be one.
+    pub fn retry(&self, seq: u64, limited: bool) -> bool {
+        let mut operations = self.operations.lock().unwrap();
+        let entry = match operations.entries.iter_mut().find(|entry| entry.seq == seq) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if limited && entry.attempts + 1 >= MAX_ATTEMPTS {
+            return false;
+        }
+
+        let backoff = std::cmp::min(FIRST_BACKOFF * 2u32.saturating_pow(entry.attempts), MAX_BACKOFF);
+        entry.attempts += 1;
+        entry.retry_at = Some(SystemTime::now() + backoff);
+        entry.running = false;
+
+        if let Err(error) = self.save(&operations) {
+            warn!("unable to save the journal: {}", error);
+        }
+        true
+    }
+
+    // Gives entries taken by a worker back without counting an attempt.
+    fn postpone(&self, seqs: &[u64], delay: Duration) {
+        let mut operations = self.operations.lock().unwrap();
+        for entry in operations.entries.iter_mut().filter(|entry| seqs.contains(&entry.seq)) {
+            entry.running = false;
+            entry.retry_at = Some(SystemTime::now() + delay);
+        }
+    }
+
+    // The provider's first operation when it is due, with the writes of other files following it
+    // as they can be sent together.
+    fn next(&self, provider: &str, parallelism: usize) -> Next {
+        let mut operations = self.operations.lock().unwrap();
+        let mut queued = operations.entries.iter_mut().filter(|entry| entry.deferred.provider() == provider).peekable();
+
+        let first = match queued.peek() {
+            Some(first) => first,
+            None => {
+                // a push coming after this sees no worker and starts one
+                self.workers.lock().unwrap().remove(provider);
+                return Next::Idle;
+            },
+        };
+        if first.running || self.is_paused() {
+            return Next::Wait(PROVIDER_WAIT);
+        }
+        if let Some(wait) = first.retry_at.and_then(|retry_at| retry_at.duration_since(SystemTime::now()).ok()) {
+            return Next::Wait(wait);
+        }
+
+        let mut batch: Vec<Entry> = Vec::new();
+        for entry in queued {
+            let together = batch.is_empty() || (entry.deferred.is_write() && batch[0].deferred.is_write());
+            if !together || entry.running || batch.len() >= parallelism || batch.iter().any(|other| other.deferred.object() == entry.deferred.object()) {
+                break;
+            }
+            entry.running = true;
+            batch.push(entry.clone());
+        }
+        drop(operations);
+
+        Next::Send(batch.into_iter().map(|entry| {
+            let content = match entry.deferred.is_write() {
+                true => self.content(&entry).ok(),
+                false => None,
+            };
+            (entry, content)
+        }).collect())
+    }
+
+    // Tells whether the caller is the one to start the provider's worker.
+    fn claim(&self, provider: &str) -> bool {
+        self.workers.lock().unwrap().insert(provider.to_string())
+    }
+
+    fn content(&self, entry: &Entry) -> io::Result<Vec<u8>> {
+        let content = fs::read(self.dir.as_ref().ok_or(io::ErrorKind::NotFound)?.join(entry.seq.to_string()))?;
+        match (entry.sealed, &self.sealer) {
+            (false, _) => Ok(content),
+            (true, Some(sealer)) => sealer.open(&content),
+            (true, None) => Err(io::Error::new(io::ErrorKind::PermissionDenied, "encrypted, mount with --encrypt-cache to send it")),
+        }
+    }
+
+    // Forgets an entry given up on, what it wrote is moved aside rather than deleted. Tells
+    // where the content went.
+    pub fn give_up(&self, seq: u64) -> Option<PathBuf> {
+        let mut operations = self.operations.lock().unwrap();
+        let index = operations.entries.iter().position(|entry| entry.seq == seq)?;
+        let entry = operations.entries.remove(index);
+        let kept = self.keep_content(&entry);
+
+        // nothing is known of the revision the next writes of the file start from
+        for other in operations.entries.iter_mut().filter(|other| other.deferred.object() == entry.deferred.object()) {
+            if let Deferred::Write { follows, .. } = &mut other.deferred {
+                *follows = false;
+            }
+        }
+        if let Err(error) = self.save(&operations) {
+            warn!("unable to save the journal: {}", error);
+        }
+        kept
+    }
+
+    // Drops the operations of a provider on the user's request, the content of its writes is
+    // kept aside. Tells how many there were.
+    pub fn discard(&self, provider: &str) -> usize {
+        let mut operations = self.operations.lock().unwrap();
+        let (discarded, kept): (Vec<Entry>, Vec<Entry>) = std::mem::take(&mut operations.entries).into_iter().partition(|entry| entry.deferred.provider() == provider);
+        operations.entries = kept;
+        for entry in &discarded {
+            if let Some(path) = self.keep_content(entry) {
+                info!("{} of {} discarded, its content is in {}", entry.deferred.operation(), entry.deferred.path().display(), path.display());
+            }
+        }
+        if let Err(error) = self.save(&operations) {
+            warn!("unable to save the journal: {}", error);
+        }
+        discarded.len()
+    }
+
+    // `failed/<seq>-<name>` next to the journal, the name tells which file it was. It is kept
+    // decrypted for the user to take back, readable by them only.
+    fn keep_content(&self, entry: &Entry) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        let content = dir.join(entry.seq.to_string());
+        if !entry.deferred.is_write() || !content.exists() {
+            return None;
+        }
+
+        let name = match &entry.deferred {
+            Deferred::Write { name, .. } if !name.is_empty() => name.clone(),
+            deferred => deferred.path().file_name().map_or_else(String::new, |name| name.to_string_lossy().to_string()),
+        };
+        let kept = dir.join(FAILED_DIR_NAME).join(format!("{}-{}", entry.seq, name));
+        let moved = fs::create_dir_all(dir.join(FAILED_DIR_NAME)).and_then(|_| match entry.sealed {
+            true => state::write_private(&kept, &self.content(entry)?).and_then(|_| fs::remove_file(&content)),
+            false => fs::rename(&content, &kept),
+        });
+        match moved {
+            Ok(()) => Some(kept),
+            Err(error) => {
+                // left where it is rather than lost
+                warn!("unable to move {} to {}: {}", content.display(), kept.display(), error);
+                Some(content)
+            },
+        }
+    }
+
+    fn save(&self, operations: &Operations) -> io::Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        // written aside first, a crash halfway must not lose the journal
+        let path = dir.join(OPERATIONS_FILE_NAME);
+        let partial = path.with_extension("partial");
+        state::write_private(&partial, &serde_json::to_vec(operations)?)?;
+        fs::rename(partial, path)
+    }
+
+    // Waits for the workers to send what they can, what waits for a provider or a retry is sent
+    // on the next mount.
+    pub async fn drain(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, async {
+            while self.is_busy() && !self.is_paused() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }).await;
+    }
+}
+
+impl FuseFS {
+    // Keeps an operation for the provider's worker, the caller answers as if it succeeded.
+    pub fn defer(&mut self, deferred: Deferred, content: Option<&[u8]>) -> Result<(), String> {
+        info!("{} of {} left to the journal", deferred.operation(), deferred.path().display());
+        let provider = deferred.provider().to_string();
+        self.journal.push(deferred, content, false).map_err(|error| format!("unable to journal: {}", error))?;
+        self.start_worker(&provider);
+        Ok(())
+    }
+
+    // Called on init for what the previous mounts left. The operations of providers not mounted
+    // this time wait until they are, or until discarded through the sync control file.
+    pub fn start_workers(&mut self) {
+        for provider in self.journal.providers() {
+            match self.capabilities.keys().any(|provider_id| provider_id.id == provider) {
+                true => self.start_worker(&provider),
+                false => warn!("provider {} isn't mounted, its {} journaled operations wait until it is", provider, self.journal.count(&provider)),
+            }
+        }
+    }
+
+    pub fn start_worker(&mut self, provider: &str) {
+        let provider_id = match self.capabilities.keys().find(|provider_id| provider_id.id == provider) {
+            Some(provider_id) => Arc::new(provider_id.clone()),
+            None => return,
+        };
+        if !self.journal.claim(provider) {
+            return;
+        }
+
+        let worker = Worker {
+            journal: self.journal.clone(),
+            providers: self.providers.clone(),
+            permanent_delete: self.permanent_delete(&provider_id),
+            parallelism: self.upload_parallelism,
+            sender: self.dispatcher.sender(),
+            provider_id,
+        };
+        self.runtime().spawn(worker.run());
+    }
+
+    // Workers can't register a provider that went offline, the next callbacks try to.
+    pub fn reconnect_queued(&mut self) {
+        let offline: Vec<ProviderId> = self.offline.keys().filter(|provider_id| self.journal.is_queued(&provider_id.id)).cloned().collect();
+        for provider_id in offline {
+            let _ = self.check_provider(&provider_id);
+        }
+    }
+
+    pub fn apply_replayed(&mut self, provider_id: &ProviderId, deferred: Deferred, result: Result<Uploaded, TaskError>) {
+        let error = match (result, &deferred) {
+            (Ok(uploaded), Deferred::Write { id, base, .. }) => {
+                let node = match self.tree.find_with_ids(ObjectId::new(id.clone(), FileType::File), provider_id.clone()) {
+                    Some(node) => node,
+                    None => return,
+                };
+                let mut node = node.lock().unwrap();
+                if let Ok(revision) = self.uploaded(&mut node, 0, base.clone(), Ok(uploaded)) {
+                    self.handles.rebase(node.inode, base, revision);
+                }
+                return;
+            },
+            (Ok(_), _) => return,
+            (Err(error), _) => error,
+        };
+
+        if error.auth {
+            self.require_reauth(provider_id);
+        }
+        self.failures.record(Failure {
+            at: SystemTime::now(),
+            operation: deferred.operation(),
+            path: deferred.path().clone(),
+            provider: provider_id.id.clone(),
+            error: error.message,
+        });
+    }
+}
+
+// Sends a provider's operations in the background, for as long as there are some.
+struct Worker {
+    journal: Journal,
+    providers: Providers,
+    provider_id: Arc<ProviderId>,
+    permanent_delete: bool,
+    parallelism: usize,
+    sender: Sender<Completion>,
+}
+
+impl Worker {
+    async fn run(self) {
+        loop {
+            let batch = match self.journal.next(&self.provider_id.id, self.parallelism) {
+                Next::Idle => return,
+                Next::Wait(delay) => {
+                    tokio::time::sleep(delay).await;
+                    continue;
+                },
+                Next::Send(batch) => batch,
+            };
+
+            let results = match self.send(&batch).await {
+                Some(results) => results,
+                None => {
+                    let seqs: Vec<u64> = batch.iter().map(|(entry, _)| entry.seq).collect();
+                    self.journal.postpone(&seqs, PROVIDER_WAIT);
+                    continue;
+                },
+            };
+
+            for ((entry, _), result) in batch.into_iter().zip(results) {
+                let result = match result {
+                    Ok(uploaded) => {
+                        // after a conflicted copy the next writes still start from the same revision
+                        let revision = match (&uploaded.copy, &entry.deferred) {
+                            (Some(_), Deferred::Write { base, .. }) => base.clone(),
+                            _ => uploaded.revision.clone(),
+                        };
+                        self.journal.finish(entry.seq, revision);
+                        Ok(uploaded)
+                    },
+                    // reported once, the operation waits for a new login
+                    Err(error) if is_auth_error(&error) => {
+                        self.journal.retry(entry.seq, false);
+                        Err(TaskError { message: error, auth: true })
+                    },
+                    Err(error) if is_network_error(&error) && self.journal.retry(entry.seq, false) => continue,
+                    Err(error) if is_transient_error(&error) && self.journal.retry(entry.seq, true) => continue,
+                    Err(error) => {
+                        let message = match self.journal.give_up(entry.seq) {
+                            Some(kept) => format!("{}, the content written is kept in {}", error, kept.display()),
+                            None => error,
+                        };
+                        Err(TaskError { message, auth: false })
+                    },
+                };
+                let _ = self.sender.send(Completion::Replayed { provider_id: self.provider_id.clone(), deferred: entry.deferred, result });
+            }
+        }
+    }
+
+    // None while the provider can't be used, e.g. it is offline or being registered again.
+    async fn send(&self, batch: &[(Entry, Option<Vec<u8>>)]) -> Option<Vec<Result<Uploaded, String>>> {
+        let providers = self.providers.get(&self.provider_id)?;
+        let provider = providers.get_provider(self.provider_id.as_ref().clone())?;
+        let filesystem = provider.as_filesystem().unwrap();
+
+        Some(join_all(batch.iter().map(|(entry, content)| self.replay(filesystem, &entry.deferred, content.clone()))).await)
+    }
+
+    async fn replay(&self, filesystem: &dyn FileSystem, deferred: &Deferred, content: Option<Vec<u8>>) -> Result<Uploaded, String> {
+        match deferred {
+            Deferred::Write { id, parent, name, base, .. } => {
+                let content = content.ok_or_else(|| "the journaled content is gone".to_string())?;
+                let id = ObjectId::new(id.clone(), FileType::File);
+                upload_checked(filesystem, &self.provider_id, id, ObjectId::directory(parent.clone()), name, base.clone(), content).await
+            },
+            Deferred::Rename { id, folder, name, parent, .. } => {
+                let mut id = object_id(id, *folder);
+                if let Some(name) = name {
+                    id = filesystem.rename(id.clone(), name.clone()).await.map_err(|error| format!("{:?}", error))?;
+                }
+                if let Some(parent) = parent {
+                    filesystem.move_to(id, ObjectId::directory(parent.clone())).await.map_err(|error| format!("{:?}", error))?;
+                }
+                Ok(Uploaded::default())
+            },
+            Deferred::Delete { id, folder, path, .. } => {
+                let delete = QueuedDelete { id: object_id(id, *folder), path: path.clone() };
+                send_deletes(filesystem, &self.provider_id, self.permanent_delete, &[delete]).await.remove(0)?;
+                Ok(Uploaded::default())
+            },
+        }
+    }
+}
+
+fn object_id(id: &str, folder: bool) -> ObjectId {
+    match folder {
+        true => ObjectId::directory(id.to_string()),
+        false => ObjectId::new(id.to_string(), FileType::File),
+    }
+}
+
+#[cfg(test)]
+mod journal_test {
+    use super::*;
+
+    fn journal(dir: &tempfile::TempDir) -> Journal {
+        Journal { dir: Some(dir.path().to_path_buf()), ..Journal::default() }
+    }
+
+    fn write(id: &str, base: Option<&str>) -> Deferred {
+        Deferred::Write {
+            provider: "gdrive".to_string(),
+            id: id.to_string(),
+            path: PathBuf::from("/gdrive").join(id),
+            parent: "root".to_string(),
+            name: id.to_string(),
+            base: base.map(str::to_string),
+            follows: false,
+        }
+    }
+
+    fn entries(journal: &Journal) -> Vec<Entry> {
+        journal.operations.lock().unwrap().entries.clone()
+    }
+
+    #[test]
+    fn push_replaces_a_write_not_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = journal(&dir);
+
+        let first = journal.push(write("a", Some("r1")), Some(b"one"), false).unwrap();
+        journal.push(write("a", None), Some(b"two"), false).unwrap();
+
+        let entries = entries(&journal);
+        assert_eq!(entries.len(), 1);
+        // written from the revision the first write was
+        assert!(matches!(&entries[0].deferred, Deferred::Write { base: Some(base), follows: false, .. } if base == "r1"));
+        assert_eq!(journal.content(&entries[0]).unwrap(), b"two");
+        assert!(!dir.path().join(first.to_string()).exists());
+    }
+
+    #[test]
+    fn push_follows_a_write_being_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = journal(&dir);
+
+        let sent = journal.push(write("a", Some("r1")), Some(b"one"), true).unwrap();
+        journal.push(write("a", None), Some(b"two"), false).unwrap();
+        journal.push(write("b", None), Some(b"other"), false).unwrap();
+
+        let entries = entries(&journal);
+        assert_eq!(entries.len(), 3);
+        assert!(matches!(&entries[1].deferred, Deferred::Write { follows: true, .. }));
+
+        journal.finish(sent, Some("r2".to_string()));
+        let entries = self::entries(&journal);
+        assert_eq!(entries.len(), 2);
+        assert!(matches!(&entries[0].deferred, Deferred::Write { base: Some(base), follows: false, .. } if base == "r2"));
+        assert!(matches!(&entries[1].deferred, Deferred::Write { base: None, follows: false, .. }));
+        assert!(!dir.path().join(sent.to_string()).exists());
+    }
+
+    #[test]
+    fn delete_drops_the_writes_not_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = journal(&dir);
+
+        journal.push(write("a", None), Some(b"one"), false).unwrap();
+        journal.push(Deferred::Delete { provider: "gdrive".to_string(), id: "a".to_string(), folder: false, path: PathBuf::from("/gdrive/a") }, None, false).unwrap();
+
+        let entries = entries(&journal);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].deferred.operation(), "delete");
+    }
+
+    #[test]
+    fn rename_keeps_the_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = journal(&dir);
+
+        journal.push(write("a", None), Some(b"one"), false).unwrap();
+        journal.push(Deferred::Rename { provider: "gdrive".to_string(), id: "a".to_string(), folder: false, name: Some("b".to_string()), parent: None, path: PathBuf::from("/gdrive/a") }, None, false).unwrap();
+
+        assert_eq!(journal.count("gdrive"), 2);
+        assert_eq!(journal.pending_bytes("gdrive"), 3);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+
+use fuser::FileType;
+
+// A folder's entry as it was when the folder was opened.
+pub struct Entry {
+    pub inode: u64,
+    pub kind: FileType,
+    pub name: String,
+}
+
+struct Listing {
+    inode: u64,
+    entries: Vec<Entry>,
+}
+
+// Entries of opened folders by directory handle. A `readdir` loop goes through what the folder
+// held when it was opened, a listing refreshed meanwhile doesn't make it skip or repeat entries.
+#[derive(Default)]
+pub struct Listings {
+    next_handle: u64,
+    handles: HashMap<u64, Listing>,
+}
+
+impl Listings {
+    // Handles start at 1, 0 is what the root, control and search folders get.
+    pub fn open(&mut self, inode: u64, mut entries: Vec<Entry>) -> u64 {
+        entries.sort_by_key(|entry| entry.inode);
+        self.next_handle += 1;
+        self.handles.insert(self.next_handle, Listing { inode, entries });
+        self.next_handle
+    }
+
+    pub fn get(&self, handle: u64, inode: u64) -> Option<&Vec<Entry>> {
+        self.handles.get(&handle).filter(|listing| listing.inode == inode).map(|listing| &listing.entries)
+    }
+
+    pub fn release(&mut self, handle: u64) {
+        self.handles.remove(&handle);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::config::config_file;
+
+// Entries of `mime-types.toml` in the config directory override the defaults below, e.g.
+//
+//     [uploads]
+//     md = "text/markdown"
+//
+//     [exports]
+//     "application/vnd.google-apps.spreadsheet" = "ods"
+//
+//     [convert]
+//     providers = ["my-drive"]
+//     directories = ["/work-drive/Reports"]
+const MIME_FILE_NAME: &str = "mime-types.toml";
+
+// MIME type sent with new files, by extension.
+const DEFAULT_UPLOADS: &[(&str, &str)] = &[
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("html", "text/html"),
+    ("csv", "text/csv"),
+    ("json", "application/json"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("svg", "image/svg+xml"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("docx", "application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+    ("xlsx", "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+    ("pptx", "application/vnd.openxmlformats-officedocument.presentationml.presentation"),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("odp", "application/vnd.oasis.opendocument.presentation"),
+];
+
+// Native documents have no file content of their own, they are listed with the extension of
+// the format they are exported to.
+const DEFAULT_EXPORTS: &[(&str, &str)] = &[
+    ("application/vnd.google-apps.document", "docx"),
+    ("application/vnd.google-apps.spreadsheet", "xlsx"),
+    ("application/vnd.google-apps.presentation", "pptx"),
+    ("application/vnd.google-apps.drawing", "png"),
+];
+
+// Office files Drive can turn into native documents when they are created with these types.
+const CONVERSIONS: &[(&str, &str)] = &[
+    ("docx", "application/vnd.google-apps.document"),
+    ("xlsx", "application/vnd.google-apps.spreadsheet"),
+    ("pptx", "application/vnd.google-apps.presentation"),
+];
+
+// Where uploaded Office files are converted, providers by id and directories by their path
+// from the mount root.
+#[derive(Debug, Default, Deserialize)]
+struct Conversion {
+    #[serde(default)]
+    providers: Vec<String>,
+    #[serde(default)]
+    directories: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MimeFile {
+    #[serde(default)]
+    uploads: HashMap<String, String>,
+    #[serde(default)]
+    exports: HashMap<String, String>,
+    #[serde(default)]
+    convert: Conversion,
+}
+
+pub struct MimeMap {
+    uploads: HashMap<String, String>,
+    exports: HashMap<String, String>,
+    convert: Conversion,
+}
+
+impl Default for MimeMap {
+    fn default() -> Self {
+        Self {
+            uploads: DEFAULT_UPLOADS.iter().map(|(extension, mime_type)| (extension.to_string(), mime_type.to_string())).collect(),
+            exports: DEFAULT_EXPORTS.iter().map(|(mime_type, extension)| (mime_type.to_string(), extension.to_string())).collect(),
+            convert: Conversion::default(),
+        }
+    }
+}
+
+impl MimeMap {
+    pub fn load() -> Self {
+        let mut map = Self::default();
+
+        let path = match config_file(MIME_FILE_NAME) {
+            Some(path) => path,
+            None => return map,
+        };
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => return map,
+        };
+
+        match toml::from_str::<MimeFile>(&content) {
+            Ok(file) => {
+                map.uploads.extend(file.uploads.into_iter().map(|(extension, mime_type)| (extension.to_lowercase(), mime_type)));
+                map.exports.extend(file.exports);
+                map.convert = file.convert;
+            },
+            Err(error) => warn!("ignoring {}: {}", path.display(), error),
+        }
+
+        map
+    }
+
+    pub fn upload_type(&self, name: &str) -> Option<String> {
+        let extension = Path::new(name).extension()?.to_str()?.to_lowercase();
+        self.uploads.get(&extension).cloned()
+    }
+
+    // Native document type to create instead of an Office file, when conversion is enabled for
+    // the provider or one of the parent directories of `path`.
+    pub fn conversion_type(&self, provider: &str, path: &Path) -> Option<String> {
+        let enabled = self.convert.providers.iter().any(|id| id == provider)
+            || self.convert.directories.iter().any(|directory| path.starts_with(directory));
+        if !enabled {
+            return None;
+        }
+
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        CONVERSIONS.iter().find(|(office, _)| *office == extension).map(|(_, native)| native.to_string())
+    }
+
+    // Name a file is listed under, native documents get their export extension appended.
+    pub fn display_name(&self, name: &str, mime_type: Option<&str>) -> String {
+        match mime_type.and_then(|mime_type| self.exports.get(mime_type)) {
+            Some(extension) if !name.ends_with(&format!(".{}", extension)) => format!("{}.{}", name, extension),
+            _ => name.to_string(),
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,29 @@
+// Permissions shown for the files of cloud providers, they have none of their own. Set with
+// `-o umask=077`, `-o file_mode=0600` or `-o dir_mode=0700` as on other network filesystems.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modes {
+    pub umask: u16,
+    // in place of what the umask leaves of 0666
+    pub file_mode: Option<u16>,
+    // in place of what the umask leaves of 0777
+    pub dir_mode: Option<u16>,
+}
+
+impl Default for Modes {
+    fn default() -> Self {
+        Self {
+            umask: 0o022,
+            file_mode: None,
+            dir_mode: None,
+        }
+    }
+}
+
+impl Modes {
+    pub fn perm(&self, directory: bool) -> u16 {
+        match directory {
+            true => self.dir_mode.unwrap_or(0o777 & !self.umask),
+            false => self.file_mode.unwrap_or(0o666 & !self.umask),
+        }
+    }
+}
\ No newline at end of file
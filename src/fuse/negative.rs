@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+// Past this many entries the expired ones are dropped.
+const PRUNE_THRESHOLD: usize = 4096;
+
+// Names looked up and not found, by parent inode. Shells and editors probe for the same
+// missing `.git` or swap files over and over, each probe would list the folder again once
+// its listing expired.
+pub struct NegativeEntries {
+    ttl: Duration,
+    entries: HashMap<(u64, String), SystemTime>,
+}
+
+impl NegativeEntries {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&self, parent: u64, name: &str) -> bool {
+        self.entries.get(&(parent, name.to_string())).map_or(false, |expire_at| *expire_at > SystemTime::now())
+    }
+
+    pub fn insert(&mut self, parent: u64, name: &str) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        let now = SystemTime::now();
+        if self.entries.len() >= PRUNE_THRESHOLD {
+            self.entries.retain(|_, expire_at| *expire_at > now);
+        }
+        self.entries.insert((parent, name.to_string()), now + self.ttl);
+    }
+
+    // A folder changed from elsewhere may hold the names missing before.
+    pub fn forget(&mut self, parent: u64) {
+        self.entries.retain(|(entry_parent, _), _| *entry_parent != parent);
+    }
+}
\ No newline at end of file
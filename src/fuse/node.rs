@@ -1,26 +1,62 @@
 use std::{ffi::OsStr};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
 use std::time::SystemTime;
-use libc::ENOENT;
-use chrono;
+use libc::{c_int, EAGAIN, ENOENT, EACCES, EBADF, EIO, ENETUNREACH, ENOTSUP, EPERM, EROFS, O_ACCMODE, O_APPEND, O_RDONLY, O_TRUNC};
 
-use fuser::{FileType, FileAttr, ReplyData, ReplyEntry, Request};
-use crossroads::interfaces::filesystem::{ObjectId, File, Metadata as CrossroadsMetadata};
+use fuser::{ReplyData, ReplyEntry};
+use fuser::consts::FOPEN_DIRECT_IO;
+use futures::FutureExt;
+use crossroads::interfaces::filesystem::ObjectId;
+use crossroads::storage::{ProviderId, ProviderType};
+use tracing::debug;
 
-use crate::fstree::FileState;
-use super::{FuseFS, TTL};
+use crate::fstree::{FileState, FsNode, Metadata};
+use crate::telemetry;
+use super::FuseFS;
+use super::interrupt::interruptible;
+use super::errors::{errno, is_auth_error};
+use super::dispatch::{Awaited, Caller, Completion, TaskError};
+use super::control::{is_control_inode, ControlFile};
+use super::dir::parse_info_inode;
+use super::policy::Operation;
+use super::journal::Deferred;
 
 impl FuseFS {
-    pub fn internal_unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-        println!("unlink: {}", name.to_str().unwrap());
+    pub fn internal_unlink(&mut self, req: &Caller, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        debug!(parent, name = ?name, "unlink");
 
         if let Some(node) = self.tree.find_with_name(parent, name.to_str().unwrap()) {
             if let Ok(node) = node.lock() {
-                let provider = self.providers.get_provider(node.provider_id.as_ref().clone()).unwrap();
-                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
-    
-                rt.block_on(async {
-                    provider.as_filesystem().unwrap().delete(node.id.clone()).await.unwrap();
-                });
+                // removed once it exists on its provider, the delete follows the creation
+                if self.dispatcher.is_awaited(Awaited::Created(node.inode)) {
+                    let awaited = Awaited::Created(node.inode);
+                    drop(node);
+                    let (req, name) = (*req, name.to_os_string());
+                    return self.wait(awaited, move |fs, _| fs.internal_unlink(&req, parent, &name, reply));
+                }
+
+                // the delete waits in the journal for a provider that can't be reached
+                match self.check_provider(&node.provider_id) {
+                    Ok(()) | Err(ENETUNREACH) => (),
+                    Err(errno) => return reply.error(errno),
+                }
+
+                let size = node.metadata.map(|metadata| metadata.size).unwrap_or(0);
+                let path = self.tree.path(node.inode).unwrap_or_default();
+                if let Err(errno) = self.check_policy(req, &node.provider_id, Operation::Delete, &path, size) {
+                    return reply.error(errno);
+                }
+
+                if node.provider_id.provider_type != ProviderType::NativeFs && !self.deletion_guard.allow(&node.provider_id, size) {
+                    self.deny(req, Operation::Delete, &path, "deletion guard, approve it in the control directory".to_string());
+                    return reply.error(EPERM);
+                }
+
+                // never made it to the provider
+                if !self.pending_creates.remove(node.inode) {
+                    self.queue_delete(&node.provider_id, node.id.clone(), path, false);
+                }
             }
 
             if let Some(parent_node) = self.tree.find_with_inode(parent) {
@@ -30,86 +66,250 @@ impl FuseFS {
             }
 
             self.tree.remove(parent, node);
+            reply.ok();
+        } else {
+            reply.error(ENOENT);
         }
-
-        reply.ok();
     }
 
     pub fn internal_mknod(
         &mut self,
-        _req: &Request<'_>,
+        req: &Caller,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        _rdev: u32,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
         reply: ReplyEntry,
     ) {
-        println!("mknod: {}", name.to_str().unwrap());
+        debug!(parent, name = ?name, "mknod");
+
+        // touching many files in a row only costs a provider request per batch
+        self.flush_pending_creates(false);
 
         if let Some(parent_dir) = self.tree.find_with_inode(parent) {
             if let Ok(mut parent_dir) = parent_dir.lock() {
-                let provider = self.providers.get_provider(parent_dir.provider_id.as_ref().clone()).unwrap();
-                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
-    
-                rt.block_on(async {
-                    let id = ObjectId::new(parent_dir.id.to_string() + "/" + name.to_str().unwrap(), crossroads::interfaces::filesystem::FileType::File);
-                    provider.as_filesystem().unwrap().create(parent_dir.id.clone(), File {
-                        id: id.clone(),
-                        name: name.to_str().unwrap().to_string(),
-                        metadata: Some(CrossroadsMetadata {
-                            mime_type: None,
-                            created_at: Some(chrono::Utc::now()),
-                            modified_at: Some(chrono::Utc::now()),
-                            meta_changed_at: Some(chrono::Utc::now()),
-                            accessed_at: None,
-                            size: None,
-                            open_path: None,
-                            owner: None,
-                            permissions: None,
-                        }),
-                    }).await.unwrap();
-
-                    let provider_id = parent_dir.provider_id.clone();
-
-                    let new_file = self.tree.new_file(&mut parent_dir, id, name.to_str().unwrap(), None, provider_id);
-
-                    reply.entry(&TTL, &FileAttr {
-                        ino: new_file.lock().unwrap().inode,
-                        size: 0,
-                        blocks: 0,
-                        atime: SystemTime::now(), // 1970-01-01 00:00:00
-                        mtime: SystemTime::now(),
-                        ctime: SystemTime::now(),
-                        crtime: SystemTime::now(),
-                        kind: FileType::RegularFile,
-                        perm: 0o755,
-                        nlink: 0,
-                        uid: 501,
-                        gid: 20,
-                        rdev: 0,
-                        flags: 0,
-                        blksize: 512,                    
-                    }, 0);
-                });
+                if let Err(errno) = self.check_provider(&parent_dir.provider_id) {
+                    return reply.error(errno);
+                }
+
+                let path = self.tree.path(parent).unwrap_or_default().join(name);
+                if let Err(errno) = self.check_policy(req, &parent_dir.provider_id, Operation::Create, &path, 0) {
+                    return reply.error(errno);
+                }
+
+                let mut mime_type = self.mime_map.upload_type(name.to_str().unwrap());
+                if parent_dir.provider_id.provider_type == ProviderType::GoogleDrive {
+                    mime_type = self.mime_map.conversion_type(&parent_dir.provider_id.id, &path).or(mime_type);
+                }
+
+                let id = ObjectId::new(parent_dir.id.to_string() + "/" + name.to_str().unwrap(), crossroads::interfaces::filesystem::FileType::File);
+                let provider_id = parent_dir.provider_id.clone();
+                // an object of the same name still waiting to be deleted must go first
+                if self.delete_queue.contains(&provider_id, &id) {
+                    self.flush_deletes(true);
+                    if self.dispatcher.is_awaited(Awaited::Deletes) {
+                        drop(parent_dir);
+                        let (req, name) = (*req, name.to_os_string());
+                        return self.wait(Awaited::Deletes, move |fs, _| fs.internal_mknod(&req, parent, &name, mode, umask, rdev, reply));
+                    }
+                }
+
+                let now = SystemTime::now();
+                let metadata = Metadata {
+                    size: 0,
+                    blocks: 0,
+                    atime: now,
+                    mtime: now,
+                    ctime: now,
+                    crtime: now,
+                    perm: self.modes.perm(false),
+                    uid: self.uid,
+                    gid: self.gid,
+                    rdev: 0,
+                    blksize: 512,
+                    flags: 0,
+                };
+
+                let node = self.tree.new_file(&mut parent_dir, id, name.to_str().unwrap(), Some(metadata), provider_id.clone());
+                let new_file = node.lock().unwrap();
+                self.pending_creates.insert(new_file.inode, mime_type);
+
+                // local files are as cheap to create right away
+                if provider_id.provider_type == ProviderType::NativeFs {
+                    if let Some(awaited) = self.create_pending(&new_file, parent_dir.id.clone()) {
+                        drop(new_file);
+                        drop(parent_dir);
+                        return self.wait(awaited, move |fs, result| match result {
+                            Ok(()) => fs.reply_entry(reply, &node.lock().unwrap()),
+                            Err(errno) => {
+                                if let Some(parent_dir) = fs.tree.find_with_inode(parent) {
+                                    parent_dir.lock().unwrap().children.retain(|child| !Arc::ptr_eq(child, &node));
+                                }
+                                fs.tree.remove(parent, node);
+                                reply.error(errno)
+                            },
+                        });
+                    }
+                }
+
+                self.reply_entry(reply, &new_file);
             }
         } else {
             reply.error(ENOENT);
         }
     }
     
-    pub fn internal_read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
-        println!("read: {}", ino);
+    pub fn internal_read(&mut self, req: &Caller, ino: u64, fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
+        debug!(ino, offset, size, "read");
+
+        if let Some(control_file) = ControlFile::from_inode(ino) {
+            let data = self.control_content(control_file);
+            let start = std::cmp::min(offset as usize, data.len());
+            return reply.data(&data[start..std::cmp::min(start + size as usize, data.len())]);
+        }
+
+        if let Some((root, Some(info_file))) = parse_info_inode(ino) {
+            let data = match self.info_content(root, info_file) {
+                Some(data) => data,
+                None => return reply.error(ENOENT),
+            };
+            let start = std::cmp::min(offset as usize, data.len());
+            return reply.data(&data[start..std::cmp::min(start + size as usize, data.len())]);
+        }
+
+        if let Err(errno) = self.handles.get(fh, ino) {
+            return reply.error(errno);
+        }
+        let sequential = self.handles.record_read(fh, offset as u64, size as u64);
 
         if let Some(file) = self.tree.find_with_inode(ino) {
             if let Ok(file) = file.lock() {
-                let provider = self.providers.get_provider(file.provider_id.as_ref().clone()).unwrap();
-                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
-    
-                rt.block_on(async {
-                    let data = provider.as_filesystem().unwrap().read_file(file.id.clone()).await.unwrap();
-                    println!("--- read {} offset: {offset}, size: {size} ---", file.id.as_str());
-                    reply.data(&data[offset as usize..std::cmp::min(offset as usize + size as usize, data.len())]);
+                // what is in memory or in the cache is still read from an unreachable provider
+                let reachable = self.check_provider(&file.provider_id);
+
+                if self.pending_creates.contains(ino) {
+                    return reply.data(&[]);
+                }
+
+                if let Some(data) = self.handles.buffer(fh, ino) {
+                    let start = std::cmp::min(offset as usize, data.len());
+                    return reply.data(&data[start..std::cmp::min(start + size as usize, data.len())]);
+                }
+
+                if let Some(data) = self.handles.snapshot(fh, ino) {
+                    let start = std::cmp::min(offset as usize, data.len());
+                    return reply.data(&data[start..std::cmp::min(start + size as usize, data.len())]);
+                }
+
+                // local files are read in place, only the requested range
+                if let Some(root) = self.native_roots.get(&file.provider_id) {
+                    return match read_range(&(root.clone() + file.id.as_str()), offset as u64, size as usize) {
+                        Ok(data) => reply.data(&data),
+                        Err(error) => {
+                            let errno = error.raw_os_error().unwrap_or(EIO);
+                            self.fail("read", ino, &file.provider_id, error.to_string());
+                            reply.error(errno)
+                        },
+                    };
+                }
+
+                // Providers only send whole files, a file is downloaded once per version and
+                // its handles read the requested ranges from the cache. A handle keeps the
+                // version it started with.
+                let version = content_version(&file);
+                if let Some(cached) = self.handles.version(fh, ino).or_else(|| version.clone()) {
+                    if let Some(data) = self.blocks.read(&file.provider_id.id, file.id.as_str(), &cached, offset as u64, size as usize) {
+                        reply.data(&data);
+                        self.cache_counters.content_hits += 1;
+                        if sequential {
+                            self.read_ahead(&file, &cached, offset as u64 + size as u64);
+                        }
+                        self.handles.set_version(fh, cached);
+                        return;
+                    }
+
+                    // whole chunks are read from the disk so the next reads stay in memory
+                    if let Some(cache) = &self.content_cache {
+                        let chunk_size = self.blocks.chunk_size();
+                        let start = offset as u64 / chunk_size * chunk_size;
+                        let length = ((offset as u64 + size as u64 - start + chunk_size - 1) / chunk_size * chunk_size) as usize;
+                        if let Some(chunks) = cache.read_range(&file.provider_id.id, file.id.as_str(), &cached, start, length) {
+                            let from = std::cmp::min((offset as u64 - start) as usize, chunks.len());
+                            reply.data(&chunks[from..std::cmp::min(from + size as usize, chunks.len())]);
+                            self.cache_counters.content_hits += 1;
+                            self.blocks.insert(&file.provider_id.id, file.id.as_str(), &cached, start, &chunks, chunks.len() < length);
+                            if sequential {
+                                self.read_ahead(&file, &cached, offset as u64 + size as u64);
+                            }
+                            self.handles.set_version(fh, cached);
+                            return;
+                        }
+                    }
+                }
+
+                if let Err(errno) = reachable {
+                    return reply.error(errno);
+                }
+
+                // the first read of a handle downloads the file, the next ones wait for it
+                let download = match self.dispatcher.download(fh, ino) {
+                    Some(download) => download,
+                    None => {
+                        self.cache_counters.content_misses += 1;
+                        self.quotas.record(&file.provider_id);
+                        let providers = match self.providers.get(&file.provider_id) {
+                            Some(providers) => providers,
+                            None => return reply.error(ENETUNREACH),
+                        };
+                        let provider_id = file.provider_id.clone();
+                        let object = file.id.clone();
+                        let trace = telemetry::provider_request(&file.provider_id, "read_file", &file.id);
+                        let download = async move {
+                            let provider = providers.get_provider(provider_id.as_ref().clone()).unwrap();
+                            let result = provider.as_filesystem().unwrap().read_file(object).await;
+                            trace.finish(&result, result.as_ref().map_or(0, |data| data.len() as u64));
+                            result.map(Arc::new).map_err(|error| TaskError { auth: is_auth_error(&error), message: format!("{:?}", error) })
+                        }.boxed().shared();
+
+                        let path = self.tree.path(ino).unwrap_or_default().display().to_string();
+                        let transfer = self.transfers.start(path, false, file.metadata.map_or(0, |metadata| metadata.size));
+                        let fetch = self.dispatcher.start_download(fh, ino, download.clone());
+                        let (provider_id, object) = (file.provider_id.clone(), file.id.clone());
+                        self.spawn(download.clone().map(move |result| Some(Completion::Read {
+                            fetch,
+                            handle: fh,
+                            inode: ino,
+                            provider_id,
+                            object,
+                            version,
+                            transfer,
+                            result,
+                        })));
+                        download
+                    },
+                };
+
+                let pid = req.pid();
+                let provider_id = file.provider_id.clone();
+                self.spawn(async move {
+                    match interruptible(pid, download).await {
+                        Ok(Ok(data)) => {
+                            debug!(ino, offset, size, "read answered");
+                            let start = std::cmp::min(offset as usize, data.len());
+                            reply.data(&data[start..std::cmp::min(start + size as usize, data.len())]);
+                            None
+                        },
+                        // recorded once by the task downloading the file
+                        Ok(Err(error)) => {
+                            reply.error(if error.auth { EACCES } else { errno(&error.message) });
+                            None
+                        },
+                        Err(errno) => {
+                            reply.error(errno);
+                            Some(Completion::Failed { operation: "read", inode: ino, provider_id, error: "interrupted".to_string() })
+                        },
+                    }
                 });
             }
         } else {
@@ -119,41 +319,120 @@ impl FuseFS {
 
     pub fn internal_rename(
             &mut self,
-            _req: &Request<'_>,
+            req: &Caller,
             parent: u64,
             name: &OsStr,
             newparent: u64,
             newname: &OsStr,
-            _flags: u32,
+            flags: u32,
             reply: fuser::ReplyEmpty,
         ) {
-        println!("rename: {} -> {}", name.to_str().unwrap(), newname.to_str().unwrap());
+        debug!(parent, name = ?name, newparent, newname = ?newname, "rename");
 
         if let Some(node) = self.tree.find_with_name(parent, name.to_str().unwrap()) {
             if let Ok(mut node) = node.lock() {
-                let provider = self.providers.get_provider(node.provider_id.as_ref().clone()).unwrap();
-                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
-    
-                rt.block_on(async {
-                    let mut object_id = node.id.clone();
-                    if name != newname {
-                        object_id = provider.as_filesystem().unwrap().rename(node.id.clone(), newname.to_str().unwrap().to_string()).await.unwrap();
-                        node.name = newname.to_str().unwrap().to_string();
-                        self.tree.rename(parent, name.to_str().unwrap(), newname.to_str().unwrap());
+                // Drive and OneDrive objects keep their id once renamed, their renames can wait
+                // in the journal
+                let reachable = self.check_provider(&node.provider_id);
+                let deferred = reachable == Err(ENETUNREACH)
+                    && matches!(node.provider_id.provider_type, ProviderType::GoogleDrive | ProviderType::OneDrive)
+                    && !self.pending_creates.contains(node.inode);
+                match reachable {
+                    Err(errno) if !deferred => return reply.error(errno),
+                    _ => (),
+                }
+
+                let capabilities = self.capabilities(&node.provider_id);
+                if (name != newname && !capabilities.rename) || (parent != newparent && !capabilities.move_to) {
+                    return reply.error(ENOTSUP);
+                }
+
+                // the target may be waiting to be deleted
+                self.flush_deletes(true);
+                if self.dispatcher.is_awaited(Awaited::Deletes) {
+                    drop(node);
+                    let (req, name, newname) = (*req, name.to_os_string(), newname.to_os_string());
+                    return self.wait(Awaited::Deletes, move |fs, _| fs.internal_rename(&req, parent, &name, newparent, &newname, flags, reply));
+                }
+
+                // editors write to a temporary file and rename it over the original
+                if self.pending_creates.contains(node.inode) {
+                    let parent_id = self.tree.find_with_inode(parent).map_or_else(ObjectId::root, |parent| parent.lock().unwrap().id.clone());
+                    if let Some(awaited) = self.create_pending(&node, parent_id) {
+                        drop(node);
+                        let (req, name, newname) = (*req, name.to_os_string(), newname.to_os_string());
+                        return self.wait(awaited, move |fs, result| match result {
+                            Ok(()) => fs.internal_rename(&req, parent, &name, newparent, &newname, flags, reply),
+                            Err(errno) => reply.error(errno),
+                        });
+                    }
+                }
+
+                // both ends of a rename are checked, moving a file out of an allowed folder is a write too
+                let from = self.tree.path(node.inode).unwrap_or_default();
+                let to = self.tree.path(newparent).unwrap_or_default().join(newname);
+                for path in [from, to] {
+                    if let Err(errno) = self.check_policy(req, &node.provider_id, Operation::Rename, &path, 0) {
+                        return reply.error(errno);
                     }
+                }
 
-                    if parent != newparent {
-                        let new_parent = self.tree.find_with_inode(newparent).unwrap();
-                        let new_parent = new_parent.lock().unwrap();
-                        object_id = provider.as_filesystem().unwrap().move_to(object_id.clone(), new_parent.id.clone()).await.unwrap();
+                if deferred {
+                    let new_parent = match parent != newparent {
+                        true => self.tree.find_with_inode(newparent).map(|new_parent| new_parent.lock().unwrap().id.as_str().to_string()),
+                        false => None,
+                    };
+                    let deferred = Deferred::Rename {
+                        provider: node.provider_id.id.clone(),
+                        id: node.id.as_str().to_string(),
+                        folder: node.id.is_directory(),
+                        name: (name != newname).then(|| newname.to_str().unwrap().to_string()),
+                        parent: new_parent,
+                        path: self.tree.path(node.inode).unwrap_or_default(),
+                    };
+                    if let Err(error) = self.defer(deferred, None) {
+                        self.fail("rename", node.inode, &node.provider_id, error);
+                        return reply.error(EIO);
                     }
 
-                    node.children = Vec::new();
-                    node.content_state = FileState::ShallowReady;
+                    if name != newname {
+                        node.name = newname.to_str().unwrap().to_string();
+                        self.tree.rename(parent, name.to_str().unwrap(), newname.to_str().unwrap());
+                    }
+                    return reply.ok();
+                }
 
-                    node.id = object_id;
+                let providers = match self.providers.get(&node.provider_id) {
+                    Some(providers) => providers,
+                    None => return reply.error(ENETUNREACH),
+                };
+                let new_parent = match parent != newparent {
+                    true => match self.tree.find_with_inode(newparent) {
+                        Some(new_parent) => Some(new_parent.lock().unwrap().id.clone()),
+                        None => return reply.error(ENOENT),
+                    },
+                    false => None,
+                };
+                self.quotas.record(&node.provider_id);
 
-                    reply.ok();
+                // answered once the node follows the object
+                let (inode, provider_id, id) = (node.inode, node.provider_id.clone(), node.id.clone());
+                let (name, newname) = (name.to_str().unwrap().to_string(), newname.to_str().unwrap().to_string());
+                self.spawn_awaited(&[], async move {
+                    let provider = providers.get_provider(provider_id.as_ref().clone()).unwrap();
+                    let mut renamed = None;
+                    let result = async {
+                        let mut object_id = id;
+                        if name != newname {
+                            object_id = provider.as_filesystem().unwrap().rename(object_id, newname.clone()).await.map_err(|error| format!("{:?}", error))?;
+                            renamed = Some(object_id.clone());
+                        }
+                        if let Some(new_parent) = new_parent {
+                            object_id = provider.as_filesystem().unwrap().move_to(object_id, new_parent).await.map_err(|error| format!("{:?}", error))?;
+                        }
+                        Ok::<ObjectId, String>(object_id)
+                    }.await;
+                    Completion::Renamed { inode, provider_id, parent, name, newname, renamed, result, reply }
                 });
             }
         } else {
@@ -161,47 +440,340 @@ impl FuseFS {
         }
     }
 
-    pub fn internal_open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-        println!("open: {}", _ino);
+    // The node takes the object's new name and id, a rename followed by a failed move leaves
+    // it renamed where it was.
+    pub fn apply_renamed(&mut self, inode: u64, provider_id: &ProviderId, parent: u64, name: &str, newname: &str, renamed: Option<ObjectId>, result: Result<ObjectId, String>, reply: fuser::ReplyEmpty) {
+        if let Some(node) = self.tree.find_with_inode(inode) {
+            let mut node = node.lock().unwrap();
+            if let Some(id) = renamed {
+                node.name = newname.to_string();
+                self.tree.rename(parent, name, newname);
+                node.id = id;
+            }
+            if let Ok(id) = &result {
+                node.children = Vec::new();
+                node.content_state = FileState::ShallowReady;
+                node.id = id.clone();
+            }
+        }
+
+        match result {
+            Ok(_) => reply.ok(),
+            Err(error) => {
+                let errno = self.failed("rename", inode, provider_id, error);
+                reply.error(errno);
+            },
+        }
+    }
+
+    pub fn internal_open(&mut self, req: &Caller, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        debug!(ino, flags, "open");
+
+        // control files have no fixed size, skip the page cache for them
+        if is_control_inode(ino) {
+            return reply.opened(0, FOPEN_DIRECT_IO);
+        }
+
+        if self.read_only && flags & O_ACCMODE != O_RDONLY {
+            return reply.error(EROFS);
+        }
+
+        let handle = self.handles.open(ino, flags);
+
+        // `>` empties the file, the empty content is uploaded when the handle is flushed
+        if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY && !self.pending_creates.contains(ino) {
+            if let Some(file) = self.tree.find_with_inode(ino) {
+                let mut file = file.lock().unwrap();
+                let path = self.tree.path(ino).unwrap_or_default();
+                if let Err(errno) = self.check_policy(req, &file.provider_id, Operation::Write, &path, 0) {
+                    self.handles.release(handle);
+                    return reply.error(errno);
+                }
+
+                if let Some(cache) = &self.content_cache {
+                    cache.remove(&file.provider_id.id, file.id.as_str());
+                }
+                self.blocks.remove(&file.provider_id.id, file.id.as_str());
+                self.handles.start_buffer(handle, Vec::new(), file.revision.clone());
+                self.handles.truncate(handle, 0);
+                if let Err(errno) = self.spill(handle, &file, 0, 0) {
+                    self.handles.release(handle);
+                    self.spills.remove(handle);
+                    return reply.error(errno);
+                }
+                if let Some(metadata) = file.metadata.as_mut() {
+                    metadata.size = 0;
+                    metadata.mtime = SystemTime::now();
+                }
+            }
+        }
+
+        reply.opened(handle, 0)
+    }
+
+    pub fn internal_release(&mut self, _req: &Caller, ino: u64, fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: fuser::ReplyEmpty) {
+        debug!(ino, fh, "release");
 
-        reply.opened(0, 0)
+        if is_control_inode(ino) {
+            return reply.ok();
+        }
+
+        if let Err(errno) = self.handles.get(fh, ino) {
+            return reply.error(errno);
+        }
+
+        // close() already got the outcome of the upload from flush, this is a last attempt
+        self.dispatcher.forget(fh);
+        self.flush_then(fh, move |fs, _| {
+            fs.handles.release(fh);
+            fs.spills.remove(fh);
+        });
+        reply.ok();
     }
 
     pub fn internal_write(
             &mut self,
-            _req: &Request<'_>,
+            req: &Caller,
             ino: u64,
-            _fh: u64,
+            fh: u64,
             offset: i64,
             data: &[u8],
-            _write_flags: u32,
-            _flags: i32,
-            _lock_owner: Option<u64>,
+            write_flags: u32,
+            flags: i32,
+            lock_owner: Option<u64>,
             reply: fuser::ReplyWrite,
         ) {
-        println!("write: {}", ino);
+        debug!(ino, fh, offset, size = data.len(), "write");
+
+        if let Some(control_file) = ControlFile::from_inode(ino) {
+            return match (self.control_write(req, control_file, data), self.dispatcher.take_resolving()) {
+                (Ok(()), _) => reply.written(data.len() as u32),
+                // a folder on the path written is listed, written again once it is
+                (Err(EAGAIN), Some(folder)) => {
+                    let (req, data) = (*req, data.to_vec());
+                    self.wait(Awaited::Listing(folder), move |fs, _| fs.internal_write(&req, ino, fh, offset, &data, write_flags, flags, lock_owner, reply))
+                },
+                (Err(errno), _) => reply.error(errno),
+            };
+        }
+        // the providers folder is read-only
+        if is_control_inode(ino) {
+            return reply.error(EACCES);
+        }
+
+        let append = match self.handles.get(fh, ino) {
+            Ok(handle) if handle.flags & O_ACCMODE == O_RDONLY => return reply.error(EBADF),
+            Ok(handle) => handle.flags & O_APPEND != 0,
+            Err(errno) => return reply.error(errno),
+        };
 
         if let Some(file) = self.tree.find_with_inode(ino) {
             if let Ok(mut file) = file.lock() {
-                let provider = self.providers.get_provider(file.provider_id.as_ref().clone()).unwrap();
-                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
-    
-                rt.block_on(async {
-                    let file_content = provider.as_filesystem().unwrap().read_file(file.id.clone()).await.unwrap();
-                    let content;
-                    if offset > 0 {
-                        content = [&file_content[0..offset as usize], data].concat();
-                    } else {
-                        content = data.to_vec();
+                // written to the buffer all the same, the flush leaves the content to the journal
+                let reachable = self.check_provider(&file.provider_id);
+                match reachable {
+                    Ok(()) | Err(ENETUNREACH) => (),
+                    Err(errno) => return reply.error(errno),
+                }
+
+                let path = self.tree.path(ino).unwrap_or_default();
+                if let Err(errno) = self.check_policy(req, &file.provider_id, Operation::Write, &path, offset as u64 + data.len() as u64) {
+                    return reply.error(errno);
+                }
+
+                let pending = self.pending_creates.contains(ino);
+                if pending {
+                    // the journal has no object to write to yet
+                    if let Err(errno) = reachable {
+                        return reply.error(errno);
                     }
-                    provider.as_filesystem().unwrap().write_file(file.id.clone(), content.into()).await.unwrap();
-                    let metadata = file.metadata.as_mut().unwrap();
-                    metadata.size = data.len() as u64;
-                    reply.written(data.len() as u32);
-                });
+                    // a file just created has nothing to keep
+                    if self.handles.buffer(fh, ino).is_none() {
+                        self.handles.start_buffer(fh, Vec::new(), file.revision.clone());
+                    }
+                    let parent_id = self.parent_id(ino);
+                    if let Some(awaited) = self.create_pending(&file, parent_id) {
+                        drop(file);
+                        let (req, data) = (*req, data.to_vec());
+                        return self.wait(awaited, move |fs, result| match result {
+                            Ok(()) => fs.internal_write(&req, ino, fh, offset, &data, write_flags, flags, lock_owner, reply),
+                            Err(errno) => reply.error(errno),
+                        });
+                    }
+                }
+
+                // the first write through a handle starts from the file's current content
+                if self.handles.buffer(fh, ino).is_none() {
+                    match self.current_content(req, "write", &file, fh, pending) {
+                        Ok(Some(content)) => self.handles.start_buffer(fh, content, file.revision.clone()),
+                        Ok(None) => {
+                            drop(file);
+                            let (req, data) = (*req, data.to_vec());
+                            return self.wait(Awaited::Content(ino), move |fs, result| match result {
+                                Ok(()) => fs.internal_write(&req, ino, fh, offset, &data, write_flags, flags, lock_owner, reply),
+                                Err(errno) => reply.error(errno),
+                            });
+                        },
+                        Err((errno, error)) => {
+                            self.fail("write", ino, &file.provider_id, error);
+                            return reply.error(errno);
+                        },
+                    }
+                }
+
+                // The cached content is outdated whatever the upload's outcome, dropping it once
+                // per upload is enough as the writes in between only reach the buffer.
+                if !self.handles.is_dirty(fh) {
+                    if let Some(cache) = &self.content_cache {
+                        cache.remove(&file.provider_id.id, file.id.as_str());
+                    }
+                    self.blocks.remove(&file.provider_id.id, file.id.as_str());
+                    self.handles.invalidate(fh);
+                    self.dispatcher.forget(fh);
+                }
+
+                // `>>` writes at the end whatever offset the kernel gives
+                let offset = match append {
+                    true => self.handles.buffer(fh, ino).map_or(0, |buffer| buffer.len()),
+                    false => offset as usize,
+                };
+
+                // uploaded on flush, fsync or release
+                let size = self.handles.write(fh, offset, data);
+                if let Err(errno) = self.spill(fh, &file, offset, data.len()) {
+                    return reply.error(errno);
+                }
+                let metadata = file.metadata.as_mut().unwrap();
+                metadata.size = size;
+                metadata.mtime = SystemTime::now();
+                reply.written(data.len() as u32);
             }
         } else {
             reply.error(ENOENT);
         }
     }
+
+    pub fn internal_flush(&mut self, _req: &Caller, ino: u64, fh: u64, _lock_owner: u64, reply: fuser::ReplyEmpty) {
+        debug!(ino, fh, "flush");
+
+        if !is_control_inode(ino) {
+            if let Err(errno) = self.handles.get(fh, ino) {
+                return reply.error(errno);
+            }
+        }
+
+        self.flush_then(fh, move |_, result| match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        });
+    }
+
+    pub fn internal_fsync(&mut self, _req: &Caller, ino: u64, fh: u64, _datasync: bool, reply: fuser::ReplyEmpty) {
+        debug!(ino, fh, "fsync");
+
+        if !is_control_inode(ino) {
+            if let Err(errno) = self.handles.get(fh, ino) {
+                return reply.error(errno);
+            }
+        }
+
+        self.flush_then(fh, move |_, result| match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        });
+    }
+
+    // What a file holds before a handle writes to it: the handle's snapshot, the cached
+    // content or the provider's. None while the provider's is fetched, the callback waits for
+    // Awaited::Content of the file and takes it when run again.
+    pub fn current_content(&mut self, req: &Caller, operation: &'static str, file: &FsNode, fh: u64, pending: bool) -> Result<Option<Vec<u8>>, (c_int, String)> {
+        // a file just created has nothing to keep
+        if pending {
+            return Ok(Some(Vec::new()));
+        }
+
+        if let Some(data) = self.handles.snapshot(fh, file.inode) {
+            return Ok(Some(data.as_ref().clone()));
+        }
+
+        if let (Some(cache), Some(version)) = (&self.content_cache, content_version(file)) {
+            if let Some(data) = cache.get(&file.provider_id.id, file.id.as_str(), &version) {
+                return Ok(Some(data));
+            }
+        }
+
+        if let Some(data) = self.dispatcher.fetched(file.inode) {
+            return Ok(Some(data));
+        }
+        // fetched once for every callback needing it
+        if self.dispatcher.is_awaited(Awaited::Content(file.inode)) {
+            return Ok(None);
+        }
+
+        let unreachable = format!("provider {} is unreachable", file.provider_id.id);
+        self.check_provider(&file.provider_id).map_err(|errno| (errno, unreachable.clone()))?;
+        let providers = self.providers.get(&file.provider_id).ok_or((ENETUNREACH, unreachable))?;
+        self.quotas.record(&file.provider_id);
+
+        let (inode, provider_id, id, pid) = (file.inode, file.provider_id.clone(), file.id.clone(), req.pid());
+        let trace = telemetry::provider_request(&file.provider_id, "read_file", &file.id);
+        self.spawn_awaited(&[Awaited::Content(inode)], async move {
+            let provider = providers.get_provider(provider_id.as_ref().clone()).unwrap();
+            let result = match interruptible(pid, provider.as_filesystem().unwrap().read_file(id)).await {
+                Ok(Ok(content)) => {
+                    trace.finish::<(), String>(&Ok(()), content.len() as u64);
+                    Ok(content)
+                },
+                Ok(Err(error)) => {
+                    let error = format!("{:?}", error);
+                    trace.finish::<(), _>(&Err(&error), 0);
+                    Err((errno(&error), error))
+                },
+                Err(errno) => {
+                    trace.finish::<(), _>(&Err(errno), 0);
+                    Err((errno, "interrupted".to_string()))
+                },
+            };
+            Completion::Fetched { operation, inode, provider_id, result }
+        });
+        Ok(None)
+    }
+
+    // The content the writes and truncates of a file waited for, they take it as they run again.
+    pub fn apply_fetched(&mut self, operation: &'static str, inode: u64, provider_id: &ProviderId, result: Result<Vec<u8>, (c_int, String)>) {
+        match result {
+            Ok(content) => {
+                self.dispatcher.set_fetched(Some((inode, content)));
+                self.resume(Awaited::Content(inode), Ok(()));
+                self.dispatcher.set_fetched(None);
+            },
+            Err((errno, error)) => {
+                self.fail(operation, inode, provider_id, error);
+                self.resume(Awaited::Content(inode), Err(errno));
+            },
+        }
+    }
+}
+
+// Content is cached per revision, a file changed on the provider side gets a new size or
+// modification time and misses the cache.
+pub fn content_version(file: &FsNode) -> Option<String> {
+    let metadata = file.metadata?;
+    let mtime = metadata.mtime.duration_since(std::time::UNIX_EPOCH).ok()?;
+
+    Some(format!("{}:{}", metadata.size, mtime.as_nanos()))
+}
+
+fn read_range(path: &str, offset: u64, size: usize) -> std::io::Result<Vec<u8>> {
+    let file = std::fs::File::open(path)?;
+    let mut data = vec![0; size];
+    let mut read = 0;
+    while read < size {
+        match file.read_at(&mut data[read..], offset + read as u64)? {
+            0 => break,
+            count => read += count,
+        }
+    }
+    data.truncate(read);
+    Ok(data)
 }
\ No newline at end of file
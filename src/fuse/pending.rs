@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crossroads::interfaces::filesystem::{ObjectId, File, Metadata as CrossroadsMetadata};
+use crossroads::storage::ProviderId;
+use futures::future::join_all;
+
+use crate::fstree::FsNode;
+use crate::telemetry;
+use super::FuseFS;
+use super::dispatch::{Awaited, Completion};
+use super::failures::Failure;
+
+// Empty files are created on their provider in batches, once this many are waiting or the
+// oldest one waited this long, unless something needs them there first. The ticker creates
+// them when nothing else comes.
+const BATCH_SIZE: usize = 32;
+const MAX_DELAY: Duration = Duration::from_secs(2);
+
+// An empty file only known to the tree so far.
+struct PendingCreate {
+    mime_type: Option<String>,
+    since: Instant,
+    // its creation is on the way, the file stays pending until it is done
+    sending: bool,
+}
+
+#[derive(Default)]
+pub struct PendingCreates {
+    files: HashMap<u64, PendingCreate>,
+}
+
+impl PendingCreates {
+    pub fn insert(&mut self, inode: u64, mime_type: Option<String>) {
+        self.files.insert(inode, PendingCreate { mime_type, since: Instant::now(), sending: false });
+    }
+
+    pub fn contains(&self, inode: u64) -> bool {
+        self.files.contains_key(&inode)
+    }
+
+    // Nothing left to send, creations may still be on the way.
+    pub fn is_empty(&self) -> bool {
+        self.files.values().all(|file| file.sending)
+    }
+
+    pub fn is_sending(&self) -> bool {
+        self.files.values().any(|file| file.sending)
+    }
+
+    // Forgets a file removed before it was ever created, one being created is deleted once it is.
+    pub fn remove(&mut self, inode: u64) -> bool {
+        match self.files.get(&inode) {
+            Some(file) if !file.sending => self.files.remove(&inode).is_some(),
+            _ => false,
+        }
+    }
+
+    fn due(&self) -> bool {
+        let waiting = self.files.values().filter(|file| !file.sending);
+        waiting.clone().count() >= BATCH_SIZE || waiting.clone().any(|file| file.since.elapsed() >= MAX_DELAY)
+    }
+}
+
+struct Creation {
+    inode: u64,
+    provider_id: Arc<ProviderId>,
+    parent: ObjectId,
+    file: File,
+}
+
+impl FuseFS {
+    // Creates a pending file on its provider from a task, the caller holds its lock and gives
+    // its parent's id. Returns the creation to wait for, None for a file already created.
+    pub fn create_pending(&mut self, file: &FsNode, parent: ObjectId) -> Option<Awaited> {
+        let pending = self.pending_creates.files.get_mut(&file.inode)?;
+        if !pending.sending {
+            pending.sending = true;
+            let creation = Creation {
+                inode: file.inode,
+                provider_id: file.provider_id.clone(),
+                parent,
+                file: new_file(file, pending.mime_type.clone()),
+            };
+            self.run_creations(vec![creation]);
+        }
+        Some(Awaited::Created(file.inode))
+    }
+
+    // Creates the pending files on their providers once a batch is due, or right away with
+    // `force`. No lock on a tree node may be held meanwhile.
+    pub fn flush_pending_creates(&mut self, force: bool) {
+        if self.pending_creates.is_empty() || !(force || self.pending_creates.due()) {
+            return;
+        }
+
+        let mut creations = Vec::new();
+        let waiting: Vec<u64> = self.pending_creates.files.iter().filter(|(_, pending)| !pending.sending).map(|(inode, _)| *inode).collect();
+        for inode in waiting {
+            // unlinked files are no longer pending, nothing is lost
+            let node = match self.tree.find_with_inode(inode) {
+                Some(node) => node,
+                None => {
+                    self.pending_creates.files.remove(&inode);
+                    continue;
+                },
+            };
+            let node = node.lock().unwrap();
+            let parent = match self.tree.parent(inode).and_then(|parent| self.tree.find_with_inode(parent)) {
+                Some(parent) => parent,
+                None => {
+                    self.pending_creates.files.remove(&inode);
+                    self.failures.record(Failure {
+                        at: SystemTime::now(),
+                        operation: "create",
+                        path: PathBuf::from(&node.name),
+                        provider: node.provider_id.id.clone(),
+                        error: "its folder is gone, the empty file wasn't created".to_string(),
+                    });
+                    continue;
+                },
+            };
+            let pending = self.pending_creates.files.get_mut(&inode).unwrap();
+            pending.sending = true;
+            creations.push(Creation {
+                inode,
+                provider_id: node.provider_id.clone(),
+                parent: parent.lock().unwrap().id.clone(),
+                file: new_file(&node, pending.mime_type.clone()),
+            });
+        }
+
+        if !creations.is_empty() {
+            self.run_creations(creations);
+        }
+    }
+
+    // Creations are sent together from a task, each provider request is still traced on its own.
+    fn run_creations(&mut self, creations: Vec<Creation>) {
+        let awaited: Vec<Awaited> = creations.iter().map(|creation| Awaited::Created(creation.inode)).collect();
+        let creations: Vec<_> = creations.into_iter().map(|creation| {
+            self.quotas.record(&creation.provider_id);
+            (self.providers.get(&creation.provider_id), creation)
+        }).collect();
+
+        self.spawn_awaited(&awaited, async move {
+            let results = join_all(creations.iter().map(|(providers, creation)| async move {
+                let provider = match providers.as_ref().and_then(|providers| providers.get_provider(creation.provider_id.as_ref().clone())) {
+                    Some(provider) => provider,
+                    None => return Err(format!("provider {} is unreachable", creation.provider_id.id)),
+                };
+                let trace = telemetry::provider_request(&creation.provider_id, "create", &creation.parent);
+                let result = provider.as_filesystem().unwrap().create(creation.parent.clone(), creation.file.clone()).await;
+                trace.finish(&result, 0);
+                result.map(|_| ()).map_err(|error| format!("{:?}", error))
+            })).await;
+
+            let creations = creations.into_iter().zip(results).map(|((_, creation), result)| (creation.inode, creation.provider_id, result)).collect();
+            Completion::Created { creations }
+        });
+    }
+
+    // The files created are like any other from now on, the callbacks waiting for one that
+    // couldn't be get its errno.
+    pub fn apply_created(&mut self, creations: Vec<(u64, Arc<ProviderId>, Result<(), String>)>) {
+        for (inode, provider_id, result) in creations {
+            self.pending_creates.files.remove(&inode);
+            let result = result.map_err(|error| self.failed("create", inode, &provider_id, error));
+            self.resume(Awaited::Created(inode), result);
+        }
+    }
+}
+
+fn new_file(node: &FsNode, mime_type: Option<String>) -> File {
+    File {
+        id: node.id.clone(),
+        name: node.name.clone(),
+        metadata: Some(CrossroadsMetadata {
+            mime_type,
+            created_at: Some(chrono::Utc::now()),
+            modified_at: Some(chrono::Utc::now()),
+            meta_changed_at: Some(chrono::Utc::now()),
+            accessed_at: None,
+            size: None,
+            open_path: None,
+            owner: None,
+            permissions: None,
+        }),
+    }
+}
\ No newline at end of file
@@ -0,0 +1,118 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::config::config_file;
+
+// Rules are read from `policies.toml` in the config directory, e.g.
+//
+//     [[rule]]
+//     provider = "work-s3"
+//     deny = ["delete"]
+//
+//     [[rule]]
+//     provider = "GoogleDrive"
+//     writes_under = "/Inbox"
+//     max_upload_size = 1073741824
+//
+// `provider = "*"` applies a rule to every provider.
+const POLICY_FILE_NAME: &str = "policies.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Create,
+    Write,
+    Delete,
+    Rename,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rule {
+    provider: String,
+    #[serde(default)]
+    deny: Vec<Operation>,
+    // mutating operations are only allowed below this path of the provider
+    writes_under: Option<String>,
+    max_upload_size: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Policy {
+    #[serde(default, rename = "rule")]
+    rules: Vec<Rule>,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "provider {}", self.provider)?;
+        if !self.deny.is_empty() {
+            write!(f, ", deny {:?}", self.deny)?;
+        }
+        if let Some(writes_under) = &self.writes_under {
+            write!(f, ", writes under {}", writes_under)?;
+        }
+        if let Some(max_upload_size) = self.max_upload_size {
+            write!(f, ", max upload size {}", max_upload_size)?;
+        }
+        Ok(())
+    }
+}
+
+impl Rule {
+    fn applies_to(&self, provider: &str) -> bool {
+        self.provider == "*" || self.provider == provider
+    }
+
+    fn allows(&self, operation: Operation, path: &Path, size: u64) -> bool {
+        if self.deny.contains(&operation) {
+            return false;
+        }
+
+        if let Some(writes_under) = &self.writes_under {
+            if !path.starts_with(writes_under) {
+                return false;
+            }
+        }
+
+        match self.max_upload_size {
+            Some(max) if matches!(operation, Operation::Create | Operation::Write) => size <= max,
+            _ => true,
+        }
+    }
+}
+
+impl Policy {
+    // A missing file means no restrictions, a malformed one is reported and ignored so a
+    // typo doesn't keep the filesystem from mounting.
+    pub fn load() -> Self {
+        let path = match config_file(POLICY_FILE_NAME) {
+            Some(path) => path,
+            None => return Self::default(),
+        };
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => return Self::default(),
+        };
+
+        match toml::from_str(&content) {
+            Ok(policy) => policy,
+            Err(error) => {
+                warn!("ignoring {}: {}", path.display(), error);
+                Self::default()
+            },
+        }
+    }
+
+    // `path` is relative to the provider root. Returns the first rule refusing the operation.
+    pub fn check(&self, provider: &str, operation: Operation, path: &Path, size: u64) -> Result<(), &Rule> {
+        match self.rules.iter().find(|rule| rule.applies_to(provider) && !rule.allows(operation, path, size)) {
+            Some(rule) => Err(rule),
+            None => Ok(()),
+        }
+    }
+}
\ No newline at end of file
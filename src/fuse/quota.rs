@@ -0,0 +1,127 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+use chrono::{Local, NaiveDate};
+use crossroads::storage::{ProviderId, ProviderType};
+
+// Providers rate limit per 100 seconds and per day.
+const SHORT_WINDOW: Duration = Duration::from_secs(100);
+// Share of each budget kept for interactive operations, background work waits once only
+// this much is left.
+const RESERVE: f64 = 0.2;
+// Background work slows down gradually below this share of the budget left.
+const SLOWDOWN: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Budget {
+    pub per_100_seconds: Option<u64>,
+    pub per_day: Option<u64>,
+}
+
+impl Budget {
+    // Default quotas of the provider APIs, local files are unlimited.
+    fn of(provider_type: &ProviderType) -> Self {
+        match provider_type {
+            ProviderType::GoogleDrive => Self { per_100_seconds: Some(20_000), per_day: None },
+            ProviderType::OneDrive => Self { per_100_seconds: Some(1_600), per_day: None },
+            _ => Self::default(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Consumption {
+    recent: VecDeque<SystemTime>,
+    day: Option<NaiveDate>,
+    today: u64,
+}
+
+impl Consumption {
+    fn expire(&mut self, now: SystemTime) {
+        while let Some(requested_at) = self.recent.front() {
+            if *requested_at + SHORT_WINDOW > now {
+                break;
+            }
+            self.recent.pop_front();
+        }
+
+        let day = Local::now().date_naive();
+        if self.day != Some(day) {
+            self.day = Some(day);
+            self.today = 0;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuotaCounters {
+    pub last_100_seconds: u64,
+    pub today: u64,
+    pub budget: Budget,
+}
+
+#[derive(Default)]
+pub struct ApiQuotas {
+    consumption: HashMap<ProviderId, Consumption>,
+}
+
+impl ApiQuotas {
+    pub fn record(&mut self, provider_id: &ProviderId) {
+        let now = SystemTime::now();
+        let consumption = self.consumption.entry(provider_id.clone()).or_default();
+
+        consumption.expire(now);
+        consumption.recent.push_back(now);
+        consumption.today += 1;
+    }
+
+    pub fn counters(&mut self, provider_id: &ProviderId) -> QuotaCounters {
+        let consumption = self.consumption.entry(provider_id.clone()).or_default();
+        consumption.expire(SystemTime::now());
+
+        QuotaCounters {
+            last_100_seconds: consumption.recent.len() as u64,
+            today: consumption.today,
+            budget: Budget::of(&provider_id.provider_type),
+        }
+    }
+
+    // How long background work should wait before its next request to a provider, `None`
+    // when it shouldn't run at all until tomorrow. Interactive operations never wait.
+    pub fn background_delay(&mut self, provider_id: &ProviderId) -> Option<Duration> {
+        let budget = Budget::of(&provider_id.provider_type);
+        let now = SystemTime::now();
+        let consumption = self.consumption.entry(provider_id.clone()).or_default();
+        consumption.expire(now);
+
+        if let Some(per_day) = budget.per_day {
+            if consumption.today as f64 >= per_day as f64 * (1.0 - RESERVE) {
+                return None;
+            }
+        }
+
+        let per_100_seconds = match budget.per_100_seconds {
+            Some(per_100_seconds) => per_100_seconds,
+            None => return Some(Duration::ZERO),
+        };
+
+        let used = consumption.recent.len() as u64;
+        let left = 1.0 - used as f64 / per_100_seconds as f64;
+
+        if left <= RESERVE {
+            // wait for enough requests to leave the window to get out of the reserve
+            let allowed = (per_100_seconds as f64 * (1.0 - RESERVE)) as u64;
+            let excess = (used - allowed.min(used)) as usize;
+            let freed_at = consumption.recent.get(excess).map_or(now, |requested_at| *requested_at + SHORT_WINDOW);
+            return Some(freed_at.duration_since(now).unwrap_or_default());
+        }
+
+        if left < SLOWDOWN {
+            // spread the requests over the window, more so as the budget runs out
+            let interval = SHORT_WINDOW.as_secs_f64() / per_100_seconds as f64;
+            return Some(Duration::from_secs_f64(interval * SLOWDOWN / left));
+        }
+
+        Some(Duration::ZERO)
+    }
+}
\ No newline at end of file
@@ -0,0 +1,31 @@
+use crate::fstree::FsNode;
+use super::FuseFS;
+
+impl FuseFS {
+    // Chunks read ahead of sequential reads of the provider's files.
+    fn read_ahead_chunks(&self, provider: &str) -> u64 {
+        self.provider_read_ahead.get(provider).copied().unwrap_or(self.read_ahead)
+    }
+
+    // Loads the chunks following `end` from the disk cache into memory, so a reader going
+    // through the file finds them there. Called once the kernel got its reply.
+    pub fn read_ahead(&mut self, file: &FsNode, version: &str, end: u64) {
+        let count = self.read_ahead_chunks(&file.provider_id.id);
+        let cache = match &self.content_cache {
+            Some(cache) if count > 0 => cache,
+            _ => return,
+        };
+
+        let chunk_size = self.blocks.chunk_size();
+        let next = (end + chunk_size - 1) / chunk_size;
+        let first = match (next..next + count).find(|index| !self.blocks.contains(&file.provider_id.id, file.id.as_str(), version, *index)) {
+            Some(first) => first,
+            None => return,
+        };
+
+        let length = ((next + count - first) * chunk_size) as usize;
+        if let Some(chunks) = cache.read_range(&file.provider_id.id, file.id.as_str(), version, first * chunk_size, length) {
+            self.blocks.insert(&file.provider_id.id, file.id.as_str(), version, first * chunk_size, &chunks, chunks.len() < length);
+        }
+    }
+}
\ No newline at end of file
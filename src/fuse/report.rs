@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use libc::c_int;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::FuseFS;
+use super::node::content_version;
+use super::walk::CRAWLING_NOTE;
+
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+// Figures the daemon doesn't know are left out, e.g. the quota of providers that don't report one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProviderUsage {
+    pub provider: String,
+    pub quota_total: Option<u64>,
+    pub quota_used: Option<u64>,
+    pub quota_free: Option<u64>,
+    pub cached_bytes: u64,
+    pub pinned_files: usize,
+    pub pending_upload_bytes: u64,
+    // API requests sent over the last 100 seconds and today, against the provider's budget
+    pub api_requests_100s: u64,
+    pub api_requests_today: u64,
+    pub api_budget_100s: Option<u64>,
+    pub api_budget_day: Option<u64>,
+}
+
+impl FuseFS {
+    pub fn usage(&mut self, uid: u32) -> Vec<ProviderUsage> {
+        self.tree.providers(uid).iter()
+            .map(|provider| {
+                let (inode, provider_id) = {
+                    let provider = provider.lock().unwrap();
+                    (provider.inode, provider.provider_id.clone())
+                };
+                let counters = self.quotas.counters(&provider_id);
+                // only local providers have a known size, the one of their disk
+                let space = self.native_statfs(inode);
+                // written and not uploaded yet, in the journal or in open handles
+                let buffered: u64 = self.handles.dirty_sizes().into_iter()
+                    .filter(|(inode, _)| self.tree.find_with_inode(*inode).map_or(false, |node| node.lock().unwrap().provider_id == provider_id))
+                    .map(|(_, size)| size)
+                    .sum();
+
+                ProviderUsage {
+                    provider: provider_id.id.clone(),
+                    quota_total: space.map(|stat| stat.f_blocks as u64 * stat.f_frsize as u64),
+                    quota_used: space.map(|stat| (stat.f_blocks - stat.f_bfree) as u64 * stat.f_frsize as u64),
+                    quota_free: space.map(|stat| stat.f_bavail as u64 * stat.f_frsize as u64),
+                    cached_bytes: self.content_cache.as_ref().map_or(0, |cache| cache.provider_size(&provider_id.id)),
+                    pinned_files: self.content_cache.as_ref().map_or(0, |cache| cache.pinned_count(&provider_id.id)),
+                    pending_upload_bytes: self.journal.pending_bytes(&provider_id.id) + buffered,
+                    api_requests_100s: counters.last_100_seconds,
+                    api_requests_today: counters.today,
+                    api_budget_100s: counters.budget.per_100_seconds,
+                    api_budget_day: counters.budget.per_day,
+                }
+            })
+            .collect()
+    }
+
+    // Lists the `top` biggest files and directories of each provider below `path`, sizes come
+    // from the listings so nothing gets downloaded.
+    pub fn report_large(&mut self, uid: u32, top: usize, path: &Path) -> Result<String, c_int> {
+        let roots = if path == Path::new("/") {
+            self.tree.providers(uid)
+        } else {
+            vec![self.resolve(path, uid)?]
+        };
+
+        let mut report = String::new();
+        let mut complete = true;
+
+        for root in roots {
+            let (root_inode, provider) = {
+                let root = root.lock().unwrap();
+                (root.inode, root.provider_id.id.clone())
+            };
+
+            let mut files = Vec::new();
+            complete &= self.walk(root, &mut |node| {
+                if node.visible_to(uid) && !node.id.is_directory() {
+                    files.push((node.inode, node.metadata.map(|metadata| metadata.size).unwrap_or(0)));
+                }
+                true
+            });
+
+            // a directory weighs as much as every file below it
+            let mut directories: HashMap<u64, u64> = HashMap::new();
+            for (inode, size) in &files {
+                let mut current = *inode;
+                while current != root_inode {
+                    current = match self.tree.parent(current) {
+                        Some(parent) => parent,
+                        None => break,
+                    };
+                    *directories.entry(current).or_default() += size;
+                }
+            }
+
+            files.sort_by(|a, b| b.1.cmp(&a.1));
+            let mut directories: Vec<(u64, u64)> = directories.into_iter().filter(|(inode, _)| *inode != root_inode).collect();
+            directories.sort_by(|a, b| b.1.cmp(&a.1));
+
+            report += &format!("{}\n  files:\n", provider);
+            for (inode, size) in files.iter().take(top) {
+                report += &format!("    {:>10}  {}\n", human_size(*size), self.tree.path(*inode).unwrap_or_default().display());
+            }
+            report += "  directories:\n";
+            for (inode, size) in directories.iter().take(top) {
+                report += &format!("    {:>10}  {}\n", human_size(*size), self.tree.path(*inode).unwrap_or_default().display());
+            }
+        }
+
+        if !complete {
+            report += CRAWLING_NOTE;
+        }
+        Ok(report)
+    }
+
+    // Groups identical files found below `path`. The md5, quickXor or ETag of Drive and OneDrive
+    // would do, but crossroads' metadata carries no checksum, so every provider falls back to
+    // hashing here: files are grouped by the size their provider reports and only the ones
+    // sharing a size are hashed, from the disk for local providers or from the content cache.
+    // Nothing is downloaded, files that aren't cached are listed apart.
+    pub fn report_duplicates(&mut self, uid: u32, path: &Path) -> Result<String, c_int> {
+        let roots = if path == Path::new("/") {
+            self.tree.providers(uid)
+        } else {
+            vec![self.resolve(path, uid)?]
+        };
+
+        let mut sizes: HashMap<u64, Vec<u64>> = HashMap::new();
+        let mut complete = true;
+        for root in roots {
+            complete &= self.walk(root, &mut |node| {
+                let size = node.metadata.map(|metadata| metadata.size).unwrap_or(0);
+                if node.visible_to(uid) && !node.id.is_directory() && size > 0 {
+                    sizes.entry(size).or_default().push(node.inode);
+                }
+                true
+            });
+        }
+
+        let mut sets: Vec<(u64, Vec<u64>)> = Vec::new();
+        let mut uncompared: Vec<(u64, Vec<u64>)> = Vec::new();
+        for (size, inodes) in sizes.into_iter().filter(|(_, inodes)| inodes.len() > 1) {
+            let mut hashes: HashMap<Vec<u8>, Vec<u64>> = HashMap::new();
+            let mut missing = Vec::new();
+            for inode in inodes {
+                match self.content_hash(inode) {
+                    Some(hash) => hashes.entry(hash).or_default().push(inode),
+                    None => missing.push(inode),
+                }
+            }
+            sets.extend(hashes.into_values().filter(|inodes| inodes.len() > 1).map(|inodes| (size, inodes)));
+            if !missing.is_empty() {
+                uncompared.push((size, missing));
+            }
+        }
+
+        sets.sort_by(|a, b| (b.0 * b.1.len() as u64).cmp(&(a.0 * a.1.len() as u64)));
+        uncompared.sort_by(|a, b| b.0.cmp(&a.0));
+
+        let mut report = String::new();
+        let mut reclaimable = 0;
+        for (size, inodes) in sets {
+            let wasted = size * (inodes.len() as u64 - 1);
+            reclaimable += wasted;
+            report += &format!("{} copies of {}, {} reclaimable\n", inodes.len(), human_size(size), human_size(wasted));
+            for inode in inodes {
+                report += &format!("  {}\n", self.tree.path(inode).unwrap_or_default().display());
+            }
+        }
+        report += &format!("total reclaimable: {}\n", human_size(reclaimable));
+
+        if !uncompared.is_empty() {
+            report += "same size as another file but not cached, read or pin them to compare:\n";
+            for (size, inodes) in uncompared {
+                for inode in inodes {
+                    report += &format!("  {:>10}  {}\n", human_size(size), self.tree.path(inode).unwrap_or_default().display());
+                }
+            }
+        }
+        if !complete {
+            report += CRAWLING_NOTE;
+        }
+
+        Ok(report)
+    }
+
+    // None when the content isn't on this machine, in the cache at its current version or on
+    // the disk of a local provider.
+    fn content_hash(&self, inode: u64) -> Option<Vec<u8>> {
+        let node = self.tree.find_with_inode(inode)?;
+        let node = node.lock().unwrap();
+
+        let mut hasher = Sha256::new();
+        match self.native_roots.get(&node.provider_id) {
+            // local files may be large, they are hashed as they are read
+            Some(root) => {
+                io::copy(&mut File::open(root.clone() + node.id.as_str()).ok()?, &mut hasher).ok()?;
+            },
+            None => {
+                let version = content_version(&node)?;
+                hasher.update(self.content_cache.as_ref()?.get(&node.provider_id.id, node.id.as_str(), &version)?);
+            },
+        }
+
+        Some(hasher.finalize().to_vec())
+    }
+}
\ No newline at end of file
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use libc::ENOENT;
+use chrono::{DateTime, Utc};
+
+use fuser::{FileType, FileAttr, ReplyDirectory, ReplyEntry};
+
+use crate::fstree::FsNode;
+use super::FuseFS;
+use super::dispatch::Caller;
+use super::walk::CRAWLING_NOTE;
+
+// `<dir>/.search/<query>/` lists the files below `<dir>` matching the query. The `.search`
+// directory isn't listed so recursive tools don't wander into it.
+pub const SEARCH_DIR_NAME: &str = ".search";
+
+// Search directories are numbered from here, far above anything FsTree hands out and below
+// the control inodes.
+const FIRST_SEARCH_INODE: u64 = 1 << 62;
+const MAX_SEARCH_RESULTS: usize = 500;
+
+// Results are computed when the directory is listed, the kernel must not cache them.
+const SEARCH_TTL: Duration = Duration::from_secs(0);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    NameContains(String),
+    NameIs(String),
+}
+
+impl Query {
+    // Understands the name clauses of the Drive query language, anything else is looked up
+    // as part of the file name.
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if text.is_empty() {
+            return None;
+        }
+
+        let quoted = |rest: &str| rest.trim().strip_prefix('\'')?.strip_suffix('\'').map(str::to_string);
+
+        if let Some(rest) = text.strip_prefix("name contains ") {
+            return quoted(rest).map(|value| Query::NameContains(value.to_lowercase()));
+        }
+        if let Some(rest) = text.strip_prefix("name = ") {
+            return quoted(rest).map(Query::NameIs);
+        }
+
+        Some(Query::NameContains(text.to_lowercase()))
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Query::NameContains(value) => name.to_lowercase().contains(value.as_str()),
+            Query::NameIs(value) => name == value,
+        }
+    }
+}
+
+struct SearchDir {
+    // directory whose subtree is searched
+    scope: u64,
+    // `None` for the `.search` directory itself
+    query: Option<Query>,
+    results: Vec<(String, u64)>,
+    // entries of the directory the kernel holds, it is dropped when they are all forgotten
+    lookups: u64,
+}
+
+#[derive(Default)]
+pub struct Searches {
+    dirs: HashMap<u64, SearchDir>,
+    names: HashMap<(u64, String), u64>,
+    // inodes aren't reused, the kernel may still ask about a forgotten one
+    next_inode: u64,
+    // answer to the last query written to the search control file
+    latest: String,
+}
+
+impl Searches {
+    pub fn contains(&self, ino: u64) -> bool {
+        self.dirs.contains_key(&ino)
+    }
+
+    pub fn latest(&self) -> &str {
+        &self.latest
+    }
+
+    // Directory a search directory was opened from.
+    pub fn parent(&self, ino: u64) -> Option<u64> {
+        self.names.iter().find(|(_, dir)| **dir == ino).map(|((parent, _), _)| *parent)
+    }
+
+    // Counts an entry handed to the kernel.
+    pub fn looked_up(&mut self, ino: u64) {
+        if let Some(dir) = self.dirs.get_mut(&ino) {
+            dir.lookups += 1;
+        }
+    }
+
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        let dir = match self.dirs.get_mut(&ino) {
+            Some(dir) => dir,
+            None => return,
+        };
+
+        dir.lookups = dir.lookups.saturating_sub(nlookup);
+        if dir.lookups == 0 {
+            self.dirs.remove(&ino);
+            self.names.retain(|_, dir| *dir != ino);
+        }
+    }
+
+    fn open(&mut self, parent: u64, name: &str, scope: u64, query: Option<Query>) -> u64 {
+        let ino = match self.names.get(&(parent, name.to_string())) {
+            Some(ino) => *ino,
+            None => {
+                let ino = FIRST_SEARCH_INODE + self.next_inode;
+                self.next_inode += 1;
+                self.dirs.insert(ino, SearchDir { scope, query, results: Vec::new(), lookups: 0 });
+                self.names.insert((parent, name.to_string()), ino);
+                ino
+            },
+        };
+
+        self.looked_up(ino);
+        ino
+    }
+}
+
+impl FuseFS {
+    pub fn search_attr(&self, ino: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::Directory,
+            perm: 0o555,
+            nlink: 2,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 512,
+        }
+    }
+
+    pub fn search_lookup(&mut self, req: &Caller, parent: u64, name: &str, reply: ReplyEntry) {
+        let (scope, query) = match self.searches.dirs.get(&parent) {
+            Some(dir) => (dir.scope, dir.query.clone()),
+            None => {
+                // `.search` right below a directory of the tree
+                match self.tree.find_with_inode(parent) {
+                    Some(node) if node.lock().unwrap().visible_to(req.uid()) => (),
+                    _ => return reply.error(ENOENT),
+                }
+                let ino = self.searches.open(parent, name, parent, None);
+                return reply.entry(&SEARCH_TTL, &self.search_attr(ino), 0);
+            },
+        };
+
+        match query {
+            None => match Query::parse(name) {
+                Some(query) => {
+                    let ino = self.searches.open(parent, name, scope, Some(query));
+                    reply.entry(&SEARCH_TTL, &self.search_attr(ino), 0);
+                },
+                None => reply.error(ENOENT),
+            },
+            Some(_) => {
+                if self.searches.dirs[&parent].results.is_empty() {
+                    self.run_search(req, parent);
+                }
+
+                let result = self.searches.dirs[&parent].results.iter().find(|(result, _)| result == name).map(|(_, ino)| *ino);
+                match result.and_then(|ino| self.tree.find_with_inode(ino)) {
+                    Some(node) => self.reply_entry(reply, &node.lock().unwrap()),
+                    None => reply.error(ENOENT),
+                }
+            },
+        }
+    }
+
+    pub fn search_readdir(&mut self, req: &Caller, ino: u64, offset: i64, mut reply: ReplyDirectory) {
+        // listing from the start runs the query again
+        if offset == 0 && self.searches.dirs[&ino].query.is_some() {
+            self.run_search(req, ino);
+        }
+
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (self.searches.dirs[&ino].scope, FileType::Directory, "..".to_string()),
+        ];
+
+        for (name, result) in &self.searches.dirs[&ino].results {
+            if let Some(node) = self.tree.find_with_inode(*result) {
+                let kind = if node.lock().unwrap().id.is_directory() { FileType::Directory } else { FileType::RegularFile };
+                entries.push((*result, kind, name.clone()));
+            }
+        }
+
+        for (index, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, index as i64 + 1, kind, OsStr::from_bytes(name.as_bytes())) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
+    // Matching files are listed under their own name, a number is appended when several
+    // folders hold a file with the same name.
+    fn run_search(&mut self, req: &Caller, ino: u64) {
+        let (scope, query) = {
+            let dir = &self.searches.dirs[&ino];
+            (dir.scope, dir.query.clone().unwrap())
+        };
+
+        let root = match self.tree.find_with_inode(scope) {
+            Some(root) => root,
+            None => return,
+        };
+
+        // folders missing from the tree are crawled meanwhile, listing again finds more
+        let mut results: Vec<(String, u64)> = Vec::new();
+        let (found, _) = self.find(root, req.uid(), &query, MAX_SEARCH_RESULTS);
+        for (name, inode) in found {
+            let mut unique = name.clone();
+            let mut copy = 1;
+            while results.iter().any(|(result, _)| *result == unique) {
+                copy += 1;
+                unique = format!("{} ({})", name, copy);
+            }
+            results.push((unique, inode));
+        }
+
+        self.searches.dirs.get_mut(&ino).unwrap().results = results;
+    }
+
+    // Runs a query on every provider the user can see, each match is reported on its own line
+    // as `path<TAB>size<TAB>modification time`.
+    pub fn search_providers(&mut self, uid: u32, query: &Query) {
+        let mut output = String::new();
+        let mut remaining = MAX_SEARCH_RESULTS;
+        let mut complete = true;
+
+        for provider in self.tree.providers(uid) {
+            let (found, listed) = self.find(provider, uid, query, remaining);
+            remaining -= found.len();
+            complete &= listed;
+
+            for (_, inode) in found {
+                let (size, modified_at) = match self.tree.find_with_inode(inode).and_then(|node| node.lock().unwrap().metadata) {
+                    Some(metadata) => (metadata.size, DateTime::<Utc>::from(metadata.mtime).to_rfc3339()),
+                    None => (0, String::new()),
+                };
+                let path = self.tree.path(inode).unwrap_or_default();
+                output += &format!("{}\t{}\t{}\n", path.display(), size, modified_at);
+            }
+        }
+
+        if !complete {
+            output += CRAWLING_NOTE;
+        }
+        self.searches.latest = output;
+    }
+
+    // Also tells whether the whole subtree was in the tree.
+    fn find(&mut self, root: Arc<Mutex<FsNode>>, uid: u32, query: &Query, limit: usize) -> (Vec<(String, u64)>, bool) {
+        let mut found = Vec::new();
+        if limit == 0 {
+            return (found, true);
+        }
+
+        let complete = self.walk(root, &mut |node| {
+            if node.visible_to(uid) && query.matches(&node.name) {
+                found.push((node.name.clone(), node.inode));
+            }
+            found.len() < limit
+        });
+
+        (found, complete)
+    }
+}
\ No newline at end of file
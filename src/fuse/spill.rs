@@ -0,0 +1,164 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+use libc::{c_int, EIO};
+use tracing::{info, warn};
+
+use crate::cache::Sealer;
+use crate::fstree::FsNode;
+use crate::state;
+use super::FuseFS;
+use super::journal::{Deferred, Journal};
+
+// What handles wrote and didn't flush yet, in the mount's cache directory.
+const SPILL_DIR_NAME: &str = "dirty";
+
+// Each dirty handle's buffer is mirrored to a file before its writes are acknowledged, with
+// the write it would become next to it. The files left by a daemon that didn't exit cleanly
+// are given to the journal on the next mount. Both are named by handle number only, and
+// encrypted like the cache with --encrypt-cache.
+#[derive(Default)]
+pub struct Spills {
+    dir: Option<PathBuf>,
+    // handles with a file up to date, the others are written whole on their next change
+    spilled: HashSet<u64>,
+    sealer: Option<Sealer>,
+}
+
+impl Spills {
+    pub fn new() -> Self {
+        Self { dir: state::cache_dir().map(|dir| dir.join(SPILL_DIR_NAME)), ..Self::default() }
+    }
+
+    pub fn set_sealer(&mut self, sealer: Option<Sealer>) {
+        self.sealer = sealer;
+    }
+
+    // The handles are numbered again by every mount, whatever is left is from a previous one.
+    // Files spilled without encryption are read as they are.
+    pub fn recover(&self, journal: &Journal) -> io::Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        fs::create_dir_all(dir)?;
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |extension| extension != "json") {
+                continue;
+            }
+            let content_path = path.with_extension("");
+            let recovered = fs::read(&path).map_err(|error| error.to_string())
+                .and_then(|write| match serde_json::from_slice::<Deferred>(&write) {
+                    Ok(write) => Ok((false, write)),
+                    Err(error) => self.open(&write).and_then(|write| serde_json::from_slice::<Deferred>(&write).map_err(|error| error.to_string()))
+                        .map(|write| (true, write))
+                        .map_err(|_| error.to_string()),
+                })
+                .and_then(|(sealed, write)| {
+                    let content = fs::read(&content_path).map_err(|error| error.to_string())?;
+                    match sealed {
+                        true => Ok((self.open(&content)?, write)),
+                        false => Ok((content, write)),
+                    }
+                });
+
+            match recovered {
+                Ok((content, write)) => {
+                    info!("recovered an unflushed write of {}", write.path().display());
+                    journal.push(write, Some(&content), false)?;
+                },
+                Err(error) => warn!("ignoring {}: {}", path.display(), error),
+            }
+            let _ = fs::remove_file(&content_path);
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, handle: u64, write: &Deferred, content: &[u8]) -> io::Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        fs::create_dir_all(dir)?;
+        let write = serde_json::to_vec(write)?;
+        let (content, write) = match &self.sealer {
+            Some(sealer) => (sealer.seal(content)?, sealer.seal(&write)?),
+            None => (content.to_vec(), write),
+        };
+        state::write_private(&dir.join(handle.to_string()), &content)?;
+        // written last, a crash in between leaves no half spill behind
+        state::write_private(&dir.join(format!("{}.json", handle)), &write)?;
+        self.spilled.insert(handle);
+        Ok(())
+    }
+
+    fn open(&self, content: &[u8]) -> Result<Vec<u8>, String> {
+        let sealer = self.sealer.as_ref().ok_or_else(|| "encrypted, mount with --encrypt-cache to recover it".to_string())?;
+        sealer.open(content).map_err(|error| error.to_string())
+    }
+
+    fn write(&self, handle: u64, offset: usize, data: &[u8], size: u64) -> io::Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        let file = OpenOptions::new().write(true).open(dir.join(handle.to_string()))?;
+        file.write_all_at(data, offset as u64)?;
+        file.set_len(size)
+    }
+
+    // Once the content is uploaded or in the journal.
+    pub fn remove(&mut self, handle: u64) {
+        if !self.spilled.remove(&handle) {
+            return;
+        }
+        if let Some(dir) = &self.dir {
+            let _ = fs::remove_file(dir.join(format!("{}.json", handle)));
+            let _ = fs::remove_file(dir.join(handle.to_string()));
+        }
+    }
+}
+
+impl FuseFS {
+    // Saves the range of a handle's buffer that changed, called before the change is
+    // acknowledged. A write only a crash could lose is refused.
+    pub fn spill(&mut self, handle: u64, node: &FsNode, offset: usize, size: usize) -> Result<(), c_int> {
+        let buffer = match self.handles.buffer(handle, node.inode) {
+            Some(buffer) => buffer,
+            None => return Ok(()),
+        };
+
+        // an encrypted spill can't be changed in place, it is written whole every time
+        let result = match self.spills.spilled.contains(&handle) && self.spills.sealer.is_none() {
+            true => {
+                let end = std::cmp::min(offset + size, buffer.len());
+                let start = std::cmp::min(offset, end);
+                self.spills.write(handle, start, &buffer[start..end], buffer.len() as u64)
+            },
+            false => {
+                let buffer = buffer.clone();
+                let write = self.deferred_write(node, self.handles.base(handle));
+                self.spills.start(handle, &write, &buffer)
+            },
+        };
+
+        result.map_err(|error| {
+            warn!("unable to save the write of {}: {}", node.name, error);
+            error.raw_os_error().unwrap_or(EIO)
+        })
+    }
+
+    // The handle's content reached its provider or the journal.
+    pub fn mark_clean(&mut self, handle: u64) {
+        self.handles.mark_clean(handle);
+        self.spills.remove(handle);
+    }
+}
\ No newline at end of file
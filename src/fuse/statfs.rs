@@ -0,0 +1,48 @@
+use std::ffi::CString;
+use std::io;
+
+use fuser::ReplyStatfs;
+use tracing::debug;
+
+use super::FuseFS;
+use super::dispatch::Caller;
+
+impl FuseFS {
+    // Inside a local provider `df` shows the disk holding it. crossroads doesn't report the
+    // quota of cloud providers, they and the root get the same empty figures as before.
+    pub fn internal_statfs(&mut self, _req: &Caller, ino: u64, reply: ReplyStatfs) {
+        debug!(ino, "statfs");
+
+        match self.native_statfs(ino) {
+            Some(stat) => reply.statfs(
+                stat.f_blocks as u64,
+                stat.f_bfree as u64,
+                stat.f_bavail as u64,
+                stat.f_files as u64,
+                stat.f_ffree as u64,
+                stat.f_bsize as u32,
+                stat.f_namemax as u32,
+                stat.f_frsize as u32,
+            ),
+            None => reply.statfs(0, 0, 0, 0, 0, 512, 255, 0),
+        }
+    }
+
+    // Space of the disk holding the local provider `inode` belongs to.
+    pub fn native_statfs(&self, inode: u64) -> Option<libc::statvfs> {
+        let node = self.tree.find_with_inode(inode)?;
+        let provider_id = node.lock().unwrap().provider_id.clone();
+        statvfs(self.native_roots.get(&provider_id)?).ok()
+    }
+}
+
+fn statvfs(path: &str) -> io::Result<libc::statvfs> {
+    let path = CString::new(path).map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(stat)
+}
\ No newline at end of file
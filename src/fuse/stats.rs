@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use serde::Serialize;
+
+use super::FuseFS;
+
+// By provider name then request. Like the circuits, filled through telemetry by every provider
+// request, the ones made by background tasks included.
+static REQUESTS: Mutex<BTreeMap<String, BTreeMap<&'static str, Timing>>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timing {
+    calls: u64,
+    errors: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Timing {
+    fn record(&mut self, elapsed: Duration) {
+        self.calls += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+}
+
+// In milliseconds, as shown in the stats control file.
+#[derive(Debug, Clone, Copy, Serialize)]
+struct TimingStats {
+    calls: u64,
+    errors: u64,
+    mean_ms: f64,
+    max_ms: f64,
+}
+
+impl From<&Timing> for TimingStats {
+    fn from(timing: &Timing) -> Self {
+        let mean = match timing.calls {
+            0 => Duration::ZERO,
+            calls => timing.total / calls as u32,
+        };
+        Self {
+            calls: timing.calls,
+            errors: timing.errors,
+            mean_ms: mean.as_secs_f64() * 1000.0,
+            max_ms: timing.max.as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Stats {
+    operations: BTreeMap<&'static str, TimingStats>,
+    providers: BTreeMap<String, BTreeMap<&'static str, TimingStats>>,
+}
+
+// FUSE callbacks by operation. The time is the callback's, what a task finishes afterwards for
+// it is only counted when it fails.
+#[derive(Default)]
+pub struct OperationStats {
+    operations: BTreeMap<&'static str, Timing>,
+}
+
+impl OperationStats {
+    pub fn record(&mut self, operation: &'static str, elapsed: Duration) {
+        self.operations.entry(operation).or_default().record(elapsed);
+    }
+
+    pub fn record_error(&mut self, operation: &'static str) {
+        self.operations.entry(operation).or_default().errors += 1;
+    }
+}
+
+pub fn record_request(provider: &str, request: &'static str, elapsed: Duration, failed: bool) {
+    let mut requests = REQUESTS.lock().unwrap();
+    let timing = requests.entry(provider.to_string()).or_default().entry(request).or_default();
+    timing.record(elapsed);
+    if failed {
+        timing.errors += 1;
+    }
+}
+
+impl FuseFS {
+    pub fn stats(&self) -> Vec<u8> {
+        let providers = REQUESTS.lock().unwrap().iter()
+            .map(|(provider, requests)| (provider.clone(), requests.iter().map(|(request, timing)| (*request, timing.into())).collect()))
+            .collect();
+        let stats = Stats {
+            operations: self.operation_stats.operations.iter().map(|(operation, timing)| (*operation, timing.into())).collect(),
+            providers,
+        };
+        serde_json::to_vec_pretty(&stats).unwrap()
+    }
+}
\ No newline at end of file
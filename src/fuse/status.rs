@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crossroads::storage::ProviderId;
+use serde::{Deserialize, Serialize};
+
+use super::FuseFS;
+use super::breaker;
+
+// Failures shown in the status, the errors control file has the full list.
+const STATUS_ERRORS: usize = 10;
+// How long a finished transfer stays in the status, with all its bytes done.
+const FINISHED_SHOWN: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    Online,
+    Offline,
+    // stopped answering while mounted, served from the cache
+    Degraded,
+    Reauth,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderStatus {
+    pub provider: String,
+    pub health: Health,
+    pub api_requests_100s: u64,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    // operations in the journal
+    #[serde(default)]
+    pub deferred: usize,
+}
+
+// crossroads sends and receives a file in a single request, `done` counts the bytes the
+// provider took or gave once that request ended.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transfer {
+    pub path: String,
+    pub upload: bool,
+    pub done: u64,
+    pub total: u64,
+    #[serde(default)]
+    pub seconds: u64,
+}
+
+// How often listings and attributes were served from the tree, and file content from memory or
+// the disk, rather than asked to the provider.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct CacheCounters {
+    pub listing_hits: u64,
+    pub listing_misses: u64,
+    pub metadata_hits: u64,
+    pub metadata_misses: u64,
+    pub content_hits: u64,
+    pub content_misses: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub providers: Vec<ProviderStatus>,
+    pub transfers: Vec<Transfer>,
+    pub cache: CacheCounters,
+    pub errors: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct Transfers {
+    next_id: u64,
+    // the transfer, when it started and when it ended
+    active: HashMap<u64, (Transfer, Instant, Option<Instant>)>,
+}
+
+impl Transfers {
+    pub fn start(&mut self, path: String, upload: bool, total: u64) -> u64 {
+        self.next_id += 1;
+        self.active.insert(self.next_id, (Transfer { path, upload, done: 0, total, seconds: 0 }, Instant::now(), None));
+        self.next_id
+    }
+
+    // `done` is 0 for a transfer that failed, it is gone from the status right away.
+    pub fn finish(&mut self, id: u64, done: u64) {
+        if done == 0 {
+            self.active.remove(&id);
+        } else if let Some((transfer, _, finished)) = self.active.get_mut(&id) {
+            transfer.done = done;
+            transfer.total = transfer.total.max(done);
+            *finished = Some(Instant::now());
+        }
+    }
+
+    fn current(&mut self) -> Vec<Transfer> {
+        self.active.retain(|_, (_, _, finished)| finished.map_or(true, |finished| finished.elapsed() < FINISHED_SHOWN));
+        let mut transfers: Vec<Transfer> = self.active.values()
+            .map(|(transfer, started, finished)| Transfer {
+                seconds: finished.unwrap_or_else(Instant::now).duration_since(*started).as_secs(),
+                ..transfer.clone()
+            })
+            .collect();
+        transfers.sort_by(|a, b| a.path.cmp(&b.path));
+        transfers
+    }
+}
+
+impl FuseFS {
+    pub fn health_of(&self, provider_id: &ProviderId) -> Health {
+        if self.reauth_required.contains(provider_id) {
+            Health::Reauth
+        } else if self.offline.contains_key(provider_id) {
+            Health::Offline
+        } else if breaker::is_open(&provider_id.id) {
+            Health::Degraded
+        } else {
+            Health::Online
+        }
+    }
+
+    pub fn status(&mut self, uid: u32) -> Status {
+        let mut providers = Vec::new();
+
+        for provider in self.tree.providers(uid) {
+            let provider_id = provider.lock().unwrap().provider_id.clone();
+            providers.push(ProviderStatus {
+                provider: provider_id.id.clone(),
+                health: self.health_of(&provider_id),
+                api_requests_100s: self.quotas.counters(&provider_id).last_100_seconds,
+                capabilities: self.capabilities(&provider_id).names(),
+                deferred: self.journal.count(&provider_id.id),
+            });
+        }
+
+        let failures: Vec<String> = self.failures.iter().map(|failure| failure.to_string()).collect();
+
+        Status {
+            providers,
+            transfers: self.transfers.current(),
+            cache: self.cache_counters,
+            errors: failures[failures.len().saturating_sub(STATUS_ERRORS)..].to_vec(),
+        }
+    }
+}
\ No newline at end of file
@@ -1,23 +1,41 @@
 use std::{ffi::OsStr};
-use libc::{ENOENT, ENOTDIR, EEXIST};
+use libc::{ENETUNREACH, ENOENT, ENOTDIR, EEXIST, EINVAL, EPERM};
 
-use fuser::{ReplyData, ReplyEntry, Request};
+use fuser::{ReplyData, ReplyEntry};
+use crossroads::storage::ProviderId;
+use tracing::debug;
 
-use super::{FuseFS, TTL};
+use super::FuseFS;
+use super::dispatch::{Awaited, Caller, Completion};
+use super::policy::Operation;
 
 impl FuseFS {
-    pub fn internal_readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+    pub fn internal_readlink(&mut self, _req: &Caller, ino: u64, reply: ReplyData) {
         if let Some(node) = self.tree.find_with_inode(ino) {
             if let Ok(node) = node.lock() {
-                let provider = self.providers.get_provider(node.provider_id.as_ref().clone()).unwrap();
-                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
-    
-                rt.block_on(async {
-                    if let Ok(link) = provider.as_filesystem().unwrap().read_link(node.id.clone()).await {
+                if let Err(errno) = self.check_provider(&node.provider_id) {
+                    return reply.error(errno);
+                }
+
+                // nothing on this provider is a link
+                if !self.capabilities(&node.provider_id).symlinks {
+                    return reply.error(EINVAL);
+                }
+
+                let providers = match self.providers.get(&node.provider_id) {
+                    Some(providers) => providers,
+                    None => return reply.error(ENETUNREACH),
+                };
+                self.quotas.record(&node.provider_id);
+
+                let (provider_id, id) = (node.provider_id.clone(), node.id.clone());
+                self.spawn(async move {
+                    let provider = providers.get_provider(provider_id.as_ref().clone()).unwrap();
+                    if let Ok(link) = provider.as_filesystem().unwrap().read_link(id).await {
                         if link.is_directory() {
                             reply.data(link.as_str().as_bytes())
                         } else {
-                            dbg!(&link);
+                            debug!(link = link.as_str(), "readlink target");
                             if let Ok(data) = provider.as_filesystem().unwrap().read_file(link.clone()).await {
                                 reply.data(&data)
                             } else {
@@ -27,6 +45,7 @@ impl FuseFS {
                     } else {
                         reply.error(ENOENT);
                     }
+                    None
                 });
             }
         }
@@ -34,17 +53,25 @@ impl FuseFS {
 
     pub fn internal_symlink(
             &mut self,
-            _req: &Request<'_>,
+            req: &Caller,
             parent: u64,
             name: &OsStr,
             link: &std::path::Path,
             reply: ReplyEntry,
         ) {
-        println!("symlink: {}", link.to_str().unwrap());
+        debug!(parent, name = ?name, link = ?link, "symlink");
 
         if let Some(_) = self.tree.find_with_name(parent, name.to_str().unwrap()) {
             return reply.error(EEXIST);
         }
+
+        // refused before resolving the target, the provider couldn't store the link anyway
+        if let Some(parent_node) = self.tree.find_with_inode(parent) {
+            let provider_id = parent_node.lock().unwrap().provider_id.clone();
+            if !self.capabilities(&provider_id).symlinks {
+                return reply.error(EPERM);
+            }
+        }
         
         let mut absolute_link = link;
         let mut parent_inode = parent;
@@ -57,13 +84,22 @@ impl FuseFS {
 
         let mut it = absolute_link.into_iter().peekable();
 
-        while let Some(name) = it.next() {
-            let mut node_option = self.tree.find_with_name(parent_inode, name.to_str().unwrap());
+        while let Some(component) = it.next() {
+            let mut node_option = if parent_inode == 1 {
+                self.tree.find_provider(component.to_str().unwrap(), req.uid())
+            } else {
+                self.tree.find_with_name(parent_inode, component.to_str().unwrap())
+            };
             if node_option.is_none() {
                 if let Some(arc_node) = self.tree.find_with_inode(parent_inode) {
                     if let Ok(mut temp_node) = arc_node.lock() {
-                        self.get_children(&mut temp_node);
-                        node_option = self.tree.find_with_name(parent_inode, name.to_str().unwrap());
+                        // resolved again once the folder is listed
+                        if self.get_children(&mut temp_node).is_none() {
+                            drop(temp_node);
+                            let (req, name, link) = (*req, name.to_os_string(), link.to_path_buf());
+                            return self.wait(Awaited::Listing(parent_inode), move |fs, _| fs.internal_symlink(&req, parent, &name, &link, reply));
+                        }
+                        node_option = self.tree.find_with_name(parent_inode, component.to_str().unwrap());
                         if node_option.is_none() {
                             return reply.error(ENOENT);
                         }
@@ -84,22 +120,59 @@ impl FuseFS {
         }
 
         if let Some(parent_node) = self.tree.find_with_inode(parent) {
-            if let Ok(mut parent_node) = parent_node.lock() {
-                let provider = self.providers.get_provider(parent_node.provider_id.as_ref().clone()).unwrap();
-        
-                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
-    
-                rt.block_on(async {
-                    provider.as_filesystem().unwrap().create_link(parent_node.id.clone(), &name.to_str().unwrap(), link_id.unwrap()).await.unwrap();
-                }); 
+            if let Ok(parent_node) = parent_node.lock() {
+                if let Err(errno) = self.check_provider(&parent_node.provider_id) {
+                    return reply.error(errno);
+                }
+
+                let path = self.tree.path(parent).unwrap_or_default().join(name);
+                if let Err(errno) = self.check_policy(req, &parent_node.provider_id, Operation::Create, &path, 0) {
+                    return reply.error(errno);
+                }
 
-                self.fetch_children(&mut parent_node);
-                let node = self.tree.find_with_name(parent, name.to_str().unwrap());
+                let providers = match self.providers.get(&parent_node.provider_id) {
+                    Some(providers) => providers,
+                    None => return reply.error(ENETUNREACH),
+                };
+                self.quotas.record(&parent_node.provider_id);
 
-                return reply.entry(&TTL, &(node.unwrap().lock().unwrap().clone()).into(), 0);
+                // answered once its folder is listed again with the link
+                let (provider_id, parent_id, link_id) = (parent_node.provider_id.clone(), parent_node.id.clone(), link_id.unwrap());
+                let name = name.to_str().unwrap().to_string();
+                self.spawn_awaited(&[], async move {
+                    let provider = providers.get_provider(provider_id.as_ref().clone()).unwrap();
+                    let result = provider.as_filesystem().unwrap().create_link(parent_id, &name, link_id).await;
+                    let result = result.map(|_| ()).map_err(|error| format!("{:?}", error));
+                    Completion::Linked { parent, provider_id, name, result, reply }
+                });
+                return;
             }
         }
 
         return reply.error(ENOENT);
     }
+
+    pub fn apply_linked(&mut self, parent: u64, provider_id: &ProviderId, name: String, result: Result<(), String>, reply: ReplyEntry) {
+        if let Err(error) = result {
+            let errno = self.failed("symlink", parent, provider_id, error);
+            return reply.error(errno);
+        }
+
+        let parent_node = match self.tree.find_with_inode(parent) {
+            Some(parent_node) => parent_node,
+            None => return reply.error(ENOENT),
+        };
+        let listed = self.fetch_children(&mut parent_node.lock().unwrap());
+        match listed {
+            Some(_) => self.reply_link(parent, &name, reply),
+            None => self.wait(Awaited::Listing(parent), move |fs, _| fs.reply_link(parent, &name, reply)),
+        }
+    }
+
+    fn reply_link(&self, parent: u64, name: &str, reply: ReplyEntry) {
+        match self.tree.find_with_name(parent, name) {
+            Some(node) => self.reply_entry(reply, &node.lock().unwrap()),
+            None => reply.error(ENOENT),
+        }
+    }
 }
\ No newline at end of file
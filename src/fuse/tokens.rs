@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{chown, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::{KeyInit, OsRng};
+use chrono::{DateTime, TimeZone, Utc};
+use crossroads::storage::{ProviderId, ProviderType, ProvidersMap};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::config::ApiKeys;
+use super::{register, FuseFS, PROVIDER_INIT_TIMEOUT};
+use super::dispatch::Completion;
+
+const GOOGLE_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const GOOGLE_AUTH_URL: &str = "https://accounts.google.com/o/oauth2/v2/auth";
+// Google doesn't grant it to the device flow, Drive logs in through a browser coming back to
+// a port of the loopback interface instead.
+const GOOGLE_DRIVE_SCOPE: &str = "https://www.googleapis.com/auth/drive";
+const MICROSOFT_TOKEN_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/token";
+const MICROSOFT_DEVICE_CODE_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/devicecode";
+pub const ONEDRIVE_SCOPES: &str = "offline_access Files.ReadWrite.All User.Read";
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+// Access tokens are refreshed this long before they expire.
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+// Both providers' access tokens last an hour, when the credential file doesn't say when its
+// token expires it is refreshed this often.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(45 * 60);
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<i64>,
+    // Microsoft rotates it on every refresh, Google keeps the same one
+    refresh_token: Option<String>,
+}
+
+// What the user enters in a browser to let the login through.
+#[derive(Deserialize)]
+pub struct DeviceCode {
+    pub user_code: String,
+    // Microsoft names it verification_uri
+    #[serde(alias = "verification_uri")]
+    pub verification_url: String,
+    device_code: String,
+    // seconds the code stays valid
+    expires_in: u64,
+    // seconds between two polls of the token endpoint
+    #[serde(default = "default_poll_interval")]
+    interval: u64,
+}
+
+fn default_poll_interval() -> u64 {
+    5
+}
+
+#[derive(Deserialize)]
+struct DeviceError {
+    error: String,
+    error_description: Option<String>,
+}
+
+// The refreshers of the providers' tokens.
+#[derive(Default)]
+pub struct Refreshed {
+    // wakes a provider's refresher up before its token expires, e.g. when it was rejected
+    wake: HashMap<ProviderId, Arc<Notify>>,
+}
+
+impl FuseFS {
+    // One task per Drive and OneDrive provider, each refreshes its provider's token before it
+    // expires and writes the new one back to the credential file it was read from.
+    pub fn start_token_refresh(&mut self) {
+        let oauth: Vec<(ProviderId, PathBuf)> = self.credential_paths.iter()
+            .filter(|(provider_id, _)| matches!(provider_id.provider_type, ProviderType::GoogleDrive | ProviderType::OneDrive))
+            .map(|(provider_id, path)| (provider_id.clone(), path.clone()))
+            .collect();
+
+        for (provider_id, path) in oauth {
+            self.start_provider_refresh(provider_id, path);
+        }
+    }
+
+    pub fn start_provider_refresh(&mut self, provider_id: ProviderId, path: PathBuf) {
+        let wake = Arc::new(Notify::new());
+        self.refreshed.wake.insert(provider_id.clone(), wake.clone());
+        let task = self.runtime().spawn(refresh_loop(provider_id.clone(), path, self.api_keys.clone(), wake, self.dispatcher.sender()));
+        self.provider_tasks.entry(provider_id).or_default().push(task);
+    }
+
+    // The provider isn't mounted anymore, its token is left as it is.
+    pub fn forget_refresh(&mut self, provider_id: &ProviderId) {
+        self.refreshed.wake.remove(provider_id);
+    }
+
+    // A rejected token may only need a refresh, tried before asking for a new login.
+    pub fn refresh_token_now(&self, provider_id: &ProviderId) {
+        if let Some(wake) = self.refreshed.wake.get(provider_id) {
+            wake.notify_one();
+        }
+    }
+
+    // The refresher registered the provider with its new token in a map of its own, used from
+    // now on.
+    pub fn apply_refreshed_token(&mut self, provider_id: &ProviderId, credentials: Value, providers: Option<ProvidersMap>) {
+        // registered with these once it is reachable again
+        if let Some(offline) = self.offline.get_mut(provider_id) {
+            offline.credentials = credentials;
+            return;
+        }
+        // detached meanwhile
+        if !self.capabilities.contains_key(provider_id) {
+            return;
+        }
+
+        match providers {
+            Some(providers) => {
+                info!("provider {} got a new access token", provider_id.id);
+                self.providers.insert(providers);
+                self.reauth_required.remove(provider_id);
+            },
+            None => warn!("unable to register provider {} with its new token", provider_id.id),
+        }
+    }
+}
+
+async fn refresh_loop(provider_id: ProviderId, path: PathBuf, keys: ApiKeys, wake: Arc<Notify>, sender: Sender<Completion>) {
+    let provider_id = Arc::new(provider_id);
+    let mut failed = false;
+
+    loop {
+        // the file is read again every time, a login may have replaced it meanwhile
+        let delay = match failed {
+            true => RETRY_DELAY,
+            false => refresh_delay(&path),
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => (),
+            _ = wake.notified() => (),
+        }
+
+        match refresh(&provider_id, &path, &keys).await {
+            Ok(credentials) => {
+                let providers = tokio::time::timeout(PROVIDER_INIT_TIMEOUT, register(&keys, &provider_id, credentials.clone())).await.ok().flatten();
+                // tried again soon, the file already holds the new token
+                failed = providers.is_none();
+                let _ = sender.send(Completion::TokenRefreshed { provider_id: provider_id.clone(), credentials, providers });
+            },
+            Err(error) => {
+                failed = true;
+                warn!("unable to refresh the token of provider {}: {}", provider_id.id, error);
+            },
+        }
+    }
+}
+
+// Until the first of the file's tokens is about to expire.
+fn refresh_delay(path: &Path) -> Duration {
+    match token_expiry(path) {
+        Some(expires_at) => (expires_at - Utc::now()).to_std().unwrap_or_default().saturating_sub(REFRESH_MARGIN),
+        None => DEFAULT_REFRESH_INTERVAL,
+    }
+}
+
+// When the first of a credential file's tokens expires, if the file tells.
+pub fn token_expiry(path: &Path) -> Option<DateTime<Utc>> {
+    let content = fs::read_to_string(path).ok().and_then(|content| serde_json::from_str::<Value>(&content).ok())?;
+    tokens(&content).into_iter().filter_map(expires_at).min()
+}
+
+// Drive credential files hold a token by scope, OneDrive ones a single token.
+fn tokens(credentials: &Value) -> Vec<&Value> {
+    if credentials.get("refresh_token").is_some() {
+        return vec![credentials];
+    }
+    match credentials {
+        Value::Object(fields) => fields.values().filter(|value| value.get("refresh_token").is_some()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn tokens_mut(credentials: &mut Value) -> Vec<&mut Value> {
+    if credentials.get("refresh_token").is_some() {
+        return vec![credentials];
+    }
+    match credentials {
+        Value::Object(fields) => fields.values_mut().filter(|value| value.get("refresh_token").is_some()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// As an RFC 3339 date or seconds since the epoch.
+fn expires_at(token: &Value) -> Option<DateTime<Utc>> {
+    match token.get("expires_at")? {
+        Value::String(date) => DateTime::parse_from_rfc3339(date).ok().map(|date| date.with_timezone(&Utc)),
+        Value::Number(seconds) => Utc.timestamp_opt(seconds.as_i64()?, 0).single(),
+        _ => None,
+    }
+}
+
+// Written back the way the file had it.
+fn set_expires_at(token: &mut Value, expires_at: DateTime<Utc>) {
+    let value = match token.get("expires_at") {
+        Some(Value::Number(_)) => Value::from(expires_at.timestamp()),
+        _ => Value::from(expires_at.to_rfc3339()),
+    };
+    token["expires_at"] = value;
+}
+
+async fn refresh(provider_id: &ProviderId, path: &Path, keys: &ApiKeys) -> Result<Value, String> {
+    let content = tokio::fs::read_to_string(path).await.map_err(|error| error.to_string())?;
+    let mut credentials: Value = serde_json::from_str(&content).map_err(|error| error.to_string())?;
+
+    let client = reqwest::Client::new();
+    for token in tokens_mut(&mut credentials) {
+        let refresh_token = token["refresh_token"].as_str().ok_or("no refresh token")?.to_string();
+        let response = request_token(&client, &provider_id.provider_type, keys, &refresh_token).await?;
+
+        token["access_token"] = Value::from(response.access_token);
+        if let Some(refresh_token) = response.refresh_token {
+            token["refresh_token"] = Value::from(refresh_token);
+        }
+        if let Some(expires_in) = response.expires_in {
+            set_expires_at(token, Utc::now() + chrono::Duration::seconds(expires_in));
+        }
+    }
+
+    let content = serde_json::to_string_pretty(&credentials).map_err(|error| error.to_string())?;
+    write_credentials(path, &content).map_err(|error| format!("unable to save {}: {}", path.display(), error))?;
+
+    let (_, credentials) = FuseFS::parse_credentials(&format!("{:?}", provider_id.provider_type), &content).ok_or("unreadable refreshed credentials")?;
+    Ok(credentials)
+}
+
+async fn request_token(client: &reqwest::Client, provider_type: &ProviderType, keys: &ApiKeys, refresh_token: &str) -> Result<TokenResponse, String> {
+    let (url, mut form) = match provider_type {
+        ProviderType::GoogleDrive => (GOOGLE_TOKEN_URL, vec![("client_id", keys.google_drive.clone().ok_or("no Google Drive API key")?)]),
+        ProviderType::OneDrive => (MICROSOFT_TOKEN_URL, vec![
+            ("client_id", keys.onedrive.clone().ok_or("no OneDrive API key")?),
+            ("scope", ONEDRIVE_SCOPES.to_string()),
+        ]),
+        _ => return Err("the provider has no token".to_string()),
+    };
+    if let (ProviderType::GoogleDrive, Some(secret)) = (provider_type, &keys.google_drive_secret) {
+        form.push(("client_secret", secret.clone()));
+    }
+    form.push(("grant_type", "refresh_token".to_string()));
+    form.push(("refresh_token", refresh_token.to_string()));
+
+    let response = client.post(url).form(&form).send().await.map_err(|error| format!("{:?}", error))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("{}: {}", status, response.text().await.unwrap_or_default()));
+    }
+    response.json().await.map_err(|error| format!("{:?}", error))
+}
+
+// Replaces the credential file in one step, keeping its owner and permissions. The sandbox
+// only lets configured credential files be written in place.
+pub fn write_credentials(path: &Path, content: &str) -> io::Result<()> {
+    // not taken for a credential file if it is left behind
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    // private from the start, the token is never readable by others even for a moment
+    let _ = fs::remove_file(&partial);
+    let written = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&partial)
+        .and_then(|mut file| file.write_all(content.as_bytes()));
+    if written.is_err() {
+        let _ = fs::remove_file(&partial);
+        return fs::write(path, content);
+    }
+
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            fs::set_permissions(&partial, fs::Permissions::from_mode(metadata.mode() & 0o7777))?;
+            // only possible to another owner when running as root
+            let _ = chown(&partial, Some(metadata.uid()), Some(metadata.gid()));
+        },
+        Err(_) => fs::set_permissions(&partial, fs::Permissions::from_mode(0o600))?,
+    }
+    fs::rename(partial, path)
+}
+
+// First step of the device authorization flow used by OneDrive, the code is shown to the user.
+pub async fn request_device_code(client: &reqwest::Client, provider_type: &ProviderType, keys: &ApiKeys) -> Result<DeviceCode, String> {
+    let (url, form) = match provider_type {
+        ProviderType::OneDrive => (MICROSOFT_DEVICE_CODE_URL, [
+            ("client_id", keys.onedrive.clone().ok_or("no OneDrive API key")?),
+            ("scope", ONEDRIVE_SCOPES.to_string()),
+        ]),
+        _ => return Err("the provider has no token".to_string()),
+    };
+
+    let response = client.post(url).form(&form).send().await.map_err(|error| format!("{:?}", error))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("{}: {}", status, response.text().await.unwrap_or_default()));
+    }
+    response.json().await.map_err(|error| format!("{:?}", error))
+}
+
+// Polls the token endpoint until the user entered the code, tells the content of the
+// credential file as `FuseFS::parse_credentials` reads it.
+pub async fn await_device_token(client: &reqwest::Client, provider_type: &ProviderType, keys: &ApiKeys, code: &DeviceCode) -> Result<String, String> {
+    let (url, mut form) = match provider_type {
+        ProviderType::OneDrive => (MICROSOFT_TOKEN_URL, vec![("client_id", keys.onedrive.clone().ok_or("no OneDrive API key")?)]),
+        _ => return Err("the provider has no device login".to_string()),
+    };
+    form.push(("grant_type", DEVICE_CODE_GRANT.to_string()));
+    form.push(("device_code", code.device_code.clone()));
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(code.expires_in);
+    let mut interval = Duration::from_secs(code.interval);
+    let response: TokenResponse = loop {
+        tokio::time::sleep(interval).await;
+        if tokio::time::Instant::now() >= deadline {
+            return Err("the code expired before it was entered".to_string());
+        }
+
+        let response = client.post(url).form(&form).send().await.map_err(|error| format!("{:?}", error))?;
+        if response.status().is_success() {
+            break response.json().await.map_err(|error| format!("{:?}", error))?;
+        }
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        match serde_json::from_str::<DeviceError>(&text) {
+            Ok(error) if error.error == "authorization_pending" => continue,
+            // asked to poll 5 seconds slower from now on
+            Ok(error) if error.error == "slow_down" => interval += Duration::from_secs(5),
+            Ok(error) => return Err(error.error_description.unwrap_or(error.error)),
+            Err(_) => return Err(format!("{}: {}", status, text)),
+        }
+    };
+
+    credentials(provider_type, response)
+}
+
+// Where the browser is sent to log in to Drive, and the port it comes back to.
+pub struct LoopbackLogin {
+    pub url: String,
+    listener: TcpListener,
+    redirect_uri: String,
+    // tells the answer to this login from any other request reaching the port
+    state: String,
+}
+
+// First step of the loopback flow, the URL is shown to the user.
+pub async fn start_loopback_login(provider_type: &ProviderType, keys: &ApiKeys) -> Result<LoopbackLogin, String> {
+    let client_id = match provider_type {
+        ProviderType::GoogleDrive => keys.google_drive.clone().ok_or("no Google Drive API key")?,
+        _ => return Err("the provider has no loopback login".to_string()),
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.map_err(|error| error.to_string())?;
+    let redirect_uri = format!("http://127.0.0.1:{}", listener.local_addr().map_err(|error| error.to_string())?.port());
+    let state: String = XChaCha20Poly1305::generate_key(&mut OsRng).iter().map(|byte| format!("{:02x}", byte)).collect();
+    let url = reqwest::Url::parse_with_params(GOOGLE_AUTH_URL, &[
+        ("client_id", client_id.as_str()),
+        ("redirect_uri", redirect_uri.as_str()),
+        ("response_type", "code"),
+        ("scope", GOOGLE_DRIVE_SCOPE),
+        // a refresh token is only granted offline, and again on every login with consent
+        ("access_type", "offline"),
+        ("prompt", "consent"),
+        ("state", state.as_str()),
+    ]).map_err(|error| error.to_string())?;
+
+    Ok(LoopbackLogin { url: url.to_string(), listener, redirect_uri, state })
+}
+
+// Waits for the browser to come back with the code and trades it for a token, tells the
+// content of the credential file as `FuseFS::parse_credentials` reads it.
+pub async fn await_loopback_token(client: &reqwest::Client, provider_type: &ProviderType, keys: &ApiKeys, login: LoopbackLogin) -> Result<String, String> {
+    let code = loop {
+        let (mut stream, _) = login.listener.accept().await.map_err(|error| error.to_string())?;
+        let mut request = Vec::new();
+        let mut buffer = [0; 4096];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 64 * 1024 {
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => request.extend_from_slice(&buffer[..read]),
+            }
+        }
+
+        // GET /?state=...&code=... HTTP/1.1
+        let request = String::from_utf8_lossy(&request);
+        let target = request.split_whitespace().nth(1).unwrap_or("/");
+        let query: HashMap<String, String> = reqwest::Url::parse(&format!("http://127.0.0.1{}", target))
+            .map(|url| url.query_pairs().into_owned().collect())
+            .unwrap_or_default();
+        if query.get("state") != Some(&login.state) {
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n").await;
+            continue;
+        }
+
+        let answer = match query.get("error") {
+            Some(_) => "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\nThe login was refused, this window can be closed.",
+            None => "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\nLogged in, this window can be closed.",
+        };
+        let _ = stream.write_all(answer.as_bytes()).await;
+        match (query.get("code"), query.get("error")) {
+            (Some(code), _) => break code.clone(),
+            (None, Some(error)) => return Err(format!("the login was refused: {}", error)),
+            (None, None) => return Err("the browser came back without a code".to_string()),
+        }
+    };
+
+    let mut form = vec![
+        ("client_id", keys.google_drive.clone().ok_or("no Google Drive API key")?),
+        ("code", code),
+        ("grant_type", "authorization_code".to_string()),
+        ("redirect_uri", login.redirect_uri.clone()),
+    ];
+    if let Some(secret) = &keys.google_drive_secret {
+        form.push(("client_secret", secret.clone()));
+    }
+
+    let response = client.post(GOOGLE_TOKEN_URL).form(&form).send().await.map_err(|error| format!("{:?}", error))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("{}: {}", status, response.text().await.unwrap_or_default()));
+    }
+    let response: TokenResponse = response.json().await.map_err(|error| format!("{:?}", error))?;
+    credentials(provider_type, response)
+}
+
+fn credentials(provider_type: &ProviderType, response: TokenResponse) -> Result<String, String> {
+    let refresh_token = response.refresh_token.ok_or("no refresh token was granted")?;
+    let expires_at = Utc::now() + chrono::Duration::seconds(response.expires_in.unwrap_or(3600));
+    let token = serde_json::json!({
+        "access_token": response.access_token,
+        "refresh_token": refresh_token,
+        "expires_at": expires_at.to_rfc3339(),
+    });
+    let credentials = match provider_type {
+        ProviderType::GoogleDrive => serde_json::json!({ GOOGLE_DRIVE_SCOPE: token }),
+        _ => token,
+    };
+
+    let content = serde_json::to_string_pretty(&credentials).map_err(|error| error.to_string())?;
+    FuseFS::parse_credentials(&format!("{:?}", provider_type), &content).ok_or("the granted token can't be read back")?;
+    Ok(content)
+}
\ No newline at end of file
@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crossroads::interfaces::filesystem::{File, ObjectId};
+use crossroads::storage::ProviderId;
+use futures::stream::{self, StreamExt};
+use libc::{c_int, EAGAIN, ENOENT};
+use tracing::warn;
+
+use crate::fstree::{FileState, FsNode};
+use crate::telemetry;
+use super::FuseFS;
+use super::dispatch::Completion;
+use super::errors::is_auth_error;
+
+// Directories listed at the same time while crawling a tree. crossroads has no recursive
+// listing, so the next best thing is to keep several listing requests in flight.
+const PARALLEL_LISTINGS: usize = 8;
+// A crawl stops there, the directories left are crawled by the next walk.
+const MAX_CRAWL_LISTINGS: usize = 2000;
+
+// Ends the answers of walks that found directories missing from the tree.
+pub const CRAWLING_NOTE: &str = "some folders are still being listed, ask again for complete results\n";
+
+// A directory listed by a crawl, or why it couldn't be.
+pub type Listing = (Arc<ProviderId>, ObjectId, Result<Vec<File>, String>);
+
+// Directories whose subtree is being listed by a crawl, by inode.
+#[derive(Default)]
+pub struct Crawls(HashSet<u64>);
+
+impl FuseFS {
+    // Visits every node below `root` the tree holds, one level at a time. Directories never
+    // listed or listed too long ago are crawled in the background, the walks coming after it
+    // see what they hold. Stops as soon as `visit` returns false, and tells whether nothing was
+    // missing from the tree.
+    pub fn walk(&mut self, root: Arc<Mutex<FsNode>>, visit: &mut dyn FnMut(&FsNode) -> bool) -> bool {
+        // each directory goes with whether one above it is crawled, which lists it too
+        let mut level = vec![(root, false)];
+        let mut stale = Vec::new();
+
+        while !level.is_empty() {
+            let mut next = Vec::new();
+
+            for (dir, crawled) in level {
+                let node = dir.lock().unwrap();
+                let fresh = node.content_state == FileState::DeepReady && node.expire_at.map_or(false, |expire_at| expire_at > SystemTime::now());
+                let missing = !fresh && !self.is_mount_point(&node);
+                if missing && !crawled {
+                    stale.push((node.inode, node.provider_id.clone(), node.id.clone()));
+                }
+                let children = node.children.clone();
+                drop(node);
+
+                for child in children {
+                    let node = child.lock().unwrap();
+                    if !visit(&node) {
+                        return true;
+                    }
+
+                    if node.id.is_directory() {
+                        drop(node);
+                        next.push((child, crawled || missing));
+                    }
+                }
+            }
+
+            level = next;
+        }
+
+        let complete = stale.is_empty();
+        self.crawl(stale);
+        complete
+    }
+
+    // Lists directories and everything below them from a task, so the FUSE thread never waits
+    // for a walk. The listings reach the tree when the crawl is over.
+    fn crawl(&mut self, dirs: Vec<(u64, Arc<ProviderId>, ObjectId)>) {
+        let mut roots = Vec::new();
+        let mut delay = Duration::ZERO;
+
+        for (inode, provider_id, id) in dirs {
+            if self.crawls.0.contains(&inode) || self.check_provider(&provider_id).is_err() {
+                continue;
+            }
+
+            // crawls are background work, they back off as the API budget runs out and leave
+            // the rest of it to interactive operations
+            match self.quotas.background_delay(&provider_id) {
+                Some(provider_delay) => {
+                    delay = delay.max(provider_delay);
+                    roots.push((inode, provider_id, id));
+                },
+                None => warn!("walk: {} is low on API quota, not listing {}", provider_id.id, id.as_str()),
+            }
+        }
+
+        if roots.is_empty() {
+            return;
+        }
+
+        self.crawls.0.extend(roots.iter().map(|(inode, _, _)| *inode));
+        let providers = self.providers.clone();
+        self.spawn(async move {
+            let inodes = roots.iter().map(|(inode, _, _)| *inode).collect();
+            let mut level: Vec<_> = roots.into_iter().map(|(_, provider_id, id)| (provider_id, id)).collect();
+            let mut listings: Vec<Listing> = Vec::new();
+
+            while !level.is_empty() && listings.len() < MAX_CRAWL_LISTINGS {
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+
+                let results = stream::iter(level.iter().map(|(provider_id, id)| {
+                    let providers = providers.get(provider_id);
+                    let trace = telemetry::provider_request(provider_id, "read_directory", id);
+                    async move {
+                        match providers.as_ref().and_then(|providers| providers.get_provider(provider_id.as_ref().clone())) {
+                            Some(provider) => {
+                                let listing = provider.as_filesystem().unwrap().read_directory(id.clone()).await;
+                                trace.finish(&listing, 0);
+                                listing.map_err(|error| format!("{:?}", error))
+                            },
+                            None => Err("the provider was detached".to_string()),
+                        }
+                    }
+                }))
+                .buffered(PARALLEL_LISTINGS)
+                .collect::<Vec<_>>()
+                .await;
+
+                let mut next = Vec::new();
+                for ((provider_id, id), result) in level.into_iter().zip(results) {
+                    if let Ok(files) = &result {
+                        next.extend(files.iter().filter(|file| file.id.is_directory()).map(|file| (provider_id.clone(), file.id.clone())));
+                    }
+                    listings.push((provider_id, id, result));
+                }
+                level = next;
+            }
+
+            Some(Completion::Crawled { roots: inodes, listings })
+        });
+    }
+
+    // Parents come before their children in the listings, each directory is in the tree by
+    // the time its listing is applied.
+    pub fn apply_crawl(&mut self, roots: Vec<u64>, listings: Vec<Listing>) {
+        for inode in roots {
+            self.crawls.0.remove(&inode);
+        }
+
+        for (provider_id, id, listing) in listings {
+            self.quotas.record(&provider_id);
+            let dir = match self.tree.find_with_ids(id, provider_id.as_ref().clone()) {
+                Some(dir) => dir,
+                None => continue,
+            };
+
+            match listing {
+                Ok(files) => self.apply_listing(&mut dir.lock().unwrap(), files),
+                Err(error) if is_auth_error(&error) => self.require_reauth(&provider_id),
+                Err(error) => {
+                    let inode = dir.lock().unwrap().inode;
+                    self.fail("walk", inode, &provider_id, error);
+                },
+            }
+        }
+    }
+
+    // Finds a node from its path on the mount, e.g. `/GoogleDrive/Documents`. EAGAIN while a
+    // folder on the way is listed, the control write is made again once it is.
+    pub fn resolve(&mut self, path: &Path, uid: u32) -> Result<Arc<Mutex<FsNode>>, c_int> {
+        let mut components = path.iter().filter(|component| *component != "/");
+        let provider = components.next().and_then(|component| component.to_str()).ok_or(ENOENT)?;
+        let mut node = self.tree.find_provider(provider, uid).ok_or(ENOENT)?;
+
+        for name in components {
+            let name = name.to_str().ok_or(ENOENT)?;
+            let parent = node.lock().unwrap().inode;
+
+            node = match self.tree.find_with_name(parent, name) {
+                Some(child) => child,
+                None => {
+                    let listed = self.get_children(&mut node.lock().unwrap());
+                    if listed.is_none() {
+                        self.dispatcher.set_resolving(Some(parent));
+                        return Err(EAGAIN);
+                    }
+                    self.tree.find_with_name(parent, name).ok_or(ENOENT)?
+                },
+            };
+        }
+
+        Ok(node)
+    }
+}
\ No newline at end of file
@@ -0,0 +1,202 @@
+use std::ffi::OsStr;
+use libc::{c_int, EINVAL, EIO, ENETUNREACH, ENODATA, ENOENT, ENOTSUP, ERANGE};
+
+use fuser::{ReplyEmpty, ReplyXattr};
+use crossroads::interfaces::filesystem::ObjectId;
+use crossroads::storage::{ProviderId, ProviderType};
+use tracing::warn;
+
+use crate::fstree::FsNode;
+use crate::telemetry;
+use super::FuseFS;
+use super::breaker;
+use super::dispatch::{Caller, Completion};
+use super::node::content_version;
+
+// What the provider knows about a file, read from the tree without any request.
+const ID_XATTR: &str = "user.crossroads.id";
+const PROVIDER_XATTR: &str = "user.crossroads.provider";
+const WEB_URL_XATTR: &str = "user.crossroads.weburl";
+const MIME_TYPE_XATTR: &str = "user.crossroads.mime_type";
+// Why the files of a provider that stopped answering are served from the cache.
+const STATUS_XATTR: &str = "user.crossroads.status";
+// Set to 1 to download a file into the content cache and keep it there, 0 to let it be evicted.
+pub const PINNED_XATTR: &str = "user.crossroads.pinned";
+
+// crossroads doesn't hand out the thumbnails the providers generate, so there is no
+// thumbnail attribute: file managers build their previews from the content as they would anyway.
+
+// getxattr and listxattr are first called with a size of 0 to learn how big the buffer must be.
+fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+// The provider attributes a node has, by name.
+fn provider_xattrs(node: &FsNode) -> Vec<(&'static str, String)> {
+    let mut xattrs = vec![
+        (ID_XATTR, node.id.as_str().to_string()),
+        (PROVIDER_XATTR, node.provider_id.id.clone()),
+    ];
+    if let Some(web_url) = &node.web_url {
+        xattrs.push((WEB_URL_XATTR, web_url.clone()));
+    }
+    if let Some(mime_type) = &node.mime_type {
+        xattrs.push((MIME_TYPE_XATTR, mime_type.clone()));
+    }
+    if let Some(status) = breaker::status(&node.provider_id.id) {
+        xattrs.push((STATUS_XATTR, status));
+    }
+
+    xattrs
+}
+
+impl FuseFS {
+    pub fn internal_getxattr(&mut self, req: &Caller, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let node = match self.tree.find_with_inode(ino) {
+            Some(node) => node,
+            None => return reply.error(ENOENT),
+        };
+        let node = node.lock().unwrap();
+
+        if !node.visible_to(req.uid()) {
+            return reply.error(ENOENT);
+        }
+
+        if let Some((_, value)) = provider_xattrs(&node).into_iter().find(|(xattr, _)| name == *xattr) {
+            return reply_xattr(value.as_bytes(), size, reply);
+        }
+
+        if name == PINNED_XATTR && self.is_pinned(&node) {
+            return reply_xattr(b"1", size, reply);
+        }
+
+        reply.error(ENODATA);
+    }
+
+    pub fn internal_listxattr(&mut self, req: &Caller, ino: u64, size: u32, reply: ReplyXattr) {
+        let node = match self.tree.find_with_inode(ino) {
+            Some(node) => node,
+            None => return reply.error(ENOENT),
+        };
+        let node = node.lock().unwrap();
+
+        if !node.visible_to(req.uid()) {
+            return reply.error(ENOENT);
+        }
+
+        let mut names = Vec::new();
+        for (xattr, _) in provider_xattrs(&node) {
+            names.extend_from_slice(xattr.as_bytes());
+            names.push(0);
+        }
+        if self.is_pinned(&node) {
+            names.extend_from_slice(PINNED_XATTR.as_bytes());
+            names.push(0);
+        }
+
+        reply_xattr(&names, size, reply);
+    }
+
+    pub fn internal_setxattr(&mut self, req: &Caller, ino: u64, name: &OsStr, value: &[u8], reply: ReplyEmpty) {
+        if name != PINNED_XATTR {
+            return reply.error(ENOTSUP);
+        }
+
+        let pinned = match value {
+            b"1" => true,
+            b"0" => false,
+            _ => return reply.error(EINVAL),
+        };
+
+        match self.pin(req, ino, pinned) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    pub fn internal_removexattr(&mut self, req: &Caller, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        if name != PINNED_XATTR {
+            return reply.error(ENODATA);
+        }
+
+        match self.pin(req, ino, false) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn is_pinned(&self, node: &FsNode) -> bool {
+        self.content_cache.as_ref().map_or(false, |cache| cache.is_pinned(&node.provider_id.id, node.id.as_str()))
+    }
+
+    // A pinned file is downloaded right away if it isn't cached yet, by a task so the caller
+    // doesn't wait for it. Local files are always there, pinning them does nothing.
+    fn pin(&mut self, req: &Caller, ino: u64, pinned: bool) -> Result<(), c_int> {
+        let node = self.tree.find_with_inode(ino).ok_or(ENOENT)?;
+        let node = node.lock().unwrap();
+
+        if !node.visible_to(req.uid()) {
+            return Err(ENOENT);
+        }
+        if node.id.is_directory() {
+            return Err(ENOTSUP);
+        }
+        if node.provider_id.provider_type == ProviderType::NativeFs {
+            return Ok(());
+        }
+        if self.content_cache.is_none() {
+            return Err(ENOTSUP);
+        }
+
+        if pinned {
+            let version = content_version(&node).ok_or(EIO)?;
+            if !self.content_cache.as_ref().unwrap().contains(&node.provider_id.id, node.id.as_str(), &version) {
+                self.check_provider(&node.provider_id)?;
+                let providers = self.providers.get(&node.provider_id).ok_or(ENETUNREACH)?;
+                self.quotas.record(&node.provider_id);
+
+                let path = self.tree.path(ino).unwrap_or_default();
+                let transfer = self.transfers.start(path.display().to_string(), false, node.metadata.map_or(0, |metadata| metadata.size));
+                let (provider_id, object) = (node.provider_id.clone(), node.id.clone());
+                let trace = telemetry::provider_request(&node.provider_id, "read_file", &node.id);
+                self.spawn(async move {
+                    let provider = providers.get_provider(provider_id.as_ref().clone()).unwrap();
+                    let result = provider.as_filesystem().unwrap().read_file(object.clone()).await;
+                    trace.finish(&result, result.as_ref().map_or(0, |data| data.len() as u64));
+                    let result = result.map_err(|error| format!("{:?}", error));
+                    Some(Completion::Pinned { inode: ino, provider_id, object, version, transfer, result })
+                });
+            }
+        }
+
+        // pinned before the download ends, the content it brings is kept
+        self.content_cache.as_ref().unwrap().set_pinned(&node.provider_id.id, node.id.as_str(), pinned).map_err(|error| {
+            warn!("unable to pin {}: {}", node.name, error);
+            EIO
+        })
+    }
+
+    // The download of a pinned file ended. A failed one leaves the file pinned, the next read
+    // brings it into the cache.
+    pub fn apply_pinned(&mut self, inode: u64, provider_id: &ProviderId, object: &ObjectId, version: &str, transfer: u64, result: Result<Vec<u8>, String>) {
+        self.transfers.finish(transfer, result.as_ref().map_or(0, |data| data.len() as u64));
+        let data = match result {
+            Ok(data) => data,
+            Err(error) => {
+                self.failed("pin", inode, provider_id, error);
+                return;
+            },
+        };
+        if let Some(cache) = &self.content_cache {
+            if let Err(error) = cache.put(&provider_id.id, object.as_str(), version, &data) {
+                self.fail("pin", inode, provider_id, error.to_string());
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,31 @@
+// set up what the daemon and the subcommands log, and where
+// Path: src/logging.rs
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use tracing_subscriber::EnvFilter;
+
+// Directives as for RUST_LOG, by module, e.g. `warn,fuse::fuse::journal=debug`. Every FUSE
+// operation is a span carrying its name and inode, `fuse::fuse=debug` logs each call.
+pub const MOUNT_FILTER: &str = "info";
+// subcommands print their own output, only what goes wrong is logged, and what sync and
+// bisync do to the files
+pub const COMMAND_FILTER: &str = "warn,fuse::sync=info,fuse::bisync=info";
+
+// Logs go to stderr, or are appended to `file` without colors.
+pub fn init(filter: &str, file: Option<&Path>) -> io::Result<()> {
+    let filter = EnvFilter::try_new(filter).map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error.to_string()))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            builder.with_ansi(false).with_writer(Mutex::new(file)).init();
+        },
+        None => builder.with_writer(io::stderr).init(),
+    }
+
+    Ok(())
+}
\ No newline at end of file
@@ -1,20 +1,295 @@
-use std::path::Path;
+use std::path::PathBuf;
+
+use clap::Parser;
 
 use crossroads::storage::*;
+use tracing::{error, info, warn};
 
+mod api_log;
+mod bisync;
+mod blocks;
+mod cache;
+mod commands;
+mod config;
+mod control_socket;
+mod daemon;
+mod dbus;
 mod fuse;
+mod logging;
 mod mount;
 mod fstree;
+mod notifications;
+mod privileges;
+mod sandbox;
+mod schedule;
+mod state;
+mod sync;
+mod systemd;
+mod telemetry;
+mod throttle;
+mod vault;
+
+#[derive(Debug, Parser)]
+#[command(name = "fuse-mount", about = "Mount cloud storage providers as a single filesystem", args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<commands::Command>,
+    #[arg(help = "Directory to mount the providers on, created if missing")]
+    mountpoint: Option<PathBuf>,
+    #[arg(long, env = "ORBITAL_MOUNT", global = true, help = "Mount point the subcommands talk to")]
+    mount: Option<PathBuf>,
+    #[arg(short, long, conflicts_with = "daemon", help = "Stay attached to the terminal, the default")]
+    foreground: bool,
+    #[arg(long, alias = "background", help = "Detach from the terminal once mounted")]
+    daemon: bool,
+    #[arg(long, value_name = "FILE", help = "Write the daemon's pid to this file, in the runtime directory by default with --daemon")]
+    pid_file: Option<PathBuf>,
+    #[arg(long, env = "ORBITAL_PROFILE", value_name = "NAME", help = "Mount the providers and settings of a [profile.NAME] section of the configuration")]
+    profile: Option<String>,
+    #[arg(long, value_name = "NAME", help = "Mount this provider's files at the root of the mount point instead of a folder by provider")]
+    provider: Option<String>,
+    #[arg(long, value_name = "USER", help = "Mount as root on behalf of this user")]
+    uid_owner: Option<String>,
+    #[arg(long, value_name = "USERS", value_delimiter = ',', help = "Other users whose own providers are served by the mount")]
+    multi_user: Vec<String>,
+    #[arg(long, help = "Delete for good instead of moving to the .Trash folder the mount keeps at the provider root")]
+    permanent_delete: bool,
+    #[arg(long, help = "Let rmdir delete non-empty Drive and OneDrive folders in one request")]
+    recursive_rmdir: bool,
+    #[arg(long, value_name = "FILES")]
+    delete_guard_files: Option<usize>,
+    #[arg(long, value_name = "BYTES")]
+    delete_guard_bytes: Option<u64>,
+    #[arg(short = 'o', value_name = "OPTIONS", help = "Comma separated mount options, as for mount(8)")]
+    options: Vec<String>,
+    #[arg(long, help = "Let other users reach the mount, as -o allow_other")]
+    allow_other: bool,
+    #[arg(long, help = "Refuse every change to the files, as -o ro")]
+    read_only: bool,
+    #[arg(long, help = "Let the kernel check the permissions of the files, as -o default_permissions, the default when other users reach the mount")]
+    default_permissions: bool,
+    #[arg(long, conflicts_with = "default_permissions", help = "Let other users reach the mount without checking the permissions of the files")]
+    no_default_permissions: bool,
+    #[arg(long, value_name = "FILES", default_value_t = 4, help = "Files uploaded at once to each provider when flushing")]
+    upload_parallelism: usize,
+    #[arg(long, help = "Record redacted provider API calls in the cache directory")]
+    debug_api: bool,
+    #[arg(long, help = "Encrypt the content cache")]
+    encrypt_cache: bool,
+    #[arg(long, help = "Don't confine the daemon with landlock and seccomp once mounted")]
+    no_sandbox: bool,
+    #[arg(long, help = "Serve the mount status and re-authentication requests on the session bus")]
+    dbus: bool,
+    #[arg(long, help = "Accept JSON commands on a unix socket next to the mount point")]
+    control_socket: bool,
+    #[arg(long, env = "ORBITAL_VAULT_KEY_FILE", value_name = "FILE", help = "File holding the passphrase of the encrypted credential store, asked on the terminal otherwise")]
+    vault_key_file: Option<PathBuf>,
+    #[arg(long, env = "ORBITAL_LOG", global = true, value_name = "FILTER", help = "What is logged, by module, e.g. warn,fuse::fuse::journal=debug")]
+    log: Option<String>,
+    #[arg(long, env = "ORBITAL_LOG_FILE", global = true, value_name = "FILE", help = "Append the logs to this file instead of stderr")]
+    log_file: Option<PathBuf>,
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT", value_name = "URL")]
+    otlp_endpoint: Option<String>,
+}
 
 fn main() {
-    let options = ProvidersOptions {
-        google_api_key: Some(env!("GOOGLE_DRIVE_CLIENT_KEY").to_string()),
-        onedrive_api_key: Some(env!("ONEDRIVE_CLIENT_ID").to_string())
+    let cli = Cli::parse();
+    let filter = cli.log.as_deref().unwrap_or(if cli.command.is_some() { logging::COMMAND_FILTER } else { logging::MOUNT_FILTER });
+    if let Err(error) = logging::init(filter, cli.log_file.as_deref()) {
+        eprintln!("unable to set up logging: {}", error);
+        std::process::exit(2);
+    }
+
+    if let Some(command) = cli.command {
+        if let Err(error) = commands::run(command, cli.mount.as_deref()) {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // `setuid=USER` is how fstab entries and mount units ask for it
+    let owner = match cli.uid_owner.clone().or_else(|| cli.options.iter().filter_map(|options| mount::option_value(options, "setuid")).last()) {
+        Some(user) => match privileges::Owner::lookup(&user) {
+            Ok(owner) => Some(owner),
+            Err(error) => {
+                eprintln!("unable to find the owner: {}", error);
+                std::process::exit(1);
+            },
+        },
+        None => None,
+    };
+    let users = match cli.multi_user.iter().map(|user| privileges::Owner::lookup(user)).collect::<Result<Vec<_>, _>>() {
+        Ok(users) => users,
+        Err(error) => {
+            eprintln!("unable to find a user of the mount: {}", error);
+            std::process::exit(1);
+        },
     };
 
-    let mut fs = None;
+    if let Some(owner) = &owner {
+        if !privileges::is_root() {
+            eprintln!("--uid-owner can only be used when mounting as root");
+            std::process::exit(1);
+        }
+        // load the owner's configuration, credentials and data instead of root's
+        std::env::set_var("HOME", &owner.home);
+    }
+
+    let (uid, gid) = match &owner {
+        Some(owner) => (owner.uid, owner.gid),
+        None => unsafe { (libc::getuid(), libc::getgid()) },
+    };
+
+    let config = match &cli.profile {
+        Some(profile) => match config::Config::load().with_profile(profile) {
+            Ok(config) => config,
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(2);
+            },
+        },
+        None => config::Config::load(),
+    };
+
+    // the journal and unflushed writes of a profile are its own, one mount at a time uses them
+    if let Some(profile) = &cli.profile {
+        state::set_profile(profile);
+    }
 
-    let mount_point = Path::new("../tmp/fuse/mnt");
+    let mount_point = match cli.mountpoint.or_else(|| config.mount.mountpoint.clone()) {
+        Some(mount_point) => mount_point,
+        None => {
+            eprintln!("a mount point is required, see --help");
+            std::process::exit(2);
+        },
+    };
+
+    // checked before anything is loaded, the directory must exist for the tree to be built
+    let mountpoint = match mount::Mount::new(&mount_point) {
+        Ok(mountpoint) => mountpoint,
+        Err(error) => {
+            eprintln!("unable to mount on {}: {}", mount_point.display(), error);
+            std::process::exit(1);
+        },
+    };
+
+    // asked before detaching from the terminal the passphrase may be typed in
+    let vault = match directories::ProjectDirs::from("", "Orbital", "Files").map(|proj_dirs| vault::Vault::path(proj_dirs.data_dir())).filter(|path| path.exists()) {
+        Some(path) => match vault::passphrase(cli.vault_key_file.as_deref(), "passphrase of the credential store: ").and_then(|passphrase| vault::Vault::open(&path, &passphrase)) {
+            Ok(vault) => vault,
+            Err(error) => {
+                eprintln!("unable to open {}: {}", path.display(), error);
+                std::process::exit(1);
+            },
+        },
+        None => vault::Vault::default(),
+    };
+
+    // before any thread or runtime exists, forking would lose them
+    let readiness = match cli.daemon && !cli.foreground {
+        true => match daemon::detach() {
+            Ok(readiness) => Some(readiness),
+            Err(error) => {
+                eprintln!("unable to detach: {}", error);
+                std::process::exit(1);
+            },
+        },
+        false => None,
+    };
+
+    let pid_file = cli.pid_file.clone().or_else(|| readiness.as_ref().and_then(|_| daemon::default_pid_file(cli.profile.as_deref())));
+    if let Some(path) = &pid_file {
+        match daemon::write_pid_file(path) {
+            Ok(()) => {
+                // removed on unmount by the owner of a mount made by root
+                let _ = std::os::unix::fs::chown(path, Some(uid), Some(gid));
+            },
+            Err(error) => warn!("unable to write the pid to {}: {}", path.display(), error),
+        }
+    }
+
+    // Everything written from here until the mount belongs to the owner of a mount made by
+    // root, the daemon couldn't use it once the privileges are dropped otherwise.
+    if let Some(owner) = &owner {
+        if let Err(error) = owner.assume() {
+            eprintln!("unable to act as the owner: {}", error);
+            std::process::exit(1);
+        }
+    }
+
+    let _state_lock = match state::lock() {
+        Ok(lock) => lock,
+        Err(error) => {
+            eprintln!("unable to lock the mount's state: {}", error);
+            std::process::exit(1);
+        },
+    };
+
+    let options = config.api_keys.providers_options();
+
+    // the command line options come last so they override the configured ones
+    let option_lists: Vec<String> = config.mount.options.iter().chain(cli.options.iter()).cloned().collect();
+    let (attr_uid, attr_gid, modes) = match mount::ownership(&option_lists, uid, gid) {
+        Ok(ownership) => ownership,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(2);
+        },
+    };
+    let mut mount_options: Vec<_> = option_lists.iter().flat_map(|options| mount::parse_options(options)).collect();
+    if cli.allow_other {
+        mount_options.push(fuser::MountOption::AllowOther);
+    }
+    if cli.read_only {
+        mount_options.push(fuser::MountOption::RO);
+    }
+    if cli.default_permissions {
+        mount_options.push(fuser::MountOption::DefaultPermissions);
+    }
+    let read_only = mount::is_read_only(&mount_options);
+
+    if let Some(endpoint) = &cli.otlp_endpoint {
+        if let Err(error) = telemetry::init(endpoint) {
+            warn!("unable to export traces to {}: {}", endpoint, error);
+        }
+    }
+
+    if cli.debug_api {
+        // the cache directory stays writable once the daemon is sandboxed
+        if let Some(proj_dirs) = directories::ProjectDirs::from("", "Orbital", "Files") {
+            let path = proj_dirs.cache_dir().join("debug-api.log");
+            match api_log::enable(path.clone()) {
+                Ok(()) => info!("recording provider API calls to {}", path.display()),
+                Err(error) => warn!("unable to record provider API calls to {}: {}", path.display(), error),
+            }
+        }
+    }
+
+    // read before the sandbox closes the data directory, the key lives there
+    let cache_config = config.cache.clone().unwrap_or_else(cache::CacheConfig::load);
+    let cache_dir = cache_config.dir.clone();
+    let (chunk_size, memory_size, read_ahead) = (cache_config.chunk_size, cache_config.memory_size, cache_config.read_ahead);
+    let content_cache = match cache_config.disk {
+        // files are then only kept in memory
+        false => Ok(None),
+        true => match cache::ContentCache::new(cache_config) {
+            Ok(cache) if cli.encrypt_cache => directories::ProjectDirs::from("", "Orbital", "Files")
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no data directory"))
+                .and_then(|proj_dirs| cache.with_encryption(proj_dirs.data_dir()))
+                .map(Some),
+            cache => cache.map(Some),
+        },
+    };
+    let content_cache = match content_cache {
+        Ok(cache) => cache,
+        Err(error) => {
+            warn!("unable to use the content cache in {}: {}", cache_dir.display(), error);
+            None
+        },
+    };
+
+    let mut fs = None;
 
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -23,10 +298,113 @@ fn main() {
         .block_on(async {
             let providers: ProvidersMap = ProvidersMap::new(options).await;
         
-            fs = Some(fuse::FuseFS::new(providers, &mount_point).await);
+            let mut filesystem = fuse::FuseFS::new(providers, &mount_point, uid, gid, &users, &config, &vault).await
+                .with_block_cache(chunk_size, memory_size, read_ahead);
+            if let Some(content_cache) = content_cache {
+                filesystem = filesystem.with_content_cache(content_cache);
+            }
+
+            if let Some(name) = &cli.provider {
+                filesystem = match filesystem.with_single_provider(name) {
+                    Ok(filesystem) => filesystem,
+                    Err(error) => {
+                        error!("{}", error);
+                        std::process::exit(1);
+                    },
+                };
+            }
+
+            fs = Some(filesystem
+                .with_permanent_delete(cli.permanent_delete)
+                .with_upload_parallelism(cli.upload_parallelism)
+                .with_recursive_rmdir(cli.recursive_rmdir)
+                .with_read_only(read_only)
+                .with_ownership(attr_uid, attr_gid, modes)
+                .with_deletion_guard(cli.delete_guard_files, cli.delete_guard_bytes)
+                .with_policy(fuse::Policy::load())
+                .with_mime_map(fuse::MimeMap::load()));
         });
 
-    let mountpoint = mount::Mount::new(&mount_point);
+    // mounting needs root, the privileges are dropped for good once mounted
+    if owner.is_some() {
+        if let Err(error) = privileges::restore_root() {
+            eprintln!("unable to act as root again: {}", error);
+            std::process::exit(1);
+        }
+    }
+
+    let scheduler = schedule::Scheduler::load();
+    let mut sandbox = sandbox::Sandbox::new();
+    sandbox.allow_read_write(&cache_dir);
+    // the pid file is removed on unmount
+    if let Some(parent) = pid_file.as_ref().and_then(|path| path.parent()) {
+        sandbox.allow_read_write(parent);
+    }
+    // The "Local files" provider serves the home directory, it is its root as the configured
+    // roots below are theirs. local_files = false keeps the home closed but for those.
+    if config.mount.local_files {
+        if let Some(user_dirs) = directories::UserDirs::new() {
+            sandbox.allow_read_write(user_dirs.home_dir());
+        }
+    }
+    // refreshed tokens are written back to the configured credential files
+    for provider in &config.providers {
+        if let Some(path) = provider.credentials.as_ref().or(provider.root.as_ref()) {
+            sandbox.allow_read_write(path);
+        }
+    }
+    if !scheduler.is_empty() || cli.control_socket || cli.dbus {
+        // scheduled jobs, socket and bus commands go through the mount, the socket was bound
+        // before and needs no rule to accept connections
+        if let Ok(path) = std::fs::canonicalize(&mount_point) {
+            sandbox.allow_mount(path);
+        }
+    }
+
+    let fs = fs.unwrap();
+    let mut mountpoint = match cli.no_sandbox {
+        true => mountpoint,
+        false => mountpoint.with_sandbox(sandbox),
+    };
+    mountpoint = mountpoint.with_scheduler(scheduler).with_options(mount_options).with_poller(fs.poller())
+        .with_default_permissions(!cli.no_default_permissions);
+
+    if cli.control_socket {
+        match control_socket::ControlSocket::bind(&mount_point) {
+            Ok(control_socket) => {
+                // the owner of a mount made by root talks to it too
+                let _ = std::os::unix::fs::chown(control_socket.path(), Some(uid), Some(gid));
+                info!("accepting commands on {}", control_socket.path().display());
+                mountpoint = mountpoint.with_control_socket(control_socket);
+            },
+            Err(error) => warn!("unable to open the control socket: {}", error),
+        }
+    }
+
+    if cli.dbus {
+        mountpoint = mountpoint.with_dbus();
+    }
+
+    if let Some(owner) = owner {
+        mountpoint = mountpoint.with_owner(owner);
+    }
+
+    if !users.is_empty() {
+        mountpoint = mountpoint.with_allow_other();
+    }
+
+    if let Some(readiness) = readiness {
+        mountpoint = mountpoint.with_readiness(readiness);
+    }
+
+    let result = mountpoint.mount(fs);
+    if let Some(path) = &pid_file {
+        let _ = std::fs::remove_file(path);
+    }
+    if let Err(error) = result {
+        error!("unable to mount on {}: {}", mount_point.display(), error);
+        std::process::exit(1);
+    }
 
-    mountpoint.mount(fs.unwrap()).unwrap();
+    telemetry::shutdown();
 }
\ No newline at end of file
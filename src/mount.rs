@@ -1,24 +1,415 @@
 // create a wrapper to handle mounting and unmounting the filesystem
 // Path: src/mount.rs
+use std::fs;
+use std::io::{self, Write};
 use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
 
-use fuser::{MountOption, Filesystem};
+use fuser::{MountOption, Filesystem, Session};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use tracing::{info, warn};
+
+use crate::control_socket::ControlSocket;
+use crate::daemon::Readiness;
+use crate::dbus;
+use crate::fuse::{Modes, Poller};
+use crate::privileges::{self, Owner};
+use crate::sandbox::Sandbox;
+use crate::schedule::Scheduler;
+use crate::systemd;
 
 pub struct Mount {
     mountpoint: String,
+    sandbox: Option<Sandbox>,
+    owner: Option<Owner>,
+    allow_other: bool,
+    scheduler: Option<Scheduler>,
+    options: Vec<MountOption>,
+    poller: Option<Poller>,
+    control_socket: Option<ControlSocket>,
+    dbus: bool,
+    readiness: Option<Readiness>,
+    default_permissions: bool,
+}
+
+const FUSE_CONF: &str = "/etc/fuse.conf";
+
+// Run by the unmount helper once told to, with the mount point as $1. umount does it as root,
+// fusermount for the user who mounted.
+const UNMOUNT_SCRIPT: &str = "read _ || exit 0; umount \"$1\" 2>/dev/null || fusermount3 -u \"$1\" 2>/dev/null || fusermount -u \"$1\"";
+
+// Options of fstab entries and mount units meant for mount(8) and systemd, the kernel would
+// refuse them.
+const USERSPACE_OPTIONS: &[&str] = &["defaults", "auto", "noauto", "nofail", "_netdev", "user", "users", "nouser", "owner", "group"];
+
+// Read by the daemon itself: `setuid=USER` as with mount.fuse, the owner and permissions of
+// the files as on other network filesystems.
+const DAEMON_OPTIONS: &[&str] = &["setuid", "uid", "gid", "umask", "file_mode", "dir_mode"];
+
+fn is_userspace_option(option: &str) -> bool {
+    let key = option.split_once('=').map_or(option, |(key, _)| key);
+    USERSPACE_OPTIONS.contains(&option) || DAEMON_OPTIONS.contains(&key) || option.starts_with("x-") || option.starts_with("comment=")
+}
+
+// The value of a `key=value` option, the last one given wins.
+pub fn option_value(options: &str, key: &str) -> Option<String> {
+    options.split(',')
+        .map(str::trim)
+        .filter_map(|option| option.split_once('='))
+        .filter(|(name, _)| *name == key)
+        .last()
+        .map(|(_, value)| value.to_string())
+}
+
+// Translates a `-o opt1,opt2=val` string the way mount(8) would, options fuser doesn't know
+// are handed to the kernel as they are, except the ones only meant for mount(8) and systemd.
+pub fn parse_options(options: &str) -> Vec<MountOption> {
+    options.split(',')
+        .map(str::trim)
+        .filter(|option| !option.is_empty() && !is_userspace_option(option))
+        .map(|option| match option.split_once('=') {
+            Some(("fsname", name)) => MountOption::FSName(name.to_string()),
+            Some(("subtype", subtype)) => MountOption::Subtype(subtype.to_string()),
+            Some(_) => MountOption::CUSTOM(option.to_string()),
+            None => match option {
+                "ro" => MountOption::RO,
+                "rw" => MountOption::RW,
+                "allow_other" => MountOption::AllowOther,
+                "allow_root" => MountOption::AllowRoot,
+                "auto_unmount" => MountOption::AutoUnmount,
+                "default_permissions" => MountOption::DefaultPermissions,
+                "dev" => MountOption::Dev,
+                "nodev" => MountOption::NoDev,
+                "suid" => MountOption::Suid,
+                "nosuid" => MountOption::NoSuid,
+                "exec" => MountOption::Exec,
+                "noexec" => MountOption::NoExec,
+                "atime" => MountOption::Atime,
+                "noatime" => MountOption::NoAtime,
+                "dirsync" => MountOption::DirSync,
+                "sync" => MountOption::Sync,
+                "async" => MountOption::Async,
+                _ => MountOption::CUSTOM(option.to_string()),
+            },
+        })
+        .collect()
+}
+
+// Owner and permissions of the cloud providers' files from the `uid`, `gid`, `umask`,
+// `file_mode` and `dir_mode` options, the mounting user's and a 022 umask by default.
+pub fn ownership(options: &[String], uid: u32, gid: u32) -> Result<(u32, u32, Modes), String> {
+    let value = |key: &str| options.iter().filter_map(|options| option_value(options, key)).last();
+    let id = |key: &str, default: u32| match value(key) {
+        Some(id) => id.parse().map_err(|_| format!("invalid {} option: {}", key, id)),
+        None => Ok(default),
+    };
+    let mode = |key: &str| match value(key) {
+        Some(mode) => u16::from_str_radix(&mode, 8).ok().filter(|mode| *mode <= 0o7777).map(Some).ok_or_else(|| format!("invalid {} option, an octal mode is expected: {}", key, mode)),
+        None => Ok(None),
+    };
+
+    let modes = Modes {
+        umask: mode("umask")?.unwrap_or(Modes::default().umask),
+        file_mode: mode("file_mode")?,
+        dir_mode: mode("dir_mode")?,
+    };
+    Ok((id("uid", uid)?, id("gid", gid)?, modes))
+}
+
+// `ro` is served by the filesystem rather than the kernel, the control files stay writable.
+pub fn is_read_only(options: &[MountOption]) -> bool {
+    options.contains(&MountOption::RO)
+}
+
+// fusermount only lets other users in when the administrator allowed it.
+fn check_allow_other(options: &[MountOption]) -> io::Result<()> {
+    let allow_other = options.contains(&MountOption::AllowOther);
+    let allow_root = options.contains(&MountOption::AllowRoot);
+    if allow_other && allow_root {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "allow_other and allow_root can't be used together"));
+    }
+    if !(allow_other || allow_root) || privileges::is_root() {
+        return Ok(());
+    }
+
+    let allowed = fs::read_to_string(FUSE_CONF).map_or(false, |conf| conf.lines().any(|line| line.trim() == "user_allow_other"));
+    match allowed {
+        true => Ok(()),
+        false => Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("allow_other and allow_root need user_allow_other in {} when not mounting as root", FUSE_CONF))),
+    }
 }
 
 impl Mount {
-    pub fn new<P: AsRef<Path>>(mountpoint: P) -> Self {
-        Self {
-            mountpoint: mountpoint.as_ref().to_str().unwrap().to_string(),
+    // The mount point is created when missing.
+    pub fn new<P: AsRef<Path>>(mountpoint: P) -> io::Result<Self> {
+        let path = mountpoint.as_ref();
+
+        if !path.exists() {
+            fs::create_dir_all(path)?;
+        } else if !path.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::Other, "not a directory"));
         }
+
+        let mountpoint = path.to_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the path isn't valid UTF-8"))?;
+
+        Ok(Self {
+            mountpoint: mountpoint.to_string(),
+            sandbox: None,
+            owner: None,
+            allow_other: false,
+            scheduler: None,
+            options: Vec::new(),
+            poller: None,
+            control_socket: None,
+            dbus: false,
+            readiness: None,
+            default_permissions: true,
+        })
+    }
+
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    // Provider IO runs as this user once the mount syscall is done.
+    pub fn with_owner(mut self, owner: Owner) -> Self {
+        self.owner = Some(owner);
+        // the mount belongs to root, let the owner reach it
+        self.allow_other = true;
+        self
+    }
+
+    pub fn with_allow_other(mut self) -> Self {
+        self.allow_other = true;
+        self
+    }
+
+    // Scheduled jobs start once the daemon is sandboxed, they reach the files through the mount.
+    pub fn with_scheduler(mut self, scheduler: Scheduler) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    // Gets the session's notifier to invalidate what the kernel cached of folders changed
+    // from elsewhere.
+    pub fn with_poller(mut self, poller: Poller) -> Self {
+        self.poller = Some(poller);
+        self
+    }
+
+    // Started with the scheduled jobs, the socket is removed on unmount.
+    pub fn with_control_socket(mut self, control_socket: ControlSocket) -> Self {
+        self.control_socket = Some(control_socket);
+        self
+    }
+
+    // Served once the daemon dropped its privileges, on the session bus of its user.
+    pub fn with_dbus(mut self) -> Self {
+        self.dbus = true;
+        self
+    }
+
+    // Told once the kernel accepted the mount, or why it didn't.
+    pub fn with_readiness(mut self, readiness: Readiness) -> Self {
+        self.readiness = Some(readiness);
+        self
+    }
+
+    // Whether the kernel checks the modes of the files when other users reach the mount, it
+    // does unless told not to.
+    pub fn with_default_permissions(mut self, default_permissions: bool) -> Self {
+        self.default_permissions = default_permissions;
+        self
+    }
+
+    // Options given with `-o`, they take precedence over the defaults.
+    pub fn with_options(mut self, options: Vec<MountOption>) -> Self {
+        self.options.extend(options);
+        self
+    }
+
+    pub fn mount<F: Filesystem + Send + Sync + 'static>(&mut self, fs: F) -> std::io::Result<()> {
+        let mut options = Vec::new();
+
+        if !self.options.contains(&MountOption::AutoUnmount) {
+            options.push(MountOption::AutoUnmount);
+        }
+
+        if !self.options.iter().any(|option| matches!(option, MountOption::FSName(_))) {
+            options.push(MountOption::FSName(String::from("rust-fuse")));
+        }
+
+        if self.allow_other && !self.options.contains(&MountOption::AllowOther) {
+            options.push(MountOption::AllowOther);
+        }
+
+        options.extend(self.options.iter().filter(|option| **option != MountOption::RO).cloned());
+
+        // Other users would otherwise be let through whatever the modes shown, the filesystem
+        // only checks which providers they see.
+        let shared = options.contains(&MountOption::AllowOther) || options.contains(&MountOption::AllowRoot);
+        if shared && !options.contains(&MountOption::DefaultPermissions) {
+            match self.default_permissions {
+                true => options.push(MountOption::DefaultPermissions),
+                false => warn!("other users reach the mount without default_permissions, the modes of the files aren't checked"),
+            }
+        }
+        if let Err(error) = check_allow_other(&options) {
+            if let Some(readiness) = self.readiness.take() {
+                readiness.failed(&error.to_string());
+            }
+            return Err(error);
+        }
+
+        let mut session = match Session::new(fs, Path::new(&self.mountpoint), &options) {
+            Ok(session) => session,
+            Err(error) => {
+                if let Some(readiness) = self.readiness.take() {
+                    readiness.failed(&format!("unable to mount on {}: {}", self.mountpoint, error));
+                }
+                return Err(error);
+            },
+        };
+        if let Some(poller) = &self.poller {
+            poller.set_notifier(session.notifier());
+        }
+        // before the privileges are dropped and the sandbox entered, neither lets the daemon
+        // unmount afterwards
+        if let Err(error) = unmount_on_signal(&self.mountpoint) {
+            warn!("unable to handle SIGINT and SIGTERM, they leave the mount point disconnected: {}", error);
+        }
+
+        if let Some(owner) = &self.owner {
+            owner.drop_privileges()?;
+        }
+
+        // an unconfined daemon is only run when asked for with --no-sandbox
+        if let Some(sandbox) = &self.sandbox {
+            if let Err(error) = sandbox.restrict() {
+                let error = io::Error::new(io::ErrorKind::Other, format!("unable to sandbox the daemon, --no-sandbox runs it unconfined: {}", error));
+                if let Some(readiness) = self.readiness.take() {
+                    readiness.failed(&error.to_string());
+                }
+                return Err(error);
+            }
+        }
+
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.start(std::fs::canonicalize(&self.mountpoint)?);
+        }
+
+        if self.dbus {
+            dbus::start(std::fs::canonicalize(&self.mountpoint)?);
+        }
+
+        let socket_path = self.control_socket.as_ref().map(|control_socket| control_socket.path().to_path_buf());
+        if let Some(control_socket) = self.control_socket.take() {
+            control_socket.start();
+        }
+
+        if let Some(readiness) = self.readiness.take() {
+            readiness.ready();
+        }
+        systemd::notify_ready(&self.mountpoint);
+
+        let result = session.run();
+        // what is written is flushed and the journal drained as the session goes
+        drop(session);
+        // the sandbox keeps the socket's directory closed, a socket left behind is replaced by
+        // the next mount
+        if let Some(path) = socket_path {
+            let _ = fs::remove_file(path);
+        }
+        result
+    }
+}
+
+// A signal ends the session the way umount does, nothing written is lost. A second one while
+// the writes are flushed exits right away.
+fn unmount_on_signal(mountpoint: &str) -> io::Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let mut unmounter = spawn_unmounter(mountpoint)?;
+    let mountpoint = mountpoint.to_string();
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            info!("signal {} received, unmounting", signal);
+            systemd::notify_stopping();
+            match unmount(&mut unmounter) {
+                Ok(true) => (),
+                Ok(false) => warn!("unable to unmount {}, it is busy or no longer mounted", mountpoint),
+                Err(error) => warn!("unable to unmount {}: {}", mountpoint, error),
+            }
+        }
+        if signals.next().is_some() {
+            warn!("exiting without flushing the pending writes");
+            std::process::exit(1);
+        }
+    });
+    Ok(())
+}
+
+// A shell waiting for a line on its stdin, it leaves when the daemon exits and closes it.
+fn spawn_unmounter(mountpoint: &str) -> io::Result<Child> {
+    Command::new("sh")
+        .args(["-c", UNMOUNT_SCRIPT, "sh", mountpoint])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+}
+
+fn unmount(unmounter: &mut Child) -> io::Result<bool> {
+    let mut stdin = unmounter.stdin.take().ok_or_else(|| io::Error::new(io::ErrorKind::Other, "the unmount helper is gone"))?;
+    stdin.write_all(b"\n")?;
+    drop(stdin);
+    Ok(unmounter.wait()?.success())
+}
+
+#[cfg(test)]
+mod mount_test {
+    use super::*;
+
+    #[test]
+    fn parse_options_translates_known_options() {
+        let options = parse_options("ro, allow_other,,fsname=orbital,nodev,max_read=131072");
+
+        assert_eq!(options, vec![
+            MountOption::RO,
+            MountOption::AllowOther,
+            MountOption::FSName("orbital".to_string()),
+            MountOption::NoDev,
+            MountOption::CUSTOM("max_read=131072".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn parse_options_leaves_out_userspace_options() {
+        let options = parse_options("noauto,nofail,_netdev,x-systemd.automount,comment=cloud,setuid=alice,uid=1000,umask=077,rw");
+
+        assert_eq!(options, vec![MountOption::RW]);
+    }
+
+    #[test]
+    fn ownership_defaults_to_the_mounting_user() {
+        assert_eq!(ownership(&[], 1000, 100), Ok((1000, 100, Modes::default())));
+    }
+
+    #[test]
+    fn ownership_from_options() {
+        let options = vec!["uid=1001,gid=50".to_string(), "umask=077,file_mode=0640,uid=1002".to_string()];
+        let modes = Modes { umask: 0o077, file_mode: Some(0o640), dir_mode: None };
+
+        assert_eq!(ownership(&options, 1000, 100), Ok((1002, 50, modes)));
     }
 
-    pub fn mount<F: Filesystem + Send + Sync + 'static>(&self, fs: F) -> std::io::Result<()> {
-        fuser::mount2(fs, &self.mountpoint, &[
-            MountOption::AutoUnmount,
-            MountOption::FSName(String::from("rust-fuse"))
-        ])
+    #[test]
+    fn ownership_refuses_invalid_values() {
+        assert!(ownership(&["uid=alice".to_string()], 1000, 100).is_err());
+        assert!(ownership(&["file_mode=0999".to_string()], 1000, 100).is_err());
+        assert!(ownership(&["dir_mode=17777".to_string()], 1000, 100).is_err());
     }
 }
\ No newline at end of file
@@ -0,0 +1,59 @@
+// surface events the user would otherwise only see on stdout
+// Path: src/notifications.rs
+use std::thread;
+
+use notify_rust::Notification;
+use tracing::warn;
+
+use crate::dbus;
+
+const APP_NAME: &str = "Orbital Files";
+
+// Uploads below this size finish too quickly to be worth a notification.
+pub const LARGE_UPLOAD_SIZE: usize = 50 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    ReauthRequired { provider: String },
+    UploadFinished { name: String, size: usize },
+    Conflict { name: String, copy: String },
+    ProviderDegraded { provider: String },
+}
+
+impl Event {
+    fn summary(&self) -> String {
+        match self {
+            Event::ReauthRequired { provider } => format!("{} needs you to log in again", provider),
+            Event::UploadFinished { name, .. } => format!("{} uploaded", name),
+            Event::Conflict { name, .. } => format!("{} was changed elsewhere", name),
+            Event::ProviderDegraded { provider } => format!("{} is unreachable", provider),
+        }
+    }
+
+    fn body(&self) -> String {
+        match self {
+            Event::ReauthRequired { .. } => "Files from this account are unavailable until you log in again.".to_string(),
+            Event::UploadFinished { size, .. } => format!("{:.1} MB sent", *size as f64 / (1024.0 * 1024.0)),
+            Event::Conflict { copy, .. } => format!("Your changes were saved as {}.", copy),
+            Event::ProviderDegraded { .. } => "Only the files already downloaded can be opened, changes are sent once it answers again.".to_string(),
+        }
+    }
+}
+
+// Talking to the notification daemon goes over D-Bus, don't make the FUSE loop wait for it.
+// Desktop shells listening to the mount's own service get the event as a signal too.
+pub fn notify(event: Event) {
+    thread::spawn(move || {
+        dbus::emit(&event);
+
+        let result = Notification::new()
+            .appname(APP_NAME)
+            .summary(&event.summary())
+            .body(&event.body())
+            .show();
+
+        if let Err(error) = result {
+            warn!("notification: unable to show {:?}: {}", event, error);
+        }
+    });
+}
\ No newline at end of file
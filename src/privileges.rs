@@ -0,0 +1,96 @@
+// drop root privileges once the filesystem is mounted
+// Path: src/privileges.rs
+use std::ffi::{CStr, CString};
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+}
+
+impl Owner {
+    // Accepts either a user name or a numeric uid, like `--uid-owner` of other mount helpers.
+    pub fn lookup(user: &str) -> io::Result<Self> {
+        let entry = if let Ok(uid) = user.parse::<u32>() {
+            unsafe { libc::getpwuid(uid) }
+        } else {
+            let name = CString::new(user).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+            unsafe { libc::getpwnam(name.as_ptr()) }
+        };
+
+        if entry.is_null() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown user {}", user)));
+        }
+
+        let entry = unsafe { &*entry };
+        let home = unsafe { CStr::from_ptr(entry.pw_dir) }.to_string_lossy().to_string();
+
+        Ok(Self {
+            uid: entry.pw_uid,
+            gid: entry.pw_gid,
+            home: PathBuf::from(home),
+        })
+    }
+
+    // Until `restore_root`, what the daemon creates belongs to the owner, e.g. the state, the
+    // cache and its key prepared before mounting. Only the effective ids change, root is
+    // regained to mount.
+    pub fn assume(&self) -> io::Result<()> {
+        unsafe {
+            if libc::setgroups(1, &self.gid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setegid(self.gid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::seteuid(self.uid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Group membership has to go first, we can't change it anymore once the uid is dropped.
+    pub fn drop_privileges(&self) -> io::Result<()> {
+        unsafe {
+            if libc::setgroups(1, &self.gid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setgid(self.gid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setuid(self.uid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // make sure root can't be regained
+            if self.uid != 0 && libc::setuid(0) == 0 {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "privileges were not dropped"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+// Back from `Owner::assume`, the real ids are still root's.
+pub fn restore_root() -> io::Result<()> {
+    unsafe {
+        if libc::seteuid(0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::setegid(0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
\ No newline at end of file
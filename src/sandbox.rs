@@ -0,0 +1,295 @@
+// restrict what the daemon can touch once the filesystem is mounted
+// Path: src/sandbox.rs
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use directories::ProjectDirs;
+use landlock::{path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus, ABI};
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+use tracing::warn;
+
+use crate::config;
+
+// Landlock version we target, newer kernels are handled on a best-effort basis.
+const LANDLOCK_ABI: ABI = ABI::V2;
+
+// Syscalls the daemon makes once mounted: files, memory, threads, timers, sockets for the
+// providers and the bus, xattrs of the control socket's commands and waiting on the unmount
+// helper. Anything else, execve and ptrace among them, is answered with EPERM instead of
+// killing the process so a misbehaving dependency fails loudly but safely.
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // files
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_lseek,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_close_range,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_getdents64,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_linkat,
+    libc::SYS_symlinkat,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_utimensat,
+    libc::SYS_ftruncate,
+    libc::SYS_fallocate,
+    libc::SYS_fadvise64,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_sync_file_range,
+    libc::SYS_copy_file_range,
+    libc::SYS_sendfile,
+    libc::SYS_splice,
+    libc::SYS_flock,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_getcwd,
+    libc::SYS_chdir,
+    libc::SYS_fchdir,
+    libc::SYS_umask,
+    libc::SYS_getxattr,
+    libc::SYS_lgetxattr,
+    libc::SYS_fgetxattr,
+    libc::SYS_setxattr,
+    libc::SYS_lsetxattr,
+    libc::SYS_fsetxattr,
+    libc::SYS_listxattr,
+    libc::SYS_llistxattr,
+    libc::SYS_flistxattr,
+    libc::SYS_removexattr,
+    libc::SYS_lremovexattr,
+    libc::SYS_fremovexattr,
+    libc::SYS_inotify_init1,
+    libc::SYS_inotify_add_watch,
+    libc::SYS_inotify_rm_watch,
+    // memory
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_msync,
+    libc::SYS_mincore,
+    libc::SYS_membarrier,
+    // threads, signals and processes
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_get_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_prctl,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_getgroups,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigtimedwait,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    // fuser unmounts with it as the session ends
+    libc::SYS_umount2,
+    libc::SYS_restart_syscall,
+    libc::SYS_getrlimit,
+    libc::SYS_prlimit64,
+    libc::SYS_getrusage,
+    libc::SYS_uname,
+    libc::SYS_sysinfo,
+    libc::SYS_getrandom,
+    // time
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_nanosleep,
+    libc::SYS_timerfd_create,
+    libc::SYS_timerfd_settime,
+    libc::SYS_timerfd_gettime,
+    // polling
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_eventfd2,
+    // sockets
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_shutdown,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+];
+
+// The older calls x86_64 still has next to their `at` and `p` variants, glibc makes some of them.
+#[cfg(target_arch = "x86_64")]
+const ALLOWED_LEGACY_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_readlink,
+    libc::SYS_rename,
+    libc::SYS_mkdir,
+    libc::SYS_rmdir,
+    libc::SYS_unlink,
+    libc::SYS_symlink,
+    libc::SYS_link,
+    libc::SYS_chmod,
+    libc::SYS_chown,
+    libc::SYS_lchown,
+    libc::SYS_getdents,
+    libc::SYS_dup2,
+    libc::SYS_pipe,
+    libc::SYS_poll,
+    libc::SYS_select,
+    libc::SYS_epoll_create,
+    libc::SYS_epoll_wait,
+    libc::SYS_eventfd,
+    libc::SYS_inotify_init,
+    libc::SYS_arch_prctl,
+    libc::SYS_time,
+];
+
+#[cfg(not(target_arch = "x86_64"))]
+const ALLOWED_LEGACY_SYSCALLS: &[libc::c_long] = &[];
+
+pub struct Sandbox {
+    read_only: Vec<PathBuf>,
+    read_write: Vec<PathBuf>,
+    mount_point: Option<PathBuf>,
+}
+
+impl Sandbox {
+    // Only the app's own directories are writable, the directories providers serve are
+    // allowed one by one by the caller.
+    pub fn new() -> Self {
+        let mut sandbox = Self {
+            // system locations needed for DNS resolution, TLS certificates and shared libraries
+            read_only: ["/etc", "/usr", "/lib", "/lib64", "/proc"].iter().map(PathBuf::from).collect(),
+            read_write: Vec::new(),
+            mount_point: None,
+        };
+
+        if let Some(proj_dirs) = ProjectDirs::from("", "Orbital", "Files") {
+            sandbox.allow_read_write(proj_dirs.data_dir());
+            sandbox.allow_read_write(proj_dirs.cache_dir());
+        }
+        for dir in [config::config_dir(), config::legacy_config_dir()].into_iter().flatten() {
+            sandbox.allow_read_only(dir);
+        }
+
+        sandbox
+    }
+
+    pub fn allow_read_only<P: AsRef<Path>>(&mut self, path: P) {
+        self.read_only.push(path.as_ref().to_path_buf());
+    }
+
+    pub fn allow_read_write<P: AsRef<Path>>(&mut self, path: P) {
+        self.read_write.push(path.as_ref().to_path_buf());
+    }
+
+    // The files under the mount, rather than the directory it covers. Its root is opened
+    // without being looked at, nothing answers the filesystem's requests yet.
+    pub fn allow_mount<P: AsRef<Path>>(&mut self, path: P) {
+        self.mount_point = Some(path.as_ref().to_path_buf());
+    }
+
+    // Must be called after the mount syscall: the FUSE device is already open and
+    // every thread spawned afterwards inherits the restrictions.
+    pub fn restrict(&self) -> Result<(), Box<dyn Error>> {
+        self.restrict_filesystem()?;
+        self.restrict_syscalls()?;
+
+        Ok(())
+    }
+
+    fn restrict_filesystem(&self) -> Result<(), Box<dyn Error>> {
+        let read_only: Vec<&PathBuf> = self.read_only.iter().filter(|path| path.exists()).collect();
+        let read_write: Vec<&PathBuf> = self.read_write.iter().filter(|path| path.exists()).chain(&self.mount_point).collect();
+
+        let status = Ruleset::new()
+            .handle_access(AccessFs::from_all(LANDLOCK_ABI))?
+            .create()?
+            .add_rules(path_beneath_rules(read_only, AccessFs::from_read(LANDLOCK_ABI)))?
+            .add_rules(path_beneath_rules(read_write, AccessFs::from_all(LANDLOCK_ABI)))?
+            .restrict_self()?;
+
+        if status.ruleset == RulesetStatus::NotEnforced {
+            warn!("sandbox: landlock is not supported by this kernel, filesystem access is not restricted");
+        }
+
+        Ok(())
+    }
+
+    fn restrict_syscalls(&self) -> Result<(), Box<dyn Error>> {
+        let rules = ALLOWED_SYSCALLS.iter().chain(ALLOWED_LEGACY_SYSCALLS)
+            .map(|syscall| (*syscall as i64, vec![]))
+            .collect::<BTreeMap<_, _>>();
+
+        let filter = SeccompFilter::new(
+            rules,
+            SeccompAction::Errno(libc::EPERM as u32),
+            SeccompAction::Allow,
+            TargetArch::try_from(std::env::consts::ARCH)?,
+        )?;
+        let program: BpfProgram = filter.try_into()?;
+
+        seccompiler::apply_filter_all_threads(&program)?;
+
+        Ok(())
+    }
+}
\ No newline at end of file
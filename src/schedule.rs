@@ -0,0 +1,217 @@
+// run sync jobs and cache warming on cron-like schedules while the filesystem is mounted
+// Path: src/schedule.rs
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::config::config_file;
+use crate::sync::{self, SyncOptions};
+use crate::throttle::Throttle;
+
+// Tasks are read from `schedule.toml` in the config directory, paths are relative to the
+// root of the mount, e.g.
+//
+//     [[task]]
+//     schedule = "0 3 * * *"
+//     sync = { src = "/GoogleDrive/Photos", dst = "/OneDrive/Backup", delete = true }
+//
+//     [[task]]
+//     schedule = "*/30 8-18 * * 1-5"
+//     warm = "/GoogleDrive/Documents"
+const SCHEDULE_FILE_NAME: &str = "schedule.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Job {
+    Sync {
+        src: PathBuf,
+        dst: PathBuf,
+        #[serde(default)]
+        delete: bool,
+    },
+    // lists a tree ahead of time so browsing it later is served from memory
+    Warm(PathBuf),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Task {
+    schedule: String,
+    #[serde(flatten)]
+    job: Job,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ScheduleFile {
+    #[serde(default, rename = "task")]
+    tasks: Vec<Task>,
+}
+
+// One of the five fields of a cron expression, the values it accepts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Field(Vec<u32>);
+
+impl Field {
+    // Supports `*`, single values, ranges, lists and steps, e.g. `*/15` or `1-5,7`.
+    fn parse(text: &str, min: u32, max: u32) -> Option<Self> {
+        let mut values = Vec::new();
+
+        for part in text.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse().ok()?),
+                None => (part, 1),
+            };
+
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else if let Some((start, end)) = range.split_once('-') {
+                (start.parse().ok()?, end.parse().ok()?)
+            } else {
+                let value = range.parse().ok()?;
+                (value, value)
+            };
+
+            if start < min || end > max || start > end || step == 0 {
+                return None;
+            }
+
+            values.extend((start..=end).step_by(step as usize));
+        }
+
+        Some(Field(values))
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        self.0.contains(&value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+impl Schedule {
+    pub fn parse(expression: &str) -> Option<Self> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return None;
+        }
+
+        Some(Self {
+            minutes: Field::parse(fields[0], 0, 59)?,
+            hours: Field::parse(fields[1], 0, 23)?,
+            days: Field::parse(fields[2], 1, 31)?,
+            months: Field::parse(fields[3], 1, 12)?,
+            // 0 is Sunday
+            weekdays: Field::parse(fields[4], 0, 6)?,
+        })
+    }
+
+    pub fn matches(&self, time: &DateTime<Local>) -> bool {
+        self.minutes.matches(time.minute())
+            && self.hours.matches(time.hour())
+            && self.days.matches(time.day())
+            && self.months.matches(time.month())
+            && self.weekdays.matches(time.weekday().num_days_from_sunday())
+    }
+}
+
+pub struct Scheduler {
+    tasks: Vec<(Schedule, Job)>,
+    throttle: Arc<Throttle>,
+}
+
+impl Scheduler {
+    // Tasks with an invalid schedule are reported and left out.
+    pub fn load() -> Self {
+        let mut scheduler = Self { tasks: Vec::new(), throttle: Arc::new(Throttle::load()) };
+
+        let path = match config_file(SCHEDULE_FILE_NAME) {
+            Some(path) => path,
+            None => return scheduler,
+        };
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => return scheduler,
+        };
+
+        match toml::from_str::<ScheduleFile>(&content) {
+            Ok(file) => {
+                for task in file.tasks {
+                    match Schedule::parse(&task.schedule) {
+                        Some(schedule) => scheduler.tasks.push((schedule, task.job)),
+                        None => warn!("schedule: ignoring invalid schedule \"{}\"", task.schedule),
+                    }
+                }
+            },
+            Err(error) => warn!("ignoring {}: {}", path.display(), error),
+        }
+
+        scheduler
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    // Jobs go through the mount like any other program would, so they are run from their own
+    // thread while the session serves their requests.
+    pub fn start(self, mount_point: PathBuf) {
+        if self.tasks.is_empty() {
+            return;
+        }
+
+        thread::spawn(move || loop {
+            // wake up at the start of every minute
+            let now = Local::now();
+            thread::sleep(Duration::from_secs(60 - now.second() as u64));
+
+            let now = Local::now();
+            for (schedule, job) in &self.tasks {
+                if schedule.matches(&now) {
+                    run(job, &mount_point, &self.throttle);
+                }
+            }
+        });
+    }
+}
+
+fn on_mount(mount_point: &Path, path: &Path) -> PathBuf {
+    mount_point.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+fn run(job: &Job, mount_point: &Path, throttle: &Arc<Throttle>) {
+    match job {
+        Job::Sync { src, dst, delete } => {
+            info!("schedule: syncing {} to {}", src.display(), dst.display());
+            let options = SyncOptions { delete: *delete, throttle: Some(throttle.clone()), ..Default::default() };
+            if let Err(error) = sync::sync(&on_mount(mount_point, src), &on_mount(mount_point, dst), &options) {
+                warn!("schedule: sync of {} failed: {}", src.display(), error);
+            }
+        },
+        Job::Warm(path) => {
+            info!("schedule: warming {}", path.display());
+            let mut pending = vec![on_mount(mount_point, path)];
+            while let Some(dir) = pending.pop() {
+                if let Ok(entries) = fs::read_dir(&dir) {
+                    for entry in entries.flatten() {
+                        if entry.file_type().map_or(false, |file_type| file_type.is_dir()) {
+                            pending.push(entry.path());
+                        }
+                    }
+                }
+            }
+        },
+    }
+}
\ No newline at end of file
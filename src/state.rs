@@ -0,0 +1,59 @@
+// where a mount keeps its journal, unflushed writes and inodes, a set of them by profile
+// Path: src/state.rs
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use directories::ProjectDirs;
+
+const PROFILES_DIR_NAME: &str = "profiles";
+const LOCK_FILE_NAME: &str = "mount.lock";
+
+static PROFILE: Mutex<Option<String>> = Mutex::new(None);
+
+// Set before anything is loaded. Without a profile the state stays where it always was.
+pub fn set_profile(name: &str) {
+    *PROFILE.lock().unwrap() = Some(name.to_string());
+}
+
+fn of_profile(dir: &Path) -> PathBuf {
+    match PROFILE.lock().unwrap().as_deref() {
+        Some(profile) => dir.join(PROFILES_DIR_NAME).join(profile),
+        None => dir.to_path_buf(),
+    }
+}
+
+// The journal, kept with the credentials as it must survive the cache being cleared.
+pub fn data_dir() -> Option<PathBuf> {
+    ProjectDirs::from("", "Orbital", "Files").map(|proj_dirs| of_profile(proj_dirs.data_dir()))
+}
+
+pub fn cache_dir() -> Option<PathBuf> {
+    ProjectDirs::from("", "Orbital", "Files").map(|proj_dirs| of_profile(proj_dirs.cache_dir()))
+}
+
+// Held for as long as the process, two mounts sharing the state would overwrite each other's
+// journal and recover each other's writes.
+pub fn lock() -> io::Result<File> {
+    let dir = cache_dir().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no cache directory"))?;
+    fs::create_dir_all(&dir)?;
+
+    let path = dir.join(LOCK_FILE_NAME);
+    let file = OpenOptions::new().create(true).write(true).open(&path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let error = io::Error::last_os_error();
+        return Err(match error.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => io::Error::new(io::ErrorKind::WouldBlock, format!("another mount uses {}", dir.display())),
+            _ => error,
+        });
+    }
+    Ok(file)
+}
+
+// What was written to the mount is only readable by the user running it, whatever the umask.
+pub fn write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?.write_all(content)
+}
\ No newline at end of file
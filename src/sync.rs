@@ -0,0 +1,192 @@
+// copy new and changed files from one path of the mount to another
+// Path: src/sync.rs
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::throttle::Throttle;
+
+pub struct SyncOptions {
+    // remove from the destination what isn't in the source anymore
+    pub delete: bool,
+    pub dry_run: bool,
+    // files transferred at the same time
+    pub jobs: usize,
+    // limits the bandwidth of background syncs, shared by every job
+    pub throttle: Option<Arc<Throttle>>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            delete: false,
+            dry_run: false,
+            jobs: 4,
+            throttle: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    pub copied: usize,
+    pub deleted: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+}
+
+// Paths of every file and directory below `root`, relative to it. Directories come before
+// their content.
+fn list(root: &Path) -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut files = Vec::new();
+    let mut directories = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(relative) = pending.pop() {
+        for entry in fs::read_dir(root.join(&relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                directories.push(path.clone());
+                pending.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    Ok((files, directories))
+}
+
+fn hash(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher.finalize().to_vec())
+}
+
+// Copies in chunks so the throttle is asked before each of them.
+fn copy(src: &Path, dst: &Path, throttle: Option<&Throttle>) -> io::Result<()> {
+    let throttle = match throttle {
+        Some(throttle) => throttle,
+        None => return fs::copy(src, dst).map(|_| ()),
+    };
+
+    let mut from = File::open(src)?;
+    let mut to = File::create(dst)?;
+    let mut buffer = vec![0; 256 * 1024];
+
+    loop {
+        let read = from.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        thread::sleep(throttle.delay(read as u64));
+        to.write_all(&buffer[..read])?;
+    }
+
+    Ok(())
+}
+
+// Sizes are compared first, the content is only hashed when they match.
+fn changed(src: &Path, dst: &Path) -> io::Result<bool> {
+    let dst_metadata = match fs::metadata(dst) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(error) => return Err(error),
+    };
+
+    if fs::metadata(src)?.len() != dst_metadata.len() {
+        return Ok(true);
+    }
+
+    Ok(hash(src)? != hash(dst)?)
+}
+
+pub fn sync(src: &Path, dst: &Path, options: &SyncOptions) -> io::Result<SyncSummary> {
+    let (files, directories) = list(src)?;
+    let summary = Mutex::new(SyncSummary::default());
+
+    for directory in &directories {
+        if !dst.join(directory).exists() {
+            info!("mkdir {}", directory.display());
+            if !options.dry_run {
+                fs::create_dir_all(dst.join(directory))?;
+            }
+        }
+    }
+
+    let queue = Mutex::new(files.iter());
+    thread::scope(|scope| {
+        for _ in 0..options.jobs.max(1) {
+            scope.spawn(|| loop {
+                let file = match queue.lock().unwrap().next() {
+                    Some(file) => file,
+                    None => break,
+                };
+
+                let result = changed(&src.join(file), &dst.join(file)).and_then(|changed| {
+                    if changed {
+                        info!("copy {}", file.display());
+                        if !options.dry_run {
+                            copy(&src.join(file), &dst.join(file), options.throttle.as_deref())?;
+                        }
+                    }
+                    Ok(changed)
+                });
+
+                let mut summary = summary.lock().unwrap();
+                match result {
+                    Ok(true) => summary.copied += 1,
+                    Ok(false) => summary.unchanged += 1,
+                    Err(error) => {
+                        warn!("unable to copy {}: {}", file.display(), error);
+                        summary.failed += 1;
+                    },
+                }
+            });
+        }
+    });
+
+    let mut summary = summary.into_inner().unwrap();
+
+    if options.delete {
+        let kept: HashSet<&PathBuf> = files.iter().chain(directories.iter()).collect();
+        let (dst_files, mut dst_directories) = list(dst)?;
+
+        for file in dst_files.iter().filter(|file| !kept.contains(file)) {
+            info!("delete {}", file.display());
+            if !options.dry_run {
+                fs::remove_file(dst.join(file))?;
+            }
+            summary.deleted += 1;
+        }
+
+        // deepest directories first so they are empty when removed
+        dst_directories.sort_by_key(|directory| std::cmp::Reverse(directory.components().count()));
+        for directory in dst_directories.iter().filter(|directory| !kept.contains(directory)) {
+            info!("delete {}", directory.display());
+            if !options.dry_run {
+                fs::remove_dir(dst.join(directory))?;
+            }
+            summary.deleted += 1;
+        }
+    }
+
+    Ok(summary)
+}
\ No newline at end of file
@@ -0,0 +1,21 @@
+// tell systemd how the mount is doing when it runs as a notify service
+// Path: src/systemd.rs
+use sd_notify::NotifyState;
+use tracing::warn;
+
+// Does nothing outside of systemd. A service detaching with --daemon needs NotifyAccess=all,
+// the readiness comes from the detached process.
+pub fn notify_ready(mount_point: &str) {
+    let status = format!("mounted on {}", mount_point);
+    if let Err(error) = sd_notify::notify(false, &[NotifyState::Ready, NotifyState::Status(&status)]) {
+        warn!("unable to notify systemd: {}", error);
+    }
+}
+
+// Flushing the pending writes may take a while, systemd waits for it instead of killing
+// the daemon.
+pub fn notify_stopping() {
+    if let Err(error) = sd_notify::notify(false, &[NotifyState::Stopping]) {
+        warn!("unable to notify systemd: {}", error);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,92 @@
+// export traces of filesystem operations and the provider requests they make over OTLP
+// Path: src/telemetry.rs
+use std::error::Error;
+use std::fmt::Debug;
+use std::time::Instant;
+
+use crossroads::interfaces::filesystem::ObjectId;
+use crossroads::storage::ProviderId;
+use opentelemetry::global::{self, BoxedSpan};
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::trace::{Span, Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, ContextGuard, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+
+use crate::api_log;
+use crate::fuse;
+
+const TRACER_NAME: &str = "orbital-files";
+
+// Spans are sent to `endpoint` as they end, e.g. `http://localhost:4318/v1/traces`. Until this
+// is called every span is a no-op.
+pub fn init(endpoint: &str) -> Result<(), Box<dyn Error>> {
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint))
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![KeyValue::new("service.name", TRACER_NAME)])))
+        .install_simple()?;
+
+    Ok(())
+}
+
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+// Span of a FUSE operation, it lasts as long as the returned guard and the provider requests
+// made meanwhile become its children.
+pub fn operation(name: &'static str, inode: u64) -> ContextGuard {
+    let mut span = global::tracer(TRACER_NAME).start(name);
+    span.set_attribute(KeyValue::new("fuse.inode", inode as i64));
+
+    Context::current_with_span(span).attach()
+}
+
+pub struct ProviderRequest {
+    span: BoxedSpan,
+    provider: String,
+    request: &'static str,
+    target: String,
+    started: Instant,
+}
+
+// Also recorded to the API debug log when it is enabled.
+pub fn provider_request(provider_id: &ProviderId, request: &'static str, target: &ObjectId) -> ProviderRequest {
+    let mut span = global::tracer(TRACER_NAME).start(format!("provider {}", request));
+    span.set_attribute(KeyValue::new("provider", provider_id.id.clone()));
+    span.set_attribute(KeyValue::new("provider.request", request));
+
+    ProviderRequest {
+        span,
+        provider: provider_id.id.clone(),
+        request,
+        target: target.as_str().to_string(),
+        started: Instant::now(),
+    }
+}
+
+impl ProviderRequest {
+    // `bytes` is what the request transferred when it succeeded.
+    pub fn finish<T, E: Debug>(mut self, result: &Result<T, E>, bytes: u64) {
+        let response = match result {
+            Ok(_) => {
+                self.span.set_attribute(KeyValue::new("bytes", bytes as i64));
+                self.span.set_status(Status::Ok);
+                format!("{} bytes", bytes)
+            },
+            Err(error) => {
+                let error = format!("{:?}", error);
+                self.span.set_status(Status::error(error.clone()));
+                error
+            },
+        };
+
+        fuse::record_result(&self.provider, result);
+        fuse::record_request(&self.provider, self.request, self.started.elapsed(), result.is_err());
+        if api_log::enabled() {
+            api_log::record(&self.provider, self.request, &self.target, self.started.elapsed(), result.is_ok(), &response);
+        }
+
+        self.span.end();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,134 @@
+// limit the bandwidth used by background transfers depending on the time of day
+// Path: src/throttle.rs
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{Datelike, Local, NaiveTime};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::config::config_file;
+
+// Windows are read from `bandwidth.toml` in the config directory, the first one matching the
+// current time applies and transfers are unlimited outside of them, e.g.
+//
+//     [[window]]
+//     start = "09:00"
+//     end = "18:00"
+//     days = [1, 2, 3, 4, 5]
+//     rate = 1048576
+const BANDWIDTH_FILE_NAME: &str = "bandwidth.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+struct WindowEntry {
+    start: String,
+    end: String,
+    // 0 is Sunday, every day when empty
+    #[serde(default)]
+    days: Vec<u32>,
+    // bytes per second, 0 means unlimited
+    rate: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct BandwidthFile {
+    #[serde(default, rename = "window")]
+    windows: Vec<WindowEntry>,
+}
+
+#[derive(Debug, Clone)]
+struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+    days: Vec<u32>,
+    rate: u64,
+}
+
+impl Window {
+    fn parse(entry: WindowEntry) -> Option<Self> {
+        Some(Self {
+            start: NaiveTime::parse_from_str(&entry.start, "%H:%M").ok()?,
+            end: NaiveTime::parse_from_str(&entry.end, "%H:%M").ok()?,
+            days: entry.days,
+            rate: entry.rate,
+        })
+    }
+
+    // A window ending before it starts runs over midnight.
+    fn contains(&self, now: &chrono::DateTime<Local>) -> bool {
+        if !self.days.is_empty() && !self.days.contains(&now.weekday().num_days_from_sunday()) {
+            return false;
+        }
+
+        let time = now.time();
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Throttle {
+    windows: Vec<Window>,
+    // when the bandwidth already handed out is used up
+    next_free: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    // Windows with an invalid time are reported and left out.
+    pub fn load() -> Self {
+        let path = match config_file(BANDWIDTH_FILE_NAME) {
+            Some(path) => path,
+            None => return Self::default(),
+        };
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => return Self::default(),
+        };
+
+        match toml::from_str::<BandwidthFile>(&content) {
+            Ok(file) => {
+                let mut windows = Vec::new();
+                for entry in file.windows {
+                    let (start, end) = (entry.start.clone(), entry.end.clone());
+                    match Window::parse(entry) {
+                        Some(window) => windows.push(window),
+                        None => warn!("bandwidth: ignoring invalid window {}-{}", start, end),
+                    }
+                }
+                Self { windows, ..Default::default() }
+            },
+            Err(error) => {
+                warn!("ignoring {}: {}", path.display(), error);
+                Self::default()
+            },
+        }
+    }
+
+    // Bytes per second allowed right now, the windows are evaluated on every call so a
+    // long transfer picks up the next window as soon as it starts.
+    pub fn rate(&self) -> Option<u64> {
+        let now = Local::now();
+        self.windows.iter().find(|window| window.contains(&now)).map(|window| window.rate).filter(|rate| *rate > 0)
+    }
+
+    // How long to wait before sending `bytes`, transfers sharing the throttle queue up behind
+    // each other.
+    pub fn delay(&self, bytes: u64) -> Duration {
+        let rate = match self.rate() {
+            Some(rate) => rate,
+            None => return Duration::ZERO,
+        };
+
+        let now = Instant::now();
+        let mut next_free = self.next_free.lock().unwrap();
+        let start = next_free.filter(|next_free| *next_free > now).unwrap_or(now);
+        *next_free = Some(start + Duration::from_secs_f64(bytes as f64 / rate as f64));
+
+        start - now
+    }
+}
\ No newline at end of file
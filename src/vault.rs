@@ -0,0 +1,118 @@
+// keep credential files in a single file encrypted with a key derived from a passphrase
+// Path: src/vault.rs
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+pub const VAULT_FILE_NAME: &str = "credentials.vault";
+const MAGIC: &[u8] = b"orbital-vault-1\n";
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+
+// Credential files by file name, e.g. `work.GoogleDrive`, with the content they would have in
+// the data directory. On disk the magic, the salt of the key and the nonce come before the
+// encrypted JSON.
+#[derive(Default)]
+pub struct Vault {
+    pub files: BTreeMap<String, String>,
+}
+
+impl Vault {
+    pub fn path(data_dir: &Path) -> PathBuf {
+        data_dir.join(VAULT_FILE_NAME)
+    }
+
+    pub fn open(path: &Path, passphrase: &str) -> io::Result<Self> {
+        let content = fs::read(path)?;
+        let rest = content.strip_prefix(MAGIC).ok_or_else(|| invalid("not a credential store"))?;
+        if rest.len() < SALT_SIZE + NONCE_SIZE {
+            return Err(invalid("truncated credential store"));
+        }
+        let (salt, rest) = rest.split_at(SALT_SIZE);
+        let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+
+        let plaintext = cipher(passphrase, salt)?.decrypt(XNonce::from_slice(nonce), ciphertext).map_err(|_| invalid("wrong passphrase"))?;
+        let files = serde_json::from_slice(&plaintext)?;
+        Ok(Self { files })
+    }
+
+    // With a new salt and nonce every time, replacing the previous store in one step.
+    pub fn save(&self, path: &Path, passphrase: &str) -> io::Result<()> {
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let plaintext = serde_json::to_vec(&self.files)?;
+        let ciphertext = cipher(passphrase, &salt)?.encrypt(&nonce, plaintext.as_slice()).map_err(|_| io::Error::new(io::ErrorKind::Other, "unable to encrypt the credential store"))?;
+
+        let mut content = MAGIC.to_vec();
+        content.extend_from_slice(&salt);
+        content.extend_from_slice(&nonce);
+        content.extend_from_slice(&ciphertext);
+
+        let partial = path.with_extension("vault.partial");
+        OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&partial)?.write_all(&content)?;
+        fs::rename(partial, path)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> io::Result<XChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key).map_err(|error| io::Error::new(io::ErrorKind::Other, error.to_string()))?;
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+// Read from the key file when there is one, asked on the terminal otherwise.
+pub fn passphrase(key_file: Option<&Path>, prompt: &str) -> io::Result<String> {
+    let passphrase = match key_file {
+        Some(path) => fs::read_to_string(path)?.trim_end_matches(['\n', '\r']).to_string(),
+        None => read_hidden(prompt)?,
+    };
+    if passphrase.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty passphrase"));
+    }
+    Ok(passphrase)
+}
+
+// Asked twice on the terminal, a typo would lock the store for good.
+pub fn new_passphrase(key_file: Option<&Path>) -> io::Result<String> {
+    let passphrase = passphrase(key_file, "new passphrase of the credential store: ")?;
+    if key_file.is_none() && read_hidden("same passphrase again: ")? != passphrase {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the passphrases differ"));
+    }
+    Ok(passphrase)
+}
+
+// Without echo when stdin is a terminal.
+fn read_hidden(prompt: &str) -> io::Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    let terminal = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } == 0;
+    if terminal {
+        let mut hidden = termios;
+        hidden.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &hidden) };
+    }
+
+    let mut line = String::new();
+    let result = io::stdin().lock().read_line(&mut line);
+    if terminal {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
+        eprintln!();
+    }
+    result?;
+
+    Ok(line.trim_end_matches(['\n', '\r']).to_string())
+}
\ No newline at end of file